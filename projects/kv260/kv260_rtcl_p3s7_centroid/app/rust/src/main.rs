@@ -7,8 +7,11 @@ use opencv::core::*;
 use jelly_lib::linux_i2c::LinuxI2c;
 use jelly_mem_access::*;
 
-use rtcl_p3s7_shared::camera_driver::CameraDriver;
+use rtcl_p3s7_shared::camera_driver::{frame_count_add, CameraDriver, SENSOR_HEIGHT, SENSOR_WIDTH};
 use rtcl_p3s7_shared::capture_driver::CaptureDriver;
+use rtcl_p3s7_shared::defect_map::DefectMap;
+use rtcl_p3s7_shared::peripheral::{CoreKind, DesignDesc, Peripherals};
+use rtcl_p3s7_shared::roi_tracker::{centroid_u16, RoiTracker};
 use rtcl_p3s7_shared::timing_generator_driver::TimingGeneratorDriver;
 
 
@@ -118,6 +121,14 @@ struct Args {
     /// Enable color mode (default: monochrome)
     #[arg(long="pgood-off", default_value_t = false)]
     pgood_off: bool,
+
+    /// Defect pixel map file (default: defect_map_<module id>.txt)
+    #[arg(long="defect-map")]
+    defect_map: Option<String>,
+
+    /// Move the sensor ROI to follow the target (toggle with 't')
+    #[arg(long, default_value_t = false)]
+    track: bool,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -154,38 +165,27 @@ fn main() -> Result<(), Box<dyn Error>> {
         udmabuf_acc.size()
     );
 
-    // UIO
+    // UIO (各ブロックの CORE_ID をビットストリームと照合)
     println!("\nuio open");
-    let uio_acc = UioAccessor::<usize>::new_with_name("uio_pl_peri").expect("Failed to open uio");
-    println!("uio_pl_peri phys addr : 0x{:x}", uio_acc.phys_addr());
-    println!("uio_pl_peri size      : 0x{:x}", uio_acc.size());
-
-    let reg_sys      = uio_acc.subclone(0x0000_0000, 0x400);
-    let reg_timgen   = uio_acc.subclone(0x0001_0000, 0x400);
-    let reg_fmtr     = uio_acc.subclone(0x0010_0000, 0x400);
-    let reg_wdma_img = uio_acc.subclone(0x0021_0000, 0x400);
-//  let reg_wdma_blk = uio_acc.subclone(0x0022_0000, 0x400);
-    let reg_log_c    = uio_acc.subclone(0x0030_0000, 0x400);
-    let reg_log_m    = uio_acc.subclone(0x0032_0000, 0x400);
-//  let reg_log_lin  = uio_acc.subclone(0x0032_0000, 0x400);
-    let reg_gauss    = uio_acc.subclone(0x0040_1000, 0x400);
-    let reg_clamp    = uio_acc.subclone(0x0040_2000, 0x400);
-    let reg_rect     = uio_acc.subclone(0x0040_3000, 0x400);
-    let reg_moment   = uio_acc.subclone(0x0040_4000, 0x400);
-    let reg_sel      = uio_acc.subclone(0x0040_f000, 0x400);
-    let reg_uart     = uio_acc.subclone(0x0050_0000, 0x400);
+    let design = DesignDesc::from_toml_str(include_str!("../../kv260_rtcl_p3s7_centroid.peripherals.toml"))?;
+    let peri = Peripherals::discover(&design)?;
+    print!("{}", peri);
+
+    let reg_sys      = peri.block("sys",      CoreKind::System)?;
+    let reg_timgen   = peri.block("timgen",   CoreKind::TimingGenerator)?;
+    let reg_fmtr     = peri.block("fmtr",     CoreKind::FormatRegularizer)?;
+    let reg_wdma_img = peri.block("wdma_img", CoreKind::VideoDmaWrite)?;
+    let reg_log_c    = peri.block("log_c",    CoreKind::DataLogger)?;
+    let reg_log_m    = peri.block("log_m",    CoreKind::DataLogger)?;
+    let reg_gauss    = peri.block("gauss",    CoreKind::Gaussian)?;
+    let reg_clamp    = peri.block("clamp",    CoreKind::Other)?;
+    let reg_rect     = peri.block("rect",     CoreKind::Other)?;
+    let reg_moment   = peri.block("moment",   CoreKind::Moment)?;
+    let reg_sel      = peri.block("sel",      CoreKind::Other)?;
+    let reg_uart     = peri.block("uart",     CoreKind::Other)?;
 
     let uio_ocm = UioAccessor::<u64>::new_with_name("uio_ocm").expect("Failed to open uio_ocm");
 
-    println!("CORE ID");
-    println!("reg_sys      : {:08x}", unsafe { reg_sys.read_reg(0) });
-    println!("reg_timgen   : {:08x}", unsafe { reg_timgen.read_reg(0) });
-    println!("reg_fmtr     : {:08x}", unsafe { reg_fmtr.read_reg(0) });
-    println!("reg_wdma_img : {:08x}", unsafe { reg_wdma_img.read_reg(0) });
-    println!("reg_gauss    : {:08x}", unsafe { reg_gauss.read_reg(0) });
-    println!("reg_rect     : {:08x}", unsafe { reg_rect.read_reg(0) });
-    println!("reg_moment   : {:08x}", unsafe { reg_moment.read_reg(0) });
-
     if false {
         for _ in 0..10 {
             // 1ms おきにループして円を描く
@@ -229,6 +229,19 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut video_capture = CaptureDriver::new(reg_wdma_img, udmabuf_acc.clone())?;
 
+    // 欠陥画素マップ
+    let defect_map_path = match &args.defect_map {
+        Some(path) => std::path::PathBuf::from(path),
+        None => DefectMap::module_path(".", cam.module_id()?),
+    };
+    let mut defect_map = match DefectMap::load(&defect_map_path) {
+        Ok(map) if map.width() == width && map.height() == height => {
+            println!("defect map : {} ({} pixels)", defect_map_path.display(), map.len());
+            Some(map)
+        }
+        _ => None,
+    };
+
     // ウィンドウ作成
     highgui::named_window("img", highgui::WINDOW_AUTOSIZE)?;
     highgui::resize_window("img", width as i32 + 64, height as i32 + 256)?;
@@ -260,6 +273,10 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut track_x: f64 = 0.0;
     let mut track_y: f64 = 0.0;
 
+    // ROI 追従
+    let mut tracking = args.track;
+    let mut roi_tracker = RoiTracker::new(&cam);
+
     // 初期値設定
     unsafe {
         reg_clamp.write_reg(REG_IMG_CLAMP_PARAM_INV, 1);
@@ -320,11 +337,16 @@ fn main() -> Result<(), Box<dyn Error>> {
         let prj_offset_x = get_cv_trackbar_pos("pox")? as f64;
         let prj_offset_y = get_cv_trackbar_pos("poy")? as f64;
 
+        // 重心をセンサー中心基準の座標で出すように ROI 位置を反映
+        let roi = roi_tracker.placement();
+        let offset_x = (roi.x as f64 - 0.5 * SENSOR_WIDTH as f64) / roi.factor as f64;
+        let offset_y = (roi.y as f64 - 0.5 * SENSOR_HEIGHT as f64) / roi.factor as f64;
+
         unsafe {
             reg_sel.write_reg(REG_IMG_SELECTOR_CTL_SELECT, 3);
 
-            uio_ocm.write_reg_f64(OCM_OFFSET_X, -0.5 * width as f64);
-            uio_ocm.write_reg_f64(OCM_OFFSET_Y, -0.5 * height as f64);
+            uio_ocm.write_reg_f64(OCM_OFFSET_X, offset_x);
+            uio_ocm.write_reg_f64(OCM_OFFSET_Y, offset_y);
             uio_ocm.write_reg_f64(OCM_M00_LIMIT, m00_lim);
             uio_ocm.write_reg_f64(OCM_PRJ_GIAN_X, prj_gain_x);
             uio_ocm.write_reg_f64(OCM_PRJ_GIAN_Y, prj_gain_y);
@@ -340,12 +362,33 @@ fn main() -> Result<(), Box<dyn Error>> {
         timgen.set_timing(period_us, exposure_us)?;
 
         // CaptureDriver で 1frame キャプチャ
+        let frame_count = frame_count_add(cam.frame_count(), 1);
         video_capture.record(width, height, 1)?;
-        let img = video_capture.read_image_mat(0)?;
+
+        let mut img = video_capture.read_image_mat(0)?;
+        if let Some(map) = &defect_map {
+            map.correct_mat(&mut img)?;
+        }
+
+        // 欠陥画素補正後の画像から重心を求める
+        // (PL の moment コアと logger は補正前の画素で計算するので輝点の影響を受ける)
+        let target = centroid_u16(img.data_typed::<u16>()?, width, height, clamp_min as u16);
+
+        // 撮影時の ROI で重心をセンサー座標に戻して ROI を動かす
+        if tracking {
+            let prev = roi_tracker.placement();
+            let roi = roi_tracker.update_from_image(&mut cam, frame_count, target)?;
+            if roi != prev {
+                println!("roi : ({}, {})", roi.x, roi.y);
+            }
+        }
 
         // 10bit 画像なので加工して表示
         let mut view = Mat::default();
         img.convert_to(&mut view, CV_16U, 64.0, 0.0)?;
+        if let Some((x, y)) = target {
+            imgproc::draw_marker(&mut view, Point::new(x.round() as i32, y.round() as i32), Scalar::all(65535.0), imgproc::MARKER_CROSS, 16, 1, imgproc::LINE_8)?;
+        }
         highgui::imshow("img", &view)?;
 
         // moment ログ取得
@@ -431,6 +474,16 @@ fn main() -> Result<(), Box<dyn Error>> {
                 }
             }
 
+            't' => {
+                tracking = !tracking;
+                if !tracking {
+                    // 中央に戻す
+                    cam.set_roi_offset(None, None)?;
+                    roi_tracker = RoiTracker::new(&cam);
+                }
+                let (x, y) = cam.roi_offset();
+                println!("roi tracking : {} ({}, {})", tracking, x, y);
+            },
             'q' => { break; },
             'p' => {
                 println!("camera module id      : {:04x}", cam.module_id()?);
@@ -443,6 +496,15 @@ fn main() -> Result<(), Box<dyn Error>> {
                 println!("write : dump.png");
                 imgcodecs::imwrite("dump.png", &view, &Vector::<i32>::new())?;
             },
+            'h' => {  // 輝点キャリブレーション (センサを遮光して実行)
+                video_capture.record(width, height, 16)?;
+                let dark = video_capture.average_frames(0, 16)?;
+                let mut map = DefectMap::new(width, height, false);
+                let n = map.detect_hot(&dark, 32.0)?;
+                map.save(&defect_map_path)?;
+                println!("hot pixels : {} -> {}", n, defect_map_path.display());
+                defect_map = Some(map);
+            },
             'r' => {  // 動画記録
                 // 日時のディレクトリを生成
                 let now = chrono::Local::now();
@@ -455,7 +517,10 @@ fn main() -> Result<(), Box<dyn Error>> {
                 let frames = args.rec_frames;
                 video_capture.record(width, height, frames)?;
                 for f in 0..frames {
-                   let img = video_capture.read_image_mat(f)?;
+                    let mut img = video_capture.read_image_mat(f)?;
+                    if let Some(map) = &defect_map {
+                        map.correct_mat(&mut img)?;
+                    }
                     let mut view = Mat::default();
                     img.convert_to(&mut view, CV_16U, 64.0, 0.0)?;
                     let file_name = format!("{}/img{:04}.png", dir_name, f);
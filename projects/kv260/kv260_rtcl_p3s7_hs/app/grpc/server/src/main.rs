@@ -1,6 +1,6 @@
 
 use tonic::{transport::Server, Request, Response, Status};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use clap::Parser;
 
 use rtcl_p3s7_control::rtcl_p3s7_control_server::{RtclP3s7Control, RtclP3s7ControlServer};
@@ -31,7 +31,7 @@ pub mod rtcl_p3s7_control {
 
 pub struct RtclP3s7ControlService {
     verbose: i32,
-    mng : Arc<Mutex<RtclP3s7Mng>>,
+    mng : Arc<RtclP3s7Mng>,
 }
 
 
@@ -51,8 +51,8 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn camera_open(&self, request: Request<Empty>) -> Result<Response<BoolResponse>, Status> {
         let _req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
-        match mng.cam_mut().open() {
+        let mng = &self.mng;
+        match mng.camera_open() {
             Ok(()) => {
                 if self.verbose >= 1 {
                     println!("camera_open()");
@@ -70,8 +70,8 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn camera_close(&self, request: Request<Empty>) -> Result<Response<BoolResponse>, Status> {
         let _req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
-        match mng.cam_mut().close() {
+        let mng = &self.mng;
+        match mng.camera_close() {
             Ok(()) => {
                 if self.verbose >= 1 {
                     println!("camera_close()");
@@ -88,8 +88,8 @@ impl RtclP3s7Control for RtclP3s7ControlService {
     }
 
     async fn camera_is_opened(&self, _request: Request<Empty>) -> Result<Response<BoolResponse>, Status> {
-        let mng = self.mng.lock().unwrap();
-        let result = mng.camera_is_opened();
+        let mng = &self.mng;
+        let result = mng.camera_is_opened().unwrap_or(false);
         if self.verbose >= 1 {
             println!("camera_is_opened() => {}", result);
         }
@@ -97,7 +97,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
     }
 
     async fn camera_get_module_id(&self, _request: Request<Empty>) -> Result<Response<U16Response>, Status> {
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_get_module_id() {
             Ok(value) => {
                 if self.verbose >= 1 {
@@ -115,7 +115,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
     }
 
     async fn camera_get_module_version(&self, _request: Request<Empty>) -> Result<Response<U16Response>, Status> {
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_get_module_version() {
             Ok(value) => {
                 if self.verbose >= 1 {
@@ -133,7 +133,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
     }
 
     async fn camera_get_sensor_id(&self, _request: Request<Empty>) -> Result<Response<U16Response>, Status> {
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_get_sensor_id() {
             Ok(value) => {
                 if self.verbose >= 1 {
@@ -152,7 +152,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn camera_set_slave_mode(&self, request: Request<BoolRequest>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_set_slave_mode(req.value) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -171,7 +171,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn camera_set_trigger_mode(&self, request: Request<BoolRequest>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_set_trigger_mode(req.value) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -190,17 +190,17 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn camera_set_color(&self, request: Request<BoolRequest>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
-        mng.camera_set_color(req.value);
+        let mng = &self.mng;
+        let result = mng.camera_set_color(req.value).is_ok();
         if self.verbose >= 1 {
             println!("camera_set_color({})", req.value);
         }
-        Ok(Response::new(BoolResponse { result: true }))
+        Ok(Response::new(BoolResponse { result }))
     }
 
     async fn camera_set_image_size(&self, request: Request<ImageSizeRequest>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_set_image_size(req.width as usize, req.height as usize) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -219,7 +219,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn camera_set_black_lines(&self, request: Request<U16Request>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_set_black_lines(req.value as u16) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -238,7 +238,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn camera_set_xsm_delay(&self, request: Request<U16Request>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_set_xsm_delay(req.value as u16) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -256,26 +256,32 @@ impl RtclP3s7Control for RtclP3s7ControlService {
     }
 
     async fn camera_get_image_width(&self, _request: Request<Empty>) -> Result<Response<U64Response>, Status> {
-        let mng = self.mng.lock().unwrap();
-        let value = mng.camera_get_image_width();
+        let mng = &self.mng;
+        let (result, value) = match mng.camera_get_image_width() {
+            Ok(value) => (true, value),
+            Err(_) => (false, 0),
+        };
         if self.verbose >= 1 {
             println!("camera_get_image_width() => {}", value);
         }
-        Ok(Response::new(U64Response { result: true, value: value as u64 }))
+        Ok(Response::new(U64Response { result, value: value as u64 }))
     }
 
     async fn camera_get_image_height(&self, _request: Request<Empty>) -> Result<Response<U64Response>, Status> {
-        let mng = self.mng.lock().unwrap();
-        let value = mng.camera_get_image_height();
+        let mng = &self.mng;
+        let (result, value) = match mng.camera_get_image_height() {
+            Ok(value) => (true, value),
+            Err(_) => (false, 0),
+        };
         if self.verbose >= 1 {
             println!("camera_get_image_height() => {}", value);
         }
-        Ok(Response::new(U64Response { result: true, value: value as u64 }))
+        Ok(Response::new(U64Response { result, value: value as u64 }))
     }
 
     async fn camera_set_gain(&self, request: Request<F32Request>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_set_gain(req.value) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -293,17 +299,20 @@ impl RtclP3s7Control for RtclP3s7ControlService {
     }
 
     async fn camera_get_gain(&self, _request: Request<Empty>) -> Result<Response<F32Response>, Status> {
-        let mng = self.mng.lock().unwrap();
-        let value = mng.camera_get_gain();
+        let mng = &self.mng;
+        let (result, value) = match mng.camera_get_gain() {
+            Ok(value) => (true, value),
+            Err(_) => (false, 0.0),
+        };
         if self.verbose >= 1 {
             println!("camera_get_gain() => {}", value);
         }
-        Ok(Response::new(F32Response { result: true, value }))
+        Ok(Response::new(F32Response { result, value }))
     }
 
     async fn camera_set_exposure(&self, request: Request<F32Request>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_set_exposure(req.value) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -321,7 +330,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
     }
 
     async fn camera_get_exposure(&self, _request: Request<Empty>) -> Result<Response<F32Response>, Status> {
-        let mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.camera_get_exposure() {
             Ok(value) => {
                 if self.verbose >= 1 {
@@ -339,21 +348,27 @@ impl RtclP3s7Control for RtclP3s7ControlService {
     }
 
     async fn camera_measure_fps(&self, _request: Request<Empty>) -> Result<Response<F32Response>, Status> {
-        let mng = self.mng.lock().unwrap();
-        let value = mng.camera_measure_fps();
+        let mng = &self.mng;
+        let (result, value) = match mng.camera_measure_fps() {
+            Ok(value) => (true, value),
+            Err(_) => (false, 0.0),
+        };
         if self.verbose >= 1 {
             println!("camera_measure_fps() => {}", value);
         }
-        Ok(Response::new(F32Response { result: true, value }))
+        Ok(Response::new(F32Response { result, value }))
     }
 
     async fn camera_measure_frame_period(&self, _request: Request<Empty>) -> Result<Response<F32Response>, Status> {
-        let mng = self.mng.lock().unwrap();
-        let value = mng.camera_measure_frame_period();
+        let mng = &self.mng;
+        let (result, value) = match mng.camera_measure_frame_period() {
+            Ok(value) => (true, value),
+            Err(_) => (false, 0.0),
+        };
         if self.verbose >= 1 {
             println!("camera_measure_frame_period() => {}", value);
         }
-        Ok(Response::new(F32Response { result: true, value }))
+        Ok(Response::new(F32Response { result, value }))
     }
 
 
@@ -361,8 +376,36 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn record_image(&self, request: Request<RecordImageRequest>) -> Result<Response<U64Response>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
-        match mng.record_image(req.width as usize, req.height as usize, req.frames as usize) {
+        // 録画中もロックを手放し、他の操作を受け付ける
+        let started = self.mng
+            .start_record_image(req.width as usize, req.height as usize, req.frames as usize)
+            .map_err(|e| e.to_string());
+        let timeout = self.mng.record_image_timeout(req.frames as usize).map_err(|e| e.to_string());
+        let result = match (started, timeout) {
+            (Ok(frames), Ok(timeout)) => {
+                let start = std::time::Instant::now();
+                loop {
+                    let done = self.mng.poll_record_image().map_err(|e| e.to_string());
+                    match done {
+                        Ok(true) => break Ok(frames),
+                        Ok(false) => {}
+                        Err(e) => break Err(e),
+                    }
+                    // センサーが止まっていても RPC を返す
+                    if start.elapsed() > timeout {
+                        let _ = self.mng.cancel_record_image();
+                        break Err("recording timeout".to_string());
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                }
+            }
+            (Ok(_), Err(e)) => {
+                let _ = self.mng.cancel_record_image();
+                Err(e)
+            }
+            (Err(e), _) => Err(e),
+        };
+        match result {
             Ok(frames) => {
                 if self.verbose >= 1 {
                     println!("record_image: width={} height={} frames={}", req.width, req.height, req.frames);
@@ -380,7 +423,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn read_image(&self, request: Request<ReadImageRequest>) -> Result<Response<ReadImageResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.read_image(req.index as usize) {
             Ok(buf) => {
                 if self.verbose >= 1 {
@@ -400,7 +443,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn record_black(&self, request: Request<RecordImageRequest>) -> Result<Response<U64Response>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.record_black(req.width as usize, req.height as usize, req.frames as usize) {
             Ok(frames) => {
                 if self.verbose >= 1 {
@@ -419,7 +462,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn read_black(&self, request: Request<ReadImageRequest>) -> Result<Response<ReadImageResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.read_black(req.index as usize) {
             Ok(buf) => {
                 if self.verbose >= 1 {
@@ -442,7 +485,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn set_timing_generator(&self, request: Request<SetTimingGeneratorRequest>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.set_timing_generator(req.period_us, req.exposure_us) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -464,7 +507,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn write_sys_reg(&self, request: Request<WriteRegRequest>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.write_sys_reg(req.addr as usize, req.data as usize) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -483,7 +526,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn read_sys_reg(&self, request: Request<ReadRegRequest>) -> Result<Response<ReadRegResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.read_sys_reg(req.addr as usize) {
             Ok(data) => {
                 if self.verbose >= 1 {
@@ -504,7 +547,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn write_cam_reg(&self, request: Request<WriteRegRequest>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.write_cam_reg(req.addr as u16, req.data as u16) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -523,7 +566,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn read_cam_reg(&self, request: Request<ReadRegRequest>) -> Result<Response<ReadRegResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.read_cam_reg(req.addr as u16) {
             Ok(data) => {
                 if self.verbose >= 1 {
@@ -543,7 +586,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn write_sensor_reg(&self, request: Request<WriteRegRequest>) -> Result<Response<BoolResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.write_sensor_reg(req.addr as u16, req.data as u16) {
             Ok(()) => {
                 if self.verbose >= 1 {
@@ -562,7 +605,7 @@ impl RtclP3s7Control for RtclP3s7ControlService {
 
     async fn read_sensor_reg(&self, request: Request<ReadRegRequest>) -> Result<Response<ReadRegResponse>, Status> {
         let req = request.into_inner();
-        let mut mng = self.mng.lock().unwrap();
+        let mng = &self.mng;
         match mng.read_sensor_reg(req.addr as u16) {
             Ok(data) => {
                 if self.verbose >= 1 {
@@ -592,7 +635,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Starting RTCL P3S7 Control gRPC server...");
     println!("address : {}", address);
     println!("verbose : {}", args.verbose);
-    let mng = Arc::new(Mutex::new(RtclP3s7Mng::new()?));
+    let mng = Arc::new(RtclP3s7Mng::new()?);
+
+    // 設定変更のログ
+    if args.verbose >= 1 {
+        let changes = mng.subscribe();
+        std::thread::spawn(move || {
+            for change in changes {
+                println!("camera changed: {:?}", change);
+            }
+        });
+    }
 
     let rtcl_p3s7_control_service = RtclP3s7ControlService{
         verbose: args.verbose,
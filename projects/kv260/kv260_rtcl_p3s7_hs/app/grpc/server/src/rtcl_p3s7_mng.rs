@@ -1,27 +1,31 @@
 #![allow(unused)]
 
 use std::error::Error;
+use std::sync::Mutex;
+use std::sync::mpsc::Receiver;
 
 use jelly_lib::i2c_hal::I2cHal;
 use jelly_lib::linux_i2c::LinuxI2c;
 use jelly_mem_access::*;
 //use rtcl_lib::rtcl_p3s7_module_driver::*;
 use rtcl_p3s7_shared::*;
+use rtcl_p3s7_shared::camera_handle::ControlChange;
 
 type UioAccessor = jelly_mem_access::UioAccessor<usize>;
 type UdmabufAccessor = jelly_mem_access::UdmabufAccessor<usize>;
 type CameraDriver = camera_driver::CameraDriver<LinuxI2c, usize>;
 type CaptureDriver =  capture_driver::CaptureDriver<UioAccessor, UdmabufAccessor>;
+type CameraControl = camera_handle::CameraControl<LinuxI2c, usize>;
+type CameraCapture = camera_handle::CameraCapture<usize, UioAccessor, UdmabufAccessor>;
 type TimingGeneratorDriver = rtcl_p3s7_shared::timing_generator_driver::TimingGeneratorDriver<UioAccessor>;
 
+// 制御 (I2C)、キャプチャ (DMA)、タイミングジェネレータを別々のロックで持つ
 pub struct RtclP3s7Mng {
-    uio_acc : UioAccessor,
-    buf_img : UdmabufAccessor,
-    buf_blk : UdmabufAccessor,
-    cam: CameraDriver,
-    cap_img : CaptureDriver,
-    cap_blk : CaptureDriver,
-    timgen : TimingGeneratorDriver,
+    uio_acc : Mutex<UioAccessor>,
+    cam: CameraControl,
+    cap_img : CameraCapture,
+    cap_blk : CameraCapture,
+    timgen : Mutex<TimingGeneratorDriver>,
 }
 
 impl RtclP3s7Mng {
@@ -36,159 +40,187 @@ impl RtclP3s7Mng {
         let reg_wdma_blk = uio_acc.subclone(0x00220000, 0x400);
 
         let i2c = LinuxI2c::new("/dev/i2c-6", 0x10)?;
-        let cam = CameraDriver::new(i2c, reg_sys, reg_fmtr);
+        let cam = CameraControl::new(CameraDriver::new(i2c, reg_sys, reg_fmtr));
         let timgen = TimingGeneratorDriver::new(reg_timgen);
-        let cap_img = CaptureDriver::new(reg_wdma_img, buf_img.clone())?;
-        let cap_blk = CaptureDriver::new(reg_wdma_blk, buf_blk.clone())?;
+        let cap_img = cam.attach_capture(CaptureDriver::new(reg_wdma_img, buf_img)?);
+        let cap_blk = cam.attach_capture(CaptureDriver::new(reg_wdma_blk, buf_blk)?);
         Ok(RtclP3s7Mng {
-            uio_acc,
-            buf_img,
-            buf_blk,
+            uio_acc: Mutex::new(uio_acc),
             cam,
             cap_img,
             cap_blk,
-            timgen,
+            timgen: Mutex::new(timgen),
         })
     }
 
-    pub fn cam_mut(&mut self) -> &mut CameraDriver {
-        &mut self.cam
+    pub fn cam(&self) -> &CameraControl {
+        &self.cam
     }
 
-    pub fn cam(&self) -> &CameraDriver {
-        &self.cam
+    /// 設定変更の通知を受け取る
+    pub fn subscribe(&self) -> Receiver<ControlChange> {
+        self.cam.subscribe()
+    }
+
+    pub fn write_sys_reg(&self, addr: usize, data: usize) -> Result<(), Box<dyn Error>> {
+        let uio_acc = self.uio_acc.lock().map_err(|_| "uio lock poisoned")?;
+        unsafe{uio_acc.write_reg(addr, data)};
+        Ok(())
     }
 
-    pub fn timgen_mut(&mut self) -> &mut TimingGeneratorDriver {
-        &mut self.timgen
+    pub fn read_sys_reg(&self, addr : usize) -> Result<usize, Box<dyn Error>> {
+        let uio_acc = self.uio_acc.lock().map_err(|_| "uio lock poisoned")?;
+        Ok(unsafe{uio_acc.read_reg(addr)})
     }
 
-    pub fn cap_img_mut(&mut self) -> &mut CaptureDriver {
-        &mut self.cap_img
+    pub fn write_cam_reg(&self, addr: u16, data: u16) -> Result<(), Box<dyn Error>> {
+        Ok(self.cam.lock()?.cam_i2c_mut().write_i2c(addr, data)?)
     }
 
-    pub fn cap_blk_mut(&mut self) -> &mut CaptureDriver {
-        &mut self.cap_blk
+    pub fn read_cam_reg(&self, addr: u16) -> Result<u16, Box<dyn Error>> {
+        Ok(self.cam.lock()?.cam_i2c_mut().read_i2c(addr)?)
     }
 
-    pub fn write_sys_reg(&mut self, addr: usize, data: usize) -> Result<(), Box<dyn Error>> {
-        unsafe{self.uio_acc.write_reg(addr, data)};
-        Ok(())
+    pub fn write_sensor_reg(&self, addr: u16, data: u16) -> Result<(), Box<dyn Error>> {
+        Ok(self.cam.lock()?.cam_i2c_mut().write_sensor_spi(addr, data)?)
     }
 
-    pub fn read_sys_reg(&mut self, addr : usize) -> Result<usize, Box<dyn Error>> {
-        Ok(unsafe{self.uio_acc.read_reg(addr)})
+    pub fn read_sensor_reg(&self, addr: u16) -> Result<u16, Box<dyn Error>> {
+        Ok(self.cam.lock()?.cam_i2c_mut().read_sensor_spi(addr)?)
     }
 
-    pub fn write_cam_reg(&mut self, addr: u16, data: u16) -> Result<(), Box<dyn Error>> {
-        Ok(self.cam.cam_i2c_mut().write_i2c(addr, data)?)
+    pub fn record_image(&self, width: usize, height: usize, frames: usize) -> Result<usize, Box<dyn Error>> {
+        self.cap_img.record(width, height, frames)
     }
 
-    pub fn read_cam_reg(&mut self, addr: u16) -> Result<u16, Box<dyn Error>> {
-        Ok(self.cam.cam_i2c_mut().read_i2c(addr)?)
+    pub fn start_record_image(&self, width: usize, height: usize, frames: usize) -> Result<usize, Box<dyn Error>> {
+        self.cap_img.start_record(width, height, frames)
     }
 
-    pub fn write_sensor_reg(&mut self, addr: u16, data: u16) -> Result<(), Box<dyn Error>> {
-        Ok(self.cam.cam_i2c_mut().write_sensor_spi(addr, data)?)
+    pub fn poll_record_image(&self) -> Result<bool, Box<dyn Error>> {
+        self.cap_img.poll_record()
     }
 
-    pub fn read_sensor_reg(&mut self, addr: u16) -> Result<u16, Box<dyn Error>> {
-        Ok(self.cam.cam_i2c_mut().read_sensor_spi(addr)?)
+    pub fn record_image_progress(&self) -> Result<usize, Box<dyn Error>> {
+        self.cap_img.record_progress()
     }
 
-    pub fn record_image(&mut self, width: usize, height: usize, frames: usize) -> Result<usize, Box<dyn Error>> {
-        Ok(self.cap_img.record(width, height, frames)?)
+    pub fn cancel_record_image(&self) -> Result<usize, Box<dyn Error>> {
+        self.cap_img.cancel_record()
     }
 
-    pub fn read_image(&mut self, index : usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        Ok(self.cap_img.read_image_vec(index)?)
+    /// `frames` フレームの録画にかかる時間の上限 (現在のフレーム周期から算出)
+    pub fn record_image_timeout(&self, frames: usize) -> Result<std::time::Duration, Box<dyn Error>> {
+        let period_us = {
+            let mut cam = self.cam.lock()?;
+            if cam.trigger_mode() {
+                self.timgen.lock().map_err(|_| "timing generator lock poisoned")?.period_us()
+            } else {
+                cam.frame_period()? as f64
+            }
+        };
+        Ok(std::time::Duration::from_secs_f64(period_us.max(0.0) * (frames + 2) as f64 / 1_000_000.0)
+            + std::time::Duration::from_secs(1))
+    }
+
+    pub fn read_image(&self, index : usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.cap_img.read_image_vec(index)
     }
 
 
-    pub fn record_black(&mut self, width: usize, height: usize, frames: usize) -> Result<usize, Box<dyn Error>> {
-        Ok(self.cap_blk.record(width, height, frames)?)
+    pub fn record_black(&self, width: usize, height: usize, frames: usize) -> Result<usize, Box<dyn Error>> {
+        self.cap_blk.record(width, height, frames)
     }
 
-    pub fn read_black(&mut self, index: usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        Ok(self.cap_blk.read_image_vec(index)?)
+    pub fn read_black(&self, index: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.cap_blk.read_image_vec(index)
     }
 
     // Camera control methods
-    pub fn camera_is_opened(&self) -> bool {
-        self.cam.opend()
+    pub fn camera_open(&self) -> Result<(), Box<dyn Error>> {
+        self.cam.open()
+    }
+
+    pub fn camera_close(&self) -> Result<(), Box<dyn Error>> {
+        self.cam.close()
+    }
+
+    pub fn camera_is_opened(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.cam.lock()?.opend())
     }
 
-    pub fn camera_get_module_id(&mut self) -> Result<u16, Box<dyn Error>> {
-        self.cam.module_id()
+    pub fn camera_get_module_id(&self) -> Result<u16, Box<dyn Error>> {
+        self.cam.lock()?.module_id()
     }
 
-    pub fn camera_get_module_version(&mut self) -> Result<u16, Box<dyn Error>> {
-        self.cam.module_version()
+    pub fn camera_get_module_version(&self) -> Result<u16, Box<dyn Error>> {
+        self.cam.lock()?.module_version()
     }
 
-    pub fn camera_get_sensor_id(&mut self) -> Result<u16, Box<dyn Error>> {
-        self.cam.sensor_id()
+    pub fn camera_get_sensor_id(&self) -> Result<u16, Box<dyn Error>> {
+        self.cam.lock()?.sensor_id()
     }
 
-    pub fn camera_set_slave_mode(&mut self, enable: bool) -> Result<(), Box<dyn Error>> {
+    pub fn camera_set_slave_mode(&self, enable: bool) -> Result<(), Box<dyn Error>> {
         self.cam.set_slave_mode(enable)
     }
 
-    pub fn camera_set_trigger_mode(&mut self, enable: bool) -> Result<(), Box<dyn Error>> {
+    pub fn camera_set_trigger_mode(&self, enable: bool) -> Result<(), Box<dyn Error>> {
         self.cam.set_trigger_mode(enable)
     }
 
-    pub fn camera_set_color(&mut self, color: bool) {
+    pub fn camera_set_color(&self, color: bool) -> Result<(), Box<dyn Error>> {
         self.cam.set_color(color)
     }
 
-    pub fn camera_set_image_size(&mut self, width: usize, height: usize) -> Result<(), Box<dyn Error>> {
+    pub fn camera_set_image_size(&self, width: usize, height: usize) -> Result<(), Box<dyn Error>> {
         self.cam.set_image_size(width, height)
     }
 
-    pub fn camera_set_black_lines(&mut self, lines: u16) -> Result<(), Box<dyn Error>> {
-        self.cam.set_black_lines(lines as usize)
+    pub fn camera_set_black_lines(&self, lines: u16) -> Result<(), Box<dyn Error>> {
+        self.cam.update(|cam| cam.set_black_lines(lines as usize))
     }
 
-    pub fn camera_set_xsm_delay(&mut self, delay: u16) -> Result<(), Box<dyn Error>> {
-        self.cam.set_xsm_delay(delay)
+    pub fn camera_set_xsm_delay(&self, delay: u16) -> Result<(), Box<dyn Error>> {
+        self.cam.update(|cam| cam.set_xsm_delay(delay))
     }
 
-    pub fn camera_get_image_width(&self) -> usize {
-        self.cam.image_width()
+    pub fn camera_get_image_width(&self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.cam.lock()?.image_width())
     }
 
-    pub fn camera_get_image_height(&self) -> usize {
-        self.cam.image_height()
+    pub fn camera_get_image_height(&self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.cam.lock()?.image_height())
     }
 
-    pub fn camera_set_gain(&mut self, db: f32) -> Result<(), Box<dyn Error>> {
+    pub fn camera_set_gain(&self, db: f32) -> Result<(), Box<dyn Error>> {
         self.cam.set_gain(db)
     }
 
-    pub fn camera_get_gain(&self) -> f32 {
-        self.cam.gain()
+    pub fn camera_get_gain(&self) -> Result<f32, Box<dyn Error>> {
+        Ok(self.cam.lock()?.gain())
     }
 
-    pub fn camera_set_exposure(&mut self, us: f32) -> Result<(), Box<dyn Error>> {
+    pub fn camera_set_exposure(&self, us: f32) -> Result<(), Box<dyn Error>> {
         self.cam.set_exposure(us)
     }
 
     pub fn camera_get_exposure(&self) -> Result<f32, Box<dyn Error>> {
-        self.cam.exposure()
+        self.cam.lock()?.exposure()
     }
 
-    pub fn camera_measure_fps(&self) -> f32 {
+    // fps はレジスタを読むだけなので制御/キャプチャのロックを待たない
+    pub fn camera_measure_fps(&self) -> Result<f32, Box<dyn Error>> {
         self.cam.measure_fps()
     }
 
-    pub fn camera_measure_frame_period(&self) -> f32 {
+    pub fn camera_measure_frame_period(&self) -> Result<f32, Box<dyn Error>> {
         self.cam.measure_frame_period()
     }
 
     // Timing Generator control methods
-    pub fn set_timing_generator(&mut self, period_us: f32, exposure_us: f32) -> Result<(), Box<dyn Error>> {
-        self.timgen.set_timing(period_us, exposure_us)
+    pub fn set_timing_generator(&self, period_us: f32, exposure_us: f32) -> Result<(), Box<dyn Error>> {
+        self.timgen.lock().map_err(|_| "timing generator lock poisoned")?.set_timing(period_us, exposure_us)
     }
 }
 
@@ -9,6 +9,7 @@ use jelly_mem_access::*;
 
 use rtcl_p3s7_shared::camera_driver::*;
 use rtcl_p3s7_shared::capture_driver::*;
+use rtcl_p3s7_shared::frame_meta::CaptureSettings;
 use rtcl_p3s7_shared::timing_generator_driver::TimingGeneratorDriver;
 
 #[derive(Parser, Debug)]
@@ -207,12 +208,17 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
         
         // CaptureDriver で 1frame キャプチャ
+        let settings = CaptureSettings::from_camera(&mut cam, Some(&timgen))?;
         let mut cap_imgs = vec![Mat::default(); slots as usize];
         video_capture.record(width, height, slots as usize)?;
         for i in 0..slots {
+            // フレームヘッダのスロット番号で格納先を決める
+            let meta = video_capture.record_frame_meta(i as usize, &settings)?;
+            let idx = match meta.header.and_then(|h| h.slot_index()) {
+                Some(idx) if idx < slots => idx,
+                _ => continue,
+            };
             let src = video_capture.read_image_mat(i as usize)?;
-            // 先頭ピクセルの上位6bitにインデックス値が入っているので、画像化前に取り出す
-            let idx = (src.at_2d::<u16>(0, 0)? >> 10) as usize;
             let mut img = Mat::default();
             src.convert_to(&mut img, CV_16U, 65535.0/1023.0, 0.0)?;
 
@@ -341,11 +347,11 @@ fn main() -> Result<(), Box<dyn Error>> {
                 video_capture.record(width, height, (frames+1) * slots)?;
 
                 // idx 0 のフレームまで読み飛ばす
+                let settings = CaptureSettings::from_camera(&mut cam, Some(&timgen))?;
                 let mut start_frame = 0;
                 for i in 0..slots {
-                     let src = video_capture.read_image_mat(i as usize)?;
-                     let idx = (src.at_2d::<u16>(0, 0)? >> 10) as usize;
-                     if idx == 0 {
+                     let meta = video_capture.record_frame_meta(i as usize, &settings)?;
+                     if meta.header.and_then(|h| h.slot_index()) == Some(0) {
                          start_frame = i as usize;
                          break;
                      }
@@ -0,0 +1,323 @@
+use std::error::Error;
+use std::io::BufRead;
+use clap::Parser;
+
+use opencv::*;
+use opencv::core::*;
+
+use rtcl_p3s7_shared::board::Board;
+use rtcl_p3s7_shared::camera_driver::*;
+use rtcl_p3s7_shared::capture_driver::*;
+use rtcl_p3s7_shared::ptc::*;
+use rtcl_p3s7_shared::timing_generator_driver::TimingGeneratorDriver;
+
+/// PYTHON300 の光電変換特性 (Photon Transfer Curve) 測定ツール
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Image width in pixels
+    #[arg(short = 'W', long, default_value_t = 256)]
+    width: usize,
+
+    /// Image height in pixels
+    #[arg(short = 'H', long, default_value_t = 256)]
+    height: usize,
+
+    /// Frame period [us]
+    #[arg(long, default_value_t = 50000.0)]
+    period: f64,
+
+    /// Minimum exposure [us]
+    #[arg(long, default_value_t = 20.0)]
+    exposure_min: f64,
+
+    /// Maximum exposure [us]
+    #[arg(long, default_value_t = 40000.0)]
+    exposure_max: f64,
+
+    /// Number of exposure steps (log spaced)
+    #[arg(short = 'n', long, default_value_t = 40)]
+    steps: usize,
+
+    /// Analog gain [dB] (multiple allowed)
+    #[arg(short = 'g', long = "gain", default_values_t = vec![0.0])]
+    gains: Vec<f32>,
+
+    /// Frames discarded after changing exposure
+    #[arg(long, default_value_t = 3)]
+    skip: usize,
+
+    /// Output file prefix (<prefix>.csv, <prefix>_summary.csv, <prefix>.png)
+    #[arg(short = 'o', long, default_value = "ptc")]
+    output: String,
+
+    /// Skip dark measurement (dark level and noise are taken as zero)
+    #[arg(long = "no-dark", default_value_t = false)]
+    no_dark: bool,
+
+    /// Do not wait for Enter before the dark and light measurements
+    #[arg(short = 'y', long, default_value_t = false)]
+    yes: bool,
+
+    /// Seconds to wait before each measurement with --yes (e.g. to switch a light source)
+    #[arg(long, default_value_t = 0.0)]
+    delay: f64,
+
+    /// Board (kv260, zybo_z7, auto or board file)
+    #[arg(long, default_value = "kv260")]
+    board: String,
+
+    /// Disable sensor power good check
+    #[arg(long="pgood-off", default_value_t = false)]
+    pgood_off: bool,
+}
+
+// 測定前の準備待ち (--yes の場合は Enter を待たずに delay 秒待つ)
+fn wait_ready(args: &Args, msg: &str) -> Result<(), Box<dyn Error>> {
+    println!("{}", msg);
+    if args.yes {
+        std::thread::sleep(std::time::Duration::from_secs_f64(args.delay.max(0.0)));
+        return Ok(());
+    }
+    println!("press Enter");
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let width = (args.width + 15) & !0xf;  // 16ピクセル境界に合わせる
+    let height = (args.height + 1) & !0x01;  // 2ピクセル境界に合わせる
+    if args.steps < 3 || args.exposure_min <= 0.0 || args.exposure_max <= args.exposure_min {
+        return Err("invalid exposure sweep".into());
+    }
+
+    // 露光は対数間隔でスイープ
+    let exposures: Vec<f64> = (0..args.steps)
+        .map(|i| {
+            let r = i as f64 / (args.steps - 1) as f64;
+            args.exposure_min * (args.exposure_max / args.exposure_min).powf(r)
+        })
+        .collect();
+
+    println!("start rtcl_p3s7_ptc");
+    println!("  size:     {}x{}", width, height);
+    println!("  period:   {} us", args.period);
+    println!("  exposure: {} - {} us ({} steps)", args.exposure_min, args.exposure_max, args.steps);
+    println!("  gain:     {:?} dB", args.gains);
+
+    // ボードのデバイスを開く
+    let board = Board::select(Some(&args.board))?;
+    println!("  board:    {}", board.name);
+    let dev = board.open()?;
+
+    let mut timgen = TimingGeneratorDriver::new(dev.reg_timgen);
+    timgen.set_timing(args.period as f32, exposures[0] as f32)?;
+
+    // 露光はトリガパルス幅で制御する
+    let mut cam = board.camera_driver(dev.i2c, dev.reg_sys, dev.reg_fmtr);
+    if args.pgood_off {
+        cam.set_sensor_pgood_enable(false);
+    }
+    cam.set_image_size(width, height)?;
+    cam.set_slave_mode(true)?;
+    cam.set_trigger_mode(true)?;
+    cam.open()?;
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+
+    let mut video_capture = CaptureDriver::new(dev.reg_wdma_img, dev.buf_img.clone())?;
+
+    // 1 点分の画像ペア統計
+    let mut measure = |timgen: &mut TimingGeneratorDriver<_>, gain_db: f32, exposure_us: f64| -> Result<(f64, f64), Box<dyn Error>> {
+        cam.set_gain(gain_db)?;
+        timgen.set_timing(args.period as f32, exposure_us as f32)?;
+
+        // 新しい露光が周期境界で取り込まれるのを待ってから、切り替え直後のフレームを捨てる
+        let frame_timeout = std::time::Duration::from_secs_f64(args.period * 4.0 / 1_000_000.0);
+        timgen.wait_update(frame_timeout)?;
+        let start_count = cam.frame_count();
+        let start = std::time::Instant::now();
+        while frame_count_diff(cam.frame_count(), start_count) < args.skip {
+            if start.elapsed() > frame_timeout * (args.skip as u32 + 1) {
+                return Err("frame count is not advancing".into());
+            }
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+
+        let frames = video_capture.record(width, height, 2)?;
+        if frames < 2 {
+            return Err("dmabuf is too small".into());
+        }
+        let a = video_capture.frame(0)?.to_u16();
+        let b = video_capture.frame(1)?.to_u16();
+        pair_stats(&a, &b)
+    };
+
+    // 暗画像
+    let mut dark = vec![vec![(0.0, 0.0); exposures.len()]; args.gains.len()];
+    if !args.no_dark {
+        wait_ready(&args, "\ncover the sensor")?;
+        for (g, &gain_db) in args.gains.iter().enumerate() {
+            for (e, &exposure_us) in exposures.iter().enumerate() {
+                dark[g][e] = measure(&mut timgen, gain_db, exposure_us)?;
+                println!("dark  gain:{:5.1}dB exposure:{:10.1}us mean:{:8.2} var:{:8.3}",
+                        gain_db, exposure_us, dark[g][e].0, dark[g][e].1);
+            }
+        }
+    }
+
+    // 明画像
+    wait_ready(&args, "\nilluminate the sensor uniformly")?;
+    let mut samples = Vec::new();
+    let mut results = Vec::new();
+    for (g, &gain_db) in args.gains.iter().enumerate() {
+        let mut gain_samples = Vec::new();
+        for (e, &exposure_us) in exposures.iter().enumerate() {
+            let (mean, variance) = measure(&mut timgen, gain_db, exposure_us)?;
+            let s = PtcSample {
+                gain_db,
+                exposure_us,
+                mean,
+                variance,
+                dark_mean: dark[g][e].0,
+                dark_variance: dark[g][e].1,
+            };
+            println!("light gain:{:5.1}dB exposure:{:10.1}us mean:{:8.2} var:{:8.3}",
+                    gain_db, exposure_us, mean, variance);
+            gain_samples.push(s);
+        }
+
+        match analyze(&gain_samples) {
+            Ok(r) => {
+                println!("\ngain {} dB", gain_db);
+                println!("  conversion gain : {:.4} DN/e-", r.conversion_gain);
+                println!("  dark noise      : {:.3} DN ({:.2} e-)", r.dark_noise_dn, r.dark_noise_e);
+                println!("  saturation      : {:.1} DN", r.saturation_dn);
+                println!("  full well       : {:.0} e-", r.full_well_e);
+                println!("  dynamic range   : {:.1} dB", r.dynamic_range_db);
+                println!("  linearity error : {:.2} .. {:.2} %", r.linearity_error_min, r.linearity_error_max);
+                results.push(r);
+            }
+            Err(err) => println!("\ngain {} dB: analysis failed: {}", gain_db, err),
+        }
+        samples.extend(gain_samples);
+    }
+
+    cam.close()?;
+
+    write_samples_csv(format!("{}.csv", args.output), &samples)?;
+    write_results_csv(format!("{}_summary.csv", args.output), &results)?;
+    let plot = plot_ptc(&samples, &args.gains)?;
+    imgcodecs::imwrite(&format!("{}.png", args.output), &plot, &Vector::<i32>::new())?;
+    println!("\nsaved {}.csv, {}_summary.csv, {}.png", args.output, args.output, args.output);
+
+    Ok(())
+}
+
+
+const PLOT_W: i32 = 640;
+const PLOT_H: i32 = 480;
+const PLOT_MARGIN: i32 = 60;
+
+const PLOT_COLORS: [(f64, f64, f64); 6] = [
+    (255.0, 0.0, 0.0),
+    (0.0, 160.0, 0.0),
+    (0.0, 0.0, 255.0),
+    (200.0, 0.0, 200.0),
+    (0.0, 160.0, 200.0),
+    (128.0, 128.0, 0.0),
+];
+
+/// 対数軸の 1 パネル
+struct LogPanel {
+    x0: i32,
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+}
+
+impl LogPanel {
+    fn new(x0: i32, points: &[(f64, f64)]) -> Self {
+        let (mut x_min, mut x_max, mut y_min, mut y_max) = (f64::MAX, f64::MIN, f64::MAX, f64::MIN);
+        for &(x, y) in points.iter().filter(|p| p.0 > 0.0 && p.1 > 0.0) {
+            x_min = x_min.min(x.log10());
+            x_max = x_max.max(x.log10());
+            y_min = y_min.min(y.log10());
+            y_max = y_max.max(y.log10());
+        }
+        if x_min >= x_max { x_min = 0.0; x_max = 1.0; }
+        if y_min >= y_max { y_min = 0.0; y_max = 1.0; }
+        Self { x0, x_min: x_min.floor(), x_max: x_max.ceil(), y_min: y_min.floor(), y_max: y_max.ceil() }
+    }
+
+    fn point(&self, x: f64, y: f64) -> Point {
+        let w = (PLOT_W - 2 * PLOT_MARGIN) as f64;
+        let h = (PLOT_H - 2 * PLOT_MARGIN) as f64;
+        let px = (x.log10() - self.x_min) / (self.x_max - self.x_min) * w;
+        let py = (y.log10() - self.y_min) / (self.y_max - self.y_min) * h;
+        Point::new(self.x0 + PLOT_MARGIN + px as i32, PLOT_H - PLOT_MARGIN - py as i32)
+    }
+
+    fn draw_axes(&self, img: &mut Mat, title: &str, x_label: &str, y_label: &str) -> Result<(), Box<dyn Error>> {
+        let black = Scalar::new(0.0, 0.0, 0.0, 0.0);
+        let gray = Scalar::new(210.0, 210.0, 210.0, 0.0);
+        let left = self.x0 + PLOT_MARGIN;
+        let right = self.x0 + PLOT_W - PLOT_MARGIN;
+        let top = PLOT_MARGIN;
+        let bottom = PLOT_H - PLOT_MARGIN;
+
+        // デケード毎のグリッド
+        for d in self.x_min as i32..=self.x_max as i32 {
+            let p = self.point(10f64.powi(d), 10f64.powf(self.y_min));
+            imgproc::line(img, Point::new(p.x, top), Point::new(p.x, bottom), gray, 1, imgproc::LINE_8, 0)?;
+            imgproc::put_text(img, &format!("1e{}", d), Point::new(p.x - 12, bottom + 16),
+                    imgproc::FONT_HERSHEY_SIMPLEX, 0.4, black, 1, imgproc::LINE_AA, false)?;
+        }
+        for d in self.y_min as i32..=self.y_max as i32 {
+            let p = self.point(10f64.powf(self.x_min), 10f64.powi(d));
+            imgproc::line(img, Point::new(left, p.y), Point::new(right, p.y), gray, 1, imgproc::LINE_8, 0)?;
+            imgproc::put_text(img, &format!("1e{}", d), Point::new(left - 40, p.y + 4),
+                    imgproc::FONT_HERSHEY_SIMPLEX, 0.4, black, 1, imgproc::LINE_AA, false)?;
+        }
+        imgproc::rectangle(img, Rect::new(left, top, right - left, bottom - top), black, 1, imgproc::LINE_8, 0)?;
+        imgproc::put_text(img, title, Point::new(left, top - 20),
+                imgproc::FONT_HERSHEY_SIMPLEX, 0.6, black, 1, imgproc::LINE_AA, false)?;
+        imgproc::put_text(img, x_label, Point::new((left + right) / 2 - 40, bottom + 40),
+                imgproc::FONT_HERSHEY_SIMPLEX, 0.5, black, 1, imgproc::LINE_AA, false)?;
+        imgproc::put_text(img, y_label, Point::new(left, top - 4),
+                imgproc::FONT_HERSHEY_SIMPLEX, 0.5, black, 1, imgproc::LINE_AA, false)?;
+        Ok(())
+    }
+}
+
+/// 左: 信号 vs 時間ノイズ (PTC), 右: 露光 vs 信号 (直線性)
+fn plot_ptc(samples: &[PtcSample], gains: &[f32]) -> Result<Mat, Box<dyn Error>> {
+    let mut img = Mat::new_rows_cols_with_default(PLOT_H, PLOT_W * 2, CV_8UC3, Scalar::all(255.0))?;
+
+    let ptc_points: Vec<(f64, f64)> = samples.iter().map(|s| (s.signal(), s.variance.max(0.0).sqrt())).collect();
+    let lin_points: Vec<(f64, f64)> = samples.iter().map(|s| (s.exposure_us, s.signal())).collect();
+    let ptc_panel = LogPanel::new(0, &ptc_points);
+    let lin_panel = LogPanel::new(PLOT_W, &lin_points);
+    ptc_panel.draw_axes(&mut img, "photon transfer", "signal [DN]", "noise [DN]")?;
+    lin_panel.draw_axes(&mut img, "linearity", "exposure [us]", "signal [DN]")?;
+
+    for (g, &gain_db) in gains.iter().enumerate() {
+        let (b, gr, r) = PLOT_COLORS[g % PLOT_COLORS.len()];
+        let color = Scalar::new(b, gr, r, 0.0);
+        for (i, s) in samples.iter().enumerate().filter(|(_, s)| s.gain_db == gain_db) {
+            let (x, y) = ptc_points[i];
+            if x > 0.0 && y > 0.0 {
+                imgproc::circle(&mut img, ptc_panel.point(x, y), 3, color, -1, imgproc::LINE_8, 0)?;
+            }
+            if s.signal() > 0.0 {
+                imgproc::circle(&mut img, lin_panel.point(s.exposure_us, s.signal()), 3, color, -1, imgproc::LINE_8, 0)?;
+            }
+        }
+        imgproc::put_text(&mut img, &format!("{} dB", gain_db), Point::new(PLOT_W - PLOT_MARGIN - 60, PLOT_MARGIN + 16 + 16 * g as i32),
+                imgproc::FONT_HERSHEY_SIMPLEX, 0.45, color, 1, imgproc::LINE_AA, false)?;
+    }
+    Ok(img)
+}
@@ -4,11 +4,18 @@ use clap::Parser;
 use opencv::*;
 use opencv::core::*;
 
-use jelly_lib::linux_i2c::LinuxI2c;
 use jelly_mem_access::*;
 
-use rtcl_p3s7_shared::camera_driver::*;
+use rtcl_p3s7_shared::awb::{AutoWhiteBalance, AwbMode};
+use rtcl_p3s7_shared::board::Board;
+use rtcl_p3s7_shared::camera_config::CameraConfig;
+use rtcl_p3s7_shared::camera_driver::ReadoutMode;
+use rtcl_p3s7_shared::color::CfaPattern;
 use rtcl_p3s7_shared::capture_driver::*;
+use rtcl_p3s7_shared::disk_recorder::DiskRecorder;
+use rtcl_p3s7_shared::fot_calibration::FotSweep;
+use rtcl_p3s7_shared::pixel_format::PixelFormat;
+use rtcl_p3s7_shared::stream_watchdog::{WatchdogConfig, WatchdogStatus};
 use rtcl_p3s7_shared::timing_generator_driver::TimingGeneratorDriver;
 
 #[derive(Parser, Debug)]
@@ -47,6 +54,22 @@ struct Args {
     /// Enable color mode (default: monochrome)
     #[arg(long="pgood-off", default_value_t = false)]
     pgood_off: bool,
+
+    /// カメラ設定ファイル (TOML/JSON, 他のオプションより優先)
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Board (kv260, zybo_z7, auto or board file)
+    #[arg(long, default_value = "kv260")]
+    board: String,
+
+    /// Readout mode (full, subsampling2x2, binning2x2)
+    #[arg(long, default_value = "full")]
+    readout: String,
+
+    /// ストリーム停止時に自動復旧する
+    #[arg(long, default_value_t = false)]
+    watchdog: bool,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -55,16 +78,9 @@ fn main() -> Result<(), Box<dyn Error>> {
     let width = (args.width + 15) & !0xf;  // 16ピクセル境界に合わせる
     let height = (args.height + 1) & !0x01;  // 2ピクセル境界に合わせる
     let color = args.color;
-    let fps = args.fps;
     let trigger_mode = args.trigger;
 
     println!("start kv260_rtcl_p3s7_hs");
-    println!("Configuration:");
-    println!("  width:  {}", width);
-    println!("  height: {}", height);
-    println!("  color:  {}", color);
-    println!("  fps:    {}", fps);
-    println!("  trigger mode: {}", trigger_mode);
 
     // Ctrl+C の設定
     let running = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
@@ -73,44 +89,23 @@ fn main() -> Result<(), Box<dyn Error>> {
         r.store(false, std::sync::atomic::Ordering::SeqCst);
     })?;
 
-    // mmap udmabuf
-    let udmabuf_device_name = "udmabuf-jelly-vram0";
-    println!("\nudmabuf open");
-    let udmabuf_acc =
-        UdmabufAccessor::<usize>::new(udmabuf_device_name, false).expect("Failed to open udmabuf");
-    println!(
-        "{} phys addr : 0x{:x}",
-        udmabuf_device_name,
-        udmabuf_acc.phys_addr()
-    );
-    println!(
-        "{} size      : 0x{:x}",
-        udmabuf_device_name,
-        udmabuf_acc.size()
-    );
-
-    // UIO
-    println!("\nuio open");
-    let uio_acc = UioAccessor::<usize>::new_with_name("uio_pl_peri").expect("Failed to open uio");
-    println!("uio_pl_peri phys addr : 0x{:x}", uio_acc.phys_addr());
-    println!("uio_pl_peri size      : 0x{:x}", uio_acc.size());
-
-    let reg_sys = uio_acc.subclone(0x00000000, 0x400);
-    let reg_timgen = uio_acc.subclone(0x00010000, 0x400);
-    let reg_fmtr = uio_acc.subclone(0x00100000, 0x400);
-    let reg_wdma_img = uio_acc.subclone(0x00210000, 0x400);
-//  let reg_wdma_blk = uio_acc.subclone(0x00220000, 0x400);
+    // I2C, udmabuf, UIO
+    let board = Board::select(Some(&args.board))?;
+    println!("\nboard : {}", board.name);
+    let dev = board.open()?;
+    println!("{} phys addr : 0x{:x}", board.udmabuf_img, dev.buf_img.phys_addr());
+    println!("{} size      : 0x{:x}", board.udmabuf_img, dev.buf_img.size());
+    println!("{} phys addr : 0x{:x}", board.uio, dev.uio.phys_addr());
+    println!("{} size      : 0x{:x}", board.uio, dev.uio.size());
 
     println!("CORE ID");
-    println!("reg_sys      : {:08x}", unsafe { reg_sys.read_reg(0) });
-    println!("reg_timgen   : {:08x}", unsafe { reg_timgen.read_reg(0) });
-    println!("reg_fmtr     : {:08x}", unsafe { reg_fmtr.read_reg(0) });
-    println!("reg_wdma_img : {:08x}", unsafe { reg_wdma_img.read_reg(0) });
+    println!("reg_sys      : {:08x}", unsafe { dev.reg_sys.read_reg(0) });
+    println!("reg_timgen   : {:08x}", unsafe { dev.reg_timgen.read_reg(0) });
+    println!("reg_fmtr     : {:08x}", unsafe { dev.reg_fmtr.read_reg(0) });
+    println!("reg_wdma_img : {:08x}", unsafe { dev.reg_wdma_img.read_reg(0) });
 
-    let mut timgen = TimingGeneratorDriver::new(reg_timgen);
-
-    let i2c = LinuxI2c::new("/dev/i2c-6", 0x10)?;
-    let mut cam = CameraDriver::new(i2c, reg_sys, reg_fmtr);
+    let mut timgen = TimingGeneratorDriver::new(dev.reg_timgen);
+    let mut cam = board.camera_driver(dev.i2c, dev.reg_sys, dev.reg_fmtr);
 
     if args.pgood_off {
         cam.set_sensor_pgood_enable(false);
@@ -118,9 +113,41 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     cam.set_color(color);
     cam.set_black_lines(15)?;
-    cam.set_image_size(width, height)?;
+    let readout = ReadoutMode::from_name(&args.readout)
+        .ok_or_else(|| format!("unknown readout mode: {}", args.readout))?;
+    cam.set_readout_geometry(readout, width, height)?;
     cam.set_slave_mode(trigger_mode)?;
     cam.set_trigger_mode(trigger_mode)?;
+
+    // 設定ファイルがあれば上書き
+    let mut fps = args.fps;
+    let mut exposure_rate = 950;
+    let mut sgain = 10;
+    if let Some(path) = &args.config {
+        let config = CameraConfig::load(path)?;
+        config.apply(&mut cam, Some(&mut timgen))?;
+        let (period_us, exposure_us) = match &config.timing_generator {
+            Some(t) if config.camera.trigger_mode => (t.period_us as f32, t.trigger_width_us as f32),
+            _ => (config.camera.frame_period_us, config.camera.exposure_us),
+        };
+        fps = (1000000.0 / period_us).round() as i32;
+        exposure_rate = (exposure_us / period_us * 1000.0).round() as i32;
+        sgain = (config.camera.gain_db * 10.0).round() as i32 + 10;
+        println!("load config : {}", path);
+    }
+    let width = cam.image_width();
+    let height = cam.image_height();
+    let color = cam.color();
+    let trigger_mode = cam.trigger_mode();
+
+    println!("Configuration:");
+    println!("  width:  {}", width);
+    println!("  height: {}", height);
+    println!("  readout: {}", cam.readout_mode().name());
+    println!("  color:  {}", color);
+    println!("  fps:    {}", fps);
+    println!("  trigger mode: {}", trigger_mode);
+
     if let Err(err) = cam.open() {
         if err.to_string().contains("Sensor power good signal indicates failure") {
             println!("\n!! sensor power good error. !! Retry with --pgood-off option.");
@@ -141,18 +168,39 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("camera module version : {:04x}", cam.module_version()?);
     println!("camera sensor id      : {:04x}", cam.sensor_id()?);
 
-    let mut video_capture = CaptureDriver::new(reg_wdma_img, udmabuf_acc.clone())?;
+    let mut video_capture = CaptureDriver::new(dev.reg_wdma_img, dev.buf_img.clone())?;
+    if color {
+        video_capture.set_pixel_format(PixelFormat::BayerRggb);
+    }
+
+    // カラー時のホワイトバランス
+    let mut awb = AutoWhiteBalance::new(CfaPattern::Rggb);
+    let mut awb_failed = false;
 
     // ウィンドウ作成
     highgui::named_window("img", highgui::WINDOW_AUTOSIZE)?;
     highgui::resize_window("img", width as i32 + 128, height as i32 + 256)?;
 
     // トラックバー生成
-    create_cv_trackbar("sgain",      0,  200,  10)?;    // センサーゲイン
+    create_cv_trackbar("sgain",      0,  200, sgain)?;  // センサーゲイン
     create_cv_trackbar("dgain",      0,  200,  10)?;    // デジタルゲイン
     create_cv_trackbar("fps",       10, 1000, fps)?;
-    create_cv_trackbar("exposure",  10, 1000, 950)?;
-    create_cv_trackbar("xsm_delay",  0,  255,   0)?;
+    create_cv_trackbar("exposure",  10, 1000, exposure_rate)?;
+    create_cv_trackbar("xsm_delay",  0,  255, cam.xsm_delay() as i32)?;
+
+    if args.watchdog {
+        cam.set_watchdog(Some(WatchdogConfig::default()));
+    }
+
+    // フレーム周期の監視
+    let mut timing = cam.timing_monitor(1000);
+
+    // DMA リングバッファへ連続キャプチャし、最新のフレームを表示する
+    let stream_slots = 8;
+    video_capture.start_stream(width, height, stream_slots, cam.frame_count())?;
+
+    // ディスク録画 (録画中は dmabuf 全体をリングバッファとして使う)
+    let mut disk_recorder: Option<DiskRecorder> = None;
     
     // 画像表示ループ
     while running.load(std::sync::atomic::Ordering::SeqCst) {
@@ -176,15 +224,57 @@ fn main() -> Result<(), Box<dyn Error>> {
         let exposure_us = period_us * (exposure as f32 / 1000.0);
         if trigger_mode {
             timgen.set_timing(period_us, exposure_us)?;
+            timing.set_expected_period_us(Some(period_us as f64));
         }
         else {
             cam.set_frame_period(period_us)?;
             cam.set_exposure(exposure_us)?;
+            timing.set_expected_period_us(Some(cam.frame_period()? as f64));
         }
+        cam.sample_timing(&mut timing);
         
-        // CaptureDriver で 1frame キャプチャ
-        video_capture.record(width, height, 1)?;
-        let mut img = video_capture.read_image_mat(0)?;
+        // ストリーム監視
+        match cam.poll_watchdog()? {
+            WatchdogStatus::Recovery(event) => println!("watchdog : {}", event),
+            WatchdogStatus::Recovered(action) => println!("watchdog : recovered by {}", action.name()),
+            _ => {}
+        }
+
+        // 書き込みが済んだフレームをディスクへ
+        if let Some(rec) = disk_recorder.as_mut() {
+            if !rec.poll(&mut video_capture)? {
+                if let Some(rec) = disk_recorder.take() {
+                    finish_disk_record(rec, &mut video_capture)?;
+                }
+                video_capture.start_stream(width, height, stream_slots, cam.frame_count())?;
+            }
+        }
+
+        // 最新のフレームをコピーして取り出す (まだ無いか、コピー中に上書きされたら次の周回で)
+        let image = match video_capture.latest_stream_frame() {
+            Some(frame) => video_capture.read_stream_frame(&frame)?,
+            None => None,
+        };
+        let mut img = match image {
+            Some(image) => image.frame().to_mat()?,
+            None => continue,
+        };
+
+        // ソフトウェア AWB
+        if color {
+            let raw = img.data_typed_mut::<u16>()?;
+            match awb.update(raw, width, height) {
+                Ok(_) => awb_failed = false,
+                Err(e) => {
+                    // 同じエラーを毎フレーム出さない
+                    if !awb_failed {
+                        println!("awb : {}", e);
+                    }
+                    awb_failed = true;
+                }
+            }
+            awb.apply_raw(raw, width, awb.gains(), 1023);
+        }
 
         // センサーゲインを適用
         cam.set_gain(sgain_db)?;
@@ -211,6 +301,15 @@ fn main() -> Result<(), Box<dyn Error>> {
         let ch = key as u8 as char;
         match ch {
             'q' => { break; },
+            'w' => {
+                awb.set_lock(!awb.locked());
+                println!("awb lock : {} (gains {:?})", awb.locked(), awb.gains());
+            },
+            'b' => {
+                let mode = if awb.mode() == AwbMode::GrayWorld { AwbMode::WhitePatch } else { AwbMode::GrayWorld };
+                awb.set_mode(mode);
+                println!("awb mode : {:?}", mode);
+            },
             'p' => {
                 println!("camera module id      : {:04x}", cam.module_id()?);
                 println!("camera module version : {:04x}", cam.module_version()?);
@@ -228,35 +327,56 @@ fn main() -> Result<(), Box<dyn Error>> {
             'z' => {
                 cam.print_timing_status();
             }
+            'k' => {
+                if trigger_mode {
+                    println!("FOT calibration needs free-run mode");
+                } else {
+                    let cal = cam.calibrate_fot(&FotSweep::default())?;
+                    println!("FOT calibration : fot={:.4} us unit={:.6} us residual={:.4} us ({} samples)",
+                             cal.fot_us, cal.time_unit_us, cal.residual_us, cal.samples);
+                    println!("press 's' to save it to the config");
+                }
+            },
+            'j' => {
+                println!("timing : {}", timing.stats());
+                print!("{}", timing.histogram(1.0, 21));
+                timing.clear();
+            },
+            's' => {
+                let path = args.config.as_deref().unwrap_or("camera_config.toml");
+                CameraConfig::from_camera(&mut cam, Some(&timgen))?.save(path)?;
+                println!("save config : {}", path);
+            },
             'd' => {
                 println!("write : dump.png");
                 imgcodecs::imwrite("dump.png", &view, &Vector::<i32>::new())?;
             },
-            'r' => {  // 動画記録
-                // 日時のディレクトリを生成
-                let now = chrono::Local::now();
-                let _ = std::fs::create_dir("record");
-                let dir_name = format!("record/{}", now.format("%Y%m%d-%H%M%S"));
-                std::fs::create_dir(&dir_name).expect("Failed to create directory");
-                println!("record to {}", dir_name);
-                
-                // 100フレーム録画
-                let frames = args.rec_frames;
-                video_capture.record(width, height, frames)?;
-                for f in 0..frames {
-                   let img = video_capture.read_image_mat(f)?;
-                    let mut view = Mat::default();
-                    img.convert_to(&mut view, CV_16U, 64.0, 0.0)?;
-                    let file_name = format!("{}/img{:04}.png", dir_name, f);
-                    imgcodecs::imwrite(&file_name, &view, &Vector::<i32>::new())?;
+            'r' => {  // ディスクへ録画 (録画中にもう一度押すと停止)
+                match disk_recorder.take() {
+                    Some(rec) => {
+                        finish_disk_record(rec, &mut video_capture)?;
+                        video_capture.start_stream(width, height, stream_slots, cam.frame_count())?;
+                    }
+                    None => {
+                        let now = chrono::Local::now();
+                        let _ = std::fs::create_dir("record");
+                        let path = format!("record/{}.raw", now.format("%Y%m%d-%H%M%S"));
+                        println!("record to {}", path);
+                        video_capture.stop_stream()?;
+                        disk_recorder = Some(DiskRecorder::start(&mut video_capture, &path, width, height, 8,
+                                                                 Some(args.rec_frames), cam.frame_count())?);
+                    }
                 }
-                println!("record done");
             },
             _ => {
             }
         }
     }
-    
+
+    if let Some(rec) = disk_recorder.take() {
+        finish_disk_record(rec, &mut video_capture)?;
+    }
+    video_capture.stop_stream()?;
     cam.close()?;
 
     println!("done");
@@ -264,6 +384,18 @@ fn main() -> Result<(), Box<dyn Error>> {
     return Ok(());
 }
 
+// ディスク録画を終了して結果を表示
+fn finish_disk_record<T0, T1>(rec: DiskRecorder, cap: &mut CaptureDriver<T0, T1>) -> Result<(), Box<dyn Error>>
+where
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+{
+    let path = rec.path().to_path_buf();
+    let status = rec.finish(cap)?;
+    println!("record done : {} ({} frames, {} overwritten, {} dropped)",
+             path.display(), status.written_frames, status.overwritten_frames, status.dropped_frames);
+    Ok(())
+}
 
 fn create_cv_trackbar(trackbarname: &str, minval: i32, maxval: i32, inival: i32) -> opencv::Result<()> {
     let winname = "img";
@@ -904,7 +904,7 @@ module kv260_rtcl_p3s7_optical_flow
                 .AXI4L_ASYNC            (1                          ),
                 .AXI4S_ASYNC            (1                          ),
                 .ADDR_BITS              (AXI4_MEM_ADDR_BITS         ),
-                .INDEX_BITS             (1                          ),
+                .INDEX_BITS             (32                         ),
                 .SIZE_OFFSET            (1'b1                       ),
                 .H_SIZE_BITS            (14                         ),
                 .V_SIZE_BITS            (14                         ),
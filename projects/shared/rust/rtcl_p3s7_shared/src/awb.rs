@@ -0,0 +1,337 @@
+#![allow(dead_code)]
+
+use crate::color::CfaPattern;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// ゲインの固定小数点 (Q12)
+const AWB_GAIN_SHIFT: u32 = 12;
+
+/// ホワイトバランスの推定方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AwbMode {
+    /// 画面全体の平均が無彩色になるように合わせる
+    #[default]
+    GrayWorld,
+    /// 明るい (白い) 領域が無彩色になるように合わせる
+    WhitePatch,
+    /// 手動設定のゲインを使う
+    Manual,
+}
+
+/// Bayer フレームから推定するソフトウェア AWB
+///
+/// 2x2 ブロック単位で R/G/B を取り出し、飽和したブロックと暗すぎる
+/// ブロックを除いて推定する。ゲインは G=1.0 に正規化した (R, G, B)。
+/// ロック中や Manual モードでは `update` してもゲインは変わらない。
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutoWhiteBalance {
+    mode: AwbMode,
+    pattern: CfaPattern,
+    gains: [f32; 3],
+    locked: bool,
+    smoothing: f32,
+    black_level: u16,
+    saturation: u16,
+    white_fraction: f32,
+    step: usize,
+}
+
+impl AutoWhiteBalance {
+    pub fn new(pattern: CfaPattern) -> Self {
+        Self {
+            mode: AwbMode::GrayWorld,
+            pattern,
+            gains: [1.0, 1.0, 1.0],
+            locked: false,
+            smoothing: 0.25,
+            black_level: 16,
+            saturation: 1000,
+            white_fraction: 0.02,
+            step: 2,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: AwbMode) {
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> AwbMode {
+        self.mode
+    }
+
+    pub fn set_pattern(&mut self, pattern: CfaPattern) {
+        self.pattern = pattern;
+    }
+
+    /// 手動ゲインを設定して Manual モードにする
+    pub fn set_manual_gains(&mut self, gains: [f32; 3]) -> Result<()> {
+        if gains.iter().any(|g| !(g.is_finite() && *g > 0.0)) {
+            return Err(format!("invalid white balance gains: {:?}", gains).into());
+        }
+        self.gains = gains;
+        self.mode = AwbMode::Manual;
+        Ok(())
+    }
+
+    /// 現在のゲインで固定 (録画中の色を一定に保つ)
+    pub fn set_lock(&mut self, lock: bool) {
+        self.locked = lock;
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// 時間方向の平滑化係数 (1.0 で毎回推定値に置き換え)
+    pub fn set_smoothing(&mut self, smoothing: f32) {
+        self.smoothing = smoothing.clamp(0.0, 1.0);
+    }
+
+    /// 推定に使う画素値の範囲 (black_level を差し引き、saturation 以上を含むブロックは除外)
+    pub fn set_valid_range(&mut self, black_level: u16, saturation: u16) {
+        self.black_level = black_level;
+        self.saturation = saturation;
+    }
+
+    /// WhitePatch で使う明るいブロックの割合
+    pub fn set_white_fraction(&mut self, fraction: f32) {
+        self.white_fraction = fraction.clamp(0.0001, 1.0);
+    }
+
+    /// 推定時の間引き (2x2 ブロック単位)
+    pub fn set_step(&mut self, step: usize) {
+        self.step = step.max(1);
+    }
+
+    pub fn gains(&self) -> [f32; 3] {
+        self.gains
+    }
+
+    /// ゲインを全色共通分と色毎の残りに分ける
+    ///
+    /// 共通分は最小ゲインで、`CameraDriver::set_digital_gain_linear` で
+    /// センサ側に掛けると残りのソフトウェアゲインはすべて 1.0 以上になる。
+    pub fn split_gains(&self) -> (f32, [f32; 3]) {
+        let common = self.gains.iter().cloned().fold(f32::MAX, f32::min);
+        (common, self.gains.map(|g| g / common))
+    }
+
+    /// フレームからゲインを推定する (内部状態は変えない)
+    pub fn estimate(&self, raw: &[u16], width: usize, height: usize) -> Result<[f32; 3]> {
+        if raw.len() != width * height {
+            return Err("invalid raw image size".into());
+        }
+        let blocks = self.collect_blocks(raw, width, height);
+        if blocks.is_empty() {
+            return Err("no valid pixels for white balance".into());
+        }
+
+        let sum = match self.mode {
+            AwbMode::WhitePatch => {
+                let mut blocks = blocks;
+                let n = ((blocks.len() as f32 * self.white_fraction).ceil() as usize).max(1);
+                blocks.select_nth_unstable_by(n - 1, |a, b| (b[0] + b[1] + b[2]).cmp(&(a[0] + a[1] + a[2])));
+                Self::sum_blocks(&blocks[..n])
+            }
+            _ => Self::sum_blocks(&blocks),
+        };
+        if sum.contains(&0) {
+            return Err("white balance estimation failed (missing channel)".into());
+        }
+        Ok([sum[1] as f32 / sum[0] as f32, 1.0, sum[1] as f32 / sum[2] as f32])
+    }
+
+    /// フレームから推定してゲインを更新し、現在のゲインを返す
+    pub fn update(&mut self, raw: &[u16], width: usize, height: usize) -> Result<[f32; 3]> {
+        if self.locked || self.mode == AwbMode::Manual {
+            return Ok(self.gains);
+        }
+        let est = self.estimate(raw, width, height)?;
+        for (g, e) in self.gains.iter_mut().zip(est) {
+            *g += (e - *g) * self.smoothing;
+        }
+        Ok(self.gains)
+    }
+
+    /// Bayer RAW に色毎のゲインを掛ける (max で飽和)
+    ///
+    /// 黒レベル (`set_valid_range`) を差し引いてからゲインを掛けて戻す。
+    pub fn apply_raw(&self, raw: &mut [u16], width: usize, gains: [f32; 3], max: u16) {
+        let g = gains.map(|v| (v * (1 << AWB_GAIN_SHIFT) as f32).round() as u32);
+        let black = self.black_level as u32;
+        for (y, row) in raw.chunks_exact_mut(width).enumerate() {
+            for (x, p) in row.iter_mut().enumerate() {
+                let v = ((*p as u32).saturating_sub(black) * g[self.pattern.color(x, y)]) >> AWB_GAIN_SHIFT;
+                *p = (v + black.min(*p as u32)).min(max as u32) as u16;
+            }
+        }
+    }
+
+    // 有効な 2x2 ブロックの (R, G, B)。G は 2 画素の平均
+    fn collect_blocks(&self, raw: &[u16], width: usize, height: usize) -> Vec<[u32; 3]> {
+        let mut blocks = Vec::new();
+        for by in (0..height / 2).step_by(self.step) {
+            for bx in (0..width / 2).step_by(self.step) {
+                let mut rgb = [0u32; 3];
+                let mut valid = true;
+                for dy in 0..2 {
+                    for dx in 0..2 {
+                        let (x, y) = (bx * 2 + dx, by * 2 + dy);
+                        let v = raw[y * width + x];
+                        if v >= self.saturation {
+                            valid = false;
+                        }
+                        rgb[self.pattern.color(x, y)] += v as u32;
+                    }
+                }
+                rgb[1] /= 2;
+                let rgb = rgb.map(|v| v.saturating_sub(self.black_level as u32));
+                if valid && rgb.iter().all(|&v| v > 0) {
+                    blocks.push(rgb);
+                }
+            }
+        }
+        blocks
+    }
+
+    fn sum_blocks(blocks: &[[u32; 3]]) -> [u64; 3] {
+        let mut sum = [0u64; 3];
+        for b in blocks {
+            for c in 0..3 {
+                sum[c] += b[c] as u64;
+            }
+        }
+        sum
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 色毎に一様な Bayer 画像
+    fn uniform(width: usize, height: usize, pattern: CfaPattern, rgb: [u16; 3]) -> Vec<u16> {
+        (0..width * height).map(|i| rgb[pattern.color(i % width, i / width)]).collect()
+    }
+
+    fn awb(pattern: CfaPattern) -> AutoWhiteBalance {
+        let mut awb = AutoWhiteBalance::new(pattern);
+        awb.set_step(1);
+        awb.set_smoothing(1.0);
+        awb
+    }
+
+    fn assert_gains(gains: [f32; 3], expect: [f32; 3]) {
+        for (g, e) in gains.iter().zip(expect) {
+            assert!((g - e).abs() < 1e-4, "{:?} != {:?}", gains, expect);
+        }
+    }
+
+    #[test]
+    fn gray_world_uniform() {
+        // 黒レベル 16 を引くと (200, 100, 50)
+        for pattern in [CfaPattern::Rggb, CfaPattern::Grbg, CfaPattern::Gbrg, CfaPattern::Bggr] {
+            let awb = awb(pattern);
+            let raw = uniform(8, 6, pattern, [216, 116, 66]);
+            assert_gains(awb.estimate(&raw, 8, 6).unwrap(), [0.5, 1.0, 2.0]);
+        }
+    }
+
+    #[test]
+    fn gray_world_skips_saturated_blocks() {
+        let awb = awb(CfaPattern::Rggb);
+        let mut raw = uniform(8, 4, CfaPattern::Rggb, [216, 116, 66]);
+        // 右半分は色が違うが B が飽和しているブロック
+        for y in 0..4 {
+            for x in 4..8 {
+                raw[y * 8 + x] = [116, 216, 1000][CfaPattern::Rggb.color(x, y)];
+            }
+        }
+        assert_gains(awb.estimate(&raw, 8, 4).unwrap(), [0.5, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn white_patch_uses_bright_blocks() {
+        let mut awb = awb(CfaPattern::Rggb);
+        let mut raw = uniform(8, 4, CfaPattern::Rggb, [416, 216, 116]);
+        // 左半分は暗い青
+        for y in 0..4 {
+            for x in 0..4 {
+                raw[y * 8 + x] = [36, 116, 216][CfaPattern::Rggb.color(x, y)];
+            }
+        }
+        awb.set_mode(AwbMode::WhitePatch);
+        awb.set_white_fraction(0.5);
+        assert_gains(awb.estimate(&raw, 8, 4).unwrap(), [0.5, 1.0, 2.0]);
+
+        // GrayWorld なら全体平均 (210, 150, 150)
+        awb.set_mode(AwbMode::GrayWorld);
+        assert_gains(awb.estimate(&raw, 8, 4).unwrap(), [150.0 / 210.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn estimate_rejects_invalid() {
+        let awb = awb(CfaPattern::Rggb);
+        assert!(awb.estimate(&[100; 15], 4, 4).is_err());
+        // 黒レベル以下だけ
+        assert!(awb.estimate(&[16; 16], 4, 4).is_err());
+        // 飽和だけ
+        assert!(awb.estimate(&[1000; 16], 4, 4).is_err());
+    }
+
+    #[test]
+    fn lock_and_manual_keep_gains() {
+        let mut awb = awb(CfaPattern::Rggb);
+        let raw = uniform(4, 4, CfaPattern::Rggb, [216, 116, 66]);
+
+        awb.set_lock(true);
+        assert_gains(awb.update(&raw, 4, 4).unwrap(), [1.0, 1.0, 1.0]);
+        awb.set_lock(false);
+        assert_gains(awb.update(&raw, 4, 4).unwrap(), [0.5, 1.0, 2.0]);
+
+        assert!(awb.set_manual_gains([1.0, 0.0, 1.0]).is_err());
+        assert!(awb.set_manual_gains([1.0, f32::NAN, 1.0]).is_err());
+        awb.set_manual_gains([1.5, 1.0, 1.25]).unwrap();
+        assert_eq!(awb.mode(), AwbMode::Manual);
+        assert_gains(awb.update(&raw, 4, 4).unwrap(), [1.5, 1.0, 1.25]);
+    }
+
+    #[test]
+    fn update_smoothing() {
+        let mut awb = awb(CfaPattern::Rggb);
+        awb.set_smoothing(0.5);
+        let raw = uniform(4, 4, CfaPattern::Rggb, [216, 116, 66]);
+        assert_gains(awb.update(&raw, 4, 4).unwrap(), [0.75, 1.0, 1.5]);
+        assert_gains(awb.update(&raw, 4, 4).unwrap(), [0.625, 1.0, 1.75]);
+
+        // 推定に失敗してもゲインは変わらない
+        assert!(awb.update(&[0; 16], 4, 4).is_err());
+        assert_gains(awb.gains(), [0.625, 1.0, 1.75]);
+    }
+
+    #[test]
+    fn split_gains_min_common() {
+        let mut awb = awb(CfaPattern::Rggb);
+        awb.set_manual_gains([0.5, 1.0, 2.0]).unwrap();
+        let (common, rest) = awb.split_gains();
+        assert_eq!(common, 0.5);
+        assert_gains(rest, [1.0, 2.0, 4.0]);
+    }
+
+    #[test]
+    fn apply_raw_keeps_black_level() {
+        let awb = awb(CfaPattern::Rggb);
+        // R, G / G, B
+        let mut raw = vec![116, 116,
+                           10, 116];
+        awb.apply_raw(&mut raw, 2, [2.0, 1.0, 0.5], 1023);
+        // (116 - 16) * gain + 16、黒レベル未満はそのまま
+        assert_eq!(raw, vec![216, 116, 10, 66]);
+
+        let mut raw = vec![600, 116, 116, 116];
+        awb.apply_raw(&mut raw, 2, [2.0, 1.0, 1.0], 1023);
+        assert_eq!(raw[0], 1023);
+    }
+}
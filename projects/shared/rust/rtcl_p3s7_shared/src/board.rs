@@ -0,0 +1,242 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use jelly_lib::linux_i2c::LinuxI2c;
+use jelly_mem_access::*;
+
+use crate::camera_driver::CameraDriver;
+use crate::peripheral::{verify_block, BlockDesc, CoreKind};
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// カメラモジュールの I2C アドレス
+const CAMERA_I2C_ADDR: u8 = 0x10;
+
+// UIO サブブロックのサイズ
+const BOARD_BLOCK_SIZE: usize = 0x400;
+
+const DEVICE_TREE_COMPATIBLE: &str = "/proc/device-tree/compatible";
+const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+
+/// ボード毎の差分 (I2C バス, D-PHY 速度, カウンタクロック, UIO 配置)
+///
+/// KV260 と ZYBO Z7 は組み込みで、それ以外は TOML / JSON ファイルから読み込む。
+/// `blocks` は `uio` 内のサブブロックのオフセットで、sys / timgen / fmtr /
+/// wdma_img / wdma_blk は必須、それ以外はデザイン固有のブロックに使える。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Board {
+    pub name: String,
+    pub i2c_bus: String,
+    pub dphy_speed: f64,
+    pub fps_counter_clock_hz: f32,
+    pub pgood_enable: bool,
+    pub uio: String,
+    pub udmabuf_img: String,
+    pub udmabuf_blk: Option<String>,
+    pub blocks: BTreeMap<String, usize>,
+}
+
+impl Board {
+    pub fn kv260() -> Self {
+        Self {
+            name: "kv260".to_string(),
+            i2c_bus: "/dev/i2c-6".to_string(),
+            dphy_speed: 1250000000.0,
+            fps_counter_clock_hz: 250_000_000.0,
+            pgood_enable: true,
+            ..Self::common()
+        }
+    }
+
+    pub fn zybo_z7() -> Self {
+        Self {
+            name: "zybo_z7".to_string(),
+            i2c_bus: "/dev/i2c-0".to_string(),
+            dphy_speed: 950_000_000.0,
+            fps_counter_clock_hz: 200_000_000.0,
+            pgood_enable: false,
+            ..Self::common()
+        }
+    }
+
+    // 両ボード共通の UIO 配置
+    fn common() -> Self {
+        let blocks = [
+            ("sys",      0x0000_0000),
+            ("timgen",   0x0001_0000),
+            ("fmtr",     0x0010_0000),
+            ("wdma_img", 0x0021_0000),
+            ("wdma_blk", 0x0022_0000),
+        ];
+        Self {
+            name: String::new(),
+            i2c_bus: String::new(),
+            dphy_speed: 0.0,
+            fps_counter_clock_hz: 0.0,
+            pgood_enable: true,
+            uio: "uio_pl_peri".to_string(),
+            udmabuf_img: "udmabuf-jelly-vram0".to_string(),
+            udmabuf_blk: Some("udmabuf-jelly-vram1".to_string()),
+            blocks: blocks.iter().map(|&(k, v)| (k.to_string(), v)).collect(),
+        }
+    }
+
+    /// 組み込みボードを名前で取得
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "kv260"                       => Some(Self::kv260()),
+            "zybo_z7" | "zybo-z7" | "zybo" => Some(Self::zybo_z7()),
+            _ => None,
+        }
+    }
+
+    /// device-tree の compatible / model から実行中のボードを判定
+    ///
+    /// SoC 名 (zynqmp など) だけでは他のボードと区別できないので、
+    /// ボード名が含まれていなければエラーにする。
+    pub fn detect() -> Result<Self> {
+        let compatible = std::fs::read(DEVICE_TREE_COMPATIBLE)
+            .map_err(|e| format!("failed to read {}: {}", DEVICE_TREE_COMPATIBLE, e))?;
+        let compatible = String::from_utf8_lossy(&compatible).to_ascii_lowercase().replace('\0', " ");
+        let model = std::fs::read(DEVICE_TREE_MODEL)
+            .map(|m| String::from_utf8_lossy(&m).to_ascii_lowercase().replace('\0', " "))
+            .unwrap_or_default();
+        let matches = |key: &str| compatible.contains(key) || model.contains(key);
+        if matches("kv260") {
+            Ok(Self::kv260())
+        }
+        else if matches("zybo") {
+            Ok(Self::zybo_z7())
+        }
+        else {
+            Err(format!("unknown board: {} ({}), specify the board explicitly", compatible.trim(), model.trim()).into())
+        }
+    }
+
+    /// 組み込み名かファイルパスで選択 (None か "auto" なら自動判定)
+    pub fn select(name: Option<&str>) -> Result<Self> {
+        match name {
+            None | Some("auto") => Self::detect(),
+            Some(name) => match Self::from_name(name) {
+                Some(board) => Ok(board),
+                None if Path::new(name).is_file() => Self::load(name),
+                None => Err(format!("unknown board (not a built-in name or file): {}", name).into()),
+            },
+        }
+    }
+
+    /// ファイルから読み込み (拡張子 .json なら JSON、それ以外は TOML)
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let board: Self = if Self::is_json(path) { serde_json::from_str(&text)? } else { toml::from_str(&text)? };
+        board.validate()?;
+        Ok(board)
+    }
+
+    /// ファイルに保存 (拡張子 .json なら JSON、それ以外は TOML)
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let text = if Self::is_json(path) { serde_json::to_string_pretty(self)? } else { toml::to_string_pretty(self)? };
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for name in ["sys", "timgen", "fmtr", "wdma_img", "wdma_blk"] {
+            if !self.blocks.contains_key(name) {
+                return Err(format!("board {}: block '{}' is not defined", self.name, name).into());
+            }
+        }
+        if !(self.dphy_speed > 0.0 && self.fps_counter_clock_hz > 0.0) {
+            return Err(format!("board {}: dphy_speed and fps_counter_clock_hz must be positive", self.name).into());
+        }
+        Ok(())
+    }
+
+    /// サブブロックのオフセット
+    pub fn block_offset(&self, name: &str) -> Result<usize> {
+        self.blocks.get(name).copied()
+            .ok_or_else(|| format!("board {}: block '{}' is not defined", self.name, name).into())
+    }
+
+    /// I2C / udmabuf / UIO をまとめて開く
+    ///
+    /// 必須ブロックは CORE_ID を確認し、ビットストリームが違えばエラーにする。
+    pub fn open(&self) -> Result<BoardDevices> {
+        self.validate()?;
+        let i2c = LinuxI2c::new(&self.i2c_bus, CAMERA_I2C_ADDR)
+            .map_err(|e| format!("failed to open {}: {}", self.i2c_bus, e))?;
+        let uio = UioAccessor::<usize>::new_with_name(&self.uio)
+            .map_err(|e| format!("failed to open {}: {}", self.uio, e))?;
+        let buf_img = UdmabufAccessor::<usize>::new(&self.udmabuf_img, false)
+            .map_err(|e| format!("failed to open {}: {}", self.udmabuf_img, e))?;
+        // ブラックライン用はデザインによって無いことがある (無い場合は None を設定する)
+        let buf_blk = match &self.udmabuf_blk {
+            Some(name) => Some(UdmabufAccessor::<usize>::new(name, false)
+                .map_err(|e| format!("failed to open {}: {}", name, e))?),
+            None => None,
+        };
+
+        let sub = |name: &str| -> Result<UioAccessor<usize>> {
+            let desc = BlockDesc::new(name, self.block_offset(name)?, CoreKind::guess(name));
+            Ok(verify_block(uio.subclone(desc.offset, BOARD_BLOCK_SIZE), &desc)?.accessor())
+        };
+        Ok(BoardDevices {
+            board: self.clone(),
+            i2c,
+            reg_sys: sub("sys")?,
+            reg_timgen: sub("timgen")?,
+            reg_fmtr: sub("fmtr")?,
+            reg_wdma_img: sub("wdma_img")?,
+            reg_wdma_blk: sub("wdma_blk")?,
+            uio,
+            buf_img,
+            buf_blk,
+        })
+    }
+
+    /// ボードに合わせた CameraDriver を生成
+    pub fn camera_driver(&self, i2c: LinuxI2c, reg_sys: UioAccessor<usize>, reg_fmtr: UioAccessor<usize>) -> CameraDriver<LinuxI2c, usize> {
+        let mut cam = CameraDriver::new(i2c, reg_sys, reg_fmtr);
+        cam.set_dphy_speed(self.dphy_speed);
+        cam.set_fps_counter_clock_hz(self.fps_counter_clock_hz);
+        cam.set_sensor_pgood_enable(self.pgood_enable);
+        cam
+    }
+
+    fn is_json(path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+    }
+}
+
+/// `Board::open` で開いたデバイス一式
+pub struct BoardDevices {
+    pub board: Board,
+    pub i2c: LinuxI2c,
+    pub uio: UioAccessor<usize>,
+    pub buf_img: UdmabufAccessor<usize>,
+    pub buf_blk: Option<UdmabufAccessor<usize>>,
+    pub reg_sys: UioAccessor<usize>,
+    pub reg_timgen: UioAccessor<usize>,
+    pub reg_fmtr: UioAccessor<usize>,
+    pub reg_wdma_img: UioAccessor<usize>,
+    pub reg_wdma_blk: UioAccessor<usize>,
+}
+
+impl BoardDevices {
+    /// ボードの blocks で名前を付けたブロック (名前から推定したコアか確認する)
+    pub fn block(&self, name: &str) -> Result<UioAccessor<usize>> {
+        let desc = BlockDesc::new(name, self.board.block_offset(name)?, CoreKind::guess(name));
+        Ok(verify_block(self.uio.subclone(desc.offset, BOARD_BLOCK_SIZE), &desc)?.accessor())
+    }
+
+    /// オフセット直接指定のブロック
+    pub fn block_at(&self, offset: usize) -> UioAccessor<usize> {
+        self.uio.subclone(offset, BOARD_BLOCK_SIZE)
+    }
+}
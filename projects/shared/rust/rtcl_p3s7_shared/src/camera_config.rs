@@ -0,0 +1,475 @@
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use jelly_lib::i2c_hal::I2cHal;
+use jelly_mem_access::*;
+
+use crate::camera_driver::{CameraDriver, ReadoutMode};
+use crate::fot_calibration::{FotCalibration, NOMINAL_FOT_US, NOMINAL_TIME_UNIT_US};
+use crate::timing_generator_driver::{TimingGeneratorDriver, TriggerPolarity};
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+/// カメラ設定プロファイル
+///
+/// `CameraDriver` (とモジュール) の設定に、PMOD とタイミングジェネレータの
+/// 設定を加えたもの。TOML / JSON で保存・読み込みでき、`apply` で
+/// オープン中・クローズ中どちらのカメラにもまとめて適用できる。
+/// 省略した項目は既定値 (`CameraDriver::new` 直後の値) になる。
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraConfig {
+    pub camera: SensorConfig,
+    pub pmod: PmodConfig,
+    pub timing_generator: Option<TimingGeneratorConfig>,
+}
+
+/// `CameraDriver` / `RtclP3s7ModuleDriver` の設定
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SensorConfig {
+    /// 出力画像サイズ (読み出しモードが間引き/ビニングならセンサー上の ROI は縦横 2 倍)
+    pub width: usize,
+    pub height: usize,
+    pub readout_mode: ReadoutMode,
+    pub color: bool,
+    pub black_lines: usize,
+    pub slave_mode: bool,
+    pub trigger_mode: bool,
+    pub gain_db: f32,
+    pub mult_timer: u16,
+    pub exposure_us: f32,
+    pub frame_period_us: f32,
+    pub dphy_speed: f64,
+    pub fps_counter_clock_hz: f32,
+    pub pgood_enable: bool,
+    /// XSM delay の下限 (0 なら画像幅からの計算値)
+    pub xsm_delay: u16,
+    pub monitor_select: Option<u16>,
+    /// `CameraDriver::calibrate_fot` の結果 (条件毎)
+    pub fot_calibration: Vec<FotCalibration>,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            width: 640,
+            height: 480,
+            readout_mode: ReadoutMode::Full,
+            color: false,
+            black_lines: 15,
+            slave_mode: false,
+            trigger_mode: false,
+            gain_db: 0.0,
+            mult_timer: 72,
+            exposure_us: 10000.0,
+            frame_period_us: 45.4133,
+            dphy_speed: 1250000000.0,
+            fps_counter_clock_hz: 250_000_000.0,
+            pgood_enable: true,
+            xsm_delay: 0,
+            monitor_select: None,
+            fot_calibration: Vec::new(),
+        }
+    }
+}
+
+/// PMOD 設定 (None の項目は書き込まない)
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PmodConfig {
+    pub mode: Option<u16>,
+    pub trigger_select: Option<u16>,
+    pub header_select: Option<u16>,
+    pub slot_len: Option<u16>,
+    /// スロット毎のパターン (index 順)
+    pub slot_pattern: Vec<u16>,
+    /// スロット毎の時間 (index 順)
+    pub slot_time: Vec<u16>,
+    pub gpio_out: Option<u8>,
+    pub gpio_dir: Option<u8>,
+}
+
+impl PmodConfig {
+    // index の位置に値を記録する (間は 0 で埋める)
+    pub(crate) fn set_slot(slots: &mut Vec<u16>, index: u16, value: u16) {
+        let index = index as usize;
+        if slots.len() <= index {
+            slots.resize(index + 1, 0);
+        }
+        slots[index] = value;
+    }
+}
+
+/// タイミングジェネレータ (外部トリガ) の設定
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimingGeneratorConfig {
+    pub clock_hz: f64,
+    pub period_us: f64,
+    pub trigger_offset_us: f64,
+    pub trigger_width_us: f64,
+    pub polarity: TriggerPolarity,
+    pub enable: bool,
+}
+
+impl Default for TimingGeneratorConfig {
+    fn default() -> Self {
+        Self {
+            clock_hz: 100_000_000.0,
+            period_us: 10000.0,
+            trigger_offset_us: 0.0,
+            trigger_width_us: 9000.0,
+            polarity: TriggerPolarity::ActiveHigh,
+            enable: false,
+        }
+    }
+}
+
+impl CameraConfig {
+    pub fn from_toml_str(s: &str) -> Result<Self> {
+        let config: Self = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml_string(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    pub fn from_json_str(s: &str) -> Result<Self> {
+        let config: Self = serde_json::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_json_string(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// ファイルから読み込み (拡張子 .json なら JSON、それ以外は TOML)
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        if Self::is_json(path) {
+            Self::from_json_str(&text)
+        } else {
+            Self::from_toml_str(&text)
+        }
+    }
+
+    /// ファイルに保存 (拡張子 .json なら JSON、それ以外は TOML)
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let text = if Self::is_json(path) { self.to_json_string()? } else { self.to_toml_string()? };
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// 値の範囲チェック (ハードウェアには触らない)
+    pub fn validate(&self) -> Result<()> {
+        let c = &self.camera;
+        c.readout_mode.check_image_size(c.width, c.height, c.color)?;
+        if c.black_lines > 254 {
+            return Err(format!("invalid black lines: {}", c.black_lines).into());
+        }
+        if c.mult_timer == 0 {
+            return Err("mult_timer must be greater than 0".into());
+        }
+        if !(c.gain_db.is_finite() && c.exposure_us.is_finite() && c.exposure_us >= 0.0 && c.frame_period_us.is_finite()) {
+            return Err("invalid gain, exposure or frame period".into());
+        }
+        // CameraDriver と同じく条件に合う校正値があればその時間単位と FOT で換算する
+        let cal = c.fot_calibration.iter().find(|cal| cal.matches(c.readout_mode, c.width, c.height, c.dphy_speed));
+        let unit = c.mult_timer as f32 * cal.map_or(NOMINAL_TIME_UNIT_US, |cal| cal.time_unit_us);
+        let fot_us = cal.map_or(NOMINAL_FOT_US, |cal| cal.fot_us);
+        if c.exposure_us / unit > u16::MAX as f32 {
+            return Err(format!("exposure {} us out of range for mult_timer {}", c.exposure_us, c.mult_timer).into());
+        }
+        if (c.frame_period_us - fot_us) / unit > u16::MAX as f32 {
+            return Err(format!("frame period {} us out of range for mult_timer {}", c.frame_period_us, c.mult_timer).into());
+        }
+        if !(c.dphy_speed > 0.0 && c.fps_counter_clock_hz > 0.0) {
+            return Err("dphy_speed and fps_counter_clock_hz must be positive".into());
+        }
+        for cal in &c.fot_calibration {
+            cal.validate()?;
+        }
+        if self.pmod.slot_len == Some(0) {
+            return Err("pmod slot_len must be greater than 0".into());
+        }
+        if self.pmod.slot_pattern.len() > 256 || self.pmod.slot_time.len() > 256 {
+            return Err("too many pmod slots (max 256)".into());
+        }
+        if let Some(t) = &self.timing_generator {
+            if !(t.clock_hz.is_finite() && t.clock_hz > 0.0) {
+                return Err(format!("invalid timing generator clock: {} Hz", t.clock_hz).into());
+            }
+            if !(t.trigger_width_us > 0.0 && t.trigger_offset_us >= 0.0
+                    && t.trigger_offset_us + t.trigger_width_us < t.period_us) {
+                return Err(format!("trigger ({} us + {} us) must fit in period {} us",
+                                    t.trigger_offset_us, t.trigger_width_us, t.period_us).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// 現在のカメラ (とタイミングジェネレータ) の設定を取り出す
+    pub fn from_camera<I2C, U, T>(cam: &mut CameraDriver<I2C, U>, timgen: Option<&TimingGeneratorDriver<T>>) -> Result<Self>
+    where
+        I2C: I2cHal,
+        <I2C as I2cHal>::Error: std::error::Error + 'static,
+        U: Copy + Clone,
+        T: MemAccess,
+    {
+        let camera = SensorConfig {
+            width: cam.image_width(),
+            height: cam.image_height(),
+            readout_mode: cam.readout_mode(),
+            color: cam.color(),
+            black_lines: cam.black_lines(),
+            slave_mode: cam.slave_mode(),
+            trigger_mode: cam.trigger_mode(),
+            gain_db: cam.gain(),
+            mult_timer: cam.mult_timer(),
+            exposure_us: cam.exposure()?,
+            frame_period_us: cam.frame_period()?,
+            dphy_speed: cam.dphy_speed(),
+            fps_counter_clock_hz: cam.fps_counter_clock_hz(),
+            pgood_enable: cam.sensor_pgood_enable(),
+            xsm_delay: cam.xsm_delay(),
+            monitor_select: cam.monitor_select(),
+            fot_calibration: cam.fot_calibrations().to_vec(),
+        };
+        let timing_generator = match timgen {
+            Some(t) => {
+                let (trigger_offset_us, trigger_width_us) = t.trigger(0)?;
+                Some(TimingGeneratorConfig {
+                    clock_hz: t.clock_hz(),
+                    period_us: t.period_us(),
+                    trigger_offset_us,
+                    trigger_width_us,
+                    polarity: t.polarity(0)?,
+                    enable: t.enabled(),
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            camera,
+            pmod: cam.pmod_config().clone(),
+            timing_generator,
+        })
+    }
+
+    /// カメラ (とタイミングジェネレータ) に設定を適用する
+    ///
+    /// 先に全項目を検査し、オープン中なら一度クローズして設定後に再オープンする。
+    /// 途中で失敗した場合は適用前の設定 (オープン状態を含む) に戻してからエラーを返す。
+    pub fn apply<I2C, U, T>(&self, cam: &mut CameraDriver<I2C, U>, mut timgen: Option<&mut TimingGeneratorDriver<T>>) -> Result<()>
+    where
+        I2C: I2cHal,
+        <I2C as I2cHal>::Error: std::error::Error + 'static,
+        U: Copy + Clone,
+        T: MemAccess,
+    {
+        self.validate()?;
+        if self.timing_generator.is_some() && timgen.is_none() {
+            return Err("config has timing generator settings but no timing generator is given".into());
+        }
+
+        // 失敗時はクローズ済みになっているので、オープン状態は最初に覚えておく
+        let was_open = cam.opend();
+        let backup = Self::from_camera(cam, timgen.as_deref())?;
+        let result = self.apply_unchecked(cam, timgen.as_deref_mut(), was_open);
+        rollback_on_error(result, || backup.apply_unchecked(cam, timgen, was_open))
+    }
+
+    // reopen が true なら設定後にオープンする
+    fn apply_unchecked<I2C, U, T>(&self, cam: &mut CameraDriver<I2C, U>, timgen: Option<&mut TimingGeneratorDriver<T>>, reopen: bool) -> Result<()>
+    where
+        I2C: I2cHal,
+        <I2C as I2cHal>::Error: std::error::Error + 'static,
+        U: Copy + Clone,
+        T: MemAccess,
+    {
+        let c = &self.camera;
+
+        // D-PHY 速度や画像サイズはオープン時に設定されるので作り直す
+        cam.close()?;
+
+        cam.set_dphy_speed(c.dphy_speed);
+        cam.set_fps_counter_clock_hz(c.fps_counter_clock_hz);
+        cam.set_sensor_pgood_enable(c.pgood_enable);
+        cam.set_color(c.color);
+        cam.set_black_lines(c.black_lines)?;
+        cam.set_readout_geometry(c.readout_mode, c.width, c.height)?;
+        cam.set_slave_mode(c.slave_mode)?;
+        cam.set_trigger_mode(c.trigger_mode)?;
+        cam.set_mult_timer(c.mult_timer)?;
+        cam.set_fot_calibrations(c.fot_calibration.clone());
+        cam.set_gain(c.gain_db)?;
+        cam.set_exposure(c.exposure_us)?;
+        cam.set_frame_period(c.frame_period_us)?;
+        cam.set_xsm_delay(c.xsm_delay)?;
+        if let Some(sel) = c.monitor_select {
+            cam.set_monitor_select(sel)?;
+        }
+
+        // PMOD はモジュール側のレジスタなのでクローズ中でも書ける
+        let p = &self.pmod;
+        if let Some(v) = p.mode {
+            cam.set_pmod_mode(v)?;
+        }
+        if let Some(v) = p.trigger_select {
+            cam.set_pmod_trigger_select(v)?;
+        }
+        if let Some(v) = p.header_select {
+            cam.set_pmod_header_select(v)?;
+        }
+        if let Some(v) = p.slot_len {
+            cam.set_pmod_slot_len(v)?;
+        }
+        for (i, &v) in p.slot_pattern.iter().enumerate() {
+            cam.set_pmod_slot_pattern(i as u16, v)?;
+        }
+        for (i, &v) in p.slot_time.iter().enumerate() {
+            cam.set_pmod_slot_time(i as u16, v)?;
+        }
+        if let Some(v) = p.gpio_dir {
+            cam.set_gpio_dir(v)?;
+        }
+        if let Some(v) = p.gpio_out {
+            cam.set_gpio_out(v)?;
+        }
+
+        if let (Some(t), Some(timgen)) = (&self.timing_generator, timgen) {
+            timgen.set_clock_hz(t.clock_hz)?;
+            timgen.set_polarity(0, t.polarity)?;
+            timgen.update_period_and_trigger(t.period_us, 0, t.trigger_offset_us, t.trigger_width_us)?;
+            if t.enable {
+                timgen.start();
+            } else {
+                timgen.stop();
+            }
+        }
+
+        if reopen {
+            cam.open()?;
+        }
+        Ok(())
+    }
+
+    fn is_json(path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+    }
+}
+
+// 適用に失敗したら restore で元に戻す (戻せなかった場合は両方のエラーを返す)
+fn rollback_on_error(result: Result<()>, restore: impl FnOnce() -> Result<()>) -> Result<()> {
+    let err = match result {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    match restore() {
+        Ok(()) => Err(err),
+        Err(restore_err) => Err(format!("{} (failed to restore previous settings: {})", err, restore_err).into()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // apply_unchecked と同じく最初にクローズするだけのカメラ
+    struct FakeCamera {
+        open: bool,
+        value: u32,
+    }
+
+    impl FakeCamera {
+        fn apply(&mut self, value: u32, reopen: bool, fail: bool) -> Result<()> {
+            self.open = false;
+            if fail {
+                return Err(format!("cannot set {}", value).into());
+            }
+            self.value = value;
+            if reopen {
+                self.open = true;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rollback_restores_open_camera() {
+        let mut cam = FakeCamera { open: true, value: 1 };
+        let was_open = cam.open;
+        let result = cam.apply(2, was_open, true);
+        let err = rollback_on_error(result, || cam.apply(1, was_open, false)).unwrap_err();
+        assert_eq!(err.to_string(), "cannot set 2");
+        assert!(cam.open);
+        assert_eq!(cam.value, 1);
+    }
+
+    #[test]
+    fn rollback_reports_restore_failure() {
+        let mut cam = FakeCamera { open: true, value: 1 };
+        let result = cam.apply(2, true, true);
+        let err = rollback_on_error(result, || cam.apply(1, true, true)).unwrap_err();
+        assert_eq!(err.to_string(), "cannot set 2 (failed to restore previous settings: cannot set 1)");
+        assert!(!cam.open);
+    }
+
+    #[test]
+    fn rollback_skips_restore_on_success() {
+        let mut restored = false;
+        assert!(rollback_on_error(Ok(()), || { restored = true; Ok(()) }).is_ok());
+        assert!(!restored);
+    }
+
+    #[test]
+    fn validate_exposure_range_uses_mult_timer() {
+        // mult_timer 72 なら 1 us 単位で 65535 us まで
+        let mut config = CameraConfig::default();
+        config.camera.exposure_us = 65000.0;
+        assert!(config.validate().is_ok());
+        config.camera.mult_timer = 36;
+        assert!(config.validate().is_err());
+        config.camera.mult_timer = 144;
+        config.camera.exposure_us = 130000.0;
+        assert!(config.validate().is_ok());
+        config.camera.mult_timer = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_uses_fot_calibration() {
+        let mut config = CameraConfig::default();
+        config.camera.exposure_us = 60000.0;
+        config.camera.frame_period_us = 60000.0;
+        assert!(config.validate().is_ok());
+
+        // 時間単位が公称より短いと同じ露光でもカウント数が 65535 を超える
+        let c = &config.camera;
+        config.camera.fot_calibration.push(FotCalibration {
+            readout_mode: c.readout_mode,
+            width: c.width,
+            height: c.height,
+            dphy_speed: c.dphy_speed,
+            fot_us: 45.0,
+            time_unit_us: NOMINAL_TIME_UNIT_US * 0.9,
+            residual_us: 0.0,
+            samples: 0,
+        });
+        assert!(config.validate().is_err());
+
+        // 条件の違う校正値は使わない
+        config.camera.fot_calibration[0].height = 240;
+        assert!(config.validate().is_ok());
+    }
+}
@@ -3,11 +3,20 @@
 use std::error::Error;
 use std::result::Result;
 
+use serde::{Deserialize, Serialize};
+
 use jelly_lib::i2c_hal::I2cHal;
 use jelly_lib::linux_i2c::LinuxI2c;
 use jelly_mem_access::*;
 use rtcl_lib::rtcl_p3s7_module_driver::*;
 
+use crate::camera_config::PmodConfig;
+use crate::fot_calibration::*;
+use crate::frame_stats::FrameAccounting;
+use crate::roi_tracker::RoiPlacement;
+use crate::stream_watchdog::*;
+use crate::timing_monitor::TimingMonitor;
+
 const SYSREG_ID: usize = 0x0000;
 const SYSREG_DPHY_SW_RESET: usize = 0x0001;
 const SYSREG_CAM_ENABLE: usize = 0x0002;
@@ -34,8 +43,143 @@ const REG_VIDEO_FMTREG_PARAM_HEIGHT: usize = 0x11;
 const REG_VIDEO_FMTREG_PARAM_FILL: usize = 0x12;
 const REG_VIDEO_FMTREG_PARAM_TIMEOUT: usize = 0x13;
 
-const MULT_TIME_UNIT : f32 = 1.0 / 72.0; // 72MHz(0.013888 us)
-const FOT_TIME : f32 = 45.4133;          // 実測値より (45.4133 us)
+const MULT_TIME_UNIT : f32 = NOMINAL_TIME_UNIT_US; // 72MHz(0.013888 us)
+const FOT_TIME : f32 = NOMINAL_FOT_US;              // 実測値より (45.4133 us)
+
+// フォーマットレギュレータの既定値 (fmtr クロックのサイクル数)
+const DEFAULT_FRAME_TIMEOUT: usize = 20000000;
+const DEFAULT_PIXEL_TIMEOUT: usize = 100000;
+
+// センサーの画素数
+pub const SENSOR_WIDTH: usize = 672;
+pub const SENSOR_HEIGHT: usize = 512;
+
+// センサー ROI 位置の単位
+pub const ROI_STEP_X: usize = 16;
+pub const ROI_STEP_Y: usize = 2;
+
+/// フレームカウンタの差 `a - b` (ハードウェアのカウンタは 32bit で一周する)
+pub fn frame_count_diff(a: usize, b: usize) -> usize {
+    (a as u32).wrapping_sub(b as u32) as usize
+}
+
+/// フレームカウンタに `n` を足した値 (32bit で一周する)
+pub fn frame_count_add(count: usize, n: usize) -> usize {
+    (count as u32).wrapping_add(n as u32) as usize
+}
+
+/// センサーの読み出しモード
+///
+/// 間引き/ビニングでは ROI をセンサー上で縦横 2 倍に取って半分の解像度で出力するので、
+/// 同じ画像サイズでも画角が広がり、読み出し時間 (最短周期) は変わらない。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadoutMode {
+    /// 全画素読み出し
+    #[default]
+    Full,
+    /// 2x2 間引き (カラーはベイヤー配列を保って間引く)
+    Subsampling2x2,
+    /// 2x2 ビニング (モノクロのみ)
+    Binning2x2,
+}
+
+impl ReadoutMode {
+    /// センサー上の ROI と出力画像の倍率
+    pub fn factor(&self) -> usize {
+        match self {
+            ReadoutMode::Full => 1,
+            ReadoutMode::Subsampling2x2 | ReadoutMode::Binning2x2 => 2,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReadoutMode::Full           => "full",
+            ReadoutMode::Subsampling2x2 => "subsampling2x2",
+            ReadoutMode::Binning2x2     => "binning2x2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ReadoutMode> {
+        match name.to_ascii_lowercase().as_str() {
+            "full"                                  => Some(ReadoutMode::Full),
+            "subsampling2x2" | "subsampling" | "sub" => Some(ReadoutMode::Subsampling2x2),
+            "binning2x2" | "binning" | "bin"         => Some(ReadoutMode::Binning2x2),
+            _ => None,
+        }
+    }
+
+    /// 出力できる最大画像サイズ (幅は 16 の倍数)
+    pub fn max_image_size(&self) -> (usize, usize) {
+        let f = self.factor();
+        ((SENSOR_WIDTH / f) & !0x0f, (SENSOR_HEIGHT / f) & !0x01)
+    }
+
+    /// 出力画像サイズに対するセンサー上の ROI サイズ
+    pub fn sensor_roi_size(&self, width: usize, height: usize) -> (usize, usize) {
+        (width * self.factor(), height * self.factor())
+    }
+
+    /// 出力画像サイズとカラー設定の検査
+    pub fn check_image_size(&self, width: usize, height: usize, color: bool) -> Result<(), Box<dyn Error>> {
+        if color && *self == ReadoutMode::Binning2x2 {
+            return Err("2x2 binning mixes bayer colors and cannot be used with color sensor".into());
+        }
+        let (max_w, max_h) = self.max_image_size();
+        if !(16..=max_w).contains(&width) || width & 0x0f != 0 {
+            return Err(format!("invalid width for {} readout: {} (16..={}, multiple of 16)", self.name(), width, max_w).into());
+        }
+        if !(2..=max_h).contains(&height) || height & 0x01 != 0 {
+            return Err(format!("invalid height for {} readout: {} (2..={}, multiple of 2)", self.name(), height, max_h).into());
+        }
+        Ok(())
+    }
+}
+
+/// sys レジスタのステータス読み出し
+///
+/// I2C を使わずにレジスタを読むだけなので、`CameraDriver` を
+/// ロックしている処理とは独立に fps やフレームカウンタを読める。
+#[derive(Clone)]
+pub struct CameraStatus<U>
+where
+    U: Copy + Clone,
+{
+    reg_sys: UioAccessor<U>,
+    fps_counter_clock_hz: f32,
+}
+
+impl<U> CameraStatus<U>
+where
+    U: Copy + Clone,
+{
+    /// ハードウェアフレームカウンタ
+    pub fn frame_count(&self) -> usize {
+        unsafe { self.reg_sys.read_reg(SYSREG_FRAME_COUNT) }
+    }
+
+    /// FPS カウンタの生値
+    pub fn fps_count(&self) -> usize {
+        unsafe { self.reg_sys.read_reg(SYSREG_FPS_COUNT) }
+    }
+
+    pub fn measure_fps(&self) -> f32 {
+        let fps_count = self.fps_count();
+        if fps_count == 0 {
+            return 0.0;
+        }
+        self.fps_counter_clock_hz / fps_count as f32
+    }
+
+    /// フレーム周期 [ns]
+    pub fn measure_frame_period(&self) -> f32 {
+        if self.fps_counter_clock_hz <= 0.0 {
+            return 0.0;
+        }
+        self.fps_count() as f32 * (1_000_000_000.0f32 / self.fps_counter_clock_hz)
+    }
+}
 
 type RtclP3s7ModuleDriverLinux = RtclP3s7ModuleDriver<LinuxI2c>;
 type RegAccess = UdmabufAccessor<usize>;
@@ -55,6 +199,9 @@ where
     color : bool,
     width: usize,
     height: usize,
+    readout_mode: ReadoutMode,
+    roi_x: Option<usize>,
+    roi_y: Option<usize>,
     slave_mode: bool,
     trigger_mode: bool,
     dphy_speed : f64,
@@ -63,6 +210,15 @@ where
     mult_timer: u16,
     fr_length: u16,
     exposure: u16,
+    xsm_delay: u16,
+    monitor_select: Option<u16>,
+    pmod: PmodConfig,
+    frame_timeout: usize,
+    pixel_timeout: usize,
+    fill_value: u16,
+    fill_value_set: bool,
+    watchdog: Option<Watchdog>,
+    fot_calibrations: Vec<FotCalibration>,
 }
 
 impl<I2C, U> CameraDriver<I2C, U>
@@ -85,6 +241,9 @@ where
             color : false,
             width: 640,
             height: 480,
+            readout_mode: ReadoutMode::Full,
+            roi_x: None,
+            roi_y: None,
             slave_mode: false,
             dphy_speed : 1250000000.0,
             fps_counter_clock_hz: 250_000_000.0,
@@ -93,6 +252,15 @@ where
             mult_timer: 72,
             fr_length: 0,
             exposure: 10000,
+            xsm_delay: 0,
+            monitor_select: None,
+            pmod: PmodConfig::default(),
+            frame_timeout: DEFAULT_FRAME_TIMEOUT,
+            pixel_timeout: DEFAULT_PIXEL_TIMEOUT,
+            fill_value: 0,
+            fill_value_set: false,
+            watchdog: None,
+            fot_calibrations: Vec::new(),
         }
     }
 
@@ -108,6 +276,10 @@ where
         self.pgood_enable = enable;
     }
 
+    pub fn sensor_pgood_enable(&self) -> bool {
+        self.pgood_enable
+    }
+
     pub fn sensor_ready(&mut self) -> Result<bool, Box<dyn Error>> {
         Ok(self.cam_i2c.sensor_ready()?)
     }
@@ -120,6 +292,10 @@ where
         self.color = color;
     }
 
+    pub fn color(&self) -> bool {
+        self.color
+    }
+
     pub fn set_dphy_speed(&mut self, dphy_speed: f64) {
         self.dphy_speed = dphy_speed;
     }
@@ -141,6 +317,10 @@ where
         Ok(())
     }
 
+    pub fn black_lines(&mut self) -> usize {
+        self.cam_i2c.black_lines() as usize
+    }
+
     pub fn opend(&self) -> bool {
         self.opend
     }
@@ -150,6 +330,7 @@ where
         if self.opend {
             return Ok(());
         }
+        self.readout_mode.check_image_size(self.width, self.height, self.color)?;
 
         // カメラモジュールリセット
         /*
@@ -209,7 +390,7 @@ where
         }
         
         // xsm_delay
-        let xsm_delay = self.cam_i2c.calc_xsm_delay(self.width);
+        let xsm_delay = self.cam_i2c.calc_xsm_delay(self.width).max(self.xsm_delay);
         self.cam_i2c.set_xsm_delay(xsm_delay)?;
         self.cam_i2c.set_nzrot_xsm_delay_enable(true)?;
         self.cam_i2c.set_zero_rot_enable(true)?;
@@ -217,10 +398,12 @@ where
         // センサー起動
         self.cam_i2c.set_color(self.color)?;
         self.cam_i2c.set_sensor_enable(true)?;
+        if let Some(sel) = self.monitor_select {
+            self.cam_i2c.set_monitor_select(sel)?;
+        }
 
-        // ROI 設定
-        self.cam_i2c
-            .set_roi0(self.width as u16, self.height as u16, None, None)?;
+        // 読み出しモードと ROI 設定
+        self.write_readout_geometry()?;
         self.cam_i2c.set_gain_db(self.gain)?;
 
         self.cam_i2c.set_mult_timer0(self.mult_timer)?;  // 68MHz
@@ -232,14 +415,15 @@ where
             self.reg_fmtr
                 .write_reg(REG_VIDEO_FMTREG_CTL_FRM_TIMER_EN, 1);
             self.reg_fmtr
-                .write_reg(REG_VIDEO_FMTREG_CTL_FRM_TIMEOUT, 20000000);
+                .write_reg(REG_VIDEO_FMTREG_CTL_FRM_TIMEOUT, self.frame_timeout);
             self.reg_fmtr
                 .write_reg(REG_VIDEO_FMTREG_PARAM_WIDTH, self.width);
             self.reg_fmtr
                 .write_reg(REG_VIDEO_FMTREG_PARAM_HEIGHT, self.height);
-            self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_PARAM_FILL, 0x0);
             self.reg_fmtr
-                .write_reg(REG_VIDEO_FMTREG_PARAM_TIMEOUT, 100000);
+                .write_reg(REG_VIDEO_FMTREG_PARAM_FILL, self.fill_value as usize);
+            self.reg_fmtr
+                .write_reg(REG_VIDEO_FMTREG_PARAM_TIMEOUT, self.pixel_timeout);
             self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_CONTROL, 0x03);
         }
         std::thread::sleep(std::time::Duration::from_micros(1000));
@@ -252,6 +436,9 @@ where
         self.cam_i2c.set_sequencer_enable(true)?;
 
         self.opend = true;
+        if let Some(wd) = self.watchdog.as_mut() {
+            wd.rearm();
+        }
         Ok(())
     }
 
@@ -295,19 +482,27 @@ where
     }
 
     pub fn set_pmod_mode(&mut self, mode: u16) -> Result<(), Box<dyn Error>> {
-        Ok(self.cam_i2c.set_pmod_mode(mode)?)
+        self.cam_i2c.set_pmod_mode(mode)?;
+        self.pmod.mode = Some(mode);
+        Ok(())
     }
 
     pub fn set_pmod_trigger_select(&mut self, sel: u16) -> Result<(), Box<dyn Error>> {
-        Ok(self.cam_i2c.set_pmod_trigger_select(sel)?)
+        self.cam_i2c.set_pmod_trigger_select(sel)?;
+        self.pmod.trigger_select = Some(sel);
+        Ok(())
     }
 
     pub fn set_pmod_header_select(&mut self, sel: u16) -> Result<(), Box<dyn Error>> {
-        Ok(self.cam_i2c.set_pmod_header_select(sel)?)
+        self.cam_i2c.set_pmod_header_select(sel)?;
+        self.pmod.header_select = Some(sel);
+        Ok(())
     }
 
     pub fn set_pmod_slot_len(&mut self, sel: u16) -> Result<(), Box<dyn Error>> {
-        Ok(self.cam_i2c.set_pmod_slot_len(sel)?)
+        self.cam_i2c.set_pmod_slot_len(sel)?;
+        self.pmod.slot_len = Some(sel);
+        Ok(())
     }
 
     pub fn set_pmod_slot_pattern(
@@ -315,7 +510,9 @@ where
         index: u16,
         pattern: u16,
     ) -> Result<(), RtclP3s7ModuleDriverError<I2C::Error>> {
-        Ok(self.cam_i2c.set_pmod_slot_pattern(index, pattern)?)
+        self.cam_i2c.set_pmod_slot_pattern(index, pattern)?;
+        PmodConfig::set_slot(&mut self.pmod.slot_pattern, index, pattern);
+        Ok(())
     }
 
     pub fn set_pmod_slot_time(
@@ -323,7 +520,14 @@ where
         index: u16,
         pattern: u16,
     ) -> Result<(), RtclP3s7ModuleDriverError<I2C::Error>> {
-        Ok(self.cam_i2c.set_pmod_slot_time(index, pattern)?)
+        self.cam_i2c.set_pmod_slot_time(index, pattern)?;
+        PmodConfig::set_slot(&mut self.pmod.slot_time, index, pattern);
+        Ok(())
+    }
+
+    /// これまでに設定した PMOD 設定
+    pub fn pmod_config(&self) -> &PmodConfig {
+        &self.pmod
     }
 
     pub fn read_pmod(&mut self) -> Result<u8, Box<dyn Error>> {
@@ -331,11 +535,15 @@ where
     }
 
     pub fn set_gpio_out(&mut self, value: u8) -> Result<(), Box<dyn Error>> {
-        Ok(self.cam_i2c.set_gpio_out(value)?)
+        self.cam_i2c.set_gpio_out(value)?;
+        self.pmod.gpio_out = Some(value);
+        Ok(())
     }
 
     pub fn set_gpio_dir(&mut self, dir: u8) -> Result<(), Box<dyn Error>> {
-        Ok(self.cam_i2c.set_gpio_dir(dir)?)
+        self.cam_i2c.set_gpio_dir(dir)?;
+        self.pmod.gpio_dir = Some(dir);
+        Ok(())
     }
 
     /// スレーブモード設定
@@ -356,17 +564,49 @@ where
         Ok(())
     }
 
+    pub fn slave_mode(&self) -> bool {
+        self.slave_mode
+    }
+
+    pub fn trigger_mode(&self) -> bool {
+        self.trigger_mode
+    }
+
+    /// 出力画像サイズ設定 (現在の読み出しモードのまま)
     pub fn set_image_size(&mut self, width: usize, height: usize) -> Result<(), Box<dyn Error>> {
+        self.set_readout_geometry(self.readout_mode, width, height)
+    }
+
+    /// 読み出しモード設定
+    ///
+    /// センサー上の ROI (画角) をなるべく保つように出力画像サイズを合わせる。
+    pub fn set_readout_mode(&mut self, mode: ReadoutMode) -> Result<(), Box<dyn Error>> {
+        let (roi_w, roi_h) = self.readout_mode.sensor_roi_size(self.width, self.height);
+        let (max_w, max_h) = mode.max_image_size();
+        let width = (roi_w / mode.factor()).clamp(16, max_w) & !0x0f;
+        let height = (roi_h / mode.factor()).clamp(2, max_h) & !0x01;
+        self.set_readout_geometry(mode, width, height)
+    }
+
+    pub fn readout_mode(&self) -> ReadoutMode {
+        self.readout_mode
+    }
+
+    /// 読み出しモードと出力画像サイズをまとめて設定
+    ///
+    /// センサー ROI、受信側の画像サイズ、fmtr のサイズ、XSM delay を一貫して設定する。
+    pub fn set_readout_geometry(&mut self, mode: ReadoutMode, width: usize, height: usize) -> Result<(), Box<dyn Error>> {
+        mode.check_image_size(width, height, self.color)?;
         if self.opend() {
             unsafe {
                 self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_CONTROL, 0x00);
             }
             self.cam_i2c.set_sequencer_enable(false)?;
             std::thread::sleep(std::time::Duration::from_millis(100));
+            self.readout_mode = mode;
             self.width = width;
             self.height = height;
-            self.cam_i2c
-                .set_roi0(self.width as u16, self.height as u16, None, None)?;
+            self.write_readout_geometry()?;
             unsafe {
                 self.reg_sys.write_reg(SYSREG_IMAGE_WIDTH, self.width);
                 self.reg_sys.write_reg(SYSREG_IMAGE_HEIGHT, self.height);
@@ -376,18 +616,70 @@ where
                     .write_reg(REG_VIDEO_FMTREG_PARAM_HEIGHT, self.height);
                 self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_CONTROL, 0x03);
             }
-            let xsm_delay = self.cam_i2c.calc_xsm_delay(self.width);
+            let xsm_delay = self.cam_i2c.calc_xsm_delay(self.width).max(self.xsm_delay);
             self.cam_i2c.set_xsm_delay(xsm_delay)?;
             self.cam_i2c.set_nzrot_xsm_delay_enable(true)?;
             self.cam_i2c.set_zero_rot_enable(true)?;
             self.cam_i2c.set_sequencer_enable(true)?;
         } else {
+            self.readout_mode = mode;
             self.width = width;
             self.height = height;
         }
         Ok(())
     }
 
+    /// センサー上の ROI サイズ
+    pub fn sensor_roi_size(&self) -> (usize, usize) {
+        self.readout_mode.sensor_roi_size(self.width, self.height)
+    }
+
+    // 読み出しモードとセンサー ROI の書き込み (XSM delay は出力ライン長で決まる)
+    fn write_readout_geometry(&mut self) -> Result<(), Box<dyn Error>> {
+        self.cam_i2c.set_subsampling(self.readout_mode == ReadoutMode::Subsampling2x2)?;
+        self.cam_i2c.set_binning(self.readout_mode == ReadoutMode::Binning2x2)?;
+        let (roi_w, roi_h) = self.sensor_roi_size();
+        let (roi_x, roi_y) = self.roi_offset();
+        self.cam_i2c.set_roi0(roi_w as u16, roi_h as u16, Some(roi_x as u16), Some(roi_y as u16))?;
+        Ok(())
+    }
+
+    /// センサー ROI の位置設定 (None ならその方向は中央)
+    ///
+    /// 出力画像サイズは変えずに ROI だけを動かすので、撮影中でも
+    /// シーケンサを止めずに変更できる。位置はセンサー内に収まるように
+    /// 制限し、x は 16、y は 2 の倍数に丸める。実際に設定した位置を返す。
+    pub fn set_roi_offset(&mut self, x: Option<usize>, y: Option<usize>) -> Result<(usize, usize), Box<dyn Error>> {
+        self.roi_x = x;
+        self.roi_y = y;
+        let (roi_x, roi_y) = self.roi_offset();
+        if self.opend() {
+            let (roi_w, roi_h) = self.sensor_roi_size();
+            self.cam_i2c.set_roi0(roi_w as u16, roi_h as u16, Some(roi_x as u16), Some(roi_y as u16))?;
+        }
+        Ok((roi_x, roi_y))
+    }
+
+    /// センサー ROI の左上位置 (センサー座標)
+    ///
+    /// 指定位置を最も近い `ROI_STEP_X` / `ROI_STEP_Y` の倍数に丸める。
+    pub fn roi_offset(&self) -> (usize, usize) {
+        let (roi_w, roi_h) = self.sensor_roi_size();
+        let max_x = SENSOR_WIDTH.saturating_sub(roi_w) / ROI_STEP_X * ROI_STEP_X;
+        let max_y = SENSOR_HEIGHT.saturating_sub(roi_h) / ROI_STEP_Y * ROI_STEP_Y;
+        let round = |v: usize, step: usize| (v + step / 2) / step * step;
+        let x = round(self.roi_x.unwrap_or(max_x / 2), ROI_STEP_X).min(max_x);
+        let y = round(self.roi_y.unwrap_or(max_y / 2), ROI_STEP_Y).min(max_y);
+        (x, y)
+    }
+
+    /// センサー ROI の配置 (画像座標とセンサー座標の変換用)
+    pub fn roi_placement(&self) -> RoiPlacement {
+        let (x, y) = self.roi_offset();
+        let (width, height) = self.sensor_roi_size();
+        RoiPlacement { x, y, width, height, factor: self.readout_mode.factor() }
+    }
+
     pub fn image_width(&self) -> usize {
         self.width
     }
@@ -396,17 +688,30 @@ where
     }
 
 
+    /// XSM delay の下限設定 (画像幅から計算した値より小さい場合は計算値を使う)
     pub fn set_xsm_delay(&mut self, delay: u16) -> Result<(), Box<dyn Error>> {
+        self.xsm_delay = delay;
         let xsm_delay = self.cam_i2c.calc_xsm_delay(self.width).max(delay);
         self.cam_i2c.set_xsm_delay(xsm_delay)?;
         Ok(())
     }
 
+    pub fn xsm_delay(&self) -> u16 {
+        self.xsm_delay
+    }
+
     pub fn set_monitor_select(&mut self, sel: u16) -> Result<(), Box<dyn Error>> {
-        self.cam_i2c.set_monitor_select(sel)?;
+        self.monitor_select = Some(sel);
+        if self.opend {
+            self.cam_i2c.set_monitor_select(sel)?;
+        }
         Ok(())
     }
 
+    pub fn monitor_select(&self) -> Option<u16> {
+        self.monitor_select
+    }
+
     pub fn set_gain(&mut self, db: f32) -> Result<(), Box<dyn Error>> {
         if self.opend {
             self.cam_i2c.set_gain_db(db)?;
@@ -421,8 +726,18 @@ where
         self.gain
     }
 
+    /// デジタルゲインを直接設定 (AWB の共通ゲイン用、set_gain で再計算される)
+    pub fn set_digital_gain_linear(&mut self, gain: f32) -> Result<(), Box<dyn Error>> {
+        self.cam_i2c.set_digital_gain_linear(gain)?;
+        Ok(())
+    }
+
+    pub fn digital_gain_linear(&self) -> f32 {
+        self.cam_i2c.digital_gain_linear()
+    }
+
     pub fn set_exposure(&mut self, us : f32) -> Result<(), Box<dyn Error>> {
-        let unit =  (self.mult_timer as f32) * MULT_TIME_UNIT;
+        let unit =  (self.mult_timer as f32) * self.time_unit_us();
         self.exposure = (us / unit) as u16;
         if self.opend {
             self.cam_i2c.set_exposure0(self.exposure)?;
@@ -431,13 +746,13 @@ where
     }
 
     pub fn exposure(&self) -> Result<f32, Box<dyn Error>> {
-        let unit =  (self.mult_timer as f32) * MULT_TIME_UNIT;
+        let unit =  (self.mult_timer as f32) * self.time_unit_us();
         Ok(self.exposure as f32 * unit)
     }
 
     pub fn set_frame_period(&mut self, us : f32) -> Result<(), Box<dyn Error>> {
-        let unit =  (self.mult_timer as f32) * MULT_TIME_UNIT;
-        self.fr_length = ((us - FOT_TIME) / unit) as u16;
+        let unit =  (self.mult_timer as f32) * self.time_unit_us();
+        self.fr_length = ((us - self.fot_us()) / unit) as u16;
         if self.opend {
             self.cam_i2c.set_fr_length0(self.fr_length)?;
         }
@@ -445,8 +760,8 @@ where
     }
 
     pub fn frame_period(&mut self) -> Result<f32, Box<dyn Error>> {
-        let unit =  (self.mult_timer as f32) * MULT_TIME_UNIT;
-        Ok(self.fr_length as f32 * unit + FOT_TIME)
+        let unit =  (self.mult_timer as f32) * self.time_unit_us();
+        Ok(self.fr_length as f32 * unit + self.fot_us())
     }
 
     pub fn set_frame_rate(&mut self, fps : f32) -> Result<(), Box<dyn Error>> {
@@ -470,6 +785,132 @@ where
         self.fr_length
     }
 
+    /// 露光/フレーム長の時間単位 (72MHz のクロック数) 設定
+    pub fn set_mult_timer(&mut self, mult_timer: u16) -> Result<(), Box<dyn Error>> {
+        if mult_timer == 0 {
+            return Err("mult_timer must be greater than 0".into());
+        }
+        self.mult_timer = mult_timer;
+        if self.opend {
+            self.cam_i2c.set_mult_timer0(self.mult_timer)?;
+        }
+        Ok(())
+    }
+
+    pub fn mult_timer(&self) -> u16 {
+        self.mult_timer
+    }
+
+    /// FOT 校正結果の設定 (読み出しモード・画像サイズ・D-PHY 速度が一致するものを使う)
+    pub fn set_fot_calibrations(&mut self, calibrations: Vec<FotCalibration>) {
+        self.fot_calibrations = calibrations;
+    }
+
+    pub fn fot_calibrations(&self) -> &[FotCalibration] {
+        &self.fot_calibrations
+    }
+
+    /// 校正結果を追加 (同じ条件のものは置き換える)
+    pub fn add_fot_calibration(&mut self, calibration: FotCalibration) {
+        self.fot_calibrations.retain(|c| !c.matches(calibration.readout_mode, calibration.width, calibration.height, calibration.dphy_speed));
+        self.fot_calibrations.push(calibration);
+    }
+
+    /// 現在の条件に一致する校正結果
+    pub fn fot_calibration(&self) -> Option<&FotCalibration> {
+        self.fot_calibrations.iter().find(|c| c.matches(self.readout_mode, self.width, self.height, self.dphy_speed))
+    }
+
+    /// フレームオーバーヘッド時間 [us] (校正結果が無ければ公称値)
+    pub fn fot_us(&self) -> f32 {
+        self.fot_calibration().map_or(FOT_TIME, |c| c.fot_us)
+    }
+
+    /// mult_timer 1 カウントの時間 [us] (校正結果が無ければ公称値)
+    pub fn time_unit_us(&self) -> f32 {
+        self.fot_calibration().map_or(MULT_TIME_UNIT, |c| c.time_unit_us)
+    }
+
+    /// FOT の自動校正
+    ///
+    /// フリーラン中に mult_timer と fr_length を掃引して FPS カウンタで周期を測り、
+    /// 周期 = fr_length * mult_timer * unit + FOT を最小二乗で求める。
+    /// 結果は現在の読み出しモード・画像サイズ・D-PHY 速度の校正値として登録し、設定は元に戻す。
+    pub fn calibrate_fot(&mut self, sweep: &FotSweep) -> Result<FotCalibration, Box<dyn Error>> {
+        if !self.opend {
+            return Err("camera is not opened".into());
+        }
+        if self.slave_mode || self.trigger_mode {
+            return Err("FOT calibration needs free-run mode".into());
+        }
+
+        let (mult_timer, fr_length, exposure) = (self.mult_timer, self.fr_length, self.exposure);
+        let result = self.sweep_fot(sweep);
+
+        // 元の設定に戻す
+        self.cam_i2c.set_mult_timer0(mult_timer)?;
+        self.cam_i2c.set_fr_length0(fr_length)?;
+        self.cam_i2c.set_exposure0(exposure)?;
+
+        let samples = result?;
+        let (fot_us, time_unit_us, residual_us) = fit_fot(&samples)?;
+        let calibration = FotCalibration {
+            readout_mode: self.readout_mode,
+            width: self.width,
+            height: self.height,
+            dphy_speed: self.dphy_speed,
+            fot_us,
+            time_unit_us,
+            residual_us,
+            samples: samples.len(),
+        };
+        calibration.validate()?;
+        self.add_fot_calibration(calibration);
+        Ok(calibration)
+    }
+
+    fn sweep_fot(&mut self, sweep: &FotSweep) -> Result<Vec<FotSample>, Box<dyn Error>> {
+        let mut samples = Vec::new();
+        for (mult_timer, fr_length) in sweep.points() {
+            // 露光が周期を延ばさないように短くしておく
+            let exposure = self.exposure.min(fr_length / 2).max(1);
+            self.cam_i2c.set_mult_timer0(mult_timer)?;
+            self.cam_i2c.set_fr_length0(fr_length)?;
+            self.cam_i2c.set_exposure0(exposure)?;
+
+            let period_us = (fr_length as f32 * mult_timer as f32 * MULT_TIME_UNIT + FOT_TIME) as f64;
+            let timeout = std::time::Duration::from_micros((period_us * 4.0) as u64 + 100_000);
+            for _ in 0..sweep.settle_frames {
+                self.wait_next_frame(timeout)?;
+            }
+
+            let mut sum = 0.0;
+            for _ in 0..sweep.average_frames.max(1) {
+                self.wait_next_frame(timeout)?;
+                sum += self.fps_count() as f64 * 1_000_000.0 / self.fps_counter_clock_hz as f64;
+            }
+            samples.push(FotSample {
+                mult_timer,
+                fr_length,
+                period_us: sum / sweep.average_frames.max(1) as f64,
+            });
+        }
+        Ok(samples)
+    }
+
+    // フレームカウンタが進むのを待つ
+    fn wait_next_frame(&self, timeout: std::time::Duration) -> Result<(), Box<dyn Error>> {
+        let start = std::time::Instant::now();
+        let count = self.frame_count();
+        while self.frame_count() == count {
+            if start.elapsed() > timeout {
+                return Err("frame timeout".into());
+            }
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+        Ok(())
+    }
+
     /// fps 計測
     pub fn measure_fps(&self) -> f32 {
         let fps_count   = unsafe{self.reg_sys.read_reg(SYSREG_FPS_COUNT)};
@@ -487,6 +928,292 @@ where
         fps_count as f32 * (1_000_000_000.0f32 / self.fps_counter_clock_hz)
     }
 
+    /// ハードウェアフレームカウンタ読み出し
+    pub fn frame_count(&self) -> usize {
+        unsafe { self.reg_sys.read_reg(SYSREG_FRAME_COUNT) }
+    }
+
+    /// FPS カウンタの生値 (直前のフレーム周期のカウンタクロック数)
+    pub fn fps_count(&self) -> usize {
+        unsafe { self.reg_sys.read_reg(SYSREG_FPS_COUNT) }
+    }
+
+    /// 他スレッドから読むためのステータス読み出し
+    pub fn status(&self) -> CameraStatus<U> {
+        CameraStatus {
+            reg_sys: self.reg_sys.clone(),
+            fps_counter_clock_hz: self.fps_counter_clock_hz,
+        }
+    }
+
+    /// 現在の設定周期で比較するタイミングモニタを作る
+    ///
+    /// 外部トリガ時は周期が分からないので `set_expected_period_us` で与えること。
+    pub fn timing_monitor(&mut self, capacity: usize) -> TimingMonitor {
+        let mut mon = TimingMonitor::new(self.fps_counter_clock_hz, capacity);
+        if !self.slave_mode && !self.trigger_mode {
+            mon.set_expected_period_us(self.frame_period().ok().map(|us| us as f64));
+        }
+        mon
+    }
+
+    /// FPS カウンタとフレームカウンタを 1 回サンプリング (センサー設定は変更しない)
+    pub fn sample_timing(&self, mon: &mut TimingMonitor) {
+        // 読み出し中にフレームが切り替わったら捨てる
+        let count0 = self.frame_count();
+        let fps_count = self.fps_count();
+        let count1 = self.frame_count();
+        if count0 == count1 {
+            mon.add_sample(fps_count, count1);
+        }
+    }
+
+    /// フレームタイムアウト設定 (fmtr クロックのサイクル数)
+    ///
+    /// この期間フレームが来ないと、fmtr は `fill_value` で埋めたフレームを出力する。
+    pub fn set_frame_timeout(&mut self, cycles: usize) {
+        self.frame_timeout = cycles;
+        if self.opend {
+            unsafe {
+                self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_FRM_TIMEOUT, cycles);
+            }
+        }
+    }
+
+    pub fn frame_timeout(&self) -> usize {
+        self.frame_timeout
+    }
+
+    /// 画素タイムアウト設定 (fmtr クロックのサイクル数)
+    ///
+    /// フレームの途中で入力が途切れると、残りを `fill_value` で埋める。
+    pub fn set_pixel_timeout(&mut self, cycles: usize) {
+        self.pixel_timeout = cycles;
+        if self.opend {
+            unsafe {
+                self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_PARAM_TIMEOUT, cycles);
+                self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_CONTROL, 0x03); // update
+            }
+        }
+    }
+
+    pub fn pixel_timeout(&self) -> usize {
+        self.pixel_timeout
+    }
+
+    /// タイムアウト時に埋める画素値
+    ///
+    /// センサーが出力しない値 (黒レベルより小さい値など) にしておくと
+    /// `FrameAccounting` でタイムアウトしたフレームを判別できる。
+    /// 設定しなければ (既定の 0 は暗画像にも現れるので) 判別しない。
+    pub fn set_fill_value(&mut self, value: u16) {
+        self.fill_value = value;
+        self.fill_value_set = true;
+        if self.opend {
+            unsafe {
+                self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_PARAM_FILL, value as usize);
+                self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_CONTROL, 0x03); // update
+            }
+        }
+    }
+
+    pub fn fill_value(&self) -> u16 {
+        self.fill_value
+    }
+
+    /// 現在のフレームカウンタから欠落フレームの集計を開始
+    ///
+    /// フリーラン時は設定したフレーム周期から届かなかったフレームも推定する。
+    pub fn start_accounting(&mut self) -> FrameAccounting {
+        let mut acc = FrameAccounting::new();
+        if self.fill_value_set {
+            acc.set_fill_value(self.fill_value);
+        }
+        if self.opend && !self.slave_mode && !self.trigger_mode {
+            acc.set_expected_period_us(self.frame_period().ok().map(|us| us as f64));
+        }
+        acc.start(self.frame_count());
+        acc
+    }
+
+    /// ストリーム監視の設定 (None で無効)
+    ///
+    /// 有効にしたら `poll_watchdog` をフレーム周期程度の間隔で呼ぶこと。
+    pub fn set_watchdog(&mut self, config: Option<WatchdogConfig>) {
+        self.watchdog = config.map(Watchdog::new);
+    }
+
+    pub fn watchdog_config(&self) -> Option<WatchdogConfig> {
+        self.watchdog.as_ref().map(|wd| wd.config)
+    }
+
+    /// これまでに実行した復旧処理の記録
+    pub fn watchdog_log(&self) -> &[RecoveryEvent] {
+        match &self.watchdog {
+            Some(wd) => wd.log(),
+            None => &[],
+        }
+    }
+
+    pub fn clear_watchdog_log(&mut self) {
+        if let Some(wd) = self.watchdog.as_mut() {
+            wd.clear_log();
+        }
+    }
+
+    /// ストリーム監視
+    ///
+    /// フレームカウンタ・fmtr の状態・FPS カウンタを確認し、停止していれば
+    /// 再アライメント → シーケンサ再起動 → D-PHY リセット → 電源再投入 の順に
+    /// 1 段ずつ復旧を試みる。すべて試しても復旧しなければエラーを返す。
+    pub fn poll_watchdog(&mut self) -> Result<WatchdogStatus, Box<dyn Error>> {
+        let mut wd = match self.watchdog.take() {
+            Some(wd) => wd,
+            None => return Ok(WatchdogStatus::Disabled),
+        };
+        let status = self.poll_watchdog_with(&mut wd);
+        self.watchdog = Some(wd);
+        status
+    }
+
+    fn poll_watchdog_with(&mut self, wd: &mut Watchdog) -> Result<WatchdogStatus, Box<dyn Error>> {
+        // 復旧途中で close されたままの場合は電源再投入を続ける
+        if !self.opend && !wd.recovering() {
+            return Ok(WatchdogStatus::Disabled);
+        }
+
+        let frame_count = self.frame_count();
+        let mut reason = None;
+        if wd.update_count(frame_count) {
+            // フリーラン時は周期も確認
+            if !self.slave_mode && !self.trigger_mode {
+                let measured_us = self.measure_frame_period() / 1000.0;
+                let expected_us = self.frame_period()?;
+                if wd.check_period(measured_us, expected_us) {
+                    reason = Some(StallReason::PeriodMismatch { measured_us, expected_us });
+                }
+            }
+            if reason.is_none() {
+                if wd.period_ok() {
+                    if let Some(action) = wd.take_recovered() {
+                        return Ok(WatchdogStatus::Recovered(action));
+                    }
+                }
+                return Ok(WatchdogStatus::Running);
+            }
+        }
+
+        let reason = match reason {
+            Some(reason) => reason,
+            None => {
+                if wd.stalled_for() < wd.config.stall_timeout {
+                    return Ok(WatchdogStatus::Running);
+                }
+                // fmtr の CTL_STATUS bit0 は動作中フラグ
+                let fmtr_status = unsafe { self.reg_fmtr.read_reg(REG_VIDEO_FMTREG_CTL_STATUS) };
+                if self.opend && wd.config.check_fmtr_status && fmtr_status & 1 == 0 {
+                    StallReason::FormatRegularizerStopped
+                } else {
+                    StallReason::FrameCountStalled
+                }
+            }
+        };
+
+        let action = match wd.next_action() {
+            Some(_) if !self.opend => RecoveryAction::PowerCycle,
+            Some(action) => action,
+            None => return Err(format!("camera stream recovery failed ({:?}, {} attempts)", reason, wd.attempts()).into()),
+        };
+        let result = match action {
+            RecoveryAction::Realign          => self.realign_receiver(),
+            RecoveryAction::RestartSequencer => self.restart_sequencer(),
+            RecoveryAction::DphyReset        => self.reset_dphy(),
+            RecoveryAction::PowerCycle       => self.power_cycle(),
+        };
+        let event = wd.record(reason, action, frame_count, result.err().map(|e| e.to_string()));
+        Ok(WatchdogStatus::Recovery(event))
+    }
+
+    /// センサー側レシーバーの再アライメント
+    ///
+    /// シーケンサを止めてトレーニングパターンで合わせ直し、再開する。
+    pub fn realign_receiver(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.opend {
+            return Err("camera is not opened".into());
+        }
+        self.cam_i2c.set_sensor_receiver_enable(true)?;
+        self.cam_i2c.set_sequencer_enable(true)?;
+        Ok(())
+    }
+
+    /// fmtr とシーケンサの再起動
+    pub fn restart_sequencer(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.opend {
+            return Err("camera is not opened".into());
+        }
+        self.cam_i2c.set_sequencer_enable(false)?;
+        unsafe {
+            self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_CONTROL, 0x00);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        unsafe {
+            self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_CONTROL, 0x03);
+        }
+        std::thread::sleep(std::time::Duration::from_micros(1000));
+        self.cam_i2c.set_sequencer_enable(true)?;
+        Ok(())
+    }
+
+    /// D-PHY のリセットシーケンスをやり直す (センサー電源は入れたまま)
+    pub fn reset_dphy(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.opend {
+            return Err("camera is not opened".into());
+        }
+
+        // video input stop
+        unsafe {
+            self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_CONTROL, 0x00);
+        }
+        self.cam_i2c.set_sequencer_enable(false)?;
+
+        // 受信側とセンサー基板側の DPHY リセット
+        unsafe {
+            self.reg_sys.write_reg(SYSREG_DPHY_SW_RESET, 1);
+        }
+        self.cam_i2c.set_dphy_reset(true)?;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        // 受信側 DPHY 解除 (必ずこちらを先に解除)
+        unsafe {
+            self.reg_sys.write_reg(SYSREG_DPHY_SW_RESET, 0);
+        }
+        self.cam_i2c.set_dphy_reset(false)?;
+        if !self.cam_i2c.dphy_init_done()? {
+            return Err("CAM DPHY TX init_done = 0".into());
+        }
+        if unsafe { self.reg_sys.read_reg(SYSREG_DPHY_INIT_DONE) } == 0 {
+            return Err("DPHY RX init_done = 0".into());
+        }
+
+        // 再アライメントして再開
+        self.cam_i2c.set_sensor_receiver_enable(true)?;
+        unsafe {
+            self.reg_fmtr.write_reg(REG_VIDEO_FMTREG_CTL_CONTROL, 0x03);
+        }
+        std::thread::sleep(std::time::Duration::from_micros(1000));
+        self.cam_i2c.set_sequencer_enable(true)?;
+        Ok(())
+    }
+
+    /// センサー電源を入れ直して設定を再適用
+    pub fn power_cycle(&mut self) -> Result<(), Box<dyn Error>> {
+        // close に失敗しても (I2C エラーなど) open からやり直す
+        if self.close().is_err() {
+            self.opend = false;
+        }
+        self.open()
+    }
+
     pub fn print_sensor_register(&mut self) {
         self.cam_i2c.sensor_reg_dump().unwrap();
     }
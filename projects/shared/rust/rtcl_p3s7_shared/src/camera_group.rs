@@ -0,0 +1,463 @@
+#![allow(dead_code)]
+
+use std::time::{Duration, Instant};
+
+use jelly_lib::i2c_hal::I2cHal;
+use jelly_mem_access::*;
+
+use crate::camera_driver::{frame_count_diff, CameraDriver};
+use crate::capture_driver::{CaptureDriver, StreamFrame};
+use crate::frame::FrameBuf;
+use crate::timing_generator_driver::TimingGeneratorDriver;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// 同期開始/キャプチャ開始の位置合わせのリトライ回数
+const ALIGN_RETRY: usize = 3;
+
+// フレームカウンタを読み揃える時のリトライ回数
+const SYNC_READ_RETRY: usize = 10;
+
+/// フレームカウンタによる同期確認の結果
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SyncReport {
+    /// 同期開始からのフレーム数 (カメラ毎)
+    pub counts: Vec<usize>,
+    /// 最大と最小の差
+    pub spread: usize,
+    /// 許容範囲内か
+    pub ok: bool,
+    /// 読み出し中にカウンタが動き続けて揃えられなかった
+    pub unstable: bool,
+}
+
+impl SyncReport {
+    /// 他より遅れているカメラの番号
+    pub fn lagging(&self) -> Vec<usize> {
+        let max = self.counts.iter().copied().max().unwrap_or(0);
+        self.counts.iter().enumerate()
+            .filter(|&(_, &c)| c != max)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+impl core::fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "counts={:?} spread={} {}", self.counts, self.spread, if self.ok { "ok" } else { "out of sync" })?;
+        if self.unstable {
+            write!(f, " (unstable)")?;
+        }
+        Ok(())
+    }
+}
+
+/// 同時録画の結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupRecord {
+    /// 先頭フレームのグループフレーム番号
+    pub first_index: usize,
+    /// 全カメラで揃っているフレーム数
+    pub frames: usize,
+}
+
+/// 全カメラで同じグループフレーム番号を持つストリームフレーム
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedFrames {
+    pub index: usize,
+    /// カメラ毎のフレーム (カメラの登録順)
+    pub frames: Vec<StreamFrame>,
+}
+
+// グループ内の 1 台分
+struct GroupCamera<I2C, U, T0, T1>
+where
+    I2C: I2cHal,
+    <I2C as I2cHal>::Error: std::error::Error + 'static,
+    U: Copy + Clone,
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+{
+    name: String,
+    cam: CameraDriver<I2C, U>,
+    capture: CaptureDriver<T0, T1>,
+    // 同期開始時のフレームカウンタ
+    start_count: usize,
+    // 録画開始時のフレームカウンタ
+    record_count: Option<usize>,
+}
+
+impl<I2C, U, T0, T1> GroupCamera<I2C, U, T0, T1>
+where
+    I2C: I2cHal,
+    <I2C as I2cHal>::Error: std::error::Error + 'static,
+    U: Copy + Clone,
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+{
+    // 同期開始からのフレーム数
+    fn frame_index(&self) -> usize {
+        frame_count_diff(self.cam.frame_count(), self.start_count)
+    }
+
+    // フレームカウンタ値 `count` の時に書き込まれるフレームのグループフレーム番号
+    fn group_index(&self, count: usize) -> usize {
+        frame_count_diff(count, self.start_count)
+    }
+}
+
+/// 複数カメラの同期運転
+///
+/// 全カメラをスレーブ + トリガモードにして 1 つのタイミングジェネレータの
+/// トリガで同時に露光させる。タイミングジェネレータ停止中に各カメラの
+/// フレームカウンタを基準として記録し、以降はその差分 (グループフレーム番号)
+/// で各カメラのフレームを対応付ける。
+///
+/// 各カメラは I2C バスと sys/fmtr/DMA のレジスタを個別に持つこと。
+pub struct CameraGroup<I2C, U, T0, T1, TG>
+where
+    I2C: I2cHal,
+    <I2C as I2cHal>::Error: std::error::Error + 'static,
+    U: Copy + Clone,
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+    TG: MemAccess,
+{
+    timgen: TimingGeneratorDriver<TG>,
+    members: Vec<GroupCamera<I2C, U, T0, T1>>,
+    period_us: f64,
+    exposure_us: f64,
+    sync_tolerance: usize,
+    running: bool,
+}
+
+impl<I2C, U, T0, T1, TG> CameraGroup<I2C, U, T0, T1, TG>
+where
+    I2C: I2cHal,
+    <I2C as I2cHal>::Error: std::error::Error + 'static,
+    U: Copy + Clone,
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+    TG: MemAccess,
+{
+    /// マスタトリガとなるタイミングジェネレータを指定して作る
+    pub fn new(timgen: TimingGeneratorDriver<TG>) -> Self {
+        Self {
+            timgen,
+            members: Vec::new(),
+            period_us: 10000.0,
+            exposure_us: 5000.0,
+            sync_tolerance: 0,
+            running: false,
+        }
+    }
+
+    /// カメラを追加 (スレーブ + トリガモードに設定する)
+    ///
+    /// カメラ番号を返す。
+    pub fn add_camera(&mut self, name: &str, mut cam: CameraDriver<I2C, U>, capture: CaptureDriver<T0, T1>) -> Result<usize> {
+        if self.running {
+            return Err("cannot add camera while group is running".into());
+        }
+        cam.set_slave_mode(true)?;
+        cam.set_trigger_mode(true)?;
+        self.members.push(GroupCamera {
+            name: name.to_string(),
+            cam,
+            capture,
+            start_count: 0,
+            record_count: None,
+        });
+        Ok(self.members.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn name(&self, index: usize) -> &str {
+        &self.members[index].name
+    }
+
+    pub fn camera(&self, index: usize) -> &CameraDriver<I2C, U> {
+        &self.members[index].cam
+    }
+
+    pub fn camera_mut(&mut self, index: usize) -> &mut CameraDriver<I2C, U> {
+        &mut self.members[index].cam
+    }
+
+    pub fn capture(&self, index: usize) -> &CaptureDriver<T0, T1> {
+        &self.members[index].capture
+    }
+
+    pub fn capture_mut(&mut self, index: usize) -> &mut CaptureDriver<T0, T1> {
+        &mut self.members[index].capture
+    }
+
+    pub fn timing_generator(&self) -> &TimingGeneratorDriver<TG> {
+        &self.timgen
+    }
+
+    /// トリガ周期と露光時間 [us] (動作中は次の周期境界で反映)
+    pub fn set_timing(&mut self, period_us: f64, exposure_us: f64) -> Result<()> {
+        if !(exposure_us > 0.0 && exposure_us < period_us) {
+            return Err(format!("exposure {} us must be shorter than period {} us", exposure_us, period_us).into());
+        }
+        if self.running {
+            self.timgen.update_timing(period_us, exposure_us)?;
+        }
+        self.period_us = period_us;
+        self.exposure_us = exposure_us;
+        Ok(())
+    }
+
+    pub fn period_us(&self) -> f64 {
+        self.period_us
+    }
+
+    pub fn exposure_us(&self) -> f64 {
+        self.exposure_us
+    }
+
+    /// 同期とみなすフレームカウンタのずれ (既定 0)
+    pub fn set_sync_tolerance(&mut self, frames: usize) {
+        self.sync_tolerance = frames;
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// 全カメラを open (トリガは止めておく)
+    pub fn open(&mut self) -> Result<()> {
+        self.timgen.stop();
+        for m in self.members.iter_mut() {
+            if !m.cam.opend() {
+                m.cam.open().map_err(|e| format!("camera '{}': {}", m.name, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 全カメラを close
+    pub fn close(&mut self) -> Result<()> {
+        self.stop()?;
+        for m in self.members.iter_mut() {
+            m.cam.close().map_err(|e| format!("camera '{}': {}", m.name, e))?;
+        }
+        Ok(())
+    }
+
+    /// 同期開始
+    ///
+    /// トリガを止めて全カメラのフレームカウンタが止まるのを待ち、
+    /// その値を基準にしてからトリガを開始する。
+    pub fn start(&mut self) -> Result<()> {
+        if self.members.is_empty() {
+            return Err("camera group is empty".into());
+        }
+        if let Some(m) = self.members.iter().find(|m| !m.cam.opend()) {
+            return Err(format!("camera '{}' is not opened", m.name).into());
+        }
+
+        self.stop()?;
+        self.wait_counters_settled()?;
+        for m in self.members.iter_mut() {
+            m.start_count = m.cam.frame_count();
+            m.record_count = None;
+        }
+
+        self.timgen.update_timing(self.period_us, self.exposure_us)?;
+        self.timgen.start();
+        self.running = true;
+        Ok(())
+    }
+
+    /// トリガ停止 (ストリーミング中なら DMA も止める)
+    pub fn stop(&mut self) -> Result<()> {
+        self.timgen.stop();
+        self.timgen.wait_stop(Duration::from_secs(1))?;
+        for m in self.members.iter_mut() {
+            m.capture.stop_stream()?;
+        }
+        self.running = false;
+        Ok(())
+    }
+
+    // トリガ停止後に書き込み中のフレームが出切るのを待つ
+    fn wait_counters_settled(&self) -> Result<()> {
+        let interval = Duration::from_secs_f64(self.period_us / 1_000_000.0) + Duration::from_millis(10);
+        let mut last = self.raw_counts();
+        for _ in 0..100 {
+            std::thread::sleep(interval);
+            let counts = self.raw_counts();
+            if counts == last {
+                return Ok(());
+            }
+            last = counts;
+        }
+        Err("frame counters do not settle after stopping trigger".into())
+    }
+
+    fn raw_counts(&self) -> Vec<usize> {
+        self.members.iter().map(|m| m.cam.frame_count()).collect()
+    }
+
+    /// 同期開始からのフレーム数 (カメラ毎)
+    pub fn frame_counts(&self) -> Vec<usize> {
+        self.members.iter().map(|m| m.frame_index()).collect()
+    }
+
+    /// フレームカウンタを比較して同期を確認
+    ///
+    /// 読み出しの途中でフレームが進むと見かけ上ずれるので、
+    /// 2 回続けて同じ値が読めるまでやり直す。
+    pub fn check_sync(&self) -> SyncReport {
+        let mut counts = self.frame_counts();
+        let mut unstable = true;
+        for _ in 0..SYNC_READ_RETRY {
+            let again = self.frame_counts();
+            if again == counts {
+                unstable = false;
+                break;
+            }
+            counts = again;
+            std::thread::sleep(Duration::from_micros(100));
+        }
+        let max = counts.iter().copied().max().unwrap_or(0);
+        let min = counts.iter().copied().min().unwrap_or(0);
+        let spread = max - min;
+        SyncReport {
+            ok: spread <= self.sync_tolerance,
+            counts,
+            spread,
+            unstable,
+        }
+    }
+
+    // 基準カメラのフレームカウンタが進むのを待つ (周期の先頭に合わせる)
+    fn wait_next_frame(&self) -> Result<()> {
+        let timeout = Duration::from_secs_f64(self.period_us * 2.0 / 1_000_000.0) + Duration::from_secs(1);
+        let start = Instant::now();
+        let count = self.members[0].cam.frame_count();
+        while self.members[0].cam.frame_count() == count {
+            if start.elapsed() > timeout {
+                return Err("timeout waiting for frame".into());
+            }
+            std::thread::sleep(Duration::from_micros(100));
+        }
+        Ok(())
+    }
+
+    /// 全カメラで同じフレームから `frames` フレーム録画
+    ///
+    /// フレームの切り替わり直後に全カメラの DMA を起動し、開始フレームの
+    /// グループフレーム番号が揃わなければやり直す。
+    pub fn record(&mut self, frames: usize) -> Result<GroupRecord> {
+        if !self.running {
+            return Err("camera group is not running".into());
+        }
+
+        let mut aligned = None;
+        for _ in 0..ALIGN_RETRY {
+            self.wait_next_frame()?;
+            let mut indices = Vec::with_capacity(self.members.len());
+            for m in self.members.iter_mut() {
+                let count = m.cam.frame_count();
+                m.capture.start_record(m.cam.image_width(), m.cam.image_height(), frames, count)?;
+                m.record_count = Some(count);
+                indices.push(m.group_index(count));
+            }
+            if indices.iter().all(|&i| i == indices[0]) {
+                aligned = Some(indices[0]);
+                break;
+            }
+            for m in self.members.iter_mut() {
+                let count = m.cam.frame_count();
+                m.capture.cancel_record(count)?;
+                m.record_count = None;
+            }
+        }
+        let first_index = aligned.ok_or("failed to start recording on the same frame")?;
+
+        let timeout = Duration::from_secs_f64(self.period_us * (frames + 2) as f64 / 1_000_000.0) + Duration::from_secs(1);
+        let mut recorded = frames;
+        for m in self.members.iter_mut() {
+            let n = m.capture.wait_record(timeout, || m.cam.frame_count()).map_err(|e| format!("camera '{}': {}", m.name, e))?;
+            recorded = recorded.min(n);
+        }
+        Ok(GroupRecord { first_index, frames: recorded })
+    }
+
+    /// グループフレーム番号に対応する録画フレーム番号
+    pub fn record_index(&self, camera: usize, group_index: usize) -> Option<usize> {
+        let m = &self.members[camera];
+        let first = m.group_index(m.record_count?);
+        let index = group_index.checked_sub(first)?;
+        if index < m.capture.record_frames() { Some(index) } else { None }
+    }
+
+    /// 全カメラでストリーミング開始
+    pub fn start_stream(&mut self, slots: usize) -> Result<()> {
+        if !self.running {
+            return Err("camera group is not running".into());
+        }
+        self.wait_next_frame()?;
+        for m in self.members.iter_mut() {
+            let count = m.cam.frame_count();
+            m.capture.start_stream(m.cam.image_width(), m.cam.image_height(), slots, count)?;
+        }
+        Ok(())
+    }
+
+    /// 全カメラのストリーミング停止
+    pub fn stop_stream(&mut self) -> Result<()> {
+        for m in self.members.iter_mut() {
+            m.capture.stop_stream()?;
+        }
+        Ok(())
+    }
+
+    /// グループフレーム番号 `index` のフレームを全カメラから集める
+    ///
+    /// どれかのカメラでまだ書き込まれていないか、既に上書きされていれば None。
+    pub fn stream_frames_at(&self, index: usize) -> Option<MatchedFrames> {
+        let mut frames = Vec::with_capacity(self.members.len());
+        for m in self.members.iter() {
+            let base = m.group_index(frame_count_diff(m.capture.stream_frame_count(0), 1));
+            let seq = index.checked_sub(base)?;
+            frames.push(m.capture.stream_frame_at(seq)?);
+        }
+        Some(MatchedFrames { index, frames })
+    }
+
+    /// 全カメラで書き込み済みの最も新しいフレーム
+    pub fn latest_matched_frames(&self) -> Option<MatchedFrames> {
+        let mut latest: Option<usize> = None;
+        for m in self.members.iter() {
+            let frame = m.capture.latest_stream_frame()?;
+            let index = m.group_index(frame_count_diff(m.capture.stream_frame_count(frame.seq), 1));
+            latest = Some(latest.map_or(index, |l| l.min(index)));
+        }
+        self.stream_frames_at(latest?)
+    }
+
+    /// 対応付けたフレームを全カメラからコピーして取り出す
+    ///
+    /// どれかのカメラでコピー中に上書きされていれば None。
+    pub fn read_matched_frames(&self, matched: &MatchedFrames) -> Result<Option<Vec<FrameBuf>>> {
+        let mut images = Vec::with_capacity(matched.frames.len());
+        for (m, frame) in self.members.iter().zip(matched.frames.iter()) {
+            match m.capture.read_stream_frame(frame)? {
+                Some(image) => images.push(image),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(images))
+    }
+}
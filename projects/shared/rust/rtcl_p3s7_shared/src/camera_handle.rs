@@ -0,0 +1,407 @@
+#![allow(dead_code)]
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use jelly_lib::i2c_hal::I2cHal;
+use jelly_mem_access::*;
+
+use crate::camera_config::PmodConfig;
+use crate::camera_driver::{CameraDriver, CameraStatus, ReadoutMode};
+use crate::capture_driver::CaptureDriver;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 制御側で変わった設定 (変更後の値)
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlChange {
+    Opened,
+    Closed,
+    ImageSize { width: usize, height: usize },
+    ReadoutMode(ReadoutMode),
+    RoiOffset { x: usize, y: usize },
+    /// アナログ+デジタルゲイン [dB]
+    Gain(f32),
+    /// 露光時間 [us]
+    Exposure(f32),
+    /// センサーのフレーム周期 [us]
+    FramePeriod(f32),
+    SlaveMode(bool),
+    TriggerMode(bool),
+    Color(bool),
+    Pmod(PmodConfig),
+}
+
+// 変更検出用の設定のスナップショット
+#[derive(Debug, Clone, PartialEq)]
+struct ControlSnapshot {
+    opened: bool,
+    width: usize,
+    height: usize,
+    readout_mode: ReadoutMode,
+    roi_offset: (usize, usize),
+    gain: f32,
+    exposure: f32,
+    frame_period: f32,
+    slave_mode: bool,
+    trigger_mode: bool,
+    color: bool,
+    pmod: PmodConfig,
+}
+
+impl ControlSnapshot {
+    fn new<I2C, U>(cam: &mut CameraDriver<I2C, U>) -> Self
+    where
+        I2C: I2cHal,
+        <I2C as I2cHal>::Error: std::error::Error + 'static,
+        U: Copy + Clone,
+    {
+        Self {
+            opened: cam.opend(),
+            width: cam.image_width(),
+            height: cam.image_height(),
+            readout_mode: cam.readout_mode(),
+            roi_offset: cam.roi_offset(),
+            gain: cam.gain(),
+            exposure: cam.exposure().unwrap_or(0.0),
+            frame_period: cam.frame_period().unwrap_or(0.0),
+            slave_mode: cam.slave_mode(),
+            trigger_mode: cam.trigger_mode(),
+            color: cam.color(),
+            pmod: cam.pmod_config().clone(),
+        }
+    }
+
+    // 前回からの変更点 (open/close は最初と最後に並べる)
+    fn changes(&self, new: &Self) -> Vec<ControlChange> {
+        let mut changes = Vec::new();
+        if !self.opened && new.opened {
+            changes.push(ControlChange::Opened);
+        }
+        if (self.width, self.height) != (new.width, new.height) {
+            changes.push(ControlChange::ImageSize { width: new.width, height: new.height });
+        }
+        if self.readout_mode != new.readout_mode {
+            changes.push(ControlChange::ReadoutMode(new.readout_mode));
+        }
+        if self.roi_offset != new.roi_offset {
+            changes.push(ControlChange::RoiOffset { x: new.roi_offset.0, y: new.roi_offset.1 });
+        }
+        if self.gain != new.gain {
+            changes.push(ControlChange::Gain(new.gain));
+        }
+        if self.exposure != new.exposure {
+            changes.push(ControlChange::Exposure(new.exposure));
+        }
+        if self.frame_period != new.frame_period {
+            changes.push(ControlChange::FramePeriod(new.frame_period));
+        }
+        if self.slave_mode != new.slave_mode {
+            changes.push(ControlChange::SlaveMode(new.slave_mode));
+        }
+        if self.trigger_mode != new.trigger_mode {
+            changes.push(ControlChange::TriggerMode(new.trigger_mode));
+        }
+        if self.color != new.color {
+            changes.push(ControlChange::Color(new.color));
+        }
+        if self.pmod != new.pmod {
+            changes.push(ControlChange::Pmod(new.pmod.clone()));
+        }
+        if self.opened && !new.opened {
+            changes.push(ControlChange::Closed);
+        }
+        changes
+    }
+}
+
+// 変更通知の配信先 (受信側が drop されたら外す)
+struct ChangeBus {
+    subscribers: Mutex<Vec<Sender<ControlChange>>>,
+}
+
+impl ChangeBus {
+    fn new() -> Self {
+        Self { subscribers: Mutex::new(Vec::new()) }
+    }
+
+    fn subscribe(&self) -> Receiver<ControlChange> {
+        let (tx, rx) = channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(tx);
+        }
+        rx
+    }
+
+    fn publish(&self, changes: &[ControlChange]) {
+        if changes.is_empty() {
+            return;
+        }
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|tx| changes.iter().all(|c| tx.send(c.clone()).is_ok()));
+        }
+    }
+}
+
+/// スレッド間で共有するカメラハンドル
+///
+/// 制御側 (`CameraControl`: I2C/センサー設定) とキャプチャ側
+/// (`CameraCapture`: DMA) を別々のロックで持つので、長い録画中でも
+/// 設定変更や fps の読み出しが待たされない。ステータスは sys レジスタを
+/// 読むだけなので、どちらのロックも取らない。
+pub struct CameraHandle<I2C, U, T0, T1>
+where
+    I2C: I2cHal,
+    <I2C as I2cHal>::Error: std::error::Error + 'static,
+    U: Copy + Clone,
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+{
+    control: CameraControl<I2C, U>,
+    capture: CameraCapture<U, T0, T1>,
+}
+
+impl<I2C, U, T0, T1> CameraHandle<I2C, U, T0, T1>
+where
+    I2C: I2cHal,
+    <I2C as I2cHal>::Error: std::error::Error + 'static,
+    U: Copy + Clone,
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+{
+    pub fn new(cam: CameraDriver<I2C, U>, capture: CaptureDriver<T0, T1>) -> Self {
+        let control = CameraControl::new(cam);
+        let capture = control.attach_capture(capture);
+        Self { control, capture }
+    }
+
+    pub fn control(&self) -> CameraControl<I2C, U> {
+        self.control.clone()
+    }
+
+    pub fn capture(&self) -> CameraCapture<U, T0, T1> {
+        self.capture.clone()
+    }
+
+    /// 制御側とキャプチャ側に分ける
+    pub fn split(self) -> (CameraControl<I2C, U>, CameraCapture<U, T0, T1>) {
+        (self.control, self.capture)
+    }
+}
+
+/// カメラの制御側 (clone して複数スレッドから使える)
+pub struct CameraControl<I2C, U>
+where
+    I2C: I2cHal,
+    <I2C as I2cHal>::Error: std::error::Error + 'static,
+    U: Copy + Clone,
+{
+    cam: Arc<Mutex<CameraDriver<I2C, U>>>,
+    status: Arc<Mutex<CameraStatus<U>>>,
+    changes: Arc<ChangeBus>,
+}
+
+impl<I2C, U> Clone for CameraControl<I2C, U>
+where
+    I2C: I2cHal,
+    <I2C as I2cHal>::Error: std::error::Error + 'static,
+    U: Copy + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            cam: self.cam.clone(),
+            status: self.status.clone(),
+            changes: self.changes.clone(),
+        }
+    }
+}
+
+impl<I2C, U> CameraControl<I2C, U>
+where
+    I2C: I2cHal,
+    <I2C as I2cHal>::Error: std::error::Error + 'static,
+    U: Copy + Clone,
+{
+    pub fn new(cam: CameraDriver<I2C, U>) -> Self {
+        let status = cam.status();
+        Self {
+            cam: Arc::new(Mutex::new(cam)),
+            status: Arc::new(Mutex::new(status)),
+            changes: Arc::new(ChangeBus::new()),
+        }
+    }
+
+    /// 同じカメラのキャプチャ側を作る (黒画素用など DMA が複数ある場合は複数回呼ぶ)
+    pub fn attach_capture<T0, T1>(&self, capture: CaptureDriver<T0, T1>) -> CameraCapture<U, T0, T1>
+    where
+        T0: MemAccess + Clone,
+        T1: MemAccess,
+    {
+        CameraCapture {
+            capture: Arc::new(Mutex::new(capture)),
+            status: self.status.clone(),
+        }
+    }
+
+    /// 変更通知の受信側を作る (受信側ごとにすべての変更が届く)
+    pub fn subscribe(&self) -> Receiver<ControlChange> {
+        self.changes.subscribe()
+    }
+
+    /// ドライバを直接ロックする (変更は通知されない)
+    pub fn lock(&self) -> Result<MutexGuard<'_, CameraDriver<I2C, U>>> {
+        self.cam.lock().map_err(|_| "camera control lock poisoned".into())
+    }
+
+    /// ドライバを操作して、変わった設定を通知する
+    ///
+    /// 操作がエラーになっても、途中で変わった設定は通知する。
+    pub fn update<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut CameraDriver<I2C, U>) -> Result<R>,
+    {
+        let mut cam = self.lock()?;
+        let before = ControlSnapshot::new(&mut cam);
+        let result = f(&mut cam);
+        let after = ControlSnapshot::new(&mut cam);
+        if let Ok(mut status) = self.status.lock() {
+            *status = cam.status();
+        }
+        drop(cam);
+        self.changes.publish(&before.changes(&after));
+        result
+    }
+
+    pub fn status(&self) -> Result<CameraStatus<U>> {
+        Ok(self.status.lock().map_err(|_| "camera status lock poisoned")?.clone())
+    }
+
+    pub fn frame_count(&self) -> Result<usize> {
+        Ok(self.status()?.frame_count())
+    }
+
+    pub fn measure_fps(&self) -> Result<f32> {
+        Ok(self.status()?.measure_fps())
+    }
+
+    pub fn measure_frame_period(&self) -> Result<f32> {
+        Ok(self.status()?.measure_frame_period())
+    }
+
+    pub fn open(&self) -> Result<()> {
+        self.update(|cam| cam.open())
+    }
+
+    pub fn close(&self) -> Result<()> {
+        self.update(|cam| cam.close())
+    }
+
+    pub fn set_gain(&self, db: f32) -> Result<()> {
+        self.update(|cam| cam.set_gain(db))
+    }
+
+    pub fn set_exposure(&self, us: f32) -> Result<()> {
+        self.update(|cam| cam.set_exposure(us))
+    }
+
+    pub fn set_frame_period(&self, us: f32) -> Result<()> {
+        self.update(|cam| cam.set_frame_period(us))
+    }
+
+    pub fn set_image_size(&self, width: usize, height: usize) -> Result<()> {
+        self.update(|cam| cam.set_image_size(width, height))
+    }
+
+    pub fn set_readout_mode(&self, mode: ReadoutMode) -> Result<()> {
+        self.update(|cam| cam.set_readout_mode(mode))
+    }
+
+    pub fn set_roi_offset(&self, x: Option<usize>, y: Option<usize>) -> Result<(usize, usize)> {
+        self.update(|cam| cam.set_roi_offset(x, y))
+    }
+
+    pub fn set_slave_mode(&self, enable: bool) -> Result<()> {
+        self.update(|cam| cam.set_slave_mode(enable))
+    }
+
+    pub fn set_trigger_mode(&self, enable: bool) -> Result<()> {
+        self.update(|cam| cam.set_trigger_mode(enable))
+    }
+
+    pub fn set_color(&self, color: bool) -> Result<()> {
+        self.update(|cam| { cam.set_color(color); Ok(()) })
+    }
+}
+
+/// カメラのキャプチャ側 (clone して複数スレッドから使える)
+pub struct CameraCapture<U, T0, T1>
+where
+    U: Copy + Clone,
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+{
+    capture: Arc<Mutex<CaptureDriver<T0, T1>>>,
+    status: Arc<Mutex<CameraStatus<U>>>,
+}
+
+impl<U, T0, T1> Clone for CameraCapture<U, T0, T1>
+where
+    U: Copy + Clone,
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+{
+    fn clone(&self) -> Self {
+        Self {
+            capture: self.capture.clone(),
+            status: self.status.clone(),
+        }
+    }
+}
+
+impl<U, T0, T1> CameraCapture<U, T0, T1>
+where
+    U: Copy + Clone,
+    T0: MemAccess + Clone,
+    T1: MemAccess,
+{
+    /// キャプチャドライバをロックする
+    pub fn lock(&self) -> Result<MutexGuard<'_, CaptureDriver<T0, T1>>> {
+        self.capture.lock().map_err(|_| "camera capture lock poisoned".into())
+    }
+
+    /// ハードウェアフレームカウンタ (制御側のロックは取らない)
+    pub fn frame_count(&self) -> Result<usize> {
+        Ok(self.status.lock().map_err(|_| "camera status lock poisoned")?.frame_count())
+    }
+
+    /// 同期録画 (録画中はキャプチャ側だけをロックする)
+    pub fn record(&self, width: usize, height: usize, frames: usize) -> Result<usize> {
+        self.lock()?.record(width, height, frames)
+    }
+
+    /// 非同期録画開始
+    pub fn start_record(&self, width: usize, height: usize, frames: usize) -> Result<usize> {
+        let frame_count = self.frame_count()?;
+        self.lock()?.start_record(width, height, frames, frame_count)
+    }
+
+    pub fn poll_record(&self) -> Result<bool> {
+        let frame_count = self.frame_count()?;
+        self.lock()?.poll_record(frame_count)
+    }
+
+    /// 非同期録画の中断 (書き込み済みのフレーム数を返す)
+    pub fn cancel_record(&self) -> Result<usize> {
+        let frame_count = self.frame_count()?;
+        self.lock()?.cancel_record(frame_count)
+    }
+
+    pub fn record_progress(&self) -> Result<usize> {
+        let frame_count = self.frame_count()?;
+        Ok(self.lock()?.record_progress(frame_count))
+    }
+
+    pub fn read_image_vec(&self, index: usize) -> Result<Vec<u8>> {
+        self.lock()?.read_image_vec(index)
+    }
+}
@@ -5,43 +5,231 @@ type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
 use jelly_mem_access::*;
 use jelly_lib::video_dma_pac::VideoDmaPac;
 
+use std::time::{Duration, SystemTime};
+
+use crate::camera_driver::{frame_count_add, frame_count_diff};
+use crate::frame::{Frame, FrameBuf};
+use crate::frame_meta::{CaptureSettings, FrameMeta};
+use crate::pixel_format::*;
+
 #[cfg(feature = "opencv")]
 use opencv::core::*;
 
-pub struct CaptureDriver<T0: MemAccess, T1: MemAccess>
+// Video DMA write
+const REG_VDMA_WRITE_CORE_ID: usize = 0x00;
+const REG_VDMA_WRITE_CORE_VERSION: usize = 0x01;
+const REG_VDMA_WRITE_CTL_CONTROL: usize = 0x04;
+const REG_VDMA_WRITE_CTL_STATUS: usize = 0x05;
+const REG_VDMA_WRITE_CTL_INDEX: usize = 0x07;
+const REG_VDMA_WRITE_PARAM_ADDR: usize = 0x10;
+const REG_VDMA_WRITE_PARAM_H_SIZE: usize = 0x20;
+const REG_VDMA_WRITE_PARAM_V_SIZE: usize = 0x24;
+const REG_VDMA_WRITE_PARAM_LINE_STEP: usize = 0x25;
+const REG_VDMA_WRITE_PARAM_F_SIZE: usize = 0x28;
+const REG_VDMA_WRITE_PARAM_FRAME_STEP: usize = 0x29;
+
+// CTL_INDEX は DMA がフレームの書き込みを開始する毎に 1 進む
+// (ストリーミングには INDEX_BITS を 32 にしたビットストリームが必要)
+
+// DMA のストリーム 1 ワードのバイト数
+// (Raw8 / PackedRaw10 は PL 側で画素を 16bit ワードに詰めて出力する)
+const DMA_WORD_BYTES: usize = 2;
+
+/// ストリーミングで受け取ったフレーム
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFrame {
+    /// ストリーム開始から DMA が書き込んだ順の通し番号
+    pub seq: usize,
+    /// 格納されているリングバッファのスロット番号
+    pub slot: usize,
+    /// 画素フォーマット
+    pub format: PixelFormat,
+}
+
+/// 録画/ストリーミングの開始情報 (メタデータ用)
+#[derive(Debug, Clone, Copy)]
+struct RecordStart {
+    time: SystemTime,
+    frame_count: Option<usize>,
+}
+
+/// 非同期録画の状態
+#[derive(Debug, Clone, Copy)]
+struct AsyncRecord {
+    frames: usize,
+    start_count: usize,
+    // DMA の busy を一度でも確認したか
+    busy_seen: bool,
+}
+
+/// プリトリガ録画の状態
+#[derive(Debug, Clone, Copy)]
+struct Pretrigger {
+    pre_frames: usize,
+    post_frames: usize,
+    trigger_seq: Option<usize>,
+    stop_written: Option<usize>,
+}
+
+/// PMOD 入力のエッジ検出トリガ
+#[derive(Debug, Clone, Copy)]
+pub struct PmodEdgeTrigger {
+    mask: u8,
+    rising: bool,
+    prev: Option<u8>,
+}
+
+impl PmodEdgeTrigger {
+    /// `bit` 番目の PMOD 入力の立ち上がり(`rising = true`)または立ち下がりを検出する
+    pub fn new(bit: u8, rising: bool) -> Self {
+        Self {
+            mask: 1 << (bit & 7),
+            rising,
+            prev: None,
+        }
+    }
+
+    /// `read_pmod` の値を渡し、エッジがあれば true を返す
+    pub fn check(&mut self, pmod: u8) -> bool {
+        let level = pmod & self.mask;
+        let edge = match self.prev {
+            Some(prev) if self.rising => prev == 0 && level != 0,
+            Some(prev) => prev != 0 && level == 0,
+            None => false,
+        };
+        self.prev = Some(level);
+        edge
+    }
+}
+
+/// フレーム平均輝度のしきい値トリガ
+#[derive(Debug, Clone, Copy)]
+pub struct LevelTrigger {
+    /// しきい値
+    pub threshold: f32,
+    /// true ならしきい値を超えたとき、false なら下回ったときにトリガ
+    pub above: bool,
+    /// 平均を取る際の画素の間引き間隔
+    pub step: usize,
+}
+
+impl LevelTrigger {
+    pub fn new(threshold: f32, above: bool) -> Self {
+        Self {
+            threshold,
+            above,
+            step: 16,
+        }
+    }
+
+    /// 指定スロットのフレームがトリガ条件を満たすか判定
+    pub fn check<T0: MemAccess + Clone, T1: MemAccess>(&self, cap: &CaptureDriver<T0, T1>, slot: usize) -> Result<bool> {
+        let mean = cap.frame_mean(slot, self.step)?;
+        Ok(if self.above { mean > self.threshold } else { mean < self.threshold })
+    }
+}
+
+pub struct CaptureDriver<T0: MemAccess + Clone, T1: MemAccess>
 {
+    reg_vdmaw: T0,
     vdmaw: VideoDmaPac<T0>,
     dmabuf: T1,
+    pixel_format: PixelFormat,
     record_frames: usize,
     record_width: usize,
     record_height: usize,
+    record_format: PixelFormat,
+    cache_sync_path: Option<std::path::PathBuf>,
+    cache_synced: bool,
+
+    streaming: bool,
+    stream_start_count: usize,
+    stream_start_index: usize,
+    stream_next_seq: usize,
+    stream_overwritten: usize,
+    pretrigger: Option<Pretrigger>,
+    async_record: Option<AsyncRecord>,
+    record_start: Option<RecordStart>,
 }
 
-impl<T0: MemAccess, T1: MemAccess> CaptureDriver<T0, T1>
+impl<T0: MemAccess + Clone, T1: MemAccess> CaptureDriver<T0, T1>
 {
     pub fn new(reg_vdmaw: T0, dmabuf: T1) -> Result<Self> {
         Ok(Self {
+            reg_vdmaw: reg_vdmaw.clone(),
             vdmaw: VideoDmaPac::<T0>::new(reg_vdmaw, 2, 2, None)?,
             dmabuf: dmabuf,
+            pixel_format: PixelFormat::default(),
             record_frames: 0,
             record_width: 0,
             record_height: 0,
+            record_format: PixelFormat::default(),
+            cache_sync_path: None,
+            cache_synced: false,
+            streaming: false,
+            stream_start_count: 0,
+            stream_start_index: 0,
+            stream_next_seq: 0,
+            stream_overwritten: 0,
+            pretrigger: None,
+            async_record: None,
+            record_start: None,
         })
     }
 
+    /// 画素フォーマット設定 (次の録画から有効)
+    ///
+    /// DMA の 1 ラインのワード数はフォーマットのライン長から求める。
+    /// Raw8 / PackedRaw10 は PL 側で画素を 16bit ワードに詰めて出力するデザインで使う。
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.pixel_format = format;
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    // DMA に設定する 1 ラインのワード数
+    fn dma_width(&self, width: usize) -> usize {
+        self.pixel_format.line_bytes(width) / DMA_WORD_BYTES
+    }
+
+    // 1 ラインが DMA のワード単位になっているか確認
+    fn check_width(&self, width: usize) -> Result<()> {
+        if width == 0 || !self.pixel_format.line_bytes(width).is_multiple_of(DMA_WORD_BYTES) {
+            return Err(format!("width {} is not a whole number of DMA words in {}", width, self.pixel_format).into());
+        }
+        Ok(())
+    }
+
+    // DMA を他の録画が使用中でないか確認
+    fn check_idle(&self) -> Result<()> {
+        if self.streaming {
+            return Err("stream is running".into());
+        }
+        if self.async_record.is_some() {
+            return Err("recording is running".into());
+        }
+        Ok(())
+    }
+
     pub fn record(&mut self, width: usize, height: usize, frames: usize) -> Result<usize> {
+        self.check_idle()?;
+        self.check_width(width)?;
+
         // 録画情報クリア
         self.record_width = width;
         self.record_height = height;
+        self.record_format = self.pixel_format;
         self.record_frames = 0;
 
         // DMAバッファへ録画
-        let buf_size = self.dmabuf.size();
-        let max_frames = buf_size / (width * height * 2);
-        let frames = core::cmp::min(frames, max_frames);
+        let frames = core::cmp::min(frames, self.max_frames(width, height));
+        self.record_start = Some(RecordStart { time: SystemTime::now(), frame_count: None });
+        self.cache_synced = false;
         self.vdmaw.oneshot(
             self.dmabuf.phys_addr(),
-            width as i32,
+            self.dma_width(width) as i32,
             height as i32,
             frames as i32,
             0,
@@ -53,12 +241,580 @@ impl<T0: MemAccess, T1: MemAccess> CaptureDriver<T0, T1>
 
         // 成功したら録画情報を更新
         self.record_frames = frames;
+        self.sync_record()?;
+
+        Ok(frames)
+    }
+
+    /// 非同期録画開始
+    ///
+    /// DMA を起動してすぐに戻る。完了は `poll_record` または `wait_record` で確認する。
+    /// `frame_count` には開始時点のハードウェアフレームカウンタ値を渡す。
+    /// 録画するフレーム数を返す。
+    pub fn start_record(&mut self, width: usize, height: usize, frames: usize, frame_count: usize) -> Result<usize> {
+        self.check_idle()?;
+        self.check_width(width)?;
+
+        // 録画情報クリア
+        self.record_width = width;
+        self.record_height = height;
+        self.record_format = self.pixel_format;
+        self.record_frames = 0;
+
+        let frames = core::cmp::min(frames, self.max_frames(width, height));
+        if frames == 0 {
+            return Err("dmabuf is too small for recording".into());
+        }
+
+        // ワンショットモードで DMA 起動
+        self.cache_synced = false;
+        let line_bytes = self.pixel_format.line_bytes(width);
+        let frame_size = self.pixel_format.frame_bytes(width, height);
+        unsafe {
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_ADDR, self.dmabuf.phys_addr());
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_LINE_STEP, line_bytes);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_H_SIZE, self.dma_width(width) - 1);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_V_SIZE, height - 1);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_FRAME_STEP, frame_size);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_F_SIZE, frames - 1);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_CTL_CONTROL, 0x07); // oneshot & update & enable
+        }
 
+        self.async_record = Some(AsyncRecord {
+            frames,
+            start_count: frame_count,
+            busy_seen: false,
+        });
+        self.record_start = Some(RecordStart { time: SystemTime::now(), frame_count: Some(frame_count) });
         Ok(frames)
     }
 
+    /// 非同期録画中か
+    pub fn recording(&self) -> bool {
+        self.async_record.is_some()
+    }
+
+    /// 非同期録画の完了確認
+    ///
+    /// `frame_count` には現在のハードウェアフレームカウンタ値を渡す。
+    /// 起動直後は DMA の busy がまだ立っていないことがあるので、busy を
+    /// 一度確認するか、フレームカウンタが録画フレーム数分進むまでは完了としない。
+    /// 完了していれば録画情報を更新して true を返す。
+    pub fn poll_record(&mut self, frame_count: usize) -> Result<bool> {
+        let rec = match self.async_record {
+            Some(rec) => rec,
+            None => return Ok(true),
+        };
+        if unsafe { self.reg_vdmaw.read_reg(REG_VDMA_WRITE_CTL_STATUS) } != 0 {
+            if let Some(rec) = self.async_record.as_mut() {
+                rec.busy_seen = true;
+            }
+            return Ok(false);
+        }
+        if !rec.busy_seen && self.record_progress(frame_count) < rec.frames {
+            return Ok(false);
+        }
+        self.async_record = None;
+        self.record_frames = rec.frames;
+        self.sync_record()?;
+        Ok(true)
+    }
+
+    /// 非同期録画の進捗 (書き込み完了済みのフレーム数)
+    pub fn record_progress(&self, frame_count: usize) -> usize {
+        match self.async_record {
+            Some(rec) => frame_count_diff(frame_count, rec.start_count)
+                .saturating_sub(1)
+                .min(rec.frames),
+            None => self.record_frames,
+        }
+    }
+
+    /// 非同期録画の完了を待つ
+    ///
+    /// `frame_count` は現在のハードウェアフレームカウンタを読む関数。
+    pub fn wait_record<F>(&mut self, timeout: std::time::Duration, mut frame_count: F) -> Result<usize>
+    where
+        F: FnMut() -> usize,
+    {
+        let start = std::time::Instant::now();
+        while !self.poll_record(frame_count())? {
+            if start.elapsed() > timeout {
+                return Err("recording timeout".into());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        Ok(self.record_frames)
+    }
+
+    /// 非同期録画の中断
+    ///
+    /// 中断までに書き込みが完了していたフレームは読み出し可能なまま残す。
+    /// 残ったフレーム数を返す。
+    pub fn cancel_record(&mut self, frame_count: usize) -> Result<usize> {
+        if self.async_record.is_none() {
+            return Ok(self.record_frames);
+        }
+        let progress = self.record_progress(frame_count);
+        unsafe {
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_CTL_CONTROL, 0x00);
+        }
+        self.async_record = None;
+        self.wait_dma_stop()?;
+        self.record_frames = progress;
+        self.sync_record()?;
+        Ok(progress)
+    }
+
+    // 書き込み中のフレームが終わるのを待つ
+    fn wait_dma_stop(&self) -> Result<()> {
+        for _ in 0..1000 {
+            if unsafe { self.reg_vdmaw.read_reg(REG_VDMA_WRITE_CTL_STATUS) } == 0 {
+                return Ok(());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        Err("video DMA stop timeout".into())
+    }
+
+    /// リングバッファへの連続キャプチャ開始
+    ///
+    /// DMA は `slots` 個のスロットを巡回しながら書き込み続ける。
+    /// 書き込み位置は DMA の CTL_INDEX から求める。`frame_count` には開始時点の
+    /// ハードウェアフレームカウンタ値 (`CameraDriver::frame_count`) を渡す
+    /// (`stream_frame_count` で使う)。使用するスロット数を返す。
+    pub fn start_stream(&mut self, width: usize, height: usize, slots: usize, frame_count: usize) -> Result<usize> {
+        self.check_idle()?;
+        self.check_width(width)?;
+
+        // 録画情報クリア
+        self.record_width = width;
+        self.record_height = height;
+        self.record_format = self.pixel_format;
+        self.record_frames = 0;
+
+        // スロット数決定
+        let line_bytes = self.pixel_format.line_bytes(width);
+        let frame_size = self.pixel_format.frame_bytes(width, height);
+        let slots = core::cmp::min(slots, self.max_frames(width, height));
+        if slots < 2 {
+            return Err("dmabuf is too small for streaming".into());
+        }
+
+        // 連続モードで DMA 起動
+        self.cache_synced = false;
+        self.stream_start_index = self.dma_index();
+        unsafe {
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_ADDR, self.dmabuf.phys_addr());
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_LINE_STEP, line_bytes);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_H_SIZE, self.dma_width(width) - 1);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_V_SIZE, height - 1);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_FRAME_STEP, frame_size);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_PARAM_F_SIZE, slots - 1);
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_CTL_CONTROL, 0x03); // update & enable
+        }
+
+        self.record_frames = slots;
+        self.streaming = true;
+        self.pretrigger = None;
+        self.stream_start_count = frame_count;
+        self.stream_next_seq = 0;
+        self.stream_overwritten = 0;
+        self.record_start = Some(RecordStart { time: SystemTime::now(), frame_count: Some(frame_count) });
+
+        Ok(slots)
+    }
+
+    /// 連続キャプチャ停止
+    pub fn stop_stream(&mut self) -> Result<()> {
+        if !self.streaming {
+            return Ok(());
+        }
+
+        unsafe {
+            self.reg_vdmaw.write_reg(REG_VDMA_WRITE_CTL_CONTROL, 0x00);
+        }
+        self.streaming = false;
+        self.wait_dma_stop()?;
+        self.sync_record()
+    }
+
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// DMA の CTL_INDEX (書き込みを開始したフレーム数、32bit で一周)
+    pub fn dma_index(&self) -> usize {
+        unsafe { self.reg_vdmaw.read_reg(REG_VDMA_WRITE_CTL_INDEX) }
+    }
+
+    /// 書き込み完了済みのフレーム数
+    ///
+    /// DMA の CTL_INDEX から求める。現在書き込み中のフレームは含めない。
+    pub fn stream_written(&self) -> usize {
+        frame_count_diff(self.dma_index(), self.stream_start_index)
+            .saturating_sub(1)
+    }
+
+    /// 録画/ストリーミング開始時のフレームカウンタ値
+    ///
+    /// 録画インデックス i のフレームはこの値 + i + 1 のフレームになる。
+    pub fn record_start_frame_count(&self) -> Option<usize> {
+        self.record_start.and_then(|rec| rec.frame_count)
+    }
+
+    /// 通し番号 `seq` のフレームを書き込んでいた時のフレームカウンタ値
+    ///
+    /// センサーのフレームがすべて DMA に書き込まれたとした場合の推定値。
+    /// フレーム毎の ROI 位置などを引くのに使う。
+    pub fn stream_frame_count(&self, seq: usize) -> usize {
+        frame_count_add(self.stream_start_count, seq + 1)
+    }
+
+    /// 次に取り出すフレームの通し番号
+    pub fn stream_next_seq(&self) -> usize {
+        self.stream_next_seq
+    }
+
+    /// 取り出しが間に合わず上書きされたフレーム数
+    pub fn stream_overwritten(&self) -> usize {
+        self.stream_overwritten
+    }
+
+    /// 次の書き込み済みフレームを取り出す
+    ///
+    /// 取り出しが遅れて上書きされたフレームは読み飛ばし、
+    /// `stream_overwritten` に計上する。
+    pub fn next_stream_frame(&mut self) -> Option<StreamFrame> {
+        if !self.streaming {
+            return None;
+        }
+
+        let written = self.stream_written();
+        if self.stream_next_seq >= written {
+            return None;
+        }
+
+        // 書き込み中のスロットを除いた範囲だけが有効
+        let oldest = written.saturating_sub(self.record_frames - 1);
+        if self.stream_next_seq < oldest {
+            self.stream_overwritten += oldest - self.stream_next_seq;
+            self.stream_next_seq = oldest;
+        }
+
+        let seq = self.stream_next_seq;
+        self.stream_next_seq += 1;
+        Some(StreamFrame {
+            seq,
+            slot: seq % self.record_frames,
+            format: self.record_format,
+        })
+    }
+
+    /// 読み出し後もフレームが上書きされていないか確認
+    pub fn stream_frame_valid(&self, frame: &StreamFrame) -> bool {
+        let written = self.stream_written();
+        frame.seq < written && frame.seq + self.record_frames > written
+    }
+
+    /// ストリーミング中のフレームをコピーして取り出す
+    ///
+    /// コピー後に DMA の書き込み位置を読み直し、コピー中に上書きされていたら None を返す。
+    pub fn read_stream_frame(&self, frame: &StreamFrame) -> Result<Option<FrameBuf>> {
+        let data = self.read_image_vec(frame.slot)?;
+        if self.streaming && !self.stream_frame_valid(frame) {
+            return Ok(None);
+        }
+        Ok(Some(FrameBuf::new(data, self.record_width, self.record_height, self.record_format, frame.slot)))
+    }
+
+    /// 通し番号 `seq` のフレーム (書き込み済みで上書きされていなければ)
+    pub fn stream_frame_at(&self, seq: usize) -> Option<StreamFrame> {
+        if !self.streaming {
+            return None;
+        }
+        let frame = StreamFrame {
+            seq,
+            slot: seq % self.record_frames,
+            format: self.record_format,
+        };
+        if self.stream_frame_valid(&frame) { Some(frame) } else { None }
+    }
+
+    /// 書き込み済みのフレームすべてに対してコールバックを呼ぶ
+    ///
+    /// 処理したフレーム数を返す。
+    pub fn poll_stream<F>(&mut self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&Self, &StreamFrame) -> Result<()>,
+    {
+        let mut n = 0;
+        while let Some(frame) = self.next_stream_frame() {
+            f(self, &frame)?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// 最も新しい書き込み済みフレーム
+    pub fn latest_stream_frame(&self) -> Option<StreamFrame> {
+        if !self.streaming {
+            return None;
+        }
+        let written = self.stream_written();
+        if written == 0 {
+            return None;
+        }
+        let seq = written - 1;
+        Some(StreamFrame {
+            seq,
+            slot: seq % self.record_frames,
+            format: self.record_format,
+        })
+    }
+
+    /// プリトリガ録画開始
+    ///
+    /// トリガがかかるまで dmabuf 全体をリングバッファとして録画し続け、
+    /// トリガ前 `pre_frames`、トリガ後 `post_frames` (トリガフレーム含む) を残す。
+    pub fn start_pretrigger(&mut self, width: usize, height: usize, pre_frames: usize, post_frames: usize, frame_count: usize) -> Result<()> {
+        // 書き込み中の 1 フレームと停止待ちの 1 フレーム分の余裕が必要
+        let need_slots = pre_frames + post_frames + 2;
+        let slots = self.start_stream(width, height, usize::MAX, frame_count)?;
+        if slots < need_slots {
+            self.stop_stream()?;
+            return Err(format!("dmabuf is too small for pretrigger ({} < {} frames)", slots, need_slots).into());
+        }
+        self.pretrigger = Some(Pretrigger {
+            pre_frames,
+            post_frames,
+            trigger_seq: None,
+            stop_written: None,
+        });
+        Ok(())
+    }
+
+    /// トリガ発行
+    ///
+    /// DMA が現在書き込み中のフレームをトリガフレームとする。
+    /// すでにトリガ済みの場合は無視する。
+    pub fn trigger(&mut self) -> Result<()> {
+        let written = self.stream_written();
+        match self.pretrigger.as_mut() {
+            Some(pt) => {
+                if pt.trigger_seq.is_none() {
+                    pt.trigger_seq = Some(written);
+                }
+                Ok(())
+            }
+            None => Err("pretrigger recording is not running".into()),
+        }
+    }
+
+    pub fn triggered(&self) -> bool {
+        matches!(self.pretrigger, Some(Pretrigger { trigger_seq: Some(_), .. }))
+    }
+
+    /// ポストトリガのフレームがそろったか確認し、そろっていれば録画を停止する
+    pub fn poll_pretrigger(&mut self) -> Result<bool> {
+        let pt = match self.pretrigger {
+            Some(pt) => pt,
+            None => return Err("pretrigger recording is not running".into()),
+        };
+        if pt.stop_written.is_some() {
+            return Ok(true);
+        }
+        let trigger_seq = match pt.trigger_seq {
+            Some(seq) => seq,
+            None => return Ok(false),
+        };
+
+        let written = self.stream_written();
+        if written < trigger_seq + pt.post_frames {
+            return Ok(false);
+        }
+
+        // 停止時に書き込み中だったフレームも完了する
+        self.stop_stream()?;
+        if let Some(pt) = self.pretrigger.as_mut() {
+            pt.stop_written = Some(written + 1);
+        }
+        Ok(true)
+    }
+
+    /// プリトリガ録画で残ったフレームを時系列順に返す
+    ///
+    /// 停止が遅れて上書きされた古いフレームは含まない。
+    pub fn pretrigger_frames(&self) -> Result<Vec<StreamFrame>> {
+        let (pt, trigger_seq, stop_written) = match self.pretrigger {
+            Some(pt @ Pretrigger { trigger_seq: Some(t), stop_written: Some(s), .. }) => (pt, t, s),
+            _ => return Err("pretrigger recording is not completed".into()),
+        };
+        Ok(pretrigger_window(trigger_seq, stop_written, pt.pre_frames, pt.post_frames, self.record_frames)
+            .map(|seq| StreamFrame {
+                seq,
+                slot: seq % self.record_frames,
+                format: self.record_format,
+            })
+            .collect())
+    }
+
+    /// プリトリガ録画でのトリガフレームの通し番号
+    pub fn pretrigger_seq(&self) -> Option<usize> {
+        self.pretrigger.and_then(|pt| pt.trigger_seq)
+    }
+
+    /// フレームの平均画素値 (`step` 画素ごとに間引いて計算)
+    pub fn frame_mean(&self, index: usize, step: usize) -> Result<f32> {
+        if index >= self.record_frames {
+            return Err("index out of range".into());
+        }
+        let width = self.record_width;
+        let pixels = width * self.record_height;
+        let step = step.max(1);
+        let offset = index * self.frame_bytes();
+        let mut sum: u64 = 0;
+        let mut n: u64 = 0;
+        for i in (0..pixels).step_by(step) {
+            sum += self.read_pixel(offset, i) as u64;
+            n += 1;
+        }
+        if n == 0 {
+            return Ok(0.0);
+        }
+        Ok(sum as f32 / n as f32)
+    }
+
+    // 録画フレームの 1 画素を直接読み出す (Planes8x2 は画像プレーン)
+    fn read_pixel(&self, frame_offset: usize, index: usize) -> u16 {
+        let width = self.record_width;
+        unsafe {
+            match self.record_format {
+                PixelFormat::Raw10In16 | PixelFormat::BayerRggb => self.dmabuf.read_mem_u16(frame_offset + index * 2),
+                PixelFormat::Raw8 => self.dmabuf.read_mem_u8(frame_offset + index) as u16,
+                PixelFormat::Planes8x2 => self.dmabuf.read_mem_u8(frame_offset + index * 2) as u16,
+                PixelFormat::PackedRaw10 => {
+                    let (y, x) = (index / width, index % width);
+                    let group = frame_offset + y * self.record_format.line_bytes(width) + (x / 4) * 5;
+                    let msb = self.dmabuf.read_mem_u8(group + x % 4) as u16;
+                    let lsb = self.dmabuf.read_mem_u8(group + 4) as u16;
+                    (msb << 2) | ((lsb >> ((x % 4) * 2)) & 0x3)
+                }
+            }
+        }
+    }
+
+    /// 録画フレーム 1 枚のバイト数
+    pub fn frame_bytes(&self) -> usize {
+        self.record_format.frame_bytes(self.record_width, self.record_height)
+    }
+
+    /// キャッシュ有効で udmabuf を開いている場合に、参照前に同期するデバイス名を設定
+    ///
+    /// `/sys/class/u-dma-buf/<name>` の sync_for_cpu を使う。
+    /// キャッシュ無効で開いている場合は不要。
+    pub fn set_cache_sync_device(&mut self, name: Option<&str>) -> Result<()> {
+        self.cache_synced = false;
+        self.cache_sync_path = match name {
+            Some(name) => {
+                let path = ["/sys/class/u-dma-buf", "/sys/class/udmabuf"]
+                    .iter()
+                    .map(|class| std::path::Path::new(class).join(name))
+                    .find(|path| path.join("sync_for_cpu").exists());
+                match path {
+                    Some(path) => Some(path),
+                    None => return Err(format!("udmabuf sync interface not found: {}", name).into()),
+                }
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    // DMA 停止後に録画範囲全体のキャッシュを 1 度だけ無効化
+    fn sync_record(&mut self) -> Result<()> {
+        self.cache_synced = false;
+        self.sync_for_cpu(0, self.record_frames * self.frame_bytes())?;
+        self.cache_synced = true;
+        Ok(())
+    }
+
+    // CPU から参照する範囲のキャッシュを無効化 (DMA 停止後に同期済みなら何もしない)
+    fn sync_for_cpu(&self, offset: usize, size: usize) -> Result<()> {
+        if self.cache_synced || size == 0 {
+            return Ok(());
+        }
+        if let Some(path) = &self.cache_sync_path {
+            std::fs::write(path.join("sync_offset"), offset.to_string())?;
+            std::fs::write(path.join("sync_size"), size.to_string())?;
+            std::fs::write(path.join("sync_direction"), "2")?; // DMA_FROM_DEVICE
+            std::fs::write(path.join("sync_for_cpu"), "1")?;
+        }
+        Ok(())
+    }
+
+    /// DMA バッファ上のフレームをコピーせずに参照
+    ///
+    /// DMA が書き込み中のバッファは参照できないので、録画完了後
+    /// (ストリーミングは停止後) のみ使える。ストリーミング中は
+    /// `read_stream_frame` でコピーして取り出す。
+    pub fn frame(&self, index: usize) -> Result<Frame<'_>> {
+        if self.streaming || self.async_record.is_some() {
+            return Err("video DMA is running".into());
+        }
+        if index >= self.record_frames {
+            return Err("index out of range".into());
+        }
+        let size = self.frame_bytes();
+        let offset = index * size;
+        if offset + size > self.dmabuf.size() {
+            return Err("frame exceeds dmabuf".into());
+        }
+        self.sync_for_cpu(offset, size)?;
+        let data = unsafe { core::slice::from_raw_parts((self.dmabuf.addr() + offset) as *const u8, size) };
+        Ok(Frame::new(
+            data,
+            self.record_width,
+            self.record_height,
+            self.record_format,
+            index,
+        ))
+    }
+
+    /// 録画フレームのメタデータ
+    ///
+    /// 時刻とフレームカウンタは録画開始時の値と周期からの推定値
+    /// (`FrameMeta::timestamp_estimated` / `frame_count_estimated` が立つ)。
+    /// フレームカウンタは `start_record` で開始した場合のみ分かる。
+    pub fn record_frame_meta(&self, index: usize, settings: &CaptureSettings) -> Result<FrameMeta> {
+        let frame = self.frame(index)?;
+        let start = self.record_start.ok_or("no recording")?;
+        let elapsed = Duration::from_secs_f64(settings.period_us().max(0.0) * (index + 1) as f64 / 1_000_000.0);
+        let frame_count = start.frame_count.map(|c| frame_count_add(c, index + 1));
+        let mut meta = FrameMeta::new(index, start.time + elapsed, frame_count, settings, &frame);
+        meta.timestamp_estimated = true;
+        meta.frame_count_estimated = frame_count.is_some();
+        Ok(meta)
+    }
+
+    /// ストリームフレームのメタデータ
+    ///
+    /// `image` は `read_stream_frame` で取り出したフレーム。時刻は取り出した時刻で、
+    /// フレームカウンタは `stream_frame_count` による推定値。
+    /// `timer` には取り出し時に読んだ `TimingGeneratorDriver::timer` を渡す (無ければ None)。
+    pub fn stream_frame_meta(&self, frame: &StreamFrame, image: &FrameBuf, settings: &CaptureSettings, timer: Option<usize>) -> FrameMeta {
+        let mut meta = FrameMeta::new(frame.seq, SystemTime::now(), Some(self.stream_frame_count(frame.seq)), settings, &image.frame());
+        meta.frame_count_estimated = true;
+        meta.timer = timer;
+        meta
+    }
+
+    /// 画像を Mat として読み出し
+    ///
+    /// RAW10 系は CV_16UC1、Raw8 は CV_8UC1 で返す。
+    /// Planes8x2 は CV_8UC2 で返すので、分離する場合は `read_planes_mat` を使う。
     #[cfg(feature = "opencv")]
-    pub fn read_image_mat(&mut self, index : usize) -> Result<Mat> {
+    pub fn read_image_mat(&self, index : usize) -> Result<Mat> {
         // 範囲チェック
         if index >= self.record_frames {
             return Err("index out of range".into());
@@ -68,19 +824,78 @@ impl<T0: MemAccess, T1: MemAccess> CaptureDriver<T0, T1>
         let width = self.record_width;
         let height = self.record_height;
         let pixels = width * height;
+        let offset = index * self.frame_bytes();
+        self.sync_for_cpu(offset, self.frame_bytes())?;
         unsafe {
-            let mut img = Mat::new_rows_cols(height as i32, width as i32, CV_16UC1)?;
-            debug_assert!(img.is_continuous());
-            let buf: &mut [u16] = img.data_typed_mut::<u16>()?;
-            let offset = index * pixels * 2;
-            self.dmabuf.copy_to_u16(offset, buf.as_mut_ptr(), pixels);
-            Ok(img)
+            match self.record_format {
+                PixelFormat::Raw10In16 | PixelFormat::BayerRggb => {
+                    let mut img = Mat::new_rows_cols(height as i32, width as i32, CV_16UC1)?;
+                    debug_assert!(img.is_continuous());
+                    let buf: &mut [u16] = img.data_typed_mut::<u16>()?;
+                    self.dmabuf.copy_to_u16(offset, buf.as_mut_ptr(), pixels);
+                    Ok(img)
+                }
+                PixelFormat::Raw8 => {
+                    let mut img = Mat::new_rows_cols(height as i32, width as i32, CV_8UC1)?;
+                    debug_assert!(img.is_continuous());
+                    let buf: &mut [u8] = img.data_typed_mut::<u8>()?;
+                    self.dmabuf.copy_to_u8(offset, buf.as_mut_ptr(), pixels);
+                    Ok(img)
+                }
+                PixelFormat::Planes8x2 => {
+                    let mut img = Mat::new_rows_cols(height as i32, width as i32, CV_8UC2)?;
+                    debug_assert!(img.is_continuous());
+                    let buf: &mut [u8] = img.data_bytes_mut()?;
+                    self.dmabuf.copy_to_u8(offset, buf.as_mut_ptr(), pixels * 2);
+                    Ok(img)
+                }
+                PixelFormat::PackedRaw10 => {
+                    let data = self.read_image_u16(index)?;
+                    let mut img = Mat::new_rows_cols(height as i32, width as i32, CV_16UC1)?;
+                    debug_assert!(img.is_continuous());
+                    img.data_typed_mut::<u16>()?.copy_from_slice(&data);
+                    Ok(img)
+                }
+            }
+        }
+    }
+
+    /// Planes8x2 の画像を 2 枚の CV_8UC1 に分離して読み出し
+    #[cfg(feature = "opencv")]
+    pub fn read_planes_mat(&self, index : usize) -> Result<(Mat, Mat)> {
+        let (lo, hi) = self.read_planes_vec(index)?;
+        let width = self.record_width as i32;
+        let height = self.record_height as i32;
+        let mut lo_img = Mat::new_rows_cols(height, width, CV_8UC1)?;
+        let mut hi_img = Mat::new_rows_cols(height, width, CV_8UC1)?;
+        lo_img.data_typed_mut::<u8>()?.copy_from_slice(&lo);
+        hi_img.data_typed_mut::<u8>()?.copy_from_slice(&hi);
+        Ok((lo_img, hi_img))
+    }
+
+    /// Planes8x2 の画像を (下位プレーン, 上位プレーン) に分離して読み出し
+    pub fn read_planes_vec(&self, index : usize) -> Result<(Vec<u8>, Vec<u8>)> {
+        if self.record_format != PixelFormat::Planes8x2 {
+            return Err(format!("pixel format {} has no planes", self.record_format).into());
         }
+        let buf = self.read_image_vec(index)?;
+        Ok(split_planes8x2(&buf))
+    }
+
+    /// 画像を 16bit 画素列に展開して読み出し (Planes8x2 は画像プレーン)
+    pub fn read_image_u16(&self, index : usize) -> Result<Vec<u16>> {
+        let buf = self.read_image_vec(index)?;
+        Ok(self.record_format.to_u16(&buf, self.record_width, self.record_height))
     }
 
-    pub fn read_image_vec(&mut self, index : usize) -> Result<Vec::<u8>> {
-        let size = self.record_width * self.record_height * 2;
-        let mut buf = vec![0u8; size as usize];
+    /// 画像をメモリ上の形式のまま読み出し
+    pub fn read_image_vec(&self, index : usize) -> Result<Vec::<u8>> {
+        if index >= self.record_frames {
+            return Err("index out of range".into());
+        }
+        let size = self.frame_bytes();
+        self.sync_for_cpu(index * size, size)?;
+        let mut buf = vec![0u8; size];
         let offset = index * size;
         unsafe {
             self.dmabuf.copy_to_u8(offset, buf.as_mut_ptr(), size);
@@ -88,6 +903,40 @@ impl<T0: MemAccess, T1: MemAccess> CaptureDriver<T0, T1>
         Ok(buf)
     }
 
+    /// 連続する複数フレームをまとめて読み出し
+    pub fn read_images_vec(&self, index : usize, frames: usize) -> Result<Vec::<u8>> {
+        if index + frames > self.record_frames {
+            return Err("index out of range".into());
+        }
+        let frame_size = self.frame_bytes();
+        let size = frame_size * frames;
+        self.sync_for_cpu(index * frame_size, size)?;
+        let mut buf = vec![0u8; size];
+        unsafe {
+            self.dmabuf.copy_to_u8(index * frame_size, buf.as_mut_ptr(), size);
+        }
+        Ok(buf)
+    }
+
+    /// 連続する複数フレームの画素毎の平均 (キャリブレーション用)
+    pub fn average_frames(&self, index: usize, frames: usize) -> Result<Vec<f32>> {
+        if frames == 0 || index + frames > self.record_frames {
+            return Err("index out of range".into());
+        }
+        let mut sum = vec![0u32; self.record_width * self.record_height];
+        for i in index..index + frames {
+            for (s, p) in sum.iter_mut().zip(self.read_image_u16(i)?) {
+                *s += p as u32;
+            }
+        }
+        Ok(sum.into_iter().map(|s| s as f32 / frames as f32).collect())
+    }
+
+    /// dmabuf に格納できる最大フレーム数
+    pub fn max_frames(&self, width: usize, height: usize) -> usize {
+        self.dmabuf.size() / self.pixel_format.frame_bytes(width, height)
+    }
+
     pub fn record_frames(&self) -> usize {
         self.record_frames
     }
@@ -97,6 +946,59 @@ impl<T0: MemAccess, T1: MemAccess> CaptureDriver<T0, T1>
     pub fn record_height(&self) -> usize {
         self.record_height
     }
+    pub fn record_format(&self) -> PixelFormat {
+        self.record_format
+    }
 }
 
+// プリトリガ録画で残るフレームの通し番号の範囲
+//
+// トリガ前 `pre_frames` からトリガ後 `post_frames` まで。停止時点で
+// `slots` より前に書き込まれたフレームは上書きされているので含めない。
+fn pretrigger_window(trigger_seq: usize, stop_written: usize, pre_frames: usize, post_frames: usize, slots: usize) -> core::ops::Range<usize> {
+    let begin = trigger_seq
+        .saturating_sub(pre_frames)
+        .max(stop_written.saturating_sub(slots));
+    begin..trigger_seq + post_frames
+}
+
+// オブジェクト解放時に DMA 停止
+impl<T0: MemAccess + Clone, T1: MemAccess> Drop for CaptureDriver<T0, T1>
+{
+    fn drop(&mut self) {
+        let _ = self.stop_stream();
+        let _ = self.cancel_record(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pretrigger_window_keeps_pre_and_post_frames() {
+        // トリガ前 3 + トリガ後 2 (トリガフレーム含む)
+        assert_eq!(pretrigger_window(25, 28, 3, 2, 10), 22..27);
+    }
+
+    #[test]
+    fn pretrigger_window_wraps_ring() {
+        let slots = 8;
+        let seqs: Vec<usize> = pretrigger_window(14, 18, 3, 3, slots).collect();
+        assert_eq!(seqs, vec![11, 12, 13, 14, 15, 16]);
+        let ring: Vec<usize> = seqs.iter().map(|seq| seq % slots).collect();
+        assert_eq!(ring, vec![3, 4, 5, 6, 7, 0]);
+    }
 
+    #[test]
+    fn pretrigger_window_drops_overwritten() {
+        // 停止が遅れてトリガ前のフレームが上書きされた
+        assert_eq!(pretrigger_window(10, 17, 5, 2, 8), 9..12);
+    }
+
+    #[test]
+    fn pretrigger_window_early_trigger() {
+        // 開始直後のトリガではトリガ前のフレームが足りない
+        assert_eq!(pretrigger_window(1, 4, 5, 2, 8), 0..3);
+    }
+}
@@ -0,0 +1,435 @@
+#![allow(dead_code)]
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// 色変換行列の固定小数点 (Q12)
+const CCM_SHIFT: u32 = 12;
+const CCM_ONE: f32 = (1 << CCM_SHIFT) as f32;
+
+/// カラーフィルタ配列の並び (左上 2x2)
+///
+/// PYTHON300 カラー版は RGGB (OpenCV の COLOR_BayerBG2BGR 相当)。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CfaPattern {
+    #[default]
+    Rggb,
+    Grbg,
+    Gbrg,
+    Bggr,
+}
+
+impl CfaPattern {
+    /// (x, y) の画素の色 (0:R, 1:G, 2:B)
+    pub fn color(&self, x: usize, y: usize) -> usize {
+        let pos = (y & 1) * 2 + (x & 1);
+        let table = match self {
+            CfaPattern::Rggb => [0, 1, 1, 2],
+            CfaPattern::Grbg => [1, 0, 2, 1],
+            CfaPattern::Gbrg => [1, 2, 0, 1],
+            CfaPattern::Bggr => [2, 1, 1, 0],
+        };
+        table[pos]
+    }
+
+    /// ROI を (x, y) だけずらした場合の並び
+    pub fn shifted(&self, x: usize, y: usize) -> Self {
+        let c = |dx, dy| self.color(x + dx, y + dy);
+        match (c(0, 0), c(1, 0)) {
+            (0, _) => CfaPattern::Rggb,
+            (2, _) => CfaPattern::Bggr,
+            (1, 0) => CfaPattern::Grbg,
+            _      => CfaPattern::Gbrg,
+        }
+    }
+}
+
+/// デモザイクの方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DemosaicMethod {
+    /// 3x3 近傍の同色画素の平均
+    Bilinear,
+    /// 勾配の小さい方向で G を補間し、R/B は色差で補間する (Hamilton-Adams)
+    #[default]
+    EdgeAware,
+}
+
+/// Bayer RAW から RGB を作るカラーパイプライン (OpenCV 不要)
+///
+/// デモザイク → ホワイトバランス → 色補正行列 → ガンマ の順に処理する。
+/// WB と CCM は 1 つの固定小数点行列にまとめ、ガンマはテーブル引きで行う。
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorPipeline {
+    pattern: CfaPattern,
+    method: DemosaicMethod,
+    input_bits: u32,
+    wb_gains: [f32; 3],
+    ccm: [[f32; 3]; 3],
+    gamma: f32,
+}
+
+impl Default for ColorPipeline {
+    fn default() -> Self {
+        Self::new(CfaPattern::Rggb)
+    }
+}
+
+impl ColorPipeline {
+    pub fn new(pattern: CfaPattern) -> Self {
+        Self {
+            pattern,
+            method: DemosaicMethod::EdgeAware,
+            input_bits: 10,
+            wb_gains: [1.0, 1.0, 1.0],
+            ccm: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            gamma: 1.0,
+        }
+    }
+
+    pub fn set_pattern(&mut self, pattern: CfaPattern) {
+        self.pattern = pattern;
+    }
+
+    pub fn pattern(&self) -> CfaPattern {
+        self.pattern
+    }
+
+    pub fn set_method(&mut self, method: DemosaicMethod) {
+        self.method = method;
+    }
+
+    pub fn method(&self) -> DemosaicMethod {
+        self.method
+    }
+
+    /// 入力 RAW のビット数 (既定 10)
+    pub fn set_input_bits(&mut self, bits: u32) -> Result<()> {
+        if !(1..=16).contains(&bits) {
+            return Err(format!("invalid input bits: {}", bits).into());
+        }
+        self.input_bits = bits;
+        Ok(())
+    }
+
+    pub fn input_bits(&self) -> u32 {
+        self.input_bits
+    }
+
+    /// ホワイトバランスゲイン (R, G, B)
+    pub fn set_wb_gains(&mut self, gains: [f32; 3]) -> Result<()> {
+        if gains.iter().any(|g| !(g.is_finite() && *g >= 0.0)) {
+            return Err(format!("invalid white balance gains: {:?}", gains).into());
+        }
+        self.wb_gains = gains;
+        Ok(())
+    }
+
+    pub fn wb_gains(&self) -> [f32; 3] {
+        self.wb_gains
+    }
+
+    /// 色補正行列 (行が出力 RGB、列が入力 RGB)
+    pub fn set_ccm(&mut self, ccm: [[f32; 3]; 3]) -> Result<()> {
+        if ccm.iter().flatten().any(|v| !v.is_finite()) {
+            return Err("invalid color correction matrix".into());
+        }
+        self.ccm = ccm;
+        Ok(())
+    }
+
+    pub fn ccm(&self) -> [[f32; 3]; 3] {
+        self.ccm
+    }
+
+    /// ガンマ (1.0 でリニア、sRGB 相当なら 2.2)
+    pub fn set_gamma(&mut self, gamma: f32) -> Result<()> {
+        if !(gamma.is_finite() && gamma > 0.0) {
+            return Err(format!("invalid gamma: {}", gamma).into());
+        }
+        self.gamma = gamma;
+        Ok(())
+    }
+
+    pub fn gamma(&self) -> f32 {
+        self.gamma
+    }
+
+    /// デモザイクのみ行い、入力ビット数のままの RGB (インターリーブ) を返す
+    pub fn demosaic(&self, raw: &[u16], width: usize, height: usize) -> Result<Vec<u16>> {
+        if raw.len() != width * height || width < 2 || height < 2 {
+            return Err("invalid raw image size".into());
+        }
+        let max = (1i32 << self.input_bits) - 1;
+        let bayer = Bayer { raw, width, height, pattern: self.pattern };
+        let mut rgb = vec![0u16; width * height * 3];
+
+        // G プレーン
+        let mut green = vec![0i32; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                green[y * width + x] = match self.pattern.color(x, y) {
+                    1 => bayer.at(x as isize, y as isize),
+                    _ => match self.method {
+                        DemosaicMethod::Bilinear  => bayer.cross_mean(x as isize, y as isize),
+                        DemosaicMethod::EdgeAware => bayer.green_hamilton_adams(x as isize, y as isize),
+                    },
+                }.clamp(0, max);
+            }
+        }
+        let g_at = |x: isize, y: isize| green[reflect(y, height) * width + reflect(x, width)];
+
+        for y in 0..height {
+            for x in 0..width {
+                let (xi, yi) = (x as isize, y as isize);
+                let i = y * width + x;
+                let g = green[i];
+                let mut px = [0i32; 3];
+                px[1] = g;
+                for c in [0usize, 2] {
+                    px[c] = if self.pattern.color(x, y) == c {
+                        bayer.at(xi, yi)
+                    }
+                    else {
+                        // 3x3 内の同色画素から補間 (EdgeAware は色差で)
+                        let mut sum = 0;
+                        let mut n = 0;
+                        for dy in -1..=1 {
+                            for dx in -1..=1 {
+                                let (nx, ny) = (xi + dx, yi + dy);
+                                if self.pattern.color(reflect(nx, width), reflect(ny, height)) == c {
+                                    sum += match self.method {
+                                        DemosaicMethod::Bilinear  => bayer.at(nx, ny),
+                                        DemosaicMethod::EdgeAware => bayer.at(nx, ny) - g_at(nx, ny),
+                                    };
+                                    n += 1;
+                                }
+                            }
+                        }
+                        let v = if n > 0 { sum / n } else { 0 };
+                        match self.method {
+                            DemosaicMethod::Bilinear  => v,
+                            DemosaicMethod::EdgeAware => v + g,
+                        }
+                    }.clamp(0, max);
+                }
+                rgb[i * 3]     = px[0] as u16;
+                rgb[i * 3 + 1] = px[1] as u16;
+                rgb[i * 3 + 2] = px[2] as u16;
+            }
+        }
+        Ok(rgb)
+    }
+
+    /// RGB 16bit (0..65535) で出力
+    pub fn process_rgb16(&self, raw: &[u16], width: usize, height: usize) -> Result<Vec<u16>> {
+        let lut: Vec<u16> = self.gamma_lut(65535.0).into_iter().map(|v| v as u16).collect();
+        self.process(raw, width, height, &lut)
+    }
+
+    /// RGB 8bit (0..255) で出力
+    pub fn process_rgb8(&self, raw: &[u16], width: usize, height: usize) -> Result<Vec<u8>> {
+        let lut: Vec<u8> = self.gamma_lut(255.0).into_iter().map(|v| v as u8).collect();
+        self.process(raw, width, height, &lut)
+    }
+
+    fn process<T: Copy + Default>(&self, raw: &[u16], width: usize, height: usize, lut: &[T]) -> Result<Vec<T>> {
+        let rgb = self.demosaic(raw, width, height)?;
+        let max = (1i32 << self.input_bits) - 1;
+
+        // WB と CCM をまとめた固定小数点行列
+        let mut m = [[0i32; 3]; 3];
+        for (r, row) in m.iter_mut().enumerate() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v = (self.ccm[r][c] * self.wb_gains[c] * CCM_ONE).round() as i32;
+            }
+        }
+
+        let mut out = vec![T::default(); rgb.len()];
+        for (src, dst) in rgb.chunks_exact(3).zip(out.chunks_exact_mut(3)) {
+            let (r, g, b) = (src[0] as i32, src[1] as i32, src[2] as i32);
+            for (k, row) in m.iter().enumerate() {
+                let v = (row[0] * r + row[1] * g + row[2] * b) >> CCM_SHIFT;
+                dst[k] = lut[v.clamp(0, max) as usize];
+            }
+        }
+        Ok(out)
+    }
+
+    // 入力値 0..max を出力 0..out_max にガンマ変換するテーブル
+    fn gamma_lut(&self, out_max: f32) -> Vec<u32> {
+        let max = (1u32 << self.input_bits) - 1;
+        let inv = 1.0 / self.gamma;
+        (0..=max)
+            .map(|v| ((v as f32 / max as f32).powf(inv) * out_max).round() as u32)
+            .collect()
+    }
+}
+
+// 端は CFA の位相を保つよう折り返す
+fn reflect(v: isize, size: usize) -> usize {
+    let size = size as isize;
+    let v = if v < 0 { -v } else { v };
+    let v = if v >= size { 2 * (size - 1) - v } else { v };
+    v.clamp(0, size - 1) as usize
+}
+
+struct Bayer<'a> {
+    raw: &'a [u16],
+    width: usize,
+    height: usize,
+    pattern: CfaPattern,
+}
+
+impl Bayer<'_> {
+    fn at(&self, x: isize, y: isize) -> i32 {
+        self.raw[reflect(y, self.height) * self.width + reflect(x, self.width)] as i32
+    }
+
+    // 上下左右 (R/B 位置では G) の平均
+    fn cross_mean(&self, x: isize, y: isize) -> i32 {
+        (self.at(x - 1, y) + self.at(x + 1, y) + self.at(x, y - 1) + self.at(x, y + 1) + 2) / 4
+    }
+
+    // R/B 位置の G を勾配の小さい方向で補間し、2 次微分で補正する
+    fn green_hamilton_adams(&self, x: isize, y: isize) -> i32 {
+        let c = self.at(x, y);
+        let gh = self.at(x - 1, y) + self.at(x + 1, y);
+        let gv = self.at(x, y - 1) + self.at(x, y + 1);
+        let ch = 2 * c - self.at(x - 2, y) - self.at(x + 2, y);
+        let cv = 2 * c - self.at(x, y - 2) - self.at(x, y + 2);
+        let dh = (self.at(x - 1, y) - self.at(x + 1, y)).abs() + ch.abs();
+        let dv = (self.at(x, y - 1) - self.at(x, y + 1)).abs() + cv.abs();
+        if dh < dv {
+            (2 * gh + ch) / 4
+        }
+        else if dv < dh {
+            (2 * gv + cv) / 4
+        }
+        else {
+            (gh + gv) / 4 + (ch + cv) / 8
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 色毎に一様な Bayer 画像
+    fn uniform(width: usize, height: usize, pattern: CfaPattern, rgb: [u16; 3]) -> Vec<u16> {
+        (0..width * height).map(|i| rgb[pattern.color(i % width, i / width)]).collect()
+    }
+
+    // 16bit 入力・リニアで変換 (LUT が恒等になる)
+    fn pipeline16() -> ColorPipeline {
+        let mut pipe = ColorPipeline::new(CfaPattern::Rggb);
+        pipe.set_input_bits(16).unwrap();
+        pipe
+    }
+
+    #[test]
+    fn cfa_shifted() {
+        assert_eq!(CfaPattern::Rggb.shifted(0, 0), CfaPattern::Rggb);
+        assert_eq!(CfaPattern::Rggb.shifted(1, 0), CfaPattern::Grbg);
+        assert_eq!(CfaPattern::Rggb.shifted(0, 1), CfaPattern::Gbrg);
+        assert_eq!(CfaPattern::Rggb.shifted(1, 1), CfaPattern::Bggr);
+        assert_eq!(CfaPattern::Bggr.shifted(3, 5), CfaPattern::Rggb);
+    }
+
+    #[test]
+    fn demosaic_uniform() {
+        for pattern in [CfaPattern::Rggb, CfaPattern::Grbg, CfaPattern::Gbrg, CfaPattern::Bggr] {
+            for method in [DemosaicMethod::Bilinear, DemosaicMethod::EdgeAware] {
+                let mut pipe = ColorPipeline::new(pattern);
+                pipe.set_method(method);
+                let raw = uniform(6, 4, pattern, [100, 200, 300]);
+                let rgb = pipe.demosaic(&raw, 6, 4).unwrap();
+                for px in rgb.chunks_exact(3) {
+                    assert_eq!(px, &[100, 200, 300], "{:?} {:?}", pattern, method);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn demosaic_rejects_size() {
+        let pipe = ColorPipeline::default();
+        assert!(pipe.demosaic(&[0; 4], 2, 3).is_err());
+        assert!(pipe.demosaic(&[0; 3], 1, 3).is_err());
+    }
+
+    #[test]
+    fn cross_mean_rounds_half_up() {
+        // 中央 (1,1) の上下左右
+        let raw = [0, 1, 0,
+                   1, 0, 2,
+                   0, 2, 0];
+        let bayer = Bayer { raw: &raw, width: 3, height: 3, pattern: CfaPattern::Rggb };
+        assert_eq!(bayer.cross_mean(1, 1), 2); // 1.5 -> 2
+        let raw = [0, 1, 0,
+                   1, 0, 1,
+                   0, 2, 0];
+        let bayer = Bayer { raw: &raw, width: 3, height: 3, pattern: CfaPattern::Rggb };
+        assert_eq!(bayer.cross_mean(1, 1), 1); // 1.25 -> 1
+    }
+
+    #[test]
+    fn demosaic_clamps_overshoot() {
+        // 中央の R だけ明るく周囲の R が 0 だと、2 次微分の補正で G が上限を超える
+        let mut raw = uniform(5, 5, CfaPattern::Rggb, [0, 1023, 0]);
+        raw[2 * 5 + 2] = 1023;
+        let rgb = ColorPipeline::new(CfaPattern::Rggb).demosaic(&raw, 5, 5).unwrap();
+        assert_eq!(rgb[(2 * 5 + 2) * 3 + 1], 1023);
+        assert!(rgb.iter().all(|&v| v <= 1023));
+    }
+
+    #[test]
+    fn white_balance_truncates() {
+        let mut pipe = pipeline16();
+        pipe.set_wb_gains([2.0, 1.0, 0.5]).unwrap();
+        let raw = uniform(4, 4, CfaPattern::Rggb, [100, 200, 301]);
+        let rgb = pipe.process_rgb16(&raw, 4, 4).unwrap();
+        for px in rgb.chunks_exact(3) {
+            assert_eq!(px, &[200, 200, 150]); // 150.5 -> 150
+        }
+    }
+
+    #[test]
+    fn ccm_coefficient_rounding() {
+        // 1/3 は Q12 で 1365 に丸められる
+        let mut pipe = pipeline16();
+        pipe.set_ccm([[1.0 / 3.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]).unwrap();
+        let raw = uniform(4, 4, CfaPattern::Rggb, [3000, 0, 0]);
+        let rgb = pipe.process_rgb16(&raw, 4, 4).unwrap();
+        assert_eq!(rgb[0], 999);
+    }
+
+    #[test]
+    fn ccm_saturates() {
+        let mut pipe = pipeline16();
+        pipe.set_ccm([[2.0, 0.0, 0.0], [0.0, 1.0, 0.0], [-1.0, 0.0, 1.0]]).unwrap();
+        let raw = uniform(4, 4, CfaPattern::Rggb, [40000, 1000, 3000]);
+        let rgb = pipe.process_rgb16(&raw, 4, 4).unwrap();
+        for px in rgb.chunks_exact(3) {
+            assert_eq!(px, &[65535, 1000, 0]);
+        }
+    }
+
+    #[test]
+    fn gamma_lut() {
+        let mut pipe = ColorPipeline::default();
+        let lut = pipe.gamma_lut(255.0);
+        assert_eq!((lut.len(), lut[0], lut[1023]), (1024, 0, 255));
+        pipe.set_gamma(2.0).unwrap();
+        assert_eq!(pipe.gamma_lut(255.0)[256], 128);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let mut pipe = ColorPipeline::default();
+        assert!(pipe.set_input_bits(0).is_err());
+        assert!(pipe.set_input_bits(17).is_err());
+        assert!(pipe.set_wb_gains([1.0, -1.0, 1.0]).is_err());
+        assert!(pipe.set_ccm([[f32::NAN, 0.0, 0.0], [0.0; 3], [0.0; 3]]).is_err());
+        assert!(pipe.set_gamma(0.0).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "opencv")]
+use opencv::core::*;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 欠陥画素の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DefectKind {
+    /// 暗画像で明るい画素
+    Hot,
+    /// 明画像で感度が極端に低い画素
+    Dead,
+    /// 光量によらず値が変わらない画素
+    Stuck,
+}
+
+impl DefectKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DefectKind::Hot   => "hot",
+            DefectKind::Dead  => "dead",
+            DefectKind::Stuck => "stuck",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hot"   => Some(DefectKind::Hot),
+            "dead"  => Some(DefectKind::Dead),
+            "stuck" => Some(DefectKind::Stuck),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Defect {
+    pub x: usize,
+    pub y: usize,
+    pub kind: DefectKind,
+}
+
+/// 欠陥画素マップ
+///
+/// モジュール毎にキャリブレーションして保存し、キャプチャ画像に
+/// `correct` を掛けて近傍補間で置き換える。カラー (Bayer) の場合は
+/// 同色の画素だけで補間する。
+#[derive(Debug, Clone, PartialEq)]
+pub struct DefectMap {
+    width: usize,
+    height: usize,
+    bayer: bool,
+    defects: Vec<Defect>,
+    mask: Vec<bool>,
+}
+
+impl DefectMap {
+    pub fn new(width: usize, height: usize, bayer: bool) -> Self {
+        Self {
+            width,
+            height,
+            bayer,
+            defects: Vec::new(),
+            mask: vec![false; width * height],
+        }
+    }
+
+    /// モジュール ID 毎の保存先
+    pub fn module_path(dir: impl AsRef<Path>, module_id: u16) -> PathBuf {
+        dir.as_ref().join(format!("defect_map_{:04x}.txt", module_id))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn bayer(&self) -> bool {
+        self.bayer
+    }
+
+    pub fn defects(&self) -> &[Defect] {
+        &self.defects
+    }
+
+    pub fn len(&self) -> usize {
+        self.defects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.defects.is_empty()
+    }
+
+    pub fn count(&self, kind: DefectKind) -> usize {
+        self.defects.iter().filter(|d| d.kind == kind).count()
+    }
+
+    pub fn is_defective(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height && self.mask[y * self.width + x]
+    }
+
+    /// 欠陥画素を追加 (登録済みなら無視)
+    pub fn add(&mut self, x: usize, y: usize, kind: DefectKind) {
+        if x >= self.width || y >= self.height || self.mask[y * self.width + x] {
+            return;
+        }
+        self.mask[y * self.width + x] = true;
+        self.defects.push(Defect { x, y, kind });
+    }
+
+    pub fn clear(&mut self) {
+        self.defects.clear();
+        self.mask.fill(false);
+    }
+
+    /// 長時間露光の暗画像平均 (`CaptureDriver::average_frames`) から輝点を検出
+    ///
+    /// 同色チャネルの中央値より `threshold` [DN] 以上明るい画素を Hot とする。
+    /// 検出した数を返す。
+    pub fn detect_hot(&mut self, dark: &[f32], threshold: f32) -> Result<usize> {
+        self.check_size(dark.len())?;
+        let medians = self.channel_medians(dark);
+        let before = self.len();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if dark[y * self.width + x] > medians[self.channel(x, y)] + threshold {
+                    self.add(x, y, DefectKind::Hot);
+                }
+            }
+        }
+        Ok(self.len() - before)
+    }
+
+    /// 光量を変えた複数の明画像平均から黒点と固着画素を検出
+    ///
+    /// `flats` は暗い順に並べること。最も明るい画像で同色チャネルの中央値の
+    /// `dead_ratio` 倍未満の画素を Dead、最暗から最明までの変化量が中央値の
+    /// 変化量の `stuck_ratio` 倍未満の画素を Stuck とする。検出した数を返す。
+    pub fn detect_flat(&mut self, flats: &[Vec<f32>], dead_ratio: f32, stuck_ratio: f32) -> Result<usize> {
+        if flats.len() < 2 {
+            return Err("at least two flat levels are required".into());
+        }
+        for f in flats {
+            self.check_size(f.len())?;
+        }
+        let lo = &flats[0];
+        let hi = &flats[flats.len() - 1];
+        let lo_med = self.channel_medians(lo);
+        let hi_med = self.channel_medians(hi);
+
+        let before = self.len();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let c = self.channel(x, y);
+                let i = y * self.width + x;
+                let swing = hi_med[c] - lo_med[c];
+                if swing > 0.0 && (hi[i] - lo[i]).abs() < swing * stuck_ratio {
+                    self.add(x, y, DefectKind::Stuck);
+                }
+                else if hi[i] < hi_med[c] * dead_ratio {
+                    self.add(x, y, DefectKind::Dead);
+                }
+            }
+        }
+        Ok(self.len() - before)
+    }
+
+    /// 欠陥画素を同色の近傍画素の平均で置き換える
+    pub fn correct<T>(&self, img: &mut [T]) -> Result<()>
+    where
+        T: Copy + Into<u32> + TryFrom<u32>,
+    {
+        self.check_size(img.len())?;
+        let offsets: &[(isize, isize)] = if self.bayer { &Self::BAYER_OFFSETS } else { &Self::MONO_OFFSETS };
+        for d in &self.defects {
+            // G 画素は斜め隣も同色
+            let diag: &[(isize, isize)] = if self.bayer && matches!(self.channel(d.x, d.y), 1 | 2) { &Self::DIAG_OFFSETS } else { &[] };
+            let mut sum = 0u32;
+            let mut n = 0u32;
+            for &(dx, dy) in offsets.iter().chain(diag.iter()) {
+                let nx = d.x as isize + dx;
+                let ny = d.y as isize + dy;
+                if nx < 0 || ny < 0 || nx >= self.width as isize || ny >= self.height as isize {
+                    continue;
+                }
+                let (nx, ny) = (nx as usize, ny as usize);
+                if self.mask[ny * self.width + nx] {
+                    continue;
+                }
+                sum += img[ny * self.width + nx].into();
+                n += 1;
+            }
+            if let Some(avg) = (sum + n / 2).checked_div(n) {
+                if let Ok(v) = T::try_from(avg) {
+                    img[d.y * self.width + d.x] = v;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Mat (CV_16UC1 / CV_8UC1) の欠陥画素を補正
+    #[cfg(feature = "opencv")]
+    pub fn correct_mat(&self, img: &mut Mat) -> Result<()> {
+        if img.cols() as usize != self.width || img.rows() as usize != self.height {
+            return Err("image size does not match defect map".into());
+        }
+        match img.typ() {
+            CV_16UC1 => self.correct(img.data_typed_mut::<u16>()?),
+            CV_8UC1  => self.correct(img.data_typed_mut::<u8>()?),
+            _ => Err("unsupported Mat type for defect correction".into()),
+        }
+    }
+
+    /// テキスト形式で保存
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        writeln!(w, "# rtcl_p3s7 defect map")?;
+        writeln!(w, "width {}", self.width)?;
+        writeln!(w, "height {}", self.height)?;
+        writeln!(w, "bayer {}", self.bayer as u8)?;
+        for d in &self.defects {
+            writeln!(w, "{} {} {}", d.kind.name(), d.x, d.y)?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut width = None;
+        let mut height = None;
+        let mut bayer = false;
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["width", v]  => width = Some(v.parse::<usize>()?),
+                ["height", v] => height = Some(v.parse::<usize>()?),
+                ["bayer", v]  => bayer = v.parse::<u8>()? != 0,
+                [kind, x, y]  => {
+                    let kind = DefectKind::from_name(kind).ok_or(format!("unknown defect kind: {}", kind))?;
+                    entries.push((x.parse::<usize>()?, y.parse::<usize>()?, kind));
+                }
+                _ => return Err(format!("invalid defect map line: {}", line).into()),
+            }
+        }
+        let width = width.ok_or("defect map has no width")?;
+        let height = height.ok_or("defect map has no height")?;
+        let mut map = Self::new(width, height, bayer);
+        for (x, y, kind) in entries {
+            map.add(x, y, kind);
+        }
+        Ok(map)
+    }
+
+    const MONO_OFFSETS:  [(isize, isize); 8] = [(-1, 0), (1, 0), (0, -1), (0, 1), (-1, -1), (1, -1), (-1, 1), (1, 1)];
+    const BAYER_OFFSETS: [(isize, isize); 4] = [(-2, 0), (2, 0), (0, -2), (0, 2)];
+    const DIAG_OFFSETS:  [(isize, isize); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+    fn check_size(&self, len: usize) -> Result<()> {
+        if len != self.width * self.height {
+            return Err(format!("image size does not match defect map ({}x{})", self.width, self.height).into());
+        }
+        Ok(())
+    }
+
+    // Bayer の場合は 2x2 の位置 (0:R, 1:Gr, 2:Gb, 3:B)、モノクロは 0
+    fn channel(&self, x: usize, y: usize) -> usize {
+        if self.bayer { (y & 1) * 2 + (x & 1) } else { 0 }
+    }
+
+    fn channel_medians(&self, img: &[f32]) -> [f32; 4] {
+        let mut medians = [0f32; 4];
+        let channels = if self.bayer { 4 } else { 1 };
+        for (c, median) in medians.iter_mut().enumerate().take(channels) {
+            let mut v: Vec<f32> = (0..self.height)
+                .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+                .filter(|&(x, y)| self.channel(x, y) == c)
+                .map(|(x, y)| img[y * self.width + x])
+                .collect();
+            if v.is_empty() {
+                continue;
+            }
+            let mid = v.len() / 2;
+            v.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
+            *median = v[mid];
+        }
+        medians
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_hot_threshold() {
+        let mut map = DefectMap::new(4, 4, false);
+        let mut dark = vec![100.0f32; 16];
+        dark[5] = 131.0;
+        dark[10] = 130.0; // 中央値 + threshold ちょうどは正常
+        assert_eq!(map.detect_hot(&dark, 30.0).unwrap(), 1);
+        assert!(map.is_defective(1, 1));
+        assert!(!map.is_defective(2, 2));
+        assert_eq!(map.defects()[0].kind, DefectKind::Hot);
+
+        // 登録済みの画素は数えない
+        assert_eq!(map.detect_hot(&dark, 30.0).unwrap(), 0);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn detect_hot_per_channel() {
+        // R だけ暗レベルが高くても R は輝点にしない
+        let mut map = DefectMap::new(4, 4, true);
+        let mut dark: Vec<f32> = (0..16).map(|i| if (i / 4) % 2 == 0 && i % 2 == 0 { 300.0 } else { 100.0 }).collect();
+        dark[7] = 250.0; // Gb (3,1)
+        assert_eq!(map.detect_hot(&dark, 50.0).unwrap(), 1);
+        assert!(map.is_defective(3, 1));
+        assert_eq!(map.count(DefectKind::Hot), 1);
+    }
+
+    #[test]
+    fn detect_hot_rejects_size_mismatch() {
+        let mut map = DefectMap::new(4, 4, false);
+        assert!(map.detect_hot(&[0.0; 15], 10.0).is_err());
+    }
+
+    #[test]
+    fn detect_flat_dead_and_stuck() {
+        let mut map = DefectMap::new(4, 4, false);
+        let lo = vec![100.0f32; 16];
+        let mut hi = vec![800.0f32; 16];
+        let mut lo_dead = lo.clone();
+        lo_dead[2] = 0.0;
+        hi[2] = 300.0;  // 変化量 300 >= 700*0.2 だが 800*0.5 未満
+        hi[7] = 220.0;  // 変化量 120 < 700*0.2
+        hi[9] = 400.0;  // 800*0.5 ちょうどは正常
+        assert_eq!(map.detect_flat(&[lo_dead, vec![400.0; 16], hi], 0.5, 0.2).unwrap(), 2);
+        assert_eq!(map.count(DefectKind::Dead), 1);
+        assert_eq!(map.count(DefectKind::Stuck), 1);
+        assert!(map.defects().contains(&Defect { x: 2, y: 0, kind: DefectKind::Dead }));
+        assert!(map.defects().contains(&Defect { x: 3, y: 1, kind: DefectKind::Stuck }));
+        assert!(!map.is_defective(1, 2));
+    }
+
+    #[test]
+    fn detect_flat_needs_two_levels() {
+        let mut map = DefectMap::new(2, 2, false);
+        assert!(map.detect_flat(&[vec![0.0; 4]], 0.5, 0.2).is_err());
+        assert!(map.detect_flat(&[vec![0.0; 4], vec![0.0; 3]], 0.5, 0.2).is_err());
+    }
+
+    #[test]
+    fn correct_mono_neighbors() {
+        let mut map = DefectMap::new(3, 3, false);
+        map.add(1, 1, DefectKind::Hot);
+        map.add(0, 0, DefectKind::Dead);
+        let mut img: Vec<u16> = vec![
+            0,  10, 20,
+            30, 999, 40,
+            50, 60, 71,
+        ];
+        map.correct(&mut img).unwrap();
+        // 中央は欠陥の (0,0) を除く 7 画素の平均 (四捨五入)
+        assert_eq!(img[4], (10 + 20 + 30 + 40 + 50 + 60 + 71 + 3) / 7);
+        // 角は欠陥の中央を使わず 2 画素の平均 (10 + 30) / 2
+        assert_eq!(img[0], 20);
+    }
+
+    #[test]
+    fn correct_bayer_same_color() {
+        // R(2,2) は 2 画素離れた R だけ、Gr(3,2) は斜め隣の G も使う
+        let mut map = DefectMap::new(6, 6, true);
+        map.add(2, 2, DefectKind::Hot);
+        map.add(3, 2, DefectKind::Dead);
+        let mut img: Vec<u8> = (0..36)
+            .map(|i| match ((i / 6) % 2, i % 2) {
+                (0, 0) => 200, // R
+                (1, 1) => 20,  // B
+                _ => 100,      // G
+            })
+            .collect();
+        img[12] = 180;  // R (0,2)
+        img[14] = 255;  // R (2,2)
+        img[15] = 0;    // Gr (3,2)
+        img[8] = 104;   // Gb (2,1) は Gr(3,2) の斜め隣
+        map.correct(&mut img).unwrap();
+        assert_eq!(img[14], ((180u32 + 200 * 3 + 2) / 4) as u8);
+        // Gr(3,2) は上下左右 2 画素先の Gr 4 画素と斜め隣の Gb 4 画素
+        assert_eq!(img[15], ((100u32 * 7 + 104 + 4) / 8) as u8);
+    }
+
+    #[test]
+    fn correct_rejects_size_mismatch() {
+        let map = DefectMap::new(2, 2, false);
+        assert!(map.correct(&mut [0u16; 3]).is_err());
+    }
+
+    #[test]
+    fn kind_name_round_trip() {
+        for kind in [DefectKind::Hot, DefectKind::Dead, DefectKind::Stuck] {
+            assert_eq!(DefectKind::from_name(kind.name()), Some(kind));
+        }
+        assert_eq!(DefectKind::from_name("cold"), None);
+    }
+}
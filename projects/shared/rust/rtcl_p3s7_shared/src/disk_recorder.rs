@@ -0,0 +1,325 @@
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use jelly_mem_access::*;
+
+use crate::capture_driver::CaptureDriver;
+use crate::pixel_format::PixelFormat;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+/// ディスク録画の状態
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskRecordStatus {
+    /// 書き込みキューに積んだフレーム数
+    pub queued_frames: usize,
+    /// ディスクへ書き込み済みのフレーム数
+    pub written_frames: usize,
+    /// 取り出しが間に合わず DMA に上書きされたフレーム数
+    pub overwritten_frames: usize,
+    /// 書き込みキューが一杯で捨てたフレーム数
+    pub dropped_frames: usize,
+}
+
+// 書き込みスレッドに渡すフレームの塊
+struct Block {
+    // 先頭フレームの通し番号
+    seq: usize,
+    // フレーム毎のハードウェアフレームカウンタ (推定値)
+    frame_counts: Vec<usize>,
+    data: Vec<u8>,
+}
+
+/// udmabuf をダブルバッファとして使い、生フレームをディスクへ書き出す録画器
+///
+/// dmabuf を前半/後半に分け、DMA が片側に書いている間にもう片側を
+/// まとめてコピーし、バックグラウンドスレッドでファイルに追記する。
+/// 書き込みキューが一杯のときはブロックせずにフレームを捨てて計上する。
+///
+/// 生ファイルの横に `<path>.idx` (CSV: seq,frame_count,offset) を書き、
+/// 書き出したフレーム毎のストリーム通し番号とファイル内オフセットを残す。
+/// 捨てたフレームは seq の欠番として分かる。
+pub struct DiskRecorder {
+    path: PathBuf,
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    half_frames: usize,
+    next_seq: usize,
+    max_frames: Option<usize>,
+    status: DiskRecordStatus,
+    tx: Option<SyncSender<Block>>,
+    written: Arc<AtomicUsize>,
+    error: Arc<Mutex<Option<String>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl DiskRecorder {
+    /// 録画開始
+    ///
+    /// `queue_depth` は書き込み待ちにできるハーフバッファの数。
+    /// `max_frames` が None の場合は `finish` を呼ぶかディスクが一杯になるまで録画する。
+    pub fn start<T0, T1>(
+        cap: &mut CaptureDriver<T0, T1>,
+        path: impl AsRef<Path>,
+        width: usize,
+        height: usize,
+        queue_depth: usize,
+        max_frames: Option<usize>,
+        frame_count: usize,
+    ) -> Result<Self>
+    where
+        T0: MemAccess + Clone,
+        T1: MemAccess,
+    {
+        // ハーフ単位で扱えるよう偶数スロットで開始
+        let slots = cap.max_frames(width, height) & !1;
+        if slots < 2 {
+            return Err("dmabuf is too small for disk recording".into());
+        }
+        let path = path.as_ref().to_path_buf();
+        let file = BufWriter::new(File::create(&path)?);
+        let mut index = BufWriter::new(File::create(Self::sibling_path(&path, "idx"))?);
+        writeln!(index, "seq,frame_count,offset")?;
+
+        // 書き込みスレッド起動
+        let format = cap.pixel_format();
+        let frame_size = format.frame_bytes(width, height);
+        let (tx, rx) = sync_channel::<Block>(queue_depth.max(1));
+        let written = Arc::new(AtomicUsize::new(0));
+        let error = Arc::new(Mutex::new(None));
+        let handle = {
+            let written = written.clone();
+            let error = error.clone();
+            std::thread::spawn(move || {
+                let mut file = file;
+                let mut index = index;
+                let mut offset = 0;
+                let mut write_block = |block: Block| -> std::io::Result<()> {
+                    file.write_all(&block.data)?;
+                    for (i, count) in block.frame_counts.iter().enumerate() {
+                        writeln!(index, "{},{},{}", block.seq + i, count, offset)?;
+                        offset += frame_size;
+                    }
+                    Ok(())
+                };
+                for block in rx {
+                    let frames = block.frame_counts.len();
+                    if let Err(e) = write_block(block) {
+                        *error.lock().unwrap() = Some(e.to_string());
+                        return;
+                    }
+                    written.fetch_add(frames, Ordering::SeqCst);
+                }
+                if let Err(e) = file.flush().and_then(|_| index.flush()) {
+                    *error.lock().unwrap() = Some(e.to_string());
+                }
+            })
+        };
+
+        cap.start_stream(width, height, slots, frame_count)?;
+
+        Ok(Self {
+            path,
+            width,
+            height,
+            format,
+            half_frames: slots / 2,
+            next_seq: 0,
+            max_frames,
+            status: DiskRecordStatus::default(),
+            tx: Some(tx),
+            written,
+            error,
+            handle: Some(handle),
+        })
+    }
+
+    /// 書き込みが完了したハーフバッファを書き込みキューへ積む
+    ///
+    /// コピー後に DMA の書き込み位置を読み直し、上書きされていないか確認する。
+    /// 録画を継続する場合は true を返す。
+    pub fn poll<T0, T1>(&mut self, cap: &mut CaptureDriver<T0, T1>) -> Result<bool>
+    where
+        T0: MemAccess + Clone,
+        T1: MemAccess,
+    {
+        self.check_error()?;
+        if self.tx.is_none() {
+            return Ok(false);
+        }
+
+        let slots = self.half_frames * 2;
+        loop {
+            if self.reached_max() {
+                return Ok(false);
+            }
+
+            let written = cap.stream_written();
+            if written < self.next_seq + self.half_frames {
+                return Ok(true);
+            }
+
+            // 取り出しが遅れて上書きされたハーフは読み飛ばす
+            if self.next_seq + slots <= written {
+                let oldest = written + 1 - slots;
+                let skip_to = oldest.div_ceil(self.half_frames) * self.half_frames;
+                self.status.overwritten_frames += skip_to - self.next_seq;
+                self.next_seq = skip_to;
+                continue;
+            }
+
+            let slot = self.next_seq % slots;
+            let block = cap.read_images_vec(slot, self.half_frames)?;
+
+            // コピー中に上書きが始まっていたら捨てる
+            let written = cap.stream_written();
+            if self.next_seq + slots <= written {
+                self.status.overwritten_frames += self.half_frames;
+                self.next_seq += self.half_frames;
+                continue;
+            }
+
+            let frames = self.limit_frames(self.half_frames);
+            self.push(cap, block, frames)?;
+            self.next_seq += self.half_frames;
+        }
+    }
+
+    /// 録画終了
+    ///
+    /// DMA を止め、残りのフレームを書き出してから書き込みスレッドの終了を待つ。
+    pub fn finish<T0, T1>(mut self, cap: &mut CaptureDriver<T0, T1>) -> Result<DiskRecordStatus>
+    where
+        T0: MemAccess + Clone,
+        T1: MemAccess,
+    {
+        // 停止時に書き込み中だったフレームも完了する
+        let written = cap.stream_written() + 1;
+        cap.stop_stream()?;
+
+        let slots = self.half_frames * 2;
+        if self.tx.is_some() && !self.reached_max() {
+            let oldest = written.saturating_sub(slots);
+            if self.next_seq < oldest {
+                self.status.overwritten_frames += oldest - self.next_seq;
+                self.next_seq = oldest;
+            }
+            while self.next_seq < written && !self.reached_max() {
+                let slot = self.next_seq % slots;
+                let frames = (written - self.next_seq).min(slots - slot);
+                let block = cap.read_images_vec(slot, frames)?;
+                let frames = self.limit_frames(frames);
+                self.push(cap, block, frames)?;
+                self.next_seq += frames;
+            }
+        }
+
+        self.close()?;
+        self.write_info()?;
+        Ok(self.status())
+    }
+
+    pub fn status(&self) -> DiskRecordStatus {
+        DiskRecordStatus {
+            written_frames: self.written.load(Ordering::SeqCst),
+            ..self.status
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn reached_max(&self) -> bool {
+        match self.max_frames {
+            Some(max) => self.next_seq >= max,
+            None => false,
+        }
+    }
+
+    fn limit_frames(&self, frames: usize) -> usize {
+        match self.max_frames {
+            Some(max) => frames.min(max - self.next_seq),
+            None => frames,
+        }
+    }
+
+    // 通し番号 `next_seq` から `frames` 枚を書き込みキューへ積む
+    fn push<T0, T1>(&mut self, cap: &CaptureDriver<T0, T1>, mut data: Vec<u8>, frames: usize) -> Result<()>
+    where
+        T0: MemAccess + Clone,
+        T1: MemAccess,
+    {
+        data.truncate(frames * self.format.frame_bytes(self.width, self.height));
+        let tx = match self.tx.as_ref() {
+            Some(tx) => tx,
+            None => return Err("disk recorder is closed".into()),
+        };
+        let block = Block {
+            seq: self.next_seq,
+            frame_counts: (self.next_seq..self.next_seq + frames).map(|seq| cap.stream_frame_count(seq)).collect(),
+            data,
+        };
+        match tx.try_send(block) {
+            Ok(()) => self.status.queued_frames += frames,
+            Err(TrySendError::Full(_)) => self.status.dropped_frames += frames,
+            Err(TrySendError::Disconnected(_)) => {
+                self.check_error()?;
+                return Err("disk writer thread stopped".into());
+            }
+        }
+        Ok(())
+    }
+
+    fn check_error(&self) -> Result<()> {
+        match self.error.lock().unwrap().as_ref() {
+            Some(e) => Err(format!("disk write failed: {}", e).into()),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.tx = None;
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                return Err("disk writer thread panicked".into());
+            }
+        }
+        self.check_error()
+    }
+
+    // `<path>.<ext>`
+    fn sibling_path(path: &Path, ext: &str) -> PathBuf {
+        let mut p = path.to_path_buf().into_os_string();
+        p.push(".");
+        p.push(ext);
+        p.into()
+    }
+
+    // 生データの読み込みに必要な情報を横に書いておく
+    fn write_info(&self) -> Result<()> {
+        let mut f = File::create(Self::sibling_path(&self.path, "info"))?;
+        writeln!(f, "width = {}", self.width)?;
+        writeln!(f, "height = {}", self.height)?;
+        writeln!(f, "format = {}", self.format)?;
+        writeln!(f, "frames = {}", self.written.load(Ordering::SeqCst))?;
+        writeln!(f, "overwritten_frames = {}", self.status.overwritten_frames)?;
+        writeln!(f, "dropped_frames = {}", self.status.dropped_frames)?;
+        Ok(())
+    }
+}
+
+// オブジェクト解放時に書き込みスレッドを終了
+impl Drop for DiskRecorder {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
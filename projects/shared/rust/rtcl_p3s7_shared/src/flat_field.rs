@@ -0,0 +1,367 @@
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "opencv")]
+use opencv::core::*;
+
+use crate::frame::Frame;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// ゲインマップの固定小数点 (Q4.12)
+const FFC_GAIN_SHIFT: u32 = 12;
+const FFC_GAIN_ONE: u32 = 1 << FFC_GAIN_SHIFT;
+
+const FFC_FILE_MAGIC: &[u8; 8] = b"P3S7FFC1";
+
+/// 暗電流・固定パターンノイズ・シェーディング (フラットフィールド) 補正
+///
+/// 補正は `out = ((raw - dark - column) * gain) >> 12` を整数演算で行う。
+/// dark は列成分を除いた画素毎のオフセット、column は列毎のオフセットで、
+/// 列成分はブラックラインから更新できる。gain は PRNU と周辺減光を
+/// 打ち消す Q4.12 の画素毎ゲインで、Bayer の場合は色毎に平均 1.0 となる。
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlatFieldCorrection {
+    width: usize,
+    height: usize,
+    bayer: bool,
+    max_value: u16,
+    dark: Vec<u16>,
+    column: Vec<i16>,
+    gain: Vec<u16>,
+}
+
+impl FlatFieldCorrection {
+    /// 補正なし (dark=0, column=0, gain=1.0) で生成
+    pub fn new(width: usize, height: usize, bayer: bool) -> Self {
+        Self {
+            width,
+            height,
+            bayer,
+            max_value: 1023,
+            dark: vec![0; width * height],
+            column: vec![0; width],
+            gain: vec![FFC_GAIN_ONE as u16; width * height],
+        }
+    }
+
+    /// モジュール ID 毎の保存先
+    pub fn module_path(dir: impl AsRef<Path>, module_id: u16) -> PathBuf {
+        dir.as_ref().join(format!("flat_field_{:04x}.bin", module_id))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn bayer(&self) -> bool {
+        self.bayer
+    }
+
+    /// 出力の最大値 (既定 1023, Raw8 なら 255)
+    pub fn set_max_value(&mut self, max_value: u16) {
+        self.max_value = max_value;
+    }
+
+    pub fn max_value(&self) -> u16 {
+        self.max_value
+    }
+
+    pub fn dark(&self) -> &[u16] {
+        &self.dark
+    }
+
+    pub fn column(&self) -> &[i16] {
+        &self.column
+    }
+
+    pub fn gain(&self) -> &[u16] {
+        &self.gain
+    }
+
+    /// 暗画像平均 (`CaptureDriver::average_frames`) から暗補正マップを作成
+    ///
+    /// 列毎の平均を列オフセットに、残りを画素毎のオフセットに分離する。
+    pub fn set_dark(&mut self, dark: &[f32]) -> Result<()> {
+        self.check_size(dark.len())?;
+        let col_mean = self.column_means(dark, self.height);
+        self.set_column_offsets(&col_mean);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = y * self.width + x;
+                let v = dark[i] - self.column[x] as f32;
+                self.dark[i] = v.round().clamp(0.0, u16::MAX as f32) as u16;
+            }
+        }
+        Ok(())
+    }
+
+    /// ブラックライン平均から列オフセットを更新 (列 FPN)
+    ///
+    /// `black` は `black_width` x `black_height` のブラックライン画像の平均で、
+    /// 画像の x=0 がブラックラインの `x_offset` 列目に対応する。
+    /// 暗画像から求めた列成分を置き換えるので、温度で変化する列 FPN を追従できる。
+    pub fn set_column_fpn_from_black(&mut self, black: &[f32], black_width: usize, black_height: usize, x_offset: usize) -> Result<()> {
+        if black_height == 0 || black.len() < black_width * black_height {
+            return Err("invalid black line image".into());
+        }
+        if x_offset + self.width > black_width {
+            return Err(format!("black lines ({} columns) do not cover image columns {}..{}",
+                                black_width, x_offset, x_offset + self.width).into());
+        }
+        let mut col_mean = vec![0f32; self.width];
+        for y in 0..black_height {
+            for x in 0..self.width {
+                col_mean[x] += black[y * black_width + x_offset + x];
+            }
+        }
+        col_mean.iter_mut().for_each(|v| *v /= black_height as f32);
+        self.set_column_offsets(&col_mean);
+        Ok(())
+    }
+
+    /// 一様光の明画像平均からゲインマップを作成 (先に暗補正を設定しておくこと)
+    pub fn set_flat(&mut self, flat: &[f32]) -> Result<()> {
+        self.check_size(flat.len())?;
+
+        // 暗補正後の信号
+        let signal: Vec<f32> = (0..self.width * self.height)
+            .map(|i| flat[i] - self.dark[i] as f32 - self.column[i % self.width] as f32)
+            .collect();
+
+        // 色毎の平均を目標値にする
+        let mut sum = [0f64; 4];
+        let mut n = [0usize; 4];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let c = self.channel(x, y);
+                sum[c] += signal[y * self.width + x] as f64;
+                n[c] += 1;
+            }
+        }
+        let mut target = [0f32; 4];
+        for c in 0..4 {
+            if n[c] > 0 {
+                target[c] = (sum[c] / n[c] as f64) as f32;
+            }
+        }
+        if target.iter().take(if self.bayer { 4 } else { 1 }).any(|&t| t <= 0.0) {
+            return Err("flat image has no signal above dark level".into());
+        }
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = y * self.width + x;
+                let s = signal[i];
+                let g = if s > 0.0 { target[self.channel(x, y)] / s } else { 1.0 };
+                self.gain[i] = (g * FFC_GAIN_ONE as f32).round().clamp(0.0, u16::MAX as f32) as u16;
+            }
+        }
+        Ok(())
+    }
+
+    /// 画像を補正 (その場で書き換え)
+    pub fn apply(&self, img: &mut [u16]) -> Result<()> {
+        self.check_size(img.len())?;
+        let max = self.max_value as i32;
+        for (y, row) in img.chunks_exact_mut(self.width).enumerate() {
+            let base = y * self.width;
+            for (x, p) in row.iter_mut().enumerate() {
+                let i = base + x;
+                let v = *p as i32 - self.dark[i] as i32 - self.column[x] as i32;
+                let v = (v.max(0) * self.gain[i] as i32) >> FFC_GAIN_SHIFT;
+                *p = v.min(max) as u16;
+            }
+        }
+        Ok(())
+    }
+
+    /// CaptureDriver のフレームを補正して 16bit 画素列で返す
+    pub fn apply_frame(&self, frame: &Frame) -> Result<Vec<u16>> {
+        let mut img = frame.to_u16();
+        self.apply(&mut img)?;
+        Ok(img)
+    }
+
+    /// Mat (CV_16UC1) を補正
+    #[cfg(feature = "opencv")]
+    pub fn apply_mat(&self, img: &mut Mat) -> Result<()> {
+        if img.typ() != CV_16UC1 {
+            return Err("flat field correction requires CV_16UC1".into());
+        }
+        if img.cols() as usize != self.width || img.rows() as usize != self.height {
+            return Err("image size does not match flat field map".into());
+        }
+        self.apply(img.data_typed_mut::<u16>()?)
+    }
+
+    /// バイナリ形式で保存
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(FFC_FILE_MAGIC)?;
+        w.write_all(&(self.width as u32).to_le_bytes())?;
+        w.write_all(&(self.height as u32).to_le_bytes())?;
+        w.write_all(&[self.bayer as u8, 0])?;
+        w.write_all(&self.max_value.to_le_bytes())?;
+        for v in &self.column {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        for v in &self.dark {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        for v in &self.gain {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != FFC_FILE_MAGIC {
+            return Err("not a flat field correction file".into());
+        }
+        let mut b4 = [0u8; 4];
+        let mut b2 = [0u8; 2];
+        r.read_exact(&mut b4)?;
+        let width = u32::from_le_bytes(b4) as usize;
+        r.read_exact(&mut b4)?;
+        let height = u32::from_le_bytes(b4) as usize;
+        r.read_exact(&mut b2)?;
+        let bayer = b2[0] != 0;
+        r.read_exact(&mut b2)?;
+        let max_value = u16::from_le_bytes(b2);
+
+        let mut ffc = Self::new(width, height, bayer);
+        ffc.max_value = max_value;
+        for v in ffc.column.iter_mut() {
+            r.read_exact(&mut b2)?;
+            *v = i16::from_le_bytes(b2);
+        }
+        for v in ffc.dark.iter_mut() {
+            r.read_exact(&mut b2)?;
+            *v = u16::from_le_bytes(b2);
+        }
+        for v in ffc.gain.iter_mut() {
+            r.read_exact(&mut b2)?;
+            *v = u16::from_le_bytes(b2);
+        }
+        Ok(ffc)
+    }
+
+    fn check_size(&self, len: usize) -> Result<()> {
+        if len != self.width * self.height {
+            return Err(format!("image size does not match flat field map ({}x{})", self.width, self.height).into());
+        }
+        Ok(())
+    }
+
+    // Bayer の場合は 2x2 の位置 (0:R, 1:Gr, 2:Gb, 3:B)、モノクロは 0
+    fn channel(&self, x: usize, y: usize) -> usize {
+        if self.bayer { (y & 1) * 2 + (x & 1) } else { 0 }
+    }
+
+    // 列平均の全体平均からの差を列オフセットにする
+    fn set_column_offsets(&mut self, col_mean: &[f32]) {
+        let level = col_mean.iter().sum::<f32>() / col_mean.len() as f32;
+        for (c, m) in self.column.iter_mut().zip(col_mean) {
+            *c = (m - level).round() as i16;
+        }
+    }
+
+    fn column_means(&self, img: &[f32], rows: usize) -> Vec<f32> {
+        let mut mean = vec![0f32; self.width];
+        for row in img.chunks_exact(self.width).take(rows) {
+            for (m, v) in mean.iter_mut().zip(row) {
+                *m += v;
+            }
+        }
+        mean.iter_mut().for_each(|m| *m /= rows as f32);
+        mean
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_subtracts_dark_and_column() {
+        let mut ffc = FlatFieldCorrection::new(2, 1, false);
+        ffc.dark = vec![10, 20];
+        ffc.column = vec![5, -5];
+        let mut img = vec![100, 100];
+        ffc.apply(&mut img).unwrap();
+        assert_eq!(img, vec![85, 85]);
+    }
+
+    #[test]
+    fn apply_gain_truncates() {
+        // 1.5 倍と 0.75 倍 (Q4.12) で端数は切り捨て
+        let mut ffc = FlatFieldCorrection::new(4, 1, false);
+        ffc.gain = vec![6144, 6144, 3072, 3072];
+        let mut img = vec![3, 5, 3, 1];
+        ffc.apply(&mut img).unwrap();
+        assert_eq!(img, vec![4, 7, 2, 0]);
+    }
+
+    #[test]
+    fn apply_saturates() {
+        let mut ffc = FlatFieldCorrection::new(3, 1, false);
+        ffc.dark = vec![0, 0, 50];
+        ffc.gain = vec![8192, 8192, 8192];
+        let mut img = vec![511, 1000, 20];
+        ffc.apply(&mut img).unwrap();
+        // 上限 1023 で飽和、暗レベル未満は 0
+        assert_eq!(img, vec![1022, 1023, 0]);
+
+        ffc.set_max_value(255);
+        let mut img = vec![127, 128, 1023];
+        ffc.apply(&mut img).unwrap();
+        assert_eq!(img, vec![254, 255, 255]);
+    }
+
+    #[test]
+    fn apply_rejects_size_mismatch() {
+        let ffc = FlatFieldCorrection::new(2, 2, false);
+        assert!(ffc.apply(&mut [0u16; 3]).is_err());
+    }
+
+    #[test]
+    fn set_dark_splits_column_offset() {
+        let mut ffc = FlatFieldCorrection::new(2, 2, false);
+        ffc.set_dark(&[10.0, 20.0, 12.0, 22.0]).unwrap();
+        assert_eq!(ffc.column(), &[-5, 5]);
+        assert_eq!(ffc.dark(), &[15, 15, 17, 17]);
+    }
+
+    #[test]
+    fn set_flat_gain_per_channel() {
+        let mut ffc = FlatFieldCorrection::new(2, 1, false);
+        ffc.set_flat(&[100.0, 200.0]).unwrap();
+        assert_eq!(ffc.gain(), &[6144, 3072]);
+
+        // Bayer は色毎に平均を合わせる
+        let mut ffc = FlatFieldCorrection::new(4, 2, true);
+        ffc.set_flat(&[100.0, 50.0, 300.0, 50.0,
+                       80.0, 40.0, 80.0, 40.0]).unwrap();
+        assert_eq!(ffc.gain(), &[8192, 4096, 2731, 4096,
+                                 4096, 4096, 4096, 4096]);
+    }
+
+    #[test]
+    fn set_flat_needs_signal() {
+        let mut ffc = FlatFieldCorrection::new(2, 1, false);
+        ffc.dark = vec![100, 100];
+        assert!(ffc.set_flat(&[50.0, 100.0]).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+
+use crate::camera_driver::ReadoutMode;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// 公称値 (mult_timer は 72MHz のクロック数、FOT は従来の実測値)
+pub const NOMINAL_TIME_UNIT_US: f32 = 1.0 / 72.0;
+pub const NOMINAL_FOT_US: f32 = 45.4133;
+
+/// フレーム周期モデルの校正結果
+///
+/// フレーム周期 = fr_length * mult_timer * time_unit_us + fot_us
+///
+/// FOT は ROI の高さや読み出しモード、D-PHY 速度で変わるので、
+/// 測定した条件と一緒に保存し、条件が一致する時だけ使う。
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FotCalibration {
+    #[serde(default)]
+    pub readout_mode: ReadoutMode,
+    pub width: usize,
+    pub height: usize,
+    pub dphy_speed: f64,
+    /// フレームオーバーヘッド時間 [us]
+    pub fot_us: f32,
+    /// mult_timer 1 カウントあたりの時間 [us] (公称 1/72)
+    pub time_unit_us: f32,
+    /// フィット残差の RMS [us]
+    #[serde(default)]
+    pub residual_us: f32,
+    /// フィットに使った測定点の数
+    #[serde(default)]
+    pub samples: usize,
+}
+
+impl FotCalibration {
+    /// 条件が一致するか
+    pub fn matches(&self, readout_mode: ReadoutMode, width: usize, height: usize, dphy_speed: f64) -> bool {
+        self.readout_mode == readout_mode
+            && self.width == width
+            && self.height == height
+            && (self.dphy_speed - dphy_speed).abs() < 1.0
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(self.fot_us.is_finite() && self.time_unit_us.is_finite() && self.time_unit_us > 0.0) {
+            return Err(format!("invalid FOT calibration for {}x{}: fot={} us, unit={} us",
+                                self.width, self.height, self.fot_us, self.time_unit_us).into());
+        }
+        Ok(())
+    }
+}
+
+/// 1 測定点
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FotSample {
+    pub mult_timer: u16,
+    pub fr_length: u16,
+    /// 実測したフレーム周期 [us]
+    pub period_us: f64,
+}
+
+/// 測定点から (fot_us, time_unit_us, residual_us) を最小二乗で求める
+pub fn fit_fot(samples: &[FotSample]) -> Result<(f32, f32, f32)> {
+    if samples.len() < 2 {
+        return Err("FOT fit needs at least 2 samples".into());
+    }
+    let xs: Vec<f64> = samples.iter().map(|s| s.mult_timer as f64 * s.fr_length as f64).collect();
+    let n = samples.len() as f64;
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = samples.iter().map(|s| s.period_us).sum::<f64>() / n;
+    let mut sxx = 0.0;
+    let mut sxy = 0.0;
+    for (x, s) in xs.iter().zip(samples) {
+        sxx += (x - mean_x) * (x - mean_x);
+        sxy += (x - mean_x) * (s.period_us - mean_y);
+    }
+    if sxx <= 0.0 {
+        return Err("FOT fit needs samples with different fr_length * mult_timer".into());
+    }
+    let unit = sxy / sxx;
+    let fot = mean_y - unit * mean_x;
+    let residual = (xs.iter().zip(samples)
+        .map(|(x, s)| { let e = s.period_us - (unit * x + fot); e * e })
+        .sum::<f64>() / n).sqrt();
+    Ok((fot as f32, unit as f32, residual as f32))
+}
+
+/// 校正時に掃引する条件
+///
+/// 周期は ROI の読み出し時間より長くしないと、センサーが周期を延ばして
+/// 直線から外れるので注意。
+#[derive(Debug, Clone, PartialEq)]
+pub struct FotSweep {
+    pub mult_timers: Vec<u16>,
+    /// 目標とするフレーム周期 [us] (fr_length は公称値から計算する)
+    pub periods_us: Vec<f32>,
+    /// 設定変更後に読み捨てるフレーム数
+    pub settle_frames: usize,
+    /// 1 点あたりの平均フレーム数
+    pub average_frames: usize,
+}
+
+impl Default for FotSweep {
+    fn default() -> Self {
+        Self {
+            mult_timers: vec![36, 72, 144],
+            periods_us: vec![4000.0, 6000.0, 10000.0, 16000.0],
+            settle_frames: 3,
+            average_frames: 8,
+        }
+    }
+}
+
+impl FotSweep {
+    /// 掃引する (mult_timer, fr_length) の組
+    pub fn points(&self) -> Vec<(u16, u16)> {
+        let mut points = Vec::new();
+        for &mult_timer in &self.mult_timers {
+            let unit = mult_timer as f32 * NOMINAL_TIME_UNIT_US;
+            for &period_us in &self.periods_us {
+                let fr_length = ((period_us - NOMINAL_FOT_US) / unit).round();
+                if fr_length >= 1.0 && fr_length <= u16::MAX as f32 {
+                    points.push((mult_timer, fr_length as u16));
+                }
+            }
+        }
+        points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(mult_timer: u16, fr_length: u16, fot_us: f64, unit_us: f64) -> FotSample {
+        FotSample { mult_timer, fr_length, period_us: mult_timer as f64 * fr_length as f64 * unit_us + fot_us }
+    }
+
+    #[test]
+    fn fit_exact_line() {
+        let samples = [
+            sample(36, 100, 45.0, 1.0 / 72.0),
+            sample(72, 200, 45.0, 1.0 / 72.0),
+            sample(144, 300, 45.0, 1.0 / 72.0),
+            sample(72, 1000, 45.0, 1.0 / 72.0),
+        ];
+        let (fot, unit, residual) = fit_fot(&samples).unwrap();
+        assert!((fot - 45.0).abs() < 1e-3, "fot={}", fot);
+        assert!((unit - 1.0 / 72.0).abs() < 1e-6, "unit={}", unit);
+        assert!(residual < 1e-3, "residual={}", residual);
+    }
+
+    #[test]
+    fn fit_needs_two_samples() {
+        assert!(fit_fot(&[]).is_err());
+        assert!(fit_fot(&[sample(72, 100, 45.0, 1.0 / 72.0)]).is_err());
+    }
+
+    #[test]
+    fn fit_needs_different_x() {
+        // fr_length * mult_timer が同じ点だけでは傾きが決まらない
+        let samples = [
+            sample(36, 200, 45.0, 1.0 / 72.0),
+            sample(72, 100, 45.0, 1.0 / 72.0),
+        ];
+        assert!(fit_fot(&samples).is_err());
+    }
+
+    #[test]
+    fn sweep_drops_out_of_range_fr_length() {
+        // mult_timer 72 で 1 カウント 1us
+        let sweep = FotSweep {
+            mult_timers: vec![72],
+            periods_us: vec![40.0, 1045.4133, 1.0e6],
+            ..FotSweep::default()
+        };
+        assert_eq!(sweep.points(), vec![(72, 1000)]);
+    }
+}
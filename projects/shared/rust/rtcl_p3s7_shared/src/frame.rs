@@ -0,0 +1,155 @@
+#![allow(dead_code)]
+
+#[cfg(feature = "opencv")]
+use opencv::core::*;
+
+use crate::pixel_format::*;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+/// udmabuf 上のフレームを直接参照するビュー
+///
+/// コピーせずに DMA バッファを参照する。所有する Vec や Mat が必要な場合は
+/// `to_vec` / `to_u16` / `to_mat` で明示的にコピーする。
+/// DMA 停止中しか作れない。ストリーミング中のフレームは
+/// `CaptureDriver::read_stream_frame` でコピーした `FrameBuf` から参照する。
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    format: PixelFormat,
+    index: usize,
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(data: &'a [u8], width: usize, height: usize, format: PixelFormat, index: usize) -> Self {
+        Self {
+            data,
+            width,
+            height,
+            stride: format.line_bytes(width),
+            format,
+            index,
+        }
+    }
+
+    /// フレーム全体のバイト列
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// 1 ラインのバイト数
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// 録画バッファ内のフレーム番号
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// 1 ライン分のバイト列
+    pub fn row(&self, y: usize) -> &'a [u8] {
+        &self.data[y * self.stride..(y + 1) * self.stride]
+    }
+
+    /// 画素値 (Planes8x2 は画像プレーン)
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.format.pixel(self.data, self.width, y * self.width + x)
+    }
+
+    /// メモリ上の形式のままコピー
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    /// 16bit 画素列に展開してコピー (Planes8x2 は画像プレーン)
+    pub fn to_u16(&self) -> Vec<u16> {
+        self.format.to_u16(self.data, self.width, self.height)
+    }
+
+    /// Planes8x2 を (下位プレーン, 上位プレーン) に分離してコピー
+    pub fn to_planes(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        if self.format != PixelFormat::Planes8x2 {
+            return Err(format!("pixel format {} has no planes", self.format).into());
+        }
+        Ok(split_planes8x2(self.data))
+    }
+
+    /// Mat にコピー
+    ///
+    /// RAW10 系は CV_16UC1、Raw8 は CV_8UC1、Planes8x2 は CV_8UC2 で返す。
+    #[cfg(feature = "opencv")]
+    pub fn to_mat(&self) -> Result<Mat> {
+        let rows = self.height as i32;
+        let cols = self.width as i32;
+        let pixels = self.width * self.height;
+        unsafe {
+            match self.format {
+                PixelFormat::Raw10In16 | PixelFormat::BayerRggb | PixelFormat::PackedRaw10 => {
+                    let mut img = Mat::new_rows_cols(rows, cols, CV_16UC1)?;
+                    img.data_typed_mut::<u16>()?.copy_from_slice(&self.to_u16());
+                    Ok(img)
+                }
+                PixelFormat::Raw8 => {
+                    let mut img = Mat::new_rows_cols(rows, cols, CV_8UC1)?;
+                    img.data_typed_mut::<u8>()?.copy_from_slice(&self.data[..pixels]);
+                    Ok(img)
+                }
+                PixelFormat::Planes8x2 => {
+                    let mut img = Mat::new_rows_cols(rows, cols, CV_8UC2)?;
+                    img.data_bytes_mut()?.copy_from_slice(&self.data[..pixels * 2]);
+                    Ok(img)
+                }
+            }
+        }
+    }
+}
+
+/// コピーしたフレーム
+///
+/// DMA が書き込み中のバッファから取り出したフレームを保持する。
+#[derive(Debug, Clone)]
+pub struct FrameBuf {
+    data: Vec<u8>,
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    index: usize,
+}
+
+impl FrameBuf {
+    pub fn new(data: Vec<u8>, width: usize, height: usize, format: PixelFormat, index: usize) -> Self {
+        Self {
+            data,
+            width,
+            height,
+            format,
+            index,
+        }
+    }
+
+    /// `Frame` として参照
+    pub fn frame(&self) -> Frame<'_> {
+        Frame::new(&self.data, self.width, self.height, self.format, self.index)
+    }
+
+    /// 中身のバイト列を取り出す
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+}
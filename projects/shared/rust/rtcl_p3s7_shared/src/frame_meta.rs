@@ -0,0 +1,291 @@
+#![allow(dead_code)]
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jelly_lib::i2c_hal::I2cHal;
+use jelly_mem_access::*;
+
+use crate::camera_driver::CameraDriver;
+use crate::frame::Frame;
+use crate::pixel_format::PixelFormat;
+use crate::roi_tracker::RoiPlacement;
+use crate::timing_generator_driver::TimingGeneratorDriver;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// 先頭画素の上位 6bit に PMOD ヘッダの下位 6bit が入る
+pub const HEADER_SHIFT: u32 = 10;
+pub const HEADER_BITS: u32 = 6;
+const HEADER_MASK: u16 = (1 << HEADER_BITS) - 1;
+
+/// PMOD ヘッダの内容 (PMOD_HDR_SEL)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderSelect {
+    /// 露光終了時の PMOD 入力
+    Pmod,
+    /// 露光終了時の出力パターン
+    Pattern,
+    /// 露光終了時のスロット番号
+    SlotIndex,
+    /// 現在の PMOD 入力
+    CurrentPmod,
+    /// 現在の出力パターン
+    CurrentPattern,
+    /// 現在のスロット番号
+    CurrentSlotIndex,
+}
+
+impl HeaderSelect {
+    pub fn from_u16(sel: u16) -> Option<HeaderSelect> {
+        match sel {
+            0 => Some(HeaderSelect::Pmod),
+            1 => Some(HeaderSelect::Pattern),
+            2 => Some(HeaderSelect::SlotIndex),
+            4 => Some(HeaderSelect::CurrentPmod),
+            5 => Some(HeaderSelect::CurrentPattern),
+            6 => Some(HeaderSelect::CurrentSlotIndex),
+            _ => None,
+        }
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            HeaderSelect::Pmod             => 0,
+            HeaderSelect::Pattern          => 1,
+            HeaderSelect::SlotIndex        => 2,
+            HeaderSelect::CurrentPmod      => 4,
+            HeaderSelect::CurrentPattern   => 5,
+            HeaderSelect::CurrentSlotIndex => 6,
+        }
+    }
+}
+
+/// FPGA がフレームに埋め込んだヘッダ
+///
+/// hs デザインでは先頭画素の上位 6bit に PMOD ヘッダの下位 6bit が入る。
+/// 内容は `CameraDriver::set_pmod_header_select` の設定で決まる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// ヘッダの生値 (6bit)
+    pub raw: u8,
+    /// ヘッダの内容 (不明なら None)
+    pub select: Option<HeaderSelect>,
+}
+
+impl FrameHeader {
+    /// 先頭画素からヘッダを取り出す (16bit 格納の形式のみ)
+    pub fn decode(frame: &Frame, select: Option<HeaderSelect>) -> Option<FrameHeader> {
+        match frame.format() {
+            PixelFormat::Raw10In16 | PixelFormat::BayerRggb => {}
+            _ => return None,
+        }
+        if frame.width() == 0 || frame.height() == 0 {
+            return None;
+        }
+        let (raw, _) = split_header_pixel(frame.pixel(0, 0));
+        Some(FrameHeader { raw, select })
+    }
+
+    /// スロット番号 (ヘッダがスロット番号の場合)
+    pub fn slot_index(&self) -> Option<usize> {
+        match self.select {
+            Some(HeaderSelect::SlotIndex) | Some(HeaderSelect::CurrentSlotIndex) => Some(self.raw as usize),
+            _ => None,
+        }
+    }
+
+    /// PMOD 入力 (ヘッダが PMOD 入力の場合、下位 6bit)
+    pub fn pmod(&self) -> Option<u8> {
+        match self.select {
+            Some(HeaderSelect::Pmod) | Some(HeaderSelect::CurrentPmod) => Some(self.raw),
+            _ => None,
+        }
+    }
+
+    /// 出力パターン (ヘッダがパターンの場合、下位 6bit)
+    pub fn pattern(&self) -> Option<u8> {
+        match self.select {
+            Some(HeaderSelect::Pattern) | Some(HeaderSelect::CurrentPattern) => Some(self.raw),
+            _ => None,
+        }
+    }
+}
+
+/// 先頭画素を (ヘッダ, 画素値) に分ける
+pub fn split_header_pixel(value: u16) -> (u8, u16) {
+    (((value >> HEADER_SHIFT) & HEADER_MASK) as u8, value & ((1 << HEADER_SHIFT) - 1))
+}
+
+/// 撮影時の設定 (フレームのメタデータに付ける)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureSettings {
+    /// 露光時間 [us] (トリガモードではトリガ幅)
+    pub exposure_us: f32,
+    pub gain_db: f32,
+    /// センサーのフレーム周期 [us]
+    pub frame_period_us: f32,
+    /// タイミングジェネレータのトリガ周期 [us] (トリガモード時)
+    pub trigger_period_us: Option<f64>,
+    pub roi: RoiPlacement,
+    pub header_select: Option<HeaderSelect>,
+}
+
+impl CaptureSettings {
+    /// 現在のカメラ (とタイミングジェネレータ) の設定を取り出す
+    pub fn from_camera<I2C, U, T>(cam: &mut CameraDriver<I2C, U>, timgen: Option<&TimingGeneratorDriver<T>>) -> Result<Self>
+    where
+        I2C: I2cHal,
+        <I2C as I2cHal>::Error: std::error::Error + 'static,
+        U: Copy + Clone,
+        T: MemAccess,
+    {
+        let mut exposure_us = cam.exposure()?;
+        let mut trigger_period_us = None;
+        if let Some(t) = timgen.filter(|_| cam.trigger_mode()) {
+            exposure_us = t.trigger(0)?.1 as f32;
+            trigger_period_us = Some(t.period_us());
+        }
+        Ok(Self {
+            exposure_us,
+            gain_db: cam.gain(),
+            frame_period_us: cam.frame_period()?,
+            trigger_period_us,
+            roi: cam.roi_placement(),
+            header_select: cam.pmod_config().header_select.and_then(HeaderSelect::from_u16),
+        })
+    }
+
+    /// 実際のフレーム周期 [us]
+    pub fn period_us(&self) -> f64 {
+        self.trigger_period_us.unwrap_or(self.frame_period_us as f64)
+    }
+}
+
+/// フレーム毎のメタデータ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameMeta {
+    /// 録画フレーム番号またはストリームの通し番号
+    pub index: usize,
+    /// ホスト時刻 (ストリームは取り出し時刻、録画は開始時刻と周期からの推定)
+    pub timestamp: SystemTime,
+    /// timestamp が実測ではなく推定値か
+    pub timestamp_estimated: bool,
+    /// フレームを書き込んでいた時のハードウェアフレームカウンタ (不明なら None)
+    pub frame_count: Option<usize>,
+    /// frame_count が読み出した値ではなく開始時の値からの推定値か
+    pub frame_count_estimated: bool,
+    /// 取り出し時のタイミングジェネレータのタイマ値 [tick] (録画フレームは None)
+    pub timer: Option<usize>,
+    pub settings: CaptureSettings,
+    pub header: Option<FrameHeader>,
+}
+
+impl FrameMeta {
+    pub(crate) fn new(index: usize, timestamp: SystemTime, frame_count: Option<usize>, settings: &CaptureSettings, frame: &Frame) -> Self {
+        Self {
+            index,
+            timestamp,
+            timestamp_estimated: false,
+            frame_count,
+            frame_count_estimated: false,
+            timer: None,
+            settings: *settings,
+            header: FrameHeader::decode(frame, settings.header_select),
+        }
+    }
+
+    /// UNIX 時刻 [us]
+    pub fn timestamp_us(&self) -> u64 {
+        self.timestamp.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_micros() as u64
+    }
+
+    /// ログ用の CSV ヘッダ
+    pub fn csv_header() -> &'static str {
+        "index,timestamp_us,timestamp_estimated,frame_count,frame_count_estimated,timer,exposure_us,gain_db,period_us,roi_x,roi_y,header"
+    }
+
+    /// ログ用の CSV 1 行
+    pub fn to_csv(&self) -> String {
+        let opt = |v: Option<usize>| v.map(|v| v.to_string()).unwrap_or_default();
+        format!("{},{},{},{},{},{},{:.3},{:.2},{:.3},{},{},{}",
+                self.index, self.timestamp_us(), self.timestamp_estimated as u8,
+                opt(self.frame_count), self.frame_count_estimated as u8, opt(self.timer),
+                self.settings.exposure_us, self.settings.gain_db, self.settings.period_us(),
+                self.settings.roi.x, self.settings.roi.y, opt(self.header.map(|h| h.raw as usize)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_header_pixel_bits() {
+        assert_eq!(split_header_pixel(0x0000), (0, 0));
+        assert_eq!(split_header_pixel(0x03ff), (0, 0x3ff));
+        assert_eq!(split_header_pixel(0xfc00), (0x3f, 0));
+        assert_eq!(split_header_pixel((0x15 << HEADER_SHIFT) | 0x123), (0x15, 0x123));
+    }
+
+    #[test]
+    fn header_select_round_trip() {
+        for sel in 0..16 {
+            match HeaderSelect::from_u16(sel) {
+                Some(h) => assert_eq!(h.to_u16(), sel),
+                None => assert!(sel == 3 || sel >= 7, "{}", sel),
+            }
+        }
+    }
+
+    #[test]
+    fn header_decode() {
+        let data: Vec<u8> = [(5 << HEADER_SHIFT) | 100u16, 200, 300, 400].iter().flat_map(|p| p.to_le_bytes()).collect();
+        let frame = Frame::new(&data, 2, 2, PixelFormat::Raw10In16, 0);
+        let header = FrameHeader::decode(&frame, Some(HeaderSelect::SlotIndex)).unwrap();
+        assert_eq!(header.raw, 5);
+        assert_eq!(header.slot_index(), Some(5));
+        assert_eq!(header.pmod(), None);
+        assert_eq!(header.pattern(), None);
+
+        let header = FrameHeader::decode(&frame, Some(HeaderSelect::CurrentPmod)).unwrap();
+        assert_eq!(header.pmod(), Some(5));
+        assert_eq!(header.slot_index(), None);
+
+        // 8bit 形式にはヘッダが無い
+        let frame = Frame::new(&data[..4], 2, 2, PixelFormat::Raw8, 0);
+        assert_eq!(FrameHeader::decode(&frame, None), None);
+    }
+
+    #[test]
+    fn meta_csv() {
+        let settings = CaptureSettings {
+            exposure_us: 1000.0,
+            gain_db: 1.5,
+            frame_period_us: 2000.0,
+            trigger_period_us: Some(5000.0),
+            roi: RoiPlacement { x: 16, y: 2, width: 640, height: 480, factor: 1 },
+            header_select: Some(HeaderSelect::SlotIndex),
+        };
+        let mut meta = FrameMeta {
+            index: 3,
+            timestamp: UNIX_EPOCH + Duration::from_micros(1_500_000),
+            timestamp_estimated: true,
+            frame_count: Some(42),
+            frame_count_estimated: true,
+            timer: None,
+            settings,
+            header: Some(FrameHeader { raw: 7, select: Some(HeaderSelect::SlotIndex) }),
+        };
+        assert_eq!(FrameMeta::csv_header().split(',').count(), meta.to_csv().split(',').count());
+        assert_eq!(meta.to_csv(), "3,1500000,1,42,1,,1000.000,1.50,5000.000,16,2,7");
+
+        meta.timestamp_estimated = false;
+        meta.frame_count = None;
+        meta.frame_count_estimated = false;
+        meta.timer = Some(123);
+        meta.header = None;
+        meta.settings.trigger_period_us = None;
+        assert_eq!(meta.to_csv(), "3,1500000,0,,0,123,1000.000,1.50,2000.000,16,2,");
+    }
+}
@@ -0,0 +1,380 @@
+#![allow(dead_code)]
+
+use std::time::Instant;
+
+use jelly_mem_access::*;
+
+use crate::camera_driver::frame_count_diff;
+use crate::capture_driver::{CaptureDriver, StreamFrame};
+use crate::frame::Frame;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 1 フレーム受け取った時の判定結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameCheck {
+    /// このフレームの前に飛んだフレーム数
+    pub dropped_before: usize,
+    /// 既に受け取った通し番号
+    pub duplicated: bool,
+    /// fmtr がタイムアウトで埋めたフレーム
+    pub timed_out: bool,
+    /// 読み出し中に DMA に上書きされたフレーム (dropped に計上)
+    pub overwritten: bool,
+}
+
+impl FrameCheck {
+    pub fn ok(&self) -> bool {
+        self.dropped_before == 0 && !self.duplicated && !self.timed_out && !self.overwritten
+    }
+}
+
+/// 1 回の録画/ストリーミングの集計
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RunStats {
+    /// ハードウェアフレームカウンタの増分
+    pub hw_frames: usize,
+    /// アプリに渡したフレーム数 (重複を除く)
+    pub delivered: usize,
+    /// 通し番号が飛んだ (取り出し前に上書きされた) フレーム数
+    pub dropped: usize,
+    /// 同じ通し番号を重複して受け取った回数
+    pub duplicated: usize,
+    /// fmtr がタイムアウトで埋めたフレーム数
+    pub timed_out: usize,
+    /// 書き込まれたが取り出されなかったフレーム数 (停止時に残っていた分)
+    pub pending: usize,
+    /// 設定周期と経過時間から見て、ハードウェアに届かなかったと推定されるフレーム数
+    pub missing: usize,
+    /// 経過時間 [s]
+    pub elapsed_s: f64,
+}
+
+impl RunStats {
+    /// アプリ側の実効フレームレート
+    pub fn delivered_fps(&self) -> f64 {
+        if self.elapsed_s > 0.0 { self.delivered as f64 / self.elapsed_s } else { 0.0 }
+    }
+
+    /// ハードウェアのフレームレート
+    pub fn hw_fps(&self) -> f64 {
+        if self.elapsed_s > 0.0 { self.hw_frames as f64 / self.elapsed_s } else { 0.0 }
+    }
+
+    /// 取りこぼし率 (dropped / (delivered + dropped))
+    pub fn drop_rate(&self) -> f64 {
+        let total = self.delivered + self.dropped;
+        if total > 0 { self.dropped as f64 / total as f64 } else { 0.0 }
+    }
+
+    /// 欠落やタイムアウトが一切無かったか
+    pub fn ok(&self) -> bool {
+        self.dropped == 0 && self.duplicated == 0 && self.timed_out == 0 && self.missing == 0
+    }
+}
+
+impl core::fmt::Display for RunStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "hw={} delivered={} dropped={} duplicated={} timed_out={} pending={} missing={} elapsed={:.3}s ({:.2} fps, hw {:.2} fps)",
+               self.hw_frames, self.delivered, self.dropped, self.duplicated, self.timed_out,
+               self.pending, self.missing, self.elapsed_s, self.delivered_fps(), self.hw_fps())
+    }
+}
+
+/// fmtr がタイムアウトで埋めたフレームか判定
+///
+/// 画素タイムアウトでは途切れた以降の画素が、フレームタイムアウトでは
+/// フレーム全体が `fill` で埋まるので、最終ラインがすべて `fill` かで判定する。
+pub fn is_fill_frame(frame: &Frame, fill: u16) -> bool {
+    if frame.width() == 0 || frame.height() == 0 {
+        return false;
+    }
+    let mask = ((1u32 << frame.format().data_bits()) - 1) as u16;
+    let fill = fill & mask;
+    let y = frame.height() - 1;
+    (0..frame.width()).all(|x| frame.pixel(x, y) == fill)
+}
+
+/// ハードウェアフレームカウンタと受け取ったフレームの突き合わせ
+///
+/// 開始時と終了時に `CameraDriver::frame_count` を渡し、その間に受け取った
+/// フレームの通し番号 (`StreamFrame::seq`) から欠落・重複を数える。
+/// `CameraDriver::start_accounting` で fmtr の設定を引き継いで作れる。
+#[derive(Debug, Clone)]
+pub struct FrameAccounting {
+    fill_value: u16,
+    check_timeout: bool,
+    period_us: Option<f64>,
+    start_count: usize,
+    last_count: usize,
+    start_time: Instant,
+    end_time: Option<Instant>,
+    next_seq: Option<usize>,
+    stats: RunStats,
+}
+
+impl Default for FrameAccounting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameAccounting {
+    pub fn new() -> Self {
+        Self {
+            fill_value: 0,
+            check_timeout: false,
+            period_us: None,
+            start_count: 0,
+            last_count: 0,
+            start_time: Instant::now(),
+            end_time: None,
+            next_seq: None,
+            stats: RunStats::default(),
+        }
+    }
+
+    /// fmtr の埋め値 (`CameraDriver::fill_value`)
+    ///
+    /// センサーが出力しない値を設定した場合に呼ぶ。タイムアウト判定を有効にする。
+    pub fn set_fill_value(&mut self, value: u16) {
+        self.fill_value = value;
+        self.check_timeout = true;
+    }
+
+    /// タイムアウト判定の有無 (既定は無効。埋め値が画像に現れうる場合は無効にする)
+    pub fn set_check_timeout(&mut self, enable: bool) {
+        self.check_timeout = enable;
+    }
+
+    /// 想定フレーム周期 [us] (フリーラン時のみ。None なら missing を数えない)
+    pub fn set_expected_period_us(&mut self, period_us: Option<f64>) {
+        self.period_us = period_us.filter(|&p| p > 0.0);
+    }
+
+    /// 集計開始
+    pub fn start(&mut self, frame_count: usize) {
+        self.start_count = frame_count;
+        self.last_count = frame_count;
+        self.start_time = Instant::now();
+        self.end_time = None;
+        self.next_seq = None;
+        self.stats = RunStats::default();
+    }
+
+    /// ハードウェアフレームカウンタの現在値を反映
+    pub fn update(&mut self, frame_count: usize) {
+        if self.end_time.is_none() {
+            self.last_count = frame_count;
+        }
+    }
+
+    /// 通し番号で 1 フレーム計上 (画像を確認しない場合)
+    pub fn add_seq(&mut self, seq: usize) -> FrameCheck {
+        let mut check = FrameCheck::default();
+        match self.next_seq {
+            Some(next) if seq < next => {
+                check.duplicated = true;
+                self.stats.duplicated += 1;
+                return check;
+            }
+            Some(next) => check.dropped_before = seq - next,
+            None => check.dropped_before = seq,
+        }
+        self.stats.dropped += check.dropped_before;
+        self.stats.delivered += 1;
+        self.next_seq = Some(seq + 1);
+        check
+    }
+
+    /// 通し番号と画像で 1 フレーム計上
+    pub fn add_frame(&mut self, seq: usize, frame: &Frame) -> FrameCheck {
+        let mut check = self.add_seq(seq);
+        if !check.duplicated && self.check_timeout && is_fill_frame(frame, self.fill_value) {
+            check.timed_out = true;
+            self.stats.timed_out += 1;
+        }
+        check
+    }
+
+    /// ストリーミングで取り出したフレームを計上
+    ///
+    /// `CaptureDriver::read_stream_frame` でコピーして確認し、コピー中に
+    /// 上書きされていれば取りこぼしとして数える。
+    pub fn add_stream_frame<T0, T1>(&mut self, cap: &CaptureDriver<T0, T1>, frame: &StreamFrame) -> Result<FrameCheck>
+    where
+        T0: MemAccess + Clone,
+        T1: MemAccess,
+    {
+        match cap.read_stream_frame(frame)? {
+            Some(image) => Ok(self.add_frame(frame.seq, &image.frame())),
+            None => {
+                let mut check = self.add_seq(frame.seq);
+                if !check.duplicated {
+                    check.overwritten = true;
+                    self.stats.delivered -= 1;
+                    self.stats.dropped += 1;
+                }
+                Ok(check)
+            }
+        }
+    }
+
+    /// 録画 (`record` / `start_record`) 済みのフレームをまとめて計上
+    ///
+    /// 録画は連続したフレームを書き込むので、欠落はタイムアウトとして現れる。
+    pub fn add_recorded<T0: MemAccess + Clone, T1: MemAccess>(&mut self, cap: &CaptureDriver<T0, T1>) -> Result<()> {
+        for index in 0..cap.record_frames() {
+            let image = cap.frame(index)?;
+            self.add_frame(index, &image);
+        }
+        Ok(())
+    }
+
+    /// 集計終了
+    pub fn finish(&mut self, frame_count: usize) -> RunStats {
+        self.update(frame_count);
+        if self.end_time.is_none() {
+            self.end_time = Some(Instant::now());
+        }
+        self.stats()
+    }
+
+    /// 現時点の集計
+    pub fn stats(&self) -> RunStats {
+        let mut stats = self.stats;
+        let end = self.end_time.unwrap_or_else(Instant::now);
+        stats.elapsed_s = end.duration_since(self.start_time).as_secs_f64();
+        stats.hw_frames = frame_count_diff(self.last_count, self.start_count);
+        // 書き込み中の 1 フレームを除く
+        let written = stats.hw_frames.saturating_sub(1);
+        stats.pending = written.saturating_sub(stats.delivered + stats.dropped);
+        if let Some(period_us) = self.period_us {
+            let expected = (stats.elapsed_s * 1_000_000.0 / period_us).floor() as usize;
+            // 開始/終了の位相で 1 フレームはずれうる
+            stats.missing = expected.saturating_sub(stats.hw_frames + 1);
+        }
+        stats
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_format::PixelFormat;
+
+    // 10bit 画像 (u16 リトルエンディアン)
+    fn raw10(pixels: &[u16]) -> Vec<u8> {
+        pixels.iter().flat_map(|p| p.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn add_seq_in_order() {
+        let mut acc = FrameAccounting::new();
+        acc.start(0);
+        for seq in 0..4 {
+            assert!(acc.add_seq(seq).ok());
+        }
+        let stats = acc.stats();
+        assert_eq!(stats.delivered, 4);
+        assert_eq!(stats.dropped, 0);
+        assert!(stats.ok());
+    }
+
+    #[test]
+    fn add_seq_dropped() {
+        let mut acc = FrameAccounting::new();
+        acc.start(0);
+        // 最初のフレームも 0 から数える
+        assert_eq!(acc.add_seq(2).dropped_before, 2);
+        assert_eq!(acc.add_seq(3).dropped_before, 0);
+        let check = acc.add_seq(7);
+        assert_eq!(check.dropped_before, 3);
+        assert!(!check.ok());
+        let stats = acc.stats();
+        assert_eq!(stats.delivered, 3);
+        assert_eq!(stats.dropped, 5);
+        assert!((stats.drop_rate() - 5.0 / 8.0).abs() < 1e-12);
+        assert!(!stats.ok());
+    }
+
+    #[test]
+    fn add_seq_duplicated() {
+        let mut acc = FrameAccounting::new();
+        acc.start(0);
+        acc.add_seq(0);
+        acc.add_seq(1);
+        let check = acc.add_seq(1);
+        assert!(check.duplicated);
+        assert_eq!(check.dropped_before, 0);
+        assert!(acc.add_seq(0).duplicated);
+        // 重複の後も続きから数える
+        assert!(acc.add_seq(2).ok());
+        let stats = acc.stats();
+        assert_eq!(stats.delivered, 3);
+        assert_eq!(stats.duplicated, 2);
+        assert_eq!(stats.dropped, 0);
+    }
+
+    #[test]
+    fn fill_frame_last_line() {
+        let fill = raw10(&[1, 2, 3, 0x3ff, 0x3ff, 0x3ff]);
+        let partial = raw10(&[0x3ff, 0x3ff, 0x3ff, 0x3ff, 5, 0x3ff]);
+        // 埋め値はデータビット幅でマスクして比べる
+        assert!(is_fill_frame(&Frame::new(&fill, 3, 2, PixelFormat::Raw10In16, 0), 0xffff));
+        assert!(!is_fill_frame(&Frame::new(&partial, 3, 2, PixelFormat::Raw10In16, 0), 0x3ff));
+        assert!(!is_fill_frame(&Frame::new(&[], 0, 0, PixelFormat::Raw10In16, 0), 0));
+    }
+
+    #[test]
+    fn add_frame_timeout() {
+        let fill = raw10(&[0x3ff; 4]);
+        let image = raw10(&[0x3ff, 0x3ff, 0x3ff, 100]);
+        let fill = Frame::new(&fill, 2, 2, PixelFormat::Raw10In16, 0);
+        let image = Frame::new(&image, 2, 2, PixelFormat::Raw10In16, 0);
+
+        // 既定ではタイムアウト判定しない
+        let mut acc = FrameAccounting::new();
+        acc.start(0);
+        assert!(acc.add_frame(0, &fill).ok());
+
+        let mut acc = FrameAccounting::new();
+        acc.set_fill_value(0x3ff);
+        acc.start(0);
+        assert!(acc.add_frame(0, &image).ok());
+        assert!(acc.add_frame(1, &fill).timed_out);
+        // 重複はタイムアウトに数えない
+        let check = acc.add_frame(1, &fill);
+        assert!(check.duplicated && !check.timed_out);
+        let stats = acc.stats();
+        assert_eq!(stats.timed_out, 1);
+        assert_eq!(stats.delivered, 2);
+        assert!(!stats.ok());
+    }
+
+    #[test]
+    fn finish_counts_pending() {
+        let mut acc = FrameAccounting::new();
+        acc.start(100);
+        acc.add_seq(0);
+        acc.add_seq(2);
+        // 106 まで進んだ: 書き込み中の 1 を除く 5 フレームのうち計上済み 3 を除いた 2 フレームが残り
+        let stats = acc.finish(106);
+        assert_eq!(stats.hw_frames, 6);
+        assert_eq!(stats.delivered, 2);
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.pending, 2);
+
+        // 終了後の update は反映しない
+        acc.update(200);
+        assert_eq!(acc.stats().hw_frames, 6);
+    }
+
+    #[test]
+    fn frame_counter_wraps() {
+        let mut acc = FrameAccounting::new();
+        acc.start(0xffff_fffe);
+        assert_eq!(acc.finish(3).hw_frames, 5);
+    }
+}
@@ -1,3 +1,23 @@
+pub mod awb;
+pub mod board;
+pub mod camera_config;
 pub mod camera_driver;
+pub mod camera_group;
+pub mod camera_handle;
 pub mod capture_driver;
+pub mod color;
+pub mod defect_map;
+pub mod disk_recorder;
+pub mod flat_field;
+pub mod fot_calibration;
+pub mod frame;
+pub mod frame_meta;
+pub mod frame_stats;
+pub mod peripheral;
+pub mod pixel_format;
+pub mod ptc;
+pub mod roi_tracker;
+pub mod stream_watchdog;
 pub mod timing_generator_driver;
+pub mod timing_monitor;
+pub mod trigger_sequence;
@@ -0,0 +1,328 @@
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use jelly_mem_access::*;
+
+use crate::board::Board;
+use crate::timing_generator_driver::TimingGeneratorDriver;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// 全ブロック共通の ID/バージョンレジスタ
+const REG_CORE_ID: usize = 0x00;
+const REG_CORE_VERSION: usize = 0x01;
+
+// UIO サブブロックのサイズ
+const BLOCK_SIZE: usize = 0x400;
+
+const SYSFS_UIO: &str = "/sys/class/uio";
+
+/// PL 上のコアの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoreKind {
+    /// 各デザインのシステムレジスタ
+    System,
+    TimingGenerator,
+    FormatRegularizer,
+    VideoDmaWrite,
+    Gaussian,
+    LkAccumulator,
+    DataLogger,
+    Moment,
+    Hub75,
+    /// 検査しない
+    Other,
+}
+
+impl CoreKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoreKind::System            => "system",
+            CoreKind::TimingGenerator   => "timing_generator",
+            CoreKind::FormatRegularizer => "format_regularizer",
+            CoreKind::VideoDmaWrite     => "video_dma_write",
+            CoreKind::Gaussian          => "gaussian",
+            CoreKind::LkAccumulator     => "lk_accumulator",
+            CoreKind::DataLogger        => "data_logger",
+            CoreKind::Moment            => "moment",
+            CoreKind::Hub75             => "hub75",
+            CoreKind::Other             => "other",
+        }
+    }
+
+    /// アプリで使っているブロック名から推定
+    pub fn guess(block_name: &str) -> Self {
+        let n = block_name.to_ascii_lowercase();
+        if n == "sys" {
+            CoreKind::System
+        } else if n.starts_with("timgen") {
+            CoreKind::TimingGenerator
+        } else if n.starts_with("fmtr") {
+            CoreKind::FormatRegularizer
+        } else if n.starts_with("wdma") {
+            CoreKind::VideoDmaWrite
+        } else if n.starts_with("gauss") {
+            CoreKind::Gaussian
+        } else if n.starts_with("lk") {
+            CoreKind::LkAccumulator
+        } else if n.starts_with("log") {
+            CoreKind::DataLogger
+        } else if n.starts_with("moment") {
+            CoreKind::Moment
+        } else if n.starts_with("hub75") {
+            CoreKind::Hub75
+        } else {
+            CoreKind::Other
+        }
+    }
+
+    /// 期待する CORE_ID の (値, マスク) の候補
+    ///
+    /// jelly のコアは上位 16bit が 0x527a で下位はコア毎に異なるので、
+    /// 上位のみ検査する (デザイン記述の core_id で厳密に指定できる)。
+    pub fn core_id_patterns(&self) -> &'static [(u32, u32)] {
+        match self {
+            CoreKind::System            => &[(0xaa55_0101, 0xffff_ffff), (0x55aa_0101, 0xffff_ffff)],
+            CoreKind::TimingGenerator   => &[(0xaaaa_1234, 0xffff_ffff)],
+            CoreKind::Gaussian          => &[(0x5254_437f, 0xffff_ffff)],
+            CoreKind::Hub75             => &[(0x5254_2421, 0xffff_ffff)],
+            CoreKind::FormatRegularizer
+            | CoreKind::VideoDmaWrite
+            | CoreKind::LkAccumulator
+            | CoreKind::DataLogger
+            | CoreKind::Moment          => &[(0x527a_0000, 0xffff_0000)],
+            CoreKind::Other             => &[],
+        }
+    }
+
+    /// 対応する CORE_VERSION のメジャーバージョン (上位 16bit)
+    pub fn core_version_major(&self) -> Option<u32> {
+        match self {
+            CoreKind::TimingGenerator | CoreKind::Gaussian | CoreKind::Hub75 => Some(0x0001),
+            _ => None,
+        }
+    }
+
+    // システムレジスタの 0x01 は CORE_VERSION ではない
+    fn has_version(&self) -> bool {
+        !matches!(self, CoreKind::System | CoreKind::Other)
+    }
+}
+
+/// デザイン記述の 1 ブロック
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockDesc {
+    pub name: String,
+    pub offset: usize,
+    pub core: CoreKind,
+    /// 期待する CORE_ID (省略時は core の既定値)
+    #[serde(default)]
+    pub core_id: Option<u32>,
+    /// 期待する CORE_VERSION (省略時はメジャーバージョンのみ検査)
+    #[serde(default)]
+    pub core_version: Option<u32>,
+}
+
+impl BlockDesc {
+    pub fn new(name: &str, offset: usize, core: CoreKind) -> Self {
+        Self { name: name.to_string(), offset, core, core_id: None, core_version: None }
+    }
+}
+
+/// デザイン (ビットストリーム) 毎のペリフェラル配置
+///
+/// `.dts` と同じ場所に TOML / JSON で置いておき、`Peripherals::discover` で
+/// 各ブロックの CORE_ID / CORE_VERSION を確認してから使う。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DesignDesc {
+    pub name: String,
+    #[serde(default = "DesignDesc::default_uio")]
+    pub uio: String,
+    pub blocks: Vec<BlockDesc>,
+}
+
+impl DesignDesc {
+    fn default_uio() -> String {
+        "uio_pl_peri".to_string()
+    }
+
+    /// ボードの blocks からブロック名でコアを推定して作る
+    pub fn from_board(board: &Board) -> Self {
+        Self {
+            name: board.name.clone(),
+            uio: board.uio.clone(),
+            blocks: board.blocks.iter()
+                .map(|(name, &offset)| BlockDesc::new(name, offset, CoreKind::guess(name)))
+                .collect(),
+        }
+    }
+
+    /// TOML から読み込み (`include_str!` でバイナリに埋め込む場合など)
+    pub fn from_toml_str(s: &str) -> Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    /// ファイルから読み込み (拡張子 .json なら JSON、それ以外は TOML)
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            Self::from_toml_str(&text)
+        }
+    }
+
+    /// ファイルに保存 (拡張子 .json なら JSON、それ以外は TOML)
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let text = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string_pretty(self)?
+        };
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    pub fn block(&self, name: &str) -> Option<&BlockDesc> {
+        self.blocks.iter().find(|b| b.name == name)
+    }
+}
+
+/// 検査済みのブロック
+#[derive(Clone)]
+pub struct VerifiedBlock {
+    pub desc: BlockDesc,
+    pub core_id: u32,
+    pub core_version: Option<u32>,
+    acc: UioAccessor<usize>,
+}
+
+impl VerifiedBlock {
+    pub fn accessor(&self) -> UioAccessor<usize> {
+        self.acc.clone()
+    }
+}
+
+/// ブロックの CORE_ID / CORE_VERSION を読んで期待値と比較する
+pub fn verify_block(acc: UioAccessor<usize>, desc: &BlockDesc) -> Result<VerifiedBlock> {
+    let core_id = unsafe { acc.read_reg(REG_CORE_ID) } as u32;
+    let id_ok = match desc.core_id {
+        Some(id) => core_id == id,
+        None => {
+            let patterns = desc.core.core_id_patterns();
+            patterns.is_empty() || patterns.iter().any(|&(id, mask)| core_id & mask == id)
+        }
+    };
+    if !id_ok {
+        let expected = match desc.core_id {
+            Some(id) => format!("{:08x}", id),
+            None => desc.core.core_id_patterns().iter()
+                .map(|&(id, mask)| if mask == 0xffff_ffff { format!("{:08x}", id) } else { format!("{:04x}xxxx", id >> 16) })
+                .collect::<Vec<_>>()
+                .join(" or "),
+        };
+        return Err(format!("block '{}' at 0x{:08x}: CORE_ID {:08x} does not match {} ({}); the loaded bitstream does not match this design",
+                            desc.name, desc.offset, core_id, desc.core.name(), expected).into());
+    }
+
+    let core_version = if desc.core.has_version() {
+        let version = unsafe { acc.read_reg(REG_CORE_VERSION) } as u32;
+        let version_ok = match (desc.core_version, desc.core.core_version_major()) {
+            (Some(v), _)     => version == v,
+            (None, Some(mj)) => version >> 16 == mj,
+            (None, None)     => true,
+        };
+        if !version_ok {
+            return Err(format!("block '{}' at 0x{:08x}: unsupported {} version {:08x}",
+                                desc.name, desc.offset, desc.core.name(), version).into());
+        }
+        Some(version)
+    } else {
+        None
+    };
+
+    Ok(VerifiedBlock { desc: desc.clone(), core_id, core_version, acc })
+}
+
+/// UIO デバイスの一覧 (デバイス名, name)
+pub fn list_uio() -> Vec<(String, String)> {
+    let mut list = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(SYSFS_UIO) {
+        for entry in entries.flatten() {
+            let dev = entry.file_name().to_string_lossy().to_string();
+            if let Ok(name) = std::fs::read_to_string(entry.path().join("name")) {
+                list.push((dev, name.trim().to_string()));
+            }
+        }
+    }
+    list.sort();
+    list
+}
+
+/// 検査済みペリフェラル一式
+pub struct Peripherals {
+    design: DesignDesc,
+    uio: UioAccessor<usize>,
+    blocks: Vec<VerifiedBlock>,
+}
+
+impl Peripherals {
+    /// UIO を開いて全ブロックを検査する
+    pub fn discover(design: &DesignDesc) -> Result<Self> {
+        let uio = UioAccessor::<usize>::new_with_name(&design.uio).map_err(|e| {
+            let found: Vec<String> = list_uio().into_iter().map(|(dev, name)| format!("{}:{}", dev, name)).collect();
+            format!("failed to open UIO '{}' ({}); available: [{}]", design.uio, e, found.join(", "))
+        })?;
+        let mut blocks = Vec::new();
+        for desc in &design.blocks {
+            blocks.push(verify_block(uio.subclone(desc.offset, BLOCK_SIZE), desc)?);
+        }
+        Ok(Self { design: design.clone(), uio, blocks })
+    }
+
+    pub fn design(&self) -> &DesignDesc {
+        &self.design
+    }
+
+    pub fn uio(&self) -> &UioAccessor<usize> {
+        &self.uio
+    }
+
+    pub fn blocks(&self) -> &[VerifiedBlock] {
+        &self.blocks
+    }
+
+    /// 名前と種類を確認してブロックを取り出す
+    pub fn block(&self, name: &str, kind: CoreKind) -> Result<UioAccessor<usize>> {
+        let block = self.blocks.iter().find(|b| b.desc.name == name)
+            .ok_or_else(|| format!("design {}: block '{}' is not defined", self.design.name, name))?;
+        if block.desc.core != kind {
+            return Err(format!("block '{}' is {}, not {}", name, block.desc.core.name(), kind.name()).into());
+        }
+        Ok(block.accessor())
+    }
+
+    pub fn timing_generator(&self, name: &str) -> Result<TimingGeneratorDriver<UioAccessor<usize>>> {
+        Ok(TimingGeneratorDriver::new(self.block(name, CoreKind::TimingGenerator)?))
+    }
+}
+
+// 検査結果 (1 ブロック 1 行)
+impl core::fmt::Display for Peripherals {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "design {} ({})", self.design.name, self.design.uio)?;
+        for b in &self.blocks {
+            match b.core_version {
+                Some(v) => writeln!(f, "  {:<12} 0x{:08x} {:<18} id={:08x} ver={:08x}", b.desc.name, b.desc.offset, b.desc.core.name(), b.core_id, v)?,
+                None    => writeln!(f, "  {:<12} 0x{:08x} {:<18} id={:08x}", b.desc.name, b.desc.offset, b.desc.core.name(), b.core_id)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+#![allow(dead_code)]
+
+#[cfg(feature = "opencv")]
+use opencv::core::*;
+
+/// 画素フォーマット
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PixelFormat {
+    /// 16bit ワードの下位 10bit に RAW10
+    #[default]
+    Raw10In16,
+    /// 8bit RAW
+    Raw8,
+    /// 4 画素を 5byte に詰めた RAW10 (MIPI CSI-2 形式)
+    PackedRaw10,
+    /// 16bit ワードの下位 8bit に画像、上位 8bit に 2 枚目のプレーン
+    Planes8x2,
+    /// 16bit ワードの下位 10bit に Bayer(RGGB) の RAW10
+    BayerRggb,
+}
+
+impl PixelFormat {
+    pub const ALL: [PixelFormat; 5] = [
+        PixelFormat::Raw10In16,
+        PixelFormat::Raw8,
+        PixelFormat::PackedRaw10,
+        PixelFormat::Planes8x2,
+        PixelFormat::BayerRggb,
+    ];
+
+    /// 1 画素あたりのビット数(メモリ上)
+    pub fn bits_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Raw10In16 => 16,
+            PixelFormat::Raw8 => 8,
+            PixelFormat::PackedRaw10 => 10,
+            PixelFormat::Planes8x2 => 16,
+            PixelFormat::BayerRggb => 16,
+        }
+    }
+
+    /// 画素値の有効ビット数
+    pub fn data_bits(&self) -> usize {
+        match self {
+            PixelFormat::Raw10In16 => 10,
+            PixelFormat::Raw8 => 8,
+            PixelFormat::PackedRaw10 => 10,
+            PixelFormat::Planes8x2 => 8,
+            PixelFormat::BayerRggb => 10,
+        }
+    }
+
+    /// プレーン数
+    pub fn planes(&self) -> usize {
+        match self {
+            PixelFormat::Planes8x2 => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn is_bayer(&self) -> bool {
+        matches!(self, PixelFormat::BayerRggb)
+    }
+
+    /// 1 ラインのバイト数
+    pub fn line_bytes(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// 1 フレームのバイト数
+    pub fn frame_bytes(&self, width: usize, height: usize) -> usize {
+        self.line_bytes(width) * height
+    }
+
+    /// 設定ファイル等で使う名前
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelFormat::Raw10In16 => "raw10in16",
+            PixelFormat::Raw8 => "raw8",
+            PixelFormat::PackedRaw10 => "packed_raw10",
+            PixelFormat::Planes8x2 => "planes8x2",
+            PixelFormat::BayerRggb => "bayer_rggb",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PixelFormat> {
+        PixelFormat::ALL.into_iter().find(|f| f.name() == name)
+    }
+
+    /// 1 プレーン分の OpenCV 型
+    #[cfg(feature = "opencv")]
+    pub fn cv_type(&self) -> i32 {
+        match self {
+            PixelFormat::Raw10In16 => CV_16UC1,
+            PixelFormat::Raw8 => CV_8UC1,
+            PixelFormat::PackedRaw10 => CV_16UC1,
+            PixelFormat::Planes8x2 => CV_8UC1,
+            PixelFormat::BayerRggb => CV_16UC1,
+        }
+    }
+
+    /// フレームの `index` 番目の画素値を取り出す (Planes8x2 は画像プレーン)
+    pub fn pixel(&self, frame: &[u8], width: usize, index: usize) -> u16 {
+        match self {
+            PixelFormat::Raw10In16 | PixelFormat::BayerRggb => {
+                u16::from_le_bytes([frame[index * 2], frame[index * 2 + 1]])
+            }
+            PixelFormat::Raw8 => frame[index] as u16,
+            PixelFormat::Planes8x2 => frame[index * 2] as u16,
+            PixelFormat::PackedRaw10 => {
+                let (y, x) = (index / width, index % width);
+                let line = &frame[y * self.line_bytes(width)..];
+                let group = &line[(x / 4) * 5..];
+                let lsb = (group[4] >> ((x % 4) * 2)) & 0x3;
+                ((group[x % 4] as u16) << 2) | lsb as u16
+            }
+        }
+    }
+
+    /// フレームを 16bit 画素列に展開する (Planes8x2 は画像プレーン)
+    pub fn to_u16(&self, frame: &[u8], width: usize, height: usize) -> Vec<u16> {
+        match self {
+            PixelFormat::Raw10In16 | PixelFormat::BayerRggb => frame[..width * height * 2]
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .collect(),
+            PixelFormat::Raw8 => frame[..width * height].iter().map(|&v| v as u16).collect(),
+            PixelFormat::Planes8x2 => frame[..width * height * 2]
+                .chunks_exact(2)
+                .map(|b| b[0] as u16)
+                .collect(),
+            PixelFormat::PackedRaw10 => {
+                let mut dst = vec![0u16; width * height];
+                let line_bytes = self.line_bytes(width);
+                for y in 0..height {
+                    unpack_raw10(
+                        &frame[y * line_bytes..(y + 1) * line_bytes],
+                        &mut dst[y * width..(y + 1) * width],
+                    );
+                }
+                dst
+            }
+        }
+    }
+}
+
+impl core::fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl core::str::FromStr for PixelFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PixelFormat::from_name(s).ok_or_else(|| format!("unknown pixel format: {}", s))
+    }
+}
+
+/// MIPI CSI-2 形式の RAW10 (4 画素 5byte) を 16bit に展開
+pub fn unpack_raw10(src: &[u8], dst: &mut [u16]) {
+    for (group, pixels) in src.chunks(5).zip(dst.chunks_mut(4)) {
+        let lsb = if group.len() == 5 { group[4] } else { 0 };
+        for (i, p) in pixels.iter_mut().enumerate() {
+            *p = ((group[i] as u16) << 2) | ((lsb >> (i * 2)) & 0x3) as u16;
+        }
+    }
+}
+
+/// 16bit ワードに詰められた 2 枚の 8bit プレーンを分離 (下位, 上位)
+pub fn split_planes8x2(src: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let lo = src.chunks_exact(2).map(|b| b[0]).collect();
+    let hi = src.chunks_exact(2).map(|b| b[1]).collect();
+    (lo, hi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0x3ff, 0x000, 0x155, 0x2aa を 4 画素 5byte に詰めたもの
+    const PACKED: [u8; 5] = [0xff, 0x00, 0x55, 0xaa, 0x93];
+    const UNPACKED: [u16; 4] = [0x3ff, 0x000, 0x155, 0x2aa];
+
+    #[test]
+    fn line_and_frame_bytes() {
+        assert_eq!(PixelFormat::Raw10In16.line_bytes(640), 1280);
+        assert_eq!(PixelFormat::BayerRggb.line_bytes(640), 1280);
+        assert_eq!(PixelFormat::Planes8x2.line_bytes(640), 1280);
+        assert_eq!(PixelFormat::Raw8.line_bytes(640), 640);
+        assert_eq!(PixelFormat::PackedRaw10.line_bytes(640), 800);
+        // 4 画素に満たない端数は切り上げ
+        assert_eq!(PixelFormat::PackedRaw10.line_bytes(6), 8);
+        assert_eq!(PixelFormat::PackedRaw10.frame_bytes(640, 480), 384000);
+        assert_eq!(PixelFormat::Raw8.frame_bytes(640, 480), 307200);
+    }
+
+    #[test]
+    fn unpack_raw10_group() {
+        let mut dst = [0u16; 4];
+        unpack_raw10(&PACKED, &mut dst);
+        assert_eq!(dst, UNPACKED);
+    }
+
+    #[test]
+    fn unpack_raw10_partial_group() {
+        // 下位ビットの無い端数は上位 8bit のみ
+        let mut dst = [0u16; 3];
+        unpack_raw10(&PACKED[..3], &mut dst);
+        assert_eq!(dst, [0x3fc, 0x000, 0x154]);
+    }
+
+    #[test]
+    fn packed_raw10_to_u16() {
+        // 2 ライン x 4 画素
+        let frame: Vec<u8> = PACKED.iter().chain(PACKED.iter().rev()).copied().collect();
+        let format = PixelFormat::PackedRaw10;
+        let pixels = format.to_u16(&frame, 4, 2);
+        assert_eq!(&pixels[..4], &UNPACKED);
+        assert_eq!(&pixels[4..], &[0x24f, 0x2ab, 0x157, 0x003]);
+        for (i, &p) in pixels.iter().enumerate() {
+            assert_eq!(format.pixel(&frame, 4, i), p);
+        }
+    }
+
+    #[test]
+    fn split_planes() {
+        let (lo, hi) = split_planes8x2(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(lo, vec![1, 3, 5]);
+        assert_eq!(hi, vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn raw8_and_planes_to_u16() {
+        assert_eq!(PixelFormat::Raw8.to_u16(&[1, 255], 2, 1), vec![1, 255]);
+        assert_eq!(PixelFormat::Planes8x2.to_u16(&[7, 9, 8, 9], 2, 1), vec![7, 8]);
+        assert_eq!(PixelFormat::Raw10In16.to_u16(&[0xff, 0x03], 1, 1), vec![0x3ff]);
+    }
+
+    #[test]
+    fn name_round_trip() {
+        for format in PixelFormat::ALL {
+            assert_eq!(format.name().parse::<PixelFormat>(), Ok(format));
+        }
+        assert!("raw12".parse::<PixelFormat>().is_err());
+    }
+}
@@ -0,0 +1,328 @@
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// 変換ゲインを求める直線区間 (飽和レベルに対する割合)
+const PTC_FIT_MIN: f64 = 0.05;
+const PTC_FIT_MAX: f64 = 0.70;
+
+// 直線性誤差を評価する区間 (EMVA1288 と同じ 5%..95%)
+const LINEARITY_MIN: f64 = 0.05;
+const LINEARITY_MAX: f64 = 0.95;
+
+/// 露光 1 ステップ分の測定値
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PtcSample {
+    pub gain_db: f32,
+    pub exposure_us: f64,
+    /// 明画像ペアの平均 [DN]
+    pub mean: f64,
+    /// 明画像ペアの時間ノイズ分散 [DN^2]
+    pub variance: f64,
+    /// 暗画像ペアの平均 [DN]
+    pub dark_mean: f64,
+    /// 暗画像ペアの時間ノイズ分散 [DN^2]
+    pub dark_variance: f64,
+}
+
+impl PtcSample {
+    /// 暗レベルを差し引いた信号 [DN]
+    pub fn signal(&self) -> f64 {
+        self.mean - self.dark_mean
+    }
+
+    /// 暗ノイズを差し引いたショットノイズ分散 [DN^2]
+    pub fn shot_variance(&self) -> f64 {
+        self.variance - self.dark_variance
+    }
+}
+
+/// 1 ゲイン分の解析結果
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PtcResult {
+    pub gain_db: f32,
+    /// 変換ゲイン K [DN/e-]
+    pub conversion_gain: f64,
+    /// 暗時間ノイズ [DN]
+    pub dark_noise_dn: f64,
+    /// 暗時間ノイズ [e-]
+    pub dark_noise_e: f64,
+    /// 飽和信号 (分散が最大となる点) [DN]
+    pub saturation_dn: f64,
+    /// フルウェル推定値 [e-]
+    pub full_well_e: f64,
+    /// ダイナミックレンジ [dB]
+    pub dynamic_range_db: f64,
+    /// 直線性誤差の最小/最大 [%]
+    pub linearity_error_min: f64,
+    pub linearity_error_max: f64,
+}
+
+/// 同一条件の 2 フレームから平均と時間ノイズ分散を求める
+///
+/// 固定パターンノイズを除くため差分画像の分散の 1/2 を時間ノイズとする。
+pub fn pair_stats(a: &[u16], b: &[u16]) -> Result<(f64, f64)> {
+    if a.len() != b.len() || a.is_empty() {
+        return Err("frame pair size mismatch".into());
+    }
+    let n = a.len() as f64;
+    let mut sum = 0.0;
+    let mut diff_sum = 0.0;
+    let mut diff_sq = 0.0;
+    for (&pa, &pb) in a.iter().zip(b.iter()) {
+        sum += pa as f64 + pb as f64;
+        let d = pa as f64 - pb as f64;
+        diff_sum += d;
+        diff_sq += d * d;
+    }
+    let mean = sum / (2.0 * n);
+    let diff_mean = diff_sum / n;
+    let variance = (diff_sq / n - diff_mean * diff_mean) / 2.0;
+    Ok((mean, variance))
+}
+
+/// 最小二乗で y = a*x + b を求める
+fn fit_line(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let sx: f64 = points.iter().map(|p| p.0).sum();
+    let sy: f64 = points.iter().map(|p| p.1).sum();
+    let sxx: f64 = points.iter().map(|p| p.0 * p.0).sum();
+    let sxy: f64 = points.iter().map(|p| p.0 * p.1).sum();
+    let den = n * sxx - sx * sx;
+    if den.abs() < f64::EPSILON {
+        return None;
+    }
+    let a = (n * sxy - sx * sy) / den;
+    let b = (sy - a * sx) / n;
+    Some((a, b))
+}
+
+/// 同一ゲインの露光スイープを解析する (サンプルは露光順)
+pub fn analyze(samples: &[PtcSample]) -> Result<PtcResult> {
+    if samples.len() < 3 {
+        return Err("too few samples for photon transfer analysis".into());
+    }
+
+    // 飽和点: 時間ノイズ分散が最大となる点
+    let sat = samples.iter()
+        .max_by(|a, b| a.variance.total_cmp(&b.variance))
+        .unwrap();
+    let saturation_dn = sat.signal();
+    if saturation_dn <= 0.0 {
+        return Err("no signal above dark level".into());
+    }
+
+    // 変換ゲイン: 飽和前の直線区間でのショットノイズ分散の傾き
+    let ptc: Vec<(f64, f64)> = samples.iter()
+        .filter(|s| s.exposure_us <= sat.exposure_us)
+        .filter(|s| s.signal() >= saturation_dn * PTC_FIT_MIN && s.signal() <= saturation_dn * PTC_FIT_MAX)
+        .map(|s| (s.signal(), s.shot_variance()))
+        .collect();
+    let (conversion_gain, _) = fit_line(&ptc).ok_or("too few samples in linear range")?;
+    if conversion_gain <= 0.0 {
+        return Err(format!("invalid conversion gain: {}", conversion_gain).into());
+    }
+
+    // 暗ノイズは最短露光の暗画像から
+    let dark_noise_dn = samples[0].dark_variance.max(0.0).sqrt();
+    let dark_noise_e = dark_noise_dn / conversion_gain;
+    let full_well_e = saturation_dn / conversion_gain;
+    let dynamic_range_db = if dark_noise_e > 0.0 {
+        20.0 * (full_well_e / dark_noise_e).log10()
+    }
+    else {
+        f64::INFINITY
+    };
+
+    // 直線性: 露光時間に対する信号の直線からのずれ
+    let lin: Vec<(f64, f64)> = samples.iter()
+        .filter(|s| s.signal() >= saturation_dn * LINEARITY_MIN && s.signal() <= saturation_dn * LINEARITY_MAX)
+        .map(|s| (s.exposure_us, s.signal()))
+        .collect();
+    let (a, b) = fit_line(&lin).ok_or("too few samples for linearity")?;
+    let mut linearity_error_min = 0.0f64;
+    let mut linearity_error_max = 0.0f64;
+    for &(x, y) in &lin {
+        let e = 100.0 * (y - (a * x + b)) / saturation_dn;
+        linearity_error_min = linearity_error_min.min(e);
+        linearity_error_max = linearity_error_max.max(e);
+    }
+
+    Ok(PtcResult {
+        gain_db: samples[0].gain_db,
+        conversion_gain,
+        dark_noise_dn,
+        dark_noise_e,
+        saturation_dn,
+        full_well_e,
+        dynamic_range_db,
+        linearity_error_min,
+        linearity_error_max,
+    })
+}
+
+/// 測定値を CSV で保存
+pub fn write_samples_csv(path: impl AsRef<Path>, samples: &[PtcSample]) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "gain_db,exposure_us,mean,variance,dark_mean,dark_variance,signal,shot_variance")?;
+    for s in samples {
+        writeln!(w, "{},{},{},{},{},{},{},{}",
+                s.gain_db, s.exposure_us, s.mean, s.variance,
+                s.dark_mean, s.dark_variance, s.signal(), s.shot_variance())?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// 解析結果を CSV で保存
+pub fn write_results_csv(path: impl AsRef<Path>, results: &[PtcResult]) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "gain_db,conversion_gain_dn_per_e,dark_noise_dn,dark_noise_e,saturation_dn,full_well_e,dynamic_range_db,linearity_error_min,linearity_error_max")?;
+    for r in results {
+        writeln!(w, "{},{},{},{},{},{},{},{},{}",
+                r.gain_db, r.conversion_gain, r.dark_noise_dn, r.dark_noise_e,
+                r.saturation_dn, r.full_well_e, r.dynamic_range_db,
+                r.linearity_error_min, r.linearity_error_max)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 再現性のある正規乱数 (xorshift + Box-Muller)
+    struct Noise(u64);
+
+    impl Noise {
+        fn uniform(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            ((self.0 >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+        }
+
+        fn normal(&mut self) -> f64 {
+            let (u1, u2) = (self.uniform(), self.uniform());
+            (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+        }
+    }
+
+    const K: f64 = 0.25;        // 変換ゲイン [DN/e-]
+    const OFFSET: f64 = 50.0;   // 暗レベル [DN]
+    const READ_NOISE: f64 = 2.0; // 読み出しノイズ [DN]
+
+    // 平均 electrons [e-] のショットノイズ (正規近似) と読み出しノイズを乗せた 10bit 画像
+    fn shot_frame(noise: &mut Noise, electrons: f64, len: usize) -> Vec<u16> {
+        (0..len)
+            .map(|_| {
+                let e = electrons + electrons.sqrt() * noise.normal();
+                let dn = OFFSET + K * e + READ_NOISE * noise.normal();
+                dn.round().clamp(0.0, 1023.0) as u16
+            })
+            .collect()
+    }
+
+    fn near(a: f64, b: f64, tol: f64) -> bool {
+        (a - b).abs() <= tol
+    }
+
+    #[test]
+    fn pair_stats_rejects_mismatch() {
+        assert!(pair_stats(&[1, 2], &[1]).is_err());
+        assert!(pair_stats(&[], &[]).is_err());
+    }
+
+    #[test]
+    fn pair_stats_removes_fixed_pattern() {
+        // 両フレームに共通のパターンは分散に入らない
+        let a: Vec<u16> = (0..64).map(|i| (i * 13 % 200) as u16).collect();
+        let (mean, variance) = pair_stats(&a, &a).unwrap();
+        let expect = a.iter().map(|&v| v as f64).sum::<f64>() / a.len() as f64;
+        assert!(near(mean, expect, 1e-9));
+        assert_eq!(variance, 0.0);
+
+        // 差分が平均のまわりで ±2 ずつ振れるなら分散は 4/2
+        let b: Vec<u16> = a.iter().enumerate().map(|(i, &v)| if i % 2 == 0 { v + 2 } else { v + 6 }).collect();
+        let (_, variance) = pair_stats(&a, &b).unwrap();
+        assert!(near(variance, 2.0, 1e-9));
+    }
+
+    #[test]
+    fn pair_stats_shot_noise() {
+        let mut noise = Noise(0x1234_5678);
+        let electrons = 2000.0;
+        let a = shot_frame(&mut noise, electrons, 128 * 128);
+        let b = shot_frame(&mut noise, electrons, 128 * 128);
+        let (mean, variance) = pair_stats(&a, &b).unwrap();
+        // 分散 = K^2 * e + 読み出しノイズ^2 + 量子化
+        let expect = K * K * electrons + READ_NOISE * READ_NOISE + 1.0 / 12.0;
+        assert!(near(mean, OFFSET + K * electrons, 0.5), "mean {}", mean);
+        assert!(near(variance, expect, expect * 0.05), "variance {} expect {}", variance, expect);
+    }
+
+    #[test]
+    fn fit_line_exact() {
+        let (a, b) = fit_line(&[(0.0, 1.0), (1.0, 3.0), (2.0, 5.0), (3.0, 7.0)]).unwrap();
+        assert!(near(a, 2.0, 1e-12));
+        assert!(near(b, 1.0, 1e-12));
+    }
+
+    #[test]
+    fn fit_line_degenerate() {
+        assert_eq!(fit_line(&[]), None);
+        assert_eq!(fit_line(&[(1.0, 2.0)]), None);
+        // x が全て同じ
+        assert_eq!(fit_line(&[(1.0, 2.0), (1.0, 3.0), (1.0, 4.0)]), None);
+    }
+
+    #[test]
+    fn analyze_rejects_too_few_samples() {
+        assert!(analyze(&[PtcSample::default(); 2]).is_err());
+        // 信号が暗レベル以下
+        assert!(analyze(&[PtcSample::default(); 4]).is_err());
+    }
+
+    #[test]
+    fn analyze_shot_noise_sweep() {
+        let mut noise = Noise(0x9e37_79b9_7f4a_7c15);
+        let len = 96 * 96;
+        let rate = 2.0; // [e-/us]
+
+        // 露光スイープ (後半は 1023 で飽和する)
+        let samples: Vec<PtcSample> = (1..=24)
+            .map(|i| {
+                let exposure_us = i as f64 * 100.0;
+                let dark = (shot_frame(&mut noise, 0.0, len), shot_frame(&mut noise, 0.0, len));
+                let light = (shot_frame(&mut noise, rate * exposure_us, len), shot_frame(&mut noise, rate * exposure_us, len));
+                let (dark_mean, dark_variance) = pair_stats(&dark.0, &dark.1).unwrap();
+                let (mean, variance) = pair_stats(&light.0, &light.1).unwrap();
+                PtcSample { gain_db: 6.0, exposure_us, mean, variance, dark_mean, dark_variance }
+            })
+            .collect();
+
+        let r = analyze(&samples).unwrap();
+        assert_eq!(r.gain_db, 6.0);
+        assert!(near(r.conversion_gain, K, K * 0.05), "K {}", r.conversion_gain);
+        assert!(near(r.dark_noise_dn, READ_NOISE, 0.1), "dark noise {}", r.dark_noise_dn);
+        assert!(near(r.dark_noise_e, r.dark_noise_dn / r.conversion_gain, 1e-9));
+
+        // 飽和は 1023 - 暗レベルの手前
+        assert!(r.saturation_dn > 850.0 && r.saturation_dn < 1023.0 - OFFSET, "saturation {}", r.saturation_dn);
+        assert!(near(r.full_well_e, r.saturation_dn / r.conversion_gain, 1e-6));
+        assert!(near(r.dynamic_range_db, 20.0 * (r.full_well_e / r.dark_noise_e).log10(), 1e-9));
+
+        // 直線区間の誤差は小さい
+        assert!(r.linearity_error_min <= 0.0 && r.linearity_error_min > -1.0, "linearity {}", r.linearity_error_min);
+        assert!(r.linearity_error_max >= 0.0 && r.linearity_error_max < 1.0, "linearity {}", r.linearity_error_max);
+    }
+}
@@ -0,0 +1,290 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+
+use jelly_lib::i2c_hal::I2cHal;
+
+use crate::camera_driver::{frame_count_add, frame_count_diff, CameraDriver, ROI_STEP_X, ROI_STEP_Y};
+use crate::frame::Frame;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+// 保持する ROI 変更履歴の数
+const ROI_HISTORY_LEN: usize = 64;
+
+/// センサー上の ROI の配置 (センサー座標)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RoiPlacement {
+    pub x: usize,
+    pub y: usize,
+    /// センサー上の ROI サイズ
+    pub width: usize,
+    pub height: usize,
+    /// センサー上の ROI と出力画像の倍率 (間引き/ビニング時は 2)
+    pub factor: usize,
+}
+
+impl RoiPlacement {
+    /// 画像座標をセンサー座標に変換
+    pub fn to_sensor(&self, x: f64, y: f64) -> (f64, f64) {
+        let f = self.factor.max(1) as f64;
+        (self.x as f64 + x * f, self.y as f64 + y * f)
+    }
+
+    /// センサー座標を画像座標に変換
+    pub fn from_sensor(&self, x: f64, y: f64) -> (f64, f64) {
+        let f = self.factor.max(1) as f64;
+        ((x - self.x as f64) / f, (y - self.y as f64) / f)
+    }
+
+    /// ROI 中心 (センサー座標)
+    pub fn center(&self) -> (f64, f64) {
+        (self.x as f64 + self.width as f64 / 2.0, self.y as f64 + self.height as f64 / 2.0)
+    }
+}
+
+/// しきい値を超えた画素の重心 (画像座標)
+///
+/// moment コアを使わない場合のソフトウェア版。
+pub fn centroid(frame: &Frame, threshold: u16) -> Option<(f64, f64)> {
+    centroid_with(frame.width(), frame.height(), threshold, |x, y| frame.pixel(x, y))
+}
+
+/// 16bit 画素列 (欠陥画素補正後の画像など) の重心 (画像座標)
+pub fn centroid_u16(pixels: &[u16], width: usize, height: usize, threshold: u16) -> Option<(f64, f64)> {
+    if pixels.len() < width * height {
+        return None;
+    }
+    centroid_with(width, height, threshold, |x, y| pixels[y * width + x])
+}
+
+fn centroid_with<F: Fn(usize, usize) -> u16>(width: usize, height: usize, threshold: u16, pixel: F) -> Option<(f64, f64)> {
+    let mut m00 = 0.0;
+    let mut m10 = 0.0;
+    let mut m01 = 0.0;
+    for y in 0..height {
+        for x in 0..width {
+            let v = pixel(x, y);
+            if v > threshold {
+                let w = (v - threshold) as f64;
+                m00 += w;
+                m10 += w * x as f64;
+                m01 += w * y as f64;
+            }
+        }
+    }
+    if m00 > 0.0 { Some((m10 / m00, m01 / m00)) } else { None }
+}
+
+/// 目標を追って ROI を動かすトラッカ
+///
+/// 出力画像サイズは変えずにセンサー ROI の位置だけを動かす。ROI の変更は
+/// 数フレーム遅れて画像に反映されるので、フレームカウンタと ROI 位置の履歴を持ち、
+/// `placement_at` で各フレームが撮られた時の ROI を引けるようにしている。
+#[derive(Debug, Clone)]
+pub struct RoiTracker {
+    /// 追従ゲイン (1.0 で 1 回の更新で目標を ROI 中心へ移す)
+    pub gain: f64,
+    /// 目標と ROI 中心の差がこれ以下 (センサー画素) なら動かさない
+    /// (ROI 位置の単位の半分以上にしないと往復して落ち着かない)
+    pub deadband_x: f64,
+    pub deadband_y: f64,
+    /// ROI の変更が画像に反映されるまでのフレーム数
+    pub latency_frames: usize,
+    history: VecDeque<(usize, RoiPlacement)>,
+}
+
+impl RoiTracker {
+    /// 現在の ROI から追従を開始
+    pub fn new<I2C, U>(cam: &CameraDriver<I2C, U>) -> Self
+    where
+        I2C: I2cHal,
+        <I2C as I2cHal>::Error: std::error::Error + 'static,
+        U: Copy + Clone,
+    {
+        let mut history = VecDeque::new();
+        history.push_back((cam.frame_count(), cam.roi_placement()));
+        Self {
+            gain: 0.5,
+            deadband_x: (ROI_STEP_X / 2) as f64,
+            deadband_y: ROI_STEP_Y as f64,
+            latency_frames: 2,
+            history,
+        }
+    }
+
+    /// `frame_count` の時点で撮られたフレームの ROI
+    ///
+    /// `frame_count` はフレームを書き込んでいた時のフレームカウンタ値
+    /// (`CaptureDriver::stream_frame_count` など)。
+    pub fn placement_at(&self, frame_count: usize) -> RoiPlacement {
+        self.history.iter().rev()
+            .find(|(start, _)| (frame_count_diff(frame_count, *start) as u32 as i32) >= 0)
+            .or(self.history.front())
+            .map(|&(_, p)| p)
+            .unwrap_or_default()
+    }
+
+    /// 最後に設定した ROI
+    pub fn placement(&self) -> RoiPlacement {
+        self.history.back().map(|&(_, p)| p).unwrap_or_default()
+    }
+
+    /// センサー座標の目標位置で ROI を更新 (None なら動かさない)
+    pub fn update<I2C, U>(&mut self, cam: &mut CameraDriver<I2C, U>, target: Option<(f64, f64)>) -> Result<RoiPlacement>
+    where
+        I2C: I2cHal,
+        <I2C as I2cHal>::Error: std::error::Error + 'static,
+        U: Copy + Clone,
+    {
+        let current = self.placement();
+        let (tx, ty) = match target {
+            Some(t) => t,
+            None => return Ok(current),
+        };
+        let (cx, cy) = current.center();
+        let x = Self::step_axis(current.x, tx - cx, self.gain, self.deadband_x, ROI_STEP_X);
+        let y = Self::step_axis(current.y, ty - cy, self.gain, self.deadband_y, ROI_STEP_Y);
+        if x == current.x && y == current.y {
+            return Ok(current);
+        }
+
+        cam.set_roi_offset(Some(x), Some(y))?;
+        let placement = cam.roi_placement();
+        if placement != current {
+            if self.history.len() >= ROI_HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back((frame_count_add(cam.frame_count(), self.latency_frames), placement));
+        }
+        Ok(placement)
+    }
+
+    // 1 軸分の移動先 (ROI 位置の単位に丸める)
+    //
+    // 不感帯を超えていれば、ゲインを掛けた移動量が単位未満でも 1 単位は動かす。
+    fn step_axis(pos: usize, err: f64, gain: f64, deadband: f64, step: usize) -> usize {
+        if err.abs() <= deadband {
+            return pos;
+        }
+        let steps = ((err * gain) / step as f64).round();
+        let steps = if steps == 0.0 { err.signum() } else { steps };
+        (pos as f64 + steps * step as f64).max(0.0) as usize
+    }
+
+    /// 画像座標の目標位置で ROI を更新
+    ///
+    /// `frame_count` は目標を求めた画像のフレームカウンタ値で、
+    /// その時の ROI でセンサー座標に変換する (moment コアの結果など)。
+    pub fn update_from_image<I2C, U>(&mut self, cam: &mut CameraDriver<I2C, U>, frame_count: usize, target: Option<(f64, f64)>) -> Result<RoiPlacement>
+    where
+        I2C: I2cHal,
+        <I2C as I2cHal>::Error: std::error::Error + 'static,
+        U: Copy + Clone,
+    {
+        let roi = self.placement_at(frame_count);
+        self.update(cam, target.map(|(x, y)| roi.to_sensor(x, y)))
+    }
+
+    /// コールバックで目標を求めて ROI を更新
+    ///
+    /// コールバックには画像を撮った時の ROI が渡され、センサー座標の目標を返す。
+    pub fn update_with<I2C, U, F>(&mut self, cam: &mut CameraDriver<I2C, U>, frame_count: usize, f: F) -> Result<RoiPlacement>
+    where
+        I2C: I2cHal,
+        <I2C as I2cHal>::Error: std::error::Error + 'static,
+        U: Copy + Clone,
+        F: FnOnce(&RoiPlacement) -> Option<(f64, f64)>,
+    {
+        let roi = self.placement_at(frame_count);
+        let target = f(&roi);
+        self.update(cam, target)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placement(x: usize, y: usize) -> RoiPlacement {
+        RoiPlacement { x, y, width: 256, height: 128, factor: 1 }
+    }
+
+    fn tracker(history: &[(usize, RoiPlacement)]) -> RoiTracker {
+        RoiTracker {
+            gain: 0.5,
+            deadband_x: (ROI_STEP_X / 2) as f64,
+            deadband_y: ROI_STEP_Y as f64,
+            latency_frames: 2,
+            history: history.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn step_axis_deadband() {
+        assert_eq!(RoiTracker::step_axis(160, 8.0, 0.5, 8.0, 16), 160);
+        assert_eq!(RoiTracker::step_axis(160, -8.0, 0.5, 8.0, 16), 160);
+        // 不感帯を超えたら移動量が単位未満でも 1 単位動かす
+        assert_eq!(RoiTracker::step_axis(160, 9.0, 0.5, 8.0, 16), 176);
+        assert_eq!(RoiTracker::step_axis(160, -9.0, 0.5, 8.0, 16), 144);
+    }
+
+    #[test]
+    fn step_axis_rounds_to_step() {
+        // 移動量 (err * gain) を単位に丸める
+        assert_eq!(RoiTracker::step_axis(160, 24.0, 0.5, 8.0, 16), 176);  // 0.75
+        assert_eq!(RoiTracker::step_axis(160, 40.0, 0.5, 8.0, 16), 176);  // 1.25
+        assert_eq!(RoiTracker::step_axis(160, 56.0, 0.5, 8.0, 16), 192);  // 1.75
+        assert_eq!(RoiTracker::step_axis(160, -56.0, 1.0, 8.0, 16), 96);  // -3.5
+        assert_eq!(RoiTracker::step_axis(10, 3.0, 0.5, 2.0, 2), 12);
+        assert_eq!(RoiTracker::step_axis(10, 9.0, 0.5, 2.0, 2), 14);      // 2.25
+    }
+
+    #[test]
+    fn step_axis_clamps_at_zero() {
+        assert_eq!(RoiTracker::step_axis(16, -100.0, 0.5, 8.0, 16), 0);
+        assert_eq!(RoiTracker::step_axis(0, -9.0, 0.5, 8.0, 16), 0);
+    }
+
+    #[test]
+    fn placement_at_history() {
+        let t = tracker(&[(100, placement(0, 0)), (110, placement(16, 0))]);
+        assert_eq!(t.placement_at(105), placement(0, 0));
+        assert_eq!(t.placement_at(110), placement(16, 0));
+        assert_eq!(t.placement_at(200), placement(16, 0));
+        // 履歴より前は最初の ROI
+        assert_eq!(t.placement_at(90), placement(0, 0));
+        assert_eq!(t.placement(), placement(16, 0));
+    }
+
+    #[test]
+    fn placement_at_counter_wraps() {
+        let t = tracker(&[(0xffff_fffe, placement(0, 0)), (2, placement(0, 4))]);
+        assert_eq!(t.placement_at(0), placement(0, 0));
+        assert_eq!(t.placement_at(3), placement(0, 4));
+    }
+
+    #[test]
+    fn placement_coordinates() {
+        let p = RoiPlacement { x: 32, y: 10, width: 512, height: 256, factor: 2 };
+        assert_eq!(p.to_sensor(4.0, 3.0), (40.0, 16.0));
+        assert_eq!(p.from_sensor(40.0, 16.0), (4.0, 3.0));
+        assert_eq!(p.center(), (288.0, 138.0));
+    }
+
+    #[test]
+    fn centroid_weights_above_threshold() {
+        let pixels = [
+            10, 10, 10, 10,
+            10, 30, 10, 10,
+            10, 10, 10, 50,
+        ];
+        // 重み 20 と 40
+        let (x, y) = centroid_u16(&pixels, 4, 3, 10).unwrap();
+        assert!((x - (20.0 * 1.0 + 40.0 * 3.0) / 60.0).abs() < 1e-12);
+        assert!((y - (20.0 * 1.0 + 40.0 * 2.0) / 60.0).abs() < 1e-12);
+        assert_eq!(centroid_u16(&pixels, 4, 3, 50), None);
+        assert_eq!(centroid_u16(&pixels[..11], 4, 3, 10), None);
+    }
+}
@@ -0,0 +1,238 @@
+#![allow(dead_code)]
+
+use std::time::{Duration, Instant};
+
+/// 復旧処理 (軽いものから順に試す)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RecoveryAction {
+    /// センサー側レシーバーの再アライメント
+    Realign,
+    /// fmtr とシーケンサの再起動
+    RestartSequencer,
+    /// D-PHY の TX/RX リセットからやり直す
+    DphyReset,
+    /// センサー電源を入れ直して open からやり直す
+    PowerCycle,
+}
+
+impl RecoveryAction {
+    pub const LADDER: [RecoveryAction; 4] = [
+        RecoveryAction::Realign,
+        RecoveryAction::RestartSequencer,
+        RecoveryAction::DphyReset,
+        RecoveryAction::PowerCycle,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecoveryAction::Realign          => "realign",
+            RecoveryAction::RestartSequencer => "restart_sequencer",
+            RecoveryAction::DphyReset        => "dphy_reset",
+            RecoveryAction::PowerCycle       => "power_cycle",
+        }
+    }
+}
+
+/// ウォッチドッグの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchdogConfig {
+    /// フレームカウンタがこの時間進まなければ停止とみなす
+    /// (トリガモードではトリガ間隔より長くすること)
+    pub stall_timeout: Duration,
+    /// 計測周期と設定周期のずれの許容率 (None なら検査しない)
+    pub period_tolerance: Option<f32>,
+    /// 周期ずれが何回続いたら異常とみなすか
+    pub period_error_count: usize,
+    /// fmtr の CTL_STATUS を検査するか
+    pub check_fmtr_status: bool,
+    /// ここまでの復旧処理を試す
+    pub max_action: RecoveryAction,
+    /// ラダーを一巡しても復旧しない場合に繰り返す回数
+    pub max_rounds: usize,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            stall_timeout: Duration::from_millis(1000),
+            period_tolerance: None,
+            period_error_count: 10,
+            check_fmtr_status: true,
+            max_action: RecoveryAction::PowerCycle,
+            max_rounds: 1,
+        }
+    }
+}
+
+/// 停止の原因
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StallReason {
+    /// フレームカウンタが進まない
+    FrameCountStalled,
+    /// fmtr が停止している
+    FormatRegularizerStopped,
+    /// 計測したフレーム周期が設定値から外れている [us]
+    PeriodMismatch { measured_us: f32, expected_us: f32 },
+}
+
+/// 1 回の復旧処理の記録
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveryEvent {
+    /// ウォッチドッグ開始からの時間
+    pub time: Duration,
+    pub reason: StallReason,
+    pub action: RecoveryAction,
+    /// 今回の停止に対する何回目の試行か (1 から)
+    pub attempt: usize,
+    /// 最後にフレームが進んでからの時間
+    pub stalled_for: Duration,
+    pub frame_count: usize,
+    /// 復旧処理自体が失敗した場合のエラー
+    pub error: Option<String>,
+}
+
+impl core::fmt::Display for RecoveryEvent {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "[{:.3}s] stream stalled ({:?}, {:.3}s, frame_count={}) -> #{} {}",
+               self.time.as_secs_f64(), self.reason, self.stalled_for.as_secs_f64(),
+               self.frame_count, self.attempt, self.action.name())?;
+        match &self.error {
+            Some(e) => write!(f, " failed: {}", e),
+            None => write!(f, " done"),
+        }
+    }
+}
+
+/// `CameraDriver::poll_watchdog` の結果
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchdogStatus {
+    /// ウォッチドッグ無効かカメラ停止中
+    Disabled,
+    /// 正常 (停止していてもタイムアウト前)
+    Running,
+    /// 復旧処理を実行した
+    Recovery(RecoveryEvent),
+    /// 復旧処理の後でフレームが再開した
+    Recovered(RecoveryAction),
+}
+
+/// ウォッチドッグの内部状態 (`CameraDriver` が保持する)
+#[derive(Debug, Clone)]
+pub(crate) struct Watchdog {
+    pub(crate) config: WatchdogConfig,
+    start: Instant,
+    last_count: Option<usize>,
+    last_progress: Instant,
+    period_errors: usize,
+    attempt: usize,
+    last_action: Option<RecoveryAction>,
+    log: Vec<RecoveryEvent>,
+}
+
+impl Watchdog {
+    pub(crate) fn new(config: WatchdogConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            start: now,
+            last_count: None,
+            last_progress: now,
+            period_errors: 0,
+            attempt: 0,
+            last_action: None,
+            log: Vec::new(),
+        }
+    }
+
+    /// open / 復旧処理の後に監視をやり直す
+    pub(crate) fn rearm(&mut self) {
+        self.last_count = None;
+        self.last_progress = Instant::now();
+        self.period_errors = 0;
+    }
+
+    pub(crate) fn log(&self) -> &[RecoveryEvent] {
+        &self.log
+    }
+
+    pub(crate) fn clear_log(&mut self) {
+        self.log.clear();
+    }
+
+    /// フレームカウンタを反映し、進んでいれば true
+    pub(crate) fn update_count(&mut self, frame_count: usize) -> bool {
+        let progressed = self.last_count.is_some_and(|c| c != frame_count);
+        if self.last_count.is_none() || progressed {
+            self.last_count = Some(frame_count);
+            self.last_progress = Instant::now();
+        }
+        progressed
+    }
+
+    /// 周期ずれの判定 (連続回数が閾値を超えたら true)
+    pub(crate) fn check_period(&mut self, measured_us: f32, expected_us: f32) -> bool {
+        let tolerance = match self.config.period_tolerance {
+            Some(t) if expected_us > 0.0 => t,
+            _ => return false,
+        };
+        if ((measured_us - expected_us) / expected_us).abs() > tolerance {
+            self.period_errors += 1;
+        } else {
+            self.period_errors = 0;
+        }
+        self.period_errors >= self.config.period_error_count
+    }
+
+    pub(crate) fn period_ok(&self) -> bool {
+        self.period_errors == 0
+    }
+
+    /// 復旧処理中 (フレームが再開していない) か
+    pub(crate) fn recovering(&self) -> bool {
+        self.attempt > 0
+    }
+
+    pub(crate) fn attempts(&self) -> usize {
+        self.attempt
+    }
+
+    pub(crate) fn stalled_for(&self) -> Duration {
+        self.last_progress.elapsed()
+    }
+
+    /// フレームが再開したら直前の復旧処理を返してラダーを戻す
+    pub(crate) fn take_recovered(&mut self) -> Option<RecoveryAction> {
+        self.attempt = 0;
+        self.last_action.take()
+    }
+
+    /// 次に試す復旧処理 (ラダーを使い切ったら None)
+    pub(crate) fn next_action(&self) -> Option<RecoveryAction> {
+        let ladder: Vec<RecoveryAction> = RecoveryAction::LADDER.iter()
+            .copied()
+            .filter(|&a| a <= self.config.max_action)
+            .collect();
+        if self.attempt >= ladder.len() * self.config.max_rounds.max(1) {
+            return None;
+        }
+        Some(ladder[self.attempt % ladder.len()])
+    }
+
+    /// 復旧処理の結果を記録
+    pub(crate) fn record(&mut self, reason: StallReason, action: RecoveryAction, frame_count: usize, error: Option<String>) -> RecoveryEvent {
+        self.attempt += 1;
+        self.last_action = Some(action);
+        let event = RecoveryEvent {
+            time: self.start.elapsed(),
+            reason,
+            action,
+            attempt: self.attempt,
+            stalled_for: self.stalled_for(),
+            frame_count,
+            error,
+        };
+        self.log.push(event.clone());
+        self.rearm();
+        event
+    }
+}
@@ -10,13 +10,38 @@ const TIMGENREG_PARAM_TRIG0_START: usize = 0x0020;
 const TIMGENREG_PARAM_TRIG0_END: usize = 0x0021;
 const TIMGENREG_PARAM_TRIG0_POL: usize = 0x0022;
 
+// トリガ n のレジスタは TRIG0 から 4 ワード単位で並ぶ
+const TIMGENREG_PARAM_TRIG_STEP: usize = 0x0004;
+
+// 現在のゲートウェアは TRIG0 のみ実装
+pub const TIMGEN_TRIGGERS: usize = 1;
+
+const TIMGEN_CTL_ENABLE: usize = 0x01;
+const TIMGEN_CTL_UPDATE: usize = 0x02;
+
+const TIMGEN_DEFAULT_CLOCK_HZ: f64 = 100_000_000.0;
+const TIMGEN_TIMER_MAX: u64 = 0xffff_ffff;
+
 use jelly_mem_access::*;
+use serde::{Deserialize, Serialize};
 
 type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
 
+/// トリガ出力の極性
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerPolarity {
+    /// 露光期間中 High
+    #[default]
+    ActiveHigh,
+    /// 露光期間中 Low (反転)
+    ActiveLow,
+}
+
 pub struct TimingGeneratorDriver<T: MemAccess>
 {
     reg_timgen: T,
+    clock_hz: f64,
 }
 
 impl<T: MemAccess> TimingGeneratorDriver<T>
@@ -24,27 +49,247 @@ impl<T: MemAccess> TimingGeneratorDriver<T>
     pub fn new(reg_timgen: T) -> Self {
         Self {
             reg_timgen: reg_timgen,
+            clock_hz: TIMGEN_DEFAULT_CLOCK_HZ,
         }
     }
 
+    /// タイミングジェネレータの動作クロック設定 (既定 100MHz)
+    pub fn set_clock_hz(&mut self, clock_hz: f64) -> Result<()> {
+        if !(clock_hz.is_finite() && clock_hz > 0.0) {
+            return Err(format!("invalid timing generator clock: {} Hz", clock_hz).into());
+        }
+        self.clock_hz = clock_hz;
+        Ok(())
+    }
+
+    pub fn clock_hz(&self) -> f64 {
+        self.clock_hz
+    }
+
+    pub fn num_triggers(&self) -> usize {
+        TIMGEN_TRIGGERS
+    }
+
+    pub fn core_id(&self) -> usize {
+        unsafe { self.reg_timgen.read_reg(TIMGENREG_CORE_ID) }
+    }
+
+    pub fn core_version(&self) -> usize {
+        unsafe { self.reg_timgen.read_reg(TIMGENREG_CORE_VERSION) }
+    }
+
+    /// 周期 period_us で exposure_us の間トリガ0 を出力して動作開始 (露光は周期に収まるよう丸める)
     pub fn set_timing(&mut self, period_us: f32, exposure_us: f32) -> Result<()> {
-        let period_us   = period_us.max(1000.0);
-        let exposure_us = exposure_us.clamp(100.0, period_us-100.0);
+        let period_us   = (period_us as f64).max(1000.0);
+        let (offset_us, _) = self.trigger(0)?;
+        let exposure_us = (exposure_us as f64).clamp(100.0, (period_us - offset_us - 100.0).max(100.0));
+        self.update_timing(period_us, exposure_us)?;
+        self.start();
+        Ok(())
+    }
 
-        let us_unit = 0.01; // 100MHz(10ns)
-        let timgen_period : usize = (period_us / us_unit) as usize;
-        let trig0_start   : usize = 1;
-        let trig0_end     : usize = ((exposure_us / us_unit) as usize).max(1);
+    /// 周期とトリガ0 を次の周期境界で切り替える (動作状態は変えない)
+    pub fn update_timing(&mut self, period_us: f64, exposure_us: f64) -> Result<()> {
+        // 現在のトリガ開始オフセットは維持する
+        let (offset_us, _) = self.trigger(0)?;
+        self.update_period_and_trigger(period_us, 0, offset_us, exposure_us)
+    }
+
+    /// 周期とトリガを同時に設定する (設定の順序による範囲外エラーを避ける)
+    pub fn update_period_and_trigger(&mut self, period_us: f64, index: usize, offset_us: f64, width_us: f64) -> Result<()> {
+        Self::check_trigger_index(index)?;
+        let period = self.period_ticks(period_us)?;
+        let start  = self.us_to_ticks(offset_us)?;
+        let end    = start + self.width_ticks(index, width_us)?;
+        if end > period - 1 {
+            return Err(format!("trigger{} ({} us + {} us) exceeds period {} us",
+                                index, offset_us, width_us, period_us).into());
+        }
+
+        // 周期とトリガは同じ周期境界で反映させる
         unsafe {
-            self.reg_timgen.write_reg(TIMGENREG_PARAM_PERIOD,      timgen_period);
-            self.reg_timgen.write_reg(TIMGENREG_PARAM_TRIG0_START, trig0_start);
-            self.reg_timgen.write_reg(TIMGENREG_PARAM_TRIG0_END,   trig0_end);
-            self.reg_timgen.write_reg(TIMGENREG_CTL_CONTROL,       0x03);
+            self.reg_timgen.write_reg(TIMGENREG_PARAM_PERIOD, (period - 1) as usize);
+            self.reg_timgen.write_reg(Self::trig_reg(index, TIMGENREG_PARAM_TRIG0_START), start as usize);
+            self.reg_timgen.write_reg(Self::trig_reg(index, TIMGENREG_PARAM_TRIG0_END),   end   as usize);
+        }
+        self.update();
+        Ok(())
+    }
+
+    /// フレーム周期設定 (動作中は次の周期境界で反映)
+    pub fn set_period(&mut self, period_us: f64) -> Result<()> {
+        let ticks = self.period_ticks(period_us)?;
+
+        // 設定済みのトリガが周期に収まらなくなる場合はエラー
+        for index in 0..TIMGEN_TRIGGERS {
+            let end = unsafe { self.reg_timgen.read_reg(Self::trig_reg(index, TIMGENREG_PARAM_TRIG0_END)) } as u64;
+            if end > ticks - 1 {
+                return Err(format!("trigger{} does not fit in period {} us", index, period_us).into());
+            }
         }
 
+        unsafe {
+            // タイマは 0..=PERIOD を数えるので 1 少なく設定する
+            self.reg_timgen.write_reg(TIMGENREG_PARAM_PERIOD, (ticks - 1) as usize);
+        }
+        self.update();
         Ok(())
     }
 
-}
+    /// 周期先頭から offset_us 遅れて width_us の間トリガを出力する
+    pub fn set_trigger(&mut self, index: usize, offset_us: f64, width_us: f64) -> Result<()> {
+        Self::check_trigger_index(index)?;
+        let start  = self.us_to_ticks(offset_us)?;
+        let end    = start + self.width_ticks(index, width_us)?;
+        let period = unsafe { self.reg_timgen.read_reg(TIMGENREG_PARAM_PERIOD) } as u64;
+        if end > period {
+            return Err(format!("trigger{} ({} us + {} us) exceeds period {} us",
+                                index, offset_us, width_us, self.ticks_to_us(period + 1)).into());
+        }
+
+        unsafe {
+            self.reg_timgen.write_reg(Self::trig_reg(index, TIMGENREG_PARAM_TRIG0_START), start as usize);
+            self.reg_timgen.write_reg(Self::trig_reg(index, TIMGENREG_PARAM_TRIG0_END),   end   as usize);
+        }
+        self.update();
+        Ok(())
+    }
+
+    /// トリガ極性設定 (即時反映)
+    pub fn set_polarity(&mut self, index: usize, polarity: TriggerPolarity) -> Result<()> {
+        Self::check_trigger_index(index)?;
+        let pol = match polarity {
+            TriggerPolarity::ActiveHigh => 0,
+            TriggerPolarity::ActiveLow  => 1,
+        };
+        unsafe { self.reg_timgen.write_reg(Self::trig_reg(index, TIMGENREG_PARAM_TRIG0_POL), pol); }
+        Ok(())
+    }
+
+    /// 設定中の周期 [us]
+    pub fn period_us(&self) -> f64 {
+        let period = unsafe { self.reg_timgen.read_reg(TIMGENREG_PARAM_PERIOD) } as u64;
+        self.ticks_to_us(period + 1)
+    }
+
+    /// 設定中のトリガ (offset_us, width_us)
+    pub fn trigger(&self, index: usize) -> Result<(f64, f64)> {
+        Self::check_trigger_index(index)?;
+        let (start, end) = unsafe {
+            (self.reg_timgen.read_reg(Self::trig_reg(index, TIMGENREG_PARAM_TRIG0_START)) as u64,
+             self.reg_timgen.read_reg(Self::trig_reg(index, TIMGENREG_PARAM_TRIG0_END))   as u64)
+        };
+        Ok((self.ticks_to_us(start), self.ticks_to_us(end.saturating_sub(start))))
+    }
 
+    pub fn polarity(&self, index: usize) -> Result<TriggerPolarity> {
+        Self::check_trigger_index(index)?;
+        let pol = unsafe { self.reg_timgen.read_reg(Self::trig_reg(index, TIMGENREG_PARAM_TRIG0_POL)) };
+        Ok(if pol & 1 != 0 { TriggerPolarity::ActiveLow } else { TriggerPolarity::ActiveHigh })
+    }
+
+    /// 周期境界で未反映のパラメータがあるか
+    pub fn update_pending(&self) -> bool {
+        unsafe { self.reg_timgen.read_reg(TIMGENREG_CTL_CONTROL) & TIMGEN_CTL_UPDATE != 0 }
+    }
+
+    /// 動作開始 (パラメータも反映)
+    pub fn start(&mut self) {
+        unsafe { self.reg_timgen.write_reg(TIMGENREG_CTL_CONTROL, TIMGEN_CTL_ENABLE | TIMGEN_CTL_UPDATE); }
+    }
+
+    /// 動作停止 (現在の周期の終わりで停止する)
+    pub fn stop(&mut self) {
+        unsafe { self.reg_timgen.write_reg(TIMGENREG_CTL_CONTROL, 0); }
+    }
+
+    pub fn enabled(&self) -> bool {
+        unsafe { self.reg_timgen.read_reg(TIMGENREG_CTL_CONTROL) & TIMGEN_CTL_ENABLE != 0 }
+    }
+
+    pub fn running(&self) -> bool {
+        unsafe { self.reg_timgen.read_reg(TIMGENREG_CTL_STATUS) & 1 != 0 }
+    }
+
+    /// 停止完了待ち
+    pub fn wait_stop(&self, timeout: std::time::Duration) -> Result<()> {
+        let start = std::time::Instant::now();
+        while self.running() {
+            if start.elapsed() > timeout {
+                return Err("timing generator stop timeout".into());
+            }
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+        Ok(())
+    }
+
+    /// 書き込んだ設定が周期境界で取り込まれるのを待つ
+    pub fn wait_update(&self, timeout: std::time::Duration) -> Result<()> {
+        let start = std::time::Instant::now();
+        while self.update_pending() {
+            if start.elapsed() > timeout {
+                return Err("timing generator update timeout".into());
+            }
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+        Ok(())
+    }
+
+    /// フリーランタイマ値 (周期内の位置 [tick])
+    pub fn timer(&self) -> usize {
+        unsafe { self.reg_timgen.read_reg(TIMGENREG_CTL_TIMER) }
+    }
+
+    pub fn timer_us(&self) -> f64 {
+        self.ticks_to_us(self.timer() as u64)
+    }
 
+    fn update(&mut self) {
+        unsafe {
+            let enable = self.reg_timgen.read_reg(TIMGENREG_CTL_CONTROL) & TIMGEN_CTL_ENABLE;
+            self.reg_timgen.write_reg(TIMGENREG_CTL_CONTROL, enable | TIMGEN_CTL_UPDATE);
+        }
+    }
+
+    fn check_trigger_index(index: usize) -> Result<()> {
+        if index >= TIMGEN_TRIGGERS {
+            return Err(format!("trigger{} is not implemented ({} triggers)", index, TIMGEN_TRIGGERS).into());
+        }
+        Ok(())
+    }
+
+    fn trig_reg(index: usize, reg: usize) -> usize {
+        reg + index * TIMGENREG_PARAM_TRIG_STEP
+    }
+
+    fn period_ticks(&self, period_us: f64) -> Result<u64> {
+        let ticks = self.us_to_ticks(period_us)?;
+        if !(2..=TIMGEN_TIMER_MAX + 1).contains(&ticks) {
+            return Err(format!("period out of range: {} us", period_us).into());
+        }
+        Ok(ticks)
+    }
+
+    fn width_ticks(&self, index: usize, width_us: f64) -> Result<u64> {
+        let ticks = self.us_to_ticks(width_us)?;
+        if ticks < 1 {
+            return Err(format!("trigger{} width too short: {} us", index, width_us).into());
+        }
+        Ok(ticks)
+    }
+
+    fn us_to_ticks(&self, us: f64) -> Result<u64> {
+        if !(us.is_finite() && us >= 0.0) {
+            return Err(format!("invalid time: {} us", us).into());
+        }
+        let ticks = (us * self.clock_hz / 1_000_000.0).round();
+        if ticks > TIMGEN_TIMER_MAX as f64 {
+            return Err(format!("time out of range: {} us", us).into());
+        }
+        Ok(ticks as u64)
+    }
+
+    fn ticks_to_us(&self, ticks: u64) -> f64 {
+        ticks as f64 * 1_000_000.0 / self.clock_hz
+    }
+}
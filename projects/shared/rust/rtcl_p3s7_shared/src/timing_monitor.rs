@@ -0,0 +1,393 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::camera_driver::frame_count_diff;
+
+/// フレーム周期の統計 [us]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TimingStats {
+    pub count: usize,
+    pub mean_us: f64,
+    pub min_us: f64,
+    pub max_us: f64,
+    pub stddev_us: f64,
+    /// 設定周期 (未設定なら None)
+    pub expected_us: Option<f64>,
+    /// 平均と設定周期の差 (mean - expected)
+    pub error_us: Option<f64>,
+    /// 前回サンプルから飛んだフレーム数の合計 (サンプリングが間に合わなかった分)
+    pub skipped: usize,
+}
+
+impl TimingStats {
+    /// ピークツーピークのジッタ
+    pub fn jitter_pp_us(&self) -> f64 {
+        self.max_us - self.min_us
+    }
+
+    pub fn mean_fps(&self) -> f64 {
+        if self.mean_us > 0.0 { 1_000_000.0 / self.mean_us } else { 0.0 }
+    }
+
+    /// ログ用の CSV ヘッダ
+    pub fn csv_header() -> &'static str {
+        "count,mean_us,min_us,max_us,stddev_us,expected_us,error_us,skipped"
+    }
+
+    /// ログ用の CSV 1 行
+    pub fn to_csv(&self) -> String {
+        let opt = |v: Option<f64>| v.map(|v| format!("{:.4}", v)).unwrap_or_default();
+        format!("{},{:.4},{:.4},{:.4},{:.4},{},{},{}",
+                self.count, self.mean_us, self.min_us, self.max_us, self.stddev_us,
+                opt(self.expected_us), opt(self.error_us), self.skipped)
+    }
+}
+
+impl core::fmt::Display for TimingStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "n={} mean={:.3}us ({:.3} fps) min={:.3}us max={:.3}us sd={:.3}us p-p={:.3}us",
+               self.count, self.mean_us, self.mean_fps(), self.min_us, self.max_us, self.stddev_us, self.jitter_pp_us())?;
+        if let (Some(expected), Some(error)) = (self.expected_us, self.error_us) {
+            write!(f, " expected={:.3}us diff={:+.3}us", expected, error)?;
+        }
+        Ok(())
+    }
+}
+
+/// フレーム周期のヒストグラム
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TimingHistogram {
+    /// 先頭ビンの下端 [us]
+    pub start_us: f64,
+    /// ビン幅 [us]
+    pub bin_us: f64,
+    pub counts: Vec<usize>,
+    /// 範囲外 (下/上)
+    pub underflow: usize,
+    pub overflow: usize,
+}
+
+impl TimingHistogram {
+    /// ビン中心 [us]
+    pub fn bin_center(&self, index: usize) -> f64 {
+        self.start_us + (index as f64 + 0.5) * self.bin_us
+    }
+
+}
+
+// テキストのヒストグラム (1 ビン 1 行)
+impl core::fmt::Display for TimingHistogram {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let max = self.counts.iter().copied().max().unwrap_or(0).max(1);
+        if self.underflow > 0 {
+            writeln!(f, "  {:>12} : {}", "<", self.underflow)?;
+        }
+        for (i, &n) in self.counts.iter().enumerate() {
+            writeln!(f, "  {:12.3} : {:6} {}", self.bin_center(i), n, "#".repeat(n * 50 / max))?;
+        }
+        if self.overflow > 0 {
+            writeln!(f, "  {:>12} : {}", ">", self.overflow)?;
+        }
+        Ok(())
+    }
+}
+
+/// FPS カウンタとフレームカウンタのサンプリングによるタイミング監視
+///
+/// センサーの設定は変更しない。`CameraDriver::sample_timing` を
+/// フレーム周期より短い間隔で呼ぶと、フレーム毎の周期を取りこぼさずに集められる。
+#[derive(Debug, Clone)]
+pub struct TimingMonitor {
+    clock_hz: f64,
+    capacity: usize,
+    expected_us: Option<f64>,
+    periods: VecDeque<f64>,
+    last_count: Option<usize>,
+    skipped: usize,
+
+    // フレームカウンタから求める FPS の履歴
+    fps_window: Duration,
+    window_start: Option<(Instant, usize)>,
+    fps_history: VecDeque<f64>,
+}
+
+impl TimingMonitor {
+    /// `clock_hz` は FPS カウンタのクロック (`CameraDriver::fps_counter_clock_hz`)、
+    /// `capacity` は保持する周期サンプル数
+    pub fn new(clock_hz: f32, capacity: usize) -> Self {
+        Self {
+            clock_hz: clock_hz as f64,
+            capacity: capacity.max(1),
+            expected_us: None,
+            periods: VecDeque::new(),
+            last_count: None,
+            skipped: 0,
+            fps_window: Duration::from_secs(1),
+            window_start: None,
+            fps_history: VecDeque::new(),
+        }
+    }
+
+    /// 比較する設定周期 [us]
+    pub fn set_expected_period_us(&mut self, period_us: Option<f64>) {
+        self.expected_us = period_us;
+    }
+
+    pub fn expected_period_us(&self) -> Option<f64> {
+        self.expected_us
+    }
+
+    /// FPS 履歴の集計間隔
+    pub fn set_fps_window(&mut self, window: Duration) {
+        self.fps_window = window;
+    }
+
+    pub fn clear(&mut self) {
+        self.periods.clear();
+        self.last_count = None;
+        self.skipped = 0;
+        self.window_start = None;
+        self.fps_history.clear();
+    }
+
+    /// 1 回分のサンプルを追加
+    ///
+    /// `fps_count` は直前のフレームの周期 (カウンタクロック数)、`frame_count` は
+    /// その時点のフレームカウンタ。フレームカウンタが進んだ時だけ周期を記録する。
+    pub fn add_sample(&mut self, fps_count: usize, frame_count: usize) {
+        self.update_fps(frame_count);
+
+        let frames = match self.last_count {
+            Some(last) => frame_count_diff(frame_count, last),
+            None => {
+                self.last_count = Some(frame_count);
+                return;
+            }
+        };
+        if frames == 0 {
+            return;
+        }
+        self.last_count = Some(frame_count);
+        self.skipped += frames - 1;
+        if fps_count == 0 || self.clock_hz <= 0.0 {
+            return;
+        }
+
+        if self.periods.len() >= self.capacity {
+            self.periods.pop_front();
+        }
+        self.periods.push_back(fps_count as f64 * 1_000_000.0 / self.clock_hz);
+    }
+
+    // 一定時間毎にフレームカウンタの増分から FPS を求める
+    fn update_fps(&mut self, frame_count: usize) {
+        let now = Instant::now();
+        match self.window_start {
+            None => self.window_start = Some((now, frame_count)),
+            Some((start, count)) => {
+                let elapsed = now.duration_since(start);
+                if elapsed >= self.fps_window {
+                    let fps = frame_count_diff(frame_count, count) as f64 / elapsed.as_secs_f64();
+                    if self.fps_history.len() >= self.capacity {
+                        self.fps_history.pop_front();
+                    }
+                    self.fps_history.push_back(fps);
+                    self.window_start = Some((now, frame_count));
+                }
+            }
+        }
+    }
+
+    /// 記録済みのフレーム周期 [us] (古い順)
+    pub fn periods(&self) -> impl Iterator<Item = f64> + '_ {
+        self.periods.iter().copied()
+    }
+
+    /// 集計間隔毎の FPS (古い順)
+    pub fn fps_history(&self) -> impl Iterator<Item = f64> + '_ {
+        self.fps_history.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.periods.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.periods.is_empty()
+    }
+
+    /// フレーム周期の統計
+    pub fn stats(&self) -> TimingStats {
+        let n = self.periods.len();
+        if n == 0 {
+            return TimingStats { expected_us: self.expected_us, skipped: self.skipped, ..Default::default() };
+        }
+        let mean = self.periods.iter().sum::<f64>() / n as f64;
+        let var = self.periods.iter().map(|p| (p - mean) * (p - mean)).sum::<f64>() / n as f64;
+        TimingStats {
+            count: n,
+            mean_us: mean,
+            min_us: self.periods.iter().copied().fold(f64::INFINITY, f64::min),
+            max_us: self.periods.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            stddev_us: var.sqrt(),
+            expected_us: self.expected_us,
+            error_us: self.expected_us.map(|e| mean - e),
+            skipped: self.skipped,
+        }
+    }
+
+    /// FPS 履歴の統計 (単位は fps)
+    pub fn fps_stats(&self) -> (f64, f64, f64) {
+        let n = self.fps_history.len();
+        if n == 0 {
+            return (0.0, 0.0, 0.0);
+        }
+        let mean = self.fps_history.iter().sum::<f64>() / n as f64;
+        let min = self.fps_history.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = self.fps_history.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        (mean, min, max)
+    }
+
+    /// 設定周期 (未設定なら平均) を中心にしたヒストグラム
+    pub fn histogram(&self, bin_us: f64, bins: usize) -> TimingHistogram {
+        let center = self.expected_us.unwrap_or_else(|| self.stats().mean_us);
+        let start_us = center - bin_us * bins as f64 / 2.0;
+        let mut hist = TimingHistogram { start_us, bin_us, counts: vec![0; bins], underflow: 0, overflow: 0 };
+        if bin_us <= 0.0 || bins == 0 {
+            return hist;
+        }
+        for p in self.periods.iter() {
+            let pos = ((p - start_us) / bin_us).floor();
+            if pos < 0.0 {
+                hist.underflow += 1;
+            } else if pos as usize >= bins {
+                hist.overflow += 1;
+            } else {
+                hist.counts[pos as usize] += 1;
+            }
+        }
+        hist
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 カウント 1 us
+    fn monitor(periods: &[usize]) -> TimingMonitor {
+        let mut mon = TimingMonitor::new(1_000_000.0, 16);
+        mon.add_sample(0, 0);
+        for (i, &p) in periods.iter().enumerate() {
+            mon.add_sample(p, i + 1);
+        }
+        mon
+    }
+
+    #[test]
+    fn stats_mean_and_jitter() {
+        let mon = monitor(&[1000, 1002, 998]);
+        let stats = mon.stats();
+        assert_eq!(stats.count, 3);
+        assert!((stats.mean_us - 1000.0).abs() < 1e-9);
+        assert_eq!(stats.min_us, 998.0);
+        assert_eq!(stats.max_us, 1002.0);
+        assert!((stats.jitter_pp_us() - 4.0).abs() < 1e-9);
+        assert!((stats.stddev_us - (8.0f64 / 3.0).sqrt()).abs() < 1e-9);
+        assert!((stats.mean_fps() - 1000.0).abs() < 1e-9);
+        assert_eq!(stats.expected_us, None);
+        assert_eq!(stats.error_us, None);
+    }
+
+    #[test]
+    fn stats_expected_error() {
+        let mut mon = monitor(&[1010, 1010]);
+        mon.set_expected_period_us(Some(1000.0));
+        let stats = mon.stats();
+        assert!((stats.error_us.unwrap() - 10.0).abs() < 1e-9);
+        assert_eq!(stats.to_csv(), "2,1010.0000,1010.0000,1010.0000,0.0000,1000.0000,10.0000,0");
+    }
+
+    #[test]
+    fn stats_empty() {
+        let mut mon = TimingMonitor::new(1_000_000.0, 16);
+        mon.set_expected_period_us(Some(500.0));
+        let stats = mon.stats();
+        assert_eq!(stats.count, 0);
+        assert_eq!(stats.expected_us, Some(500.0));
+        assert_eq!(stats.mean_fps(), 0.0);
+        assert_eq!(stats.to_csv(), "0,0.0000,0.0000,0.0000,0.0000,500.0000,,0");
+    }
+
+    #[test]
+    fn sample_needs_new_frame() {
+        let mut mon = TimingMonitor::new(1_000_000.0, 16);
+        // 最初のサンプルは基準にするだけ
+        mon.add_sample(1000, 10);
+        assert!(mon.is_empty());
+        // フレームカウンタが進まなければ記録しない
+        mon.add_sample(1000, 10);
+        assert!(mon.is_empty());
+        // 周期 0 (未計測) はフレームだけ進める
+        mon.add_sample(0, 11);
+        assert!(mon.is_empty());
+        mon.add_sample(1000, 12);
+        assert_eq!(mon.periods().collect::<Vec<_>>(), vec![1000.0]);
+        assert_eq!(mon.stats().skipped, 0);
+    }
+
+    #[test]
+    fn sample_counts_skipped_frames() {
+        let mut mon = TimingMonitor::new(1_000_000.0, 16);
+        mon.add_sample(0, 0xffff_fffe);
+        mon.add_sample(1000, 0xffff_ffff);
+        // カウンタが一周して 3 フレーム進んだ
+        mon.add_sample(1000, 2);
+        assert_eq!(mon.len(), 2);
+        assert_eq!(mon.stats().skipped, 2);
+
+        mon.clear();
+        assert!(mon.is_empty());
+        assert_eq!(mon.stats().skipped, 0);
+    }
+
+    #[test]
+    fn capacity_keeps_latest() {
+        let mut mon = TimingMonitor::new(2_000_000.0, 2);
+        mon.add_sample(0, 0);
+        mon.add_sample(2000, 1);
+        mon.add_sample(4000, 2);
+        mon.add_sample(6000, 3);
+        assert_eq!(mon.periods().collect::<Vec<_>>(), vec![2000.0, 3000.0]);
+    }
+
+    #[test]
+    fn histogram_bins() {
+        let mut mon = monitor(&[997, 998, 999, 1000, 1000, 1001, 1002]);
+        mon.set_expected_period_us(Some(1000.0));
+        let hist = mon.histogram(1.0, 4);
+        // 998..1002 を 4 ビン
+        assert_eq!(hist.start_us, 998.0);
+        assert_eq!(hist.counts, vec![1, 1, 2, 1]);
+        assert_eq!(hist.underflow, 1);
+        assert_eq!(hist.overflow, 1);
+        assert_eq!(hist.bin_center(0), 998.5);
+    }
+
+    #[test]
+    fn histogram_centers_on_mean() {
+        let mon = monitor(&[100, 110, 120]);
+        let hist = mon.histogram(10.0, 2);
+        assert_eq!(hist.start_us, 100.0);
+        assert_eq!(hist.counts, vec![1, 1]);
+        assert_eq!(hist.overflow, 1);
+
+        // ビン指定が不正なら空
+        let hist = mon.histogram(0.0, 4);
+        assert_eq!(hist.counts, vec![0; 4]);
+        assert_eq!(hist.underflow + hist.overflow, 0);
+    }
+}
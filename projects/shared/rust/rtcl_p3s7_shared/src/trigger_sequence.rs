@@ -0,0 +1,358 @@
+#![allow(dead_code)]
+
+use std::time::{Duration, Instant};
+
+use jelly_mem_access::*;
+
+use crate::camera_driver::{frame_count_add, frame_count_diff};
+use crate::timing_generator_driver::TimingGeneratorDriver;
+
+type Result<T> = core::result::Result<T, Box<dyn std::error::Error>>;
+
+/// トリガシーケンスの 1 ステップ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TriggerStep {
+    pub period_us: f64,
+    pub exposure_us: f64,
+}
+
+impl TriggerStep {
+    pub fn new(period_us: f64, exposure_us: f64) -> Self {
+        Self { period_us, exposure_us }
+    }
+}
+
+/// 繰り返し再生する (周期, 露光) のリスト
+#[derive(Debug, Clone, PartialEq)]
+pub struct TriggerSequence {
+    steps: Vec<TriggerStep>,
+    repeat: Option<usize>,
+}
+
+impl TriggerSequence {
+    /// ステップ列を無限に繰り返すシーケンス
+    pub fn new(steps: Vec<TriggerStep>) -> Result<Self> {
+        if steps.is_empty() {
+            return Err("trigger sequence is empty".into());
+        }
+        for (i, step) in steps.iter().enumerate() {
+            if !(step.period_us > 0.0 && step.exposure_us > 0.0 && step.exposure_us < step.period_us) {
+                return Err(format!("invalid trigger step{}: period {} us, exposure {} us",
+                                    i, step.period_us, step.exposure_us).into());
+            }
+        }
+        Ok(Self { steps, repeat: None })
+    }
+
+    /// 一定周期で露光だけを切り替える露光ブラケット
+    pub fn bracketing(period_us: f64, exposures_us: &[f64]) -> Result<Self> {
+        Self::new(exposures_us.iter().map(|&e| TriggerStep::new(period_us, e)).collect())
+    }
+
+    /// 繰り返し回数を指定 (None で無限)
+    pub fn with_repeat(mut self, repeat: Option<usize>) -> Self {
+        self.repeat = repeat;
+        self
+    }
+
+    pub fn steps(&self) -> &[TriggerStep] {
+        &self.steps
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn repeat(&self) -> Option<usize> {
+        self.repeat
+    }
+
+    /// 全フレーム数 (無限の場合は None)
+    pub fn total_frames(&self) -> Option<usize> {
+        self.repeat.map(|r| r * self.steps.len())
+    }
+}
+
+/// シーケンス再生中に出力したトリガ (フレーム) の記録
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SequenceFrame {
+    /// シーケンス開始からのトリガ番号
+    pub frame: usize,
+    /// このトリガで撮像されるフレームのカメラのフレームカウンタ値 (推定)
+    pub frame_count: usize,
+    /// 繰り返し回数
+    pub cycle: usize,
+    /// ステップ番号
+    pub step: usize,
+    pub period_us: f64,
+    pub exposure_us: f64,
+    /// poll が遅れて同じステップを繰り返し出力したフレーム
+    pub repeated: bool,
+}
+
+/// TriggerSequence をソフトウェアタイミングで再生する
+///
+/// ゲートウェアのタイミングジェネレータは設定スロットが 1 つなので、
+/// 現在の周期中に次のステップを書き込んで update を立て、周期境界で
+/// 取り込まれた (update が落ちた) ことを poll で検出して次を積む。
+/// poll が遅れて周期境界をまたいだ場合は同じ設定のフレームが
+/// 追加で出力されるので、タイマ値から経過周期数を推定して記録に残す。
+/// カメラはトリガモードで動かしておくこと。
+///
+/// 開始時のカメラのフレームカウンタ値を覚えておき、トリガ毎に 1 フレーム
+/// 撮像される前提でフレームカウンタ値 (および CaptureDriver の録画インデックス)
+/// と対応付ける。
+pub struct TriggerSequencer {
+    sequence: TriggerSequence,
+    frames: Vec<SequenceFrame>,
+    start_count: usize,
+    active: usize,
+    active_cycle: usize,
+    queued: Option<(usize, usize)>,
+    boundary: Instant,
+    stopping: bool,
+    running: bool,
+}
+
+impl TriggerSequencer {
+    pub fn new(sequence: TriggerSequence) -> Self {
+        Self {
+            sequence,
+            frames: Vec::new(),
+            start_count: 0,
+            active: 0,
+            active_cycle: 0,
+            queued: None,
+            boundary: Instant::now(),
+            stopping: false,
+            running: false,
+        }
+    }
+
+    pub fn sequence(&self) -> &TriggerSequence {
+        &self.sequence
+    }
+
+    /// 再生開始 (タイミングジェネレータは一旦停止して先頭から始める)
+    ///
+    /// `frame_count` には停止後のカメラのフレームカウンタ値
+    /// (`CameraDriver::frame_count`) を渡す。
+    pub fn start<T: MemAccess>(&mut self, timgen: &mut TimingGeneratorDriver<T>, frame_count: usize) -> Result<()> {
+        timgen.stop();
+        timgen.wait_stop(Duration::from_secs(1))?;
+
+        self.frames.clear();
+        self.start_count = frame_count;
+        self.stopping = false;
+        let step = self.sequence.steps[0];
+        timgen.update_timing(step.period_us, step.exposure_us)?;
+        timgen.start();
+        self.boundary = Instant::now();
+        self.running = true;
+        self.activate(0, 0, false);
+        self.queue_next(timgen)?;
+        Ok(())
+    }
+
+    /// 周期境界の検出と次ステップの書き込み
+    ///
+    /// 再生中なら true を返す。周期より十分短い間隔で呼ぶこと。
+    pub fn poll<T: MemAccess>(&mut self, timgen: &mut TimingGeneratorDriver<T>) -> Result<bool> {
+        if !self.running {
+            return Ok(false);
+        }
+        if self.stopping {
+            if !timgen.running() {
+                self.running = false;
+            }
+            return Ok(self.running);
+        }
+        if timgen.update_pending() {
+            return Ok(true);
+        }
+
+        // 取り込まれた周期境界の時刻をタイマ値から逆算
+        let now = Instant::now();
+        let boundary = now.checked_sub(Duration::from_secs_f64(timgen.timer_us() / 1_000_000.0)).unwrap_or(now);
+
+        // 書き込んだステップは直前ステップ 1 周期後の境界で取り込まれており、
+        // poll が遅れた分はそのステップが繰り返し出力されている
+        let prev = self.sequence.steps[self.active];
+        let elapsed_us = boundary.saturating_duration_since(self.boundary).as_secs_f64() * 1_000_000.0;
+        self.boundary = boundary;
+        if let Some((step, cycle)) = self.queued.take() {
+            let cur = self.sequence.steps[step];
+            let extra = ((elapsed_us - prev.period_us) / cur.period_us).round().max(0.0) as usize;
+            self.activate(step, cycle, false);
+            for _ in 0..extra {
+                self.activate(step, cycle, true);
+            }
+        }
+        self.queue_next(timgen)?;
+        Ok(true)
+    }
+
+    /// 再生中止 (現在の周期の終わりで止まる)
+    pub fn stop<T: MemAccess>(&mut self, timgen: &mut TimingGeneratorDriver<T>) {
+        timgen.stop();
+        self.queued = None;
+        self.stopping = true;
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// これまでに出力したフレームの記録
+    pub fn frames(&self) -> &[SequenceFrame] {
+        &self.frames
+    }
+
+    /// シーケンス開始からのトリガ番号に対応する記録
+    pub fn frame(&self, frame: usize) -> Option<&SequenceFrame> {
+        self.frames.get(frame)
+    }
+
+    /// 開始時のカメラのフレームカウンタ値
+    pub fn start_frame_count(&self) -> usize {
+        self.start_count
+    }
+
+    /// カメラのフレームカウンタ値に対応する記録
+    pub fn frame_by_count(&self, frame_count: usize) -> Option<&SequenceFrame> {
+        frame_count_diff(frame_count, self.start_count).checked_sub(1).and_then(|i| self.frames.get(i))
+    }
+
+    /// 録画インデックスに対応する記録
+    ///
+    /// `record_start_count` は録画開始時のフレームカウンタ値
+    /// (`CaptureDriver::record_start_frame_count`)。
+    pub fn frame_by_capture_index(&self, record_start_count: usize, index: usize) -> Option<&SequenceFrame> {
+        self.frame_by_count(frame_count_add(record_start_count, index + 1))
+    }
+
+    /// 記録に対応する録画インデックス (録画開始前のフレームは None)
+    pub fn capture_index(&self, frame: &SequenceFrame, record_start_count: usize) -> Option<usize> {
+        let diff = frame_count_diff(frame.frame_count, record_start_count);
+        if diff == 0 || diff > u32::MAX as usize / 2 {
+            return None;
+        }
+        Some(diff - 1)
+    }
+
+    fn activate(&mut self, step: usize, cycle: usize, repeated: bool) {
+        let s = self.sequence.steps[step];
+        self.frames.push(SequenceFrame {
+            frame: self.frames.len(),
+            frame_count: frame_count_add(self.start_count, self.frames.len() + 1),
+            cycle,
+            step,
+            period_us: s.period_us,
+            exposure_us: s.exposure_us,
+            repeated,
+        });
+        self.active = step;
+        self.active_cycle = cycle;
+    }
+
+    fn queue_next<T: MemAccess>(&mut self, timgen: &mut TimingGeneratorDriver<T>) -> Result<()> {
+        let mut step  = self.active + 1;
+        let mut cycle = self.active_cycle;
+        if step >= self.sequence.steps.len() {
+            step = 0;
+            cycle += 1;
+        }
+
+        if let Some(repeat) = self.sequence.repeat {
+            if cycle >= repeat {
+                // 最後のステップを出力中なので周期の終わりで停止
+                self.stop(timgen);
+                return Ok(());
+            }
+        }
+
+        let s = self.sequence.steps[step];
+        timgen.update_timing(s.period_us, s.exposure_us)?;
+        self.queued = Some((step, cycle));
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_rejects_invalid_steps() {
+        assert!(TriggerSequence::new(vec![]).is_err());
+        assert!(TriggerSequence::new(vec![TriggerStep::new(0.0, 0.0)]).is_err());
+        assert!(TriggerSequence::new(vec![TriggerStep::new(1000.0, 0.0)]).is_err());
+        assert!(TriggerSequence::new(vec![TriggerStep::new(1000.0, -10.0)]).is_err());
+        assert!(TriggerSequence::new(vec![TriggerStep::new(1000.0, 1000.0)]).is_err());
+        assert!(TriggerSequence::new(vec![TriggerStep::new(1000.0, 500.0), TriggerStep::new(1000.0, 1500.0)]).is_err());
+        assert!(TriggerSequence::new(vec![TriggerStep::new(1000.0, f64::NAN)]).is_err());
+        assert!(TriggerSequence::new(vec![TriggerStep::new(1000.0, 999.0)]).is_ok());
+        assert!(TriggerSequence::bracketing(1000.0, &[]).is_err());
+        assert!(TriggerSequence::bracketing(1000.0, &[100.0, 1000.0]).is_err());
+    }
+
+    #[test]
+    fn sequence_total_frames() {
+        let seq = TriggerSequence::bracketing(10000.0, &[100.0, 1000.0, 5000.0]).unwrap();
+        assert_eq!(seq.len(), 3);
+        assert_eq!(seq.repeat(), None);
+        assert_eq!(seq.total_frames(), None);
+
+        let seq = seq.with_repeat(Some(4));
+        assert_eq!(seq.total_frames(), Some(12));
+        assert_eq!(seq.with_repeat(Some(0)).total_frames(), Some(0));
+    }
+
+    fn sequencer(start_count: usize, frames: usize) -> TriggerSequencer {
+        let seq = TriggerSequence::bracketing(10000.0, &[100.0, 1000.0]).unwrap();
+        let mut sequencer = TriggerSequencer::new(seq);
+        sequencer.start_count = start_count;
+        for i in 0..frames {
+            sequencer.activate(i % 2, i / 2, false);
+        }
+        sequencer
+    }
+
+    #[test]
+    fn frame_count_mapping() {
+        let s = sequencer(100, 4);
+        assert_eq!(s.frames()[0].frame_count, 101);
+        assert_eq!(s.frames()[3].frame_count, 104);
+        assert_eq!(s.frame_by_count(100), None);
+        assert_eq!(s.frame_by_count(101).unwrap().frame, 0);
+        assert_eq!(s.frame_by_count(104).unwrap().step, 1);
+        assert_eq!(s.frame_by_count(105), None);
+        assert_eq!(s.frame_by_count(50), None);
+    }
+
+    #[test]
+    fn capture_index_mapping() {
+        // 録画はシーケンスの 2 フレーム目から
+        let s = sequencer(100, 4);
+        let rec = 101;
+        assert_eq!(s.capture_index(&s.frames()[0], rec), None);
+        assert_eq!(s.capture_index(&s.frames()[1], rec), Some(0));
+        assert_eq!(s.capture_index(&s.frames()[3], rec), Some(2));
+        assert_eq!(s.frame_by_capture_index(rec, 0).unwrap().frame, 1);
+        assert_eq!(s.frame_by_capture_index(rec, 3), None);
+    }
+
+    #[test]
+    fn frame_count_mapping_wraps() {
+        let s = sequencer(0xffff_fffe, 4);
+        assert_eq!(s.frames()[0].frame_count, 0xffff_ffff);
+        assert_eq!(s.frames()[1].frame_count, 0);
+        assert_eq!(s.frame_by_count(1).unwrap().frame, 2);
+        assert_eq!(s.capture_index(&s.frames()[3], 0xffff_ffff), Some(2));
+    }
+}
@@ -1,19 +1,15 @@
 use std::error::Error;
 
 use clap::Parser;
-use jelly_lib::linux_i2c::LinuxI2c;
 use jelly_mem_access::*;
 
-use rtcl_p3s7_shared::camera_driver::*;
+use rtcl_p3s7_shared::board::Board;
 use rtcl_p3s7_shared::capture_driver::*;
 use rtcl_p3s7_shared::timing_generator_driver::TimingGeneratorDriver;
 
 use opencv::*;
 use opencv::core::*;
 
-const ZYBO_DPHY_SPEED_BPS: f64 = 950_000_000.0;
-const ZYBO_FPS_COUNTER_CLOCK_HZ: f32 = 200_000_000.0;
-
 /// ZYBO Z7 RTCL P3S7 High Speed Camera Application
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -33,6 +29,10 @@ struct Args {
     /// Frame Rate
     #[arg(short = 'f', long, default_value_t = 60)]
     fps: usize,
+
+    /// Board (zybo_z7, kv260, auto or board file)
+    #[arg(long, default_value = "zybo_z7")]
+    board: String,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -43,8 +43,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("  width:  {}", args.width);
     println!("  height: {}", args.height);
     println!("  color:  {}", args.color);
-    println!("  dphy_speed[bps]: {}", ZYBO_DPHY_SPEED_BPS);
-    println!("  fps_counter_clock[Hz]: {}", ZYBO_FPS_COUNTER_CLOCK_HZ);
+
+    let board = Board::select(Some(&args.board))?;
+    println!("  board:  {}", board.name);
+    println!("  dphy_speed[bps]: {}", board.dphy_speed);
+    println!("  fps_counter_clock[Hz]: {}", board.fps_counter_clock_hz);
 
     let width = args.width;
     let height = args.height;
@@ -57,48 +60,22 @@ fn main() -> Result<(), Box<dyn Error>> {
         r.store(false, std::sync::atomic::Ordering::SeqCst);
     })?;
 
-    // mmap udmabuf
-    let udmabuf_device_name = "udmabuf-jelly-vram0";
-    println!("\nudmabuf open");
-    let udmabuf_acc =
-        UdmabufAccessor::<usize>::new(udmabuf_device_name, false).expect("Failed to open udmabuf");
-    println!(
-        "{} phys addr : 0x{:x}",
-        udmabuf_device_name,
-        udmabuf_acc.phys_addr()
-    );
-    println!(
-        "{} size      : 0x{:x}",
-        udmabuf_device_name,
-        udmabuf_acc.size()
-    );
-
-    // UIO
-    println!("\nuio open");
-    let uio_acc = UioAccessor::<usize>::new_with_name("uio_pl_peri").expect("Failed to open uio");
-    println!("uio_pl_peri phys addr : 0x{:x}", uio_acc.phys_addr());
-    println!("uio_pl_peri size      : 0x{:x}", uio_acc.size());
-
-    let reg_sys      = uio_acc.subclone(0x00000000, 0x400);
-    let reg_timgen   = uio_acc.subclone(0x00010000, 0x400);
-    let reg_fmtr     = uio_acc.subclone(0x00100000, 0x400);
-    let reg_wdma_img = uio_acc.subclone(0x00210000, 0x400);
-    let reg_wdma_blk = uio_acc.subclone(0x00220000, 0x400);
+    // I2C, udmabuf, UIO
+    let dev = board.open()?;
+    println!("\n{} phys addr : 0x{:x}", board.udmabuf_img, dev.buf_img.phys_addr());
+    println!("{} size      : 0x{:x}", board.udmabuf_img, dev.buf_img.size());
+    println!("{} phys addr : 0x{:x}", board.uio, dev.uio.phys_addr());
+    println!("{} size      : 0x{:x}", board.uio, dev.uio.size());
 
     println!("CORE ID");
-    println!("reg_sys      : {:08x}", unsafe { reg_sys.read_reg(0) });
-    println!("reg_timgen   : {:08x}", unsafe { reg_timgen.read_reg(0) });
-    println!("reg_fmtr     : {:08x}", unsafe { reg_fmtr.read_reg(0) });
-    println!("reg_wdma_img : {:08x}", unsafe { reg_wdma_img.read_reg(0) });
-    println!("reg_wdma_blk : {:08x}", unsafe { reg_wdma_blk.read_reg(0) });
-
-    let mut timgen = TimingGeneratorDriver::new(reg_timgen);
-
-    let i2c = LinuxI2c::new("/dev/i2c-0", 0x10)?;
-    let mut cam = CameraDriver::new(i2c, reg_sys, reg_fmtr);
-    cam.set_sensor_pgood_enable(false);
-    cam.set_dphy_speed(ZYBO_DPHY_SPEED_BPS);
-    cam.set_fps_counter_clock_hz(ZYBO_FPS_COUNTER_CLOCK_HZ);
+    println!("reg_sys      : {:08x}", unsafe { dev.reg_sys.read_reg(0) });
+    println!("reg_timgen   : {:08x}", unsafe { dev.reg_timgen.read_reg(0) });
+    println!("reg_fmtr     : {:08x}", unsafe { dev.reg_fmtr.read_reg(0) });
+    println!("reg_wdma_img : {:08x}", unsafe { dev.reg_wdma_img.read_reg(0) });
+    println!("reg_wdma_blk : {:08x}", unsafe { dev.reg_wdma_blk.read_reg(0) });
+
+    let mut timgen = TimingGeneratorDriver::new(dev.reg_timgen);
+    let mut cam = board.camera_driver(dev.i2c, dev.reg_sys, dev.reg_fmtr);
     cam.set_color(color);
     cam.set_black_lines(1)?;
     cam.set_image_size(width, height)?;
@@ -112,7 +89,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("camera sensor id      : {:04x}", cam.sensor_id()?);
 
 
-    let mut video_capture = CaptureDriver::new(reg_wdma_img, udmabuf_acc.clone())?;
+    let mut video_capture = CaptureDriver::new(dev.reg_wdma_img, dev.buf_img.clone())?;
 
     // ウィンドウ作成
     highgui::named_window("img", highgui::WINDOW_AUTOSIZE)?;
@@ -4,8 +4,11 @@ use clap::Parser;
 use jelly_lib::linux_i2c::LinuxI2c;
 use jelly_mem_access::*;
 
+use rtcl_p3s7_shared::awb::{AutoWhiteBalance, AwbMode};
+use rtcl_p3s7_shared::color::CfaPattern;
 use rtcl_p3s7_shared::camera_driver::CameraDriver;
 use rtcl_p3s7_shared::capture_driver::CaptureDriver;
+use rtcl_p3s7_shared::pixel_format::PixelFormat;
 use rtcl_p3s7_shared::timing_generator_driver::TimingGeneratorDriver;
 
 use opencv::*;
@@ -128,6 +131,13 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("camera sensor id      : {:04x}", cam.sensor_id()?);
 
     let mut video_capture = CaptureDriver::new(reg_wdma_img, udmabuf_acc.clone())?;
+    if color {
+        video_capture.set_pixel_format(PixelFormat::BayerRggb);
+    }
+
+    // カラー時のホワイトバランス
+    let mut awb = AutoWhiteBalance::new(CfaPattern::Rggb);
+    let mut awb_failed = false;
 
     // ウィンドウ作成
     highgui::named_window("img", highgui::WINDOW_AUTOSIZE)?;
@@ -159,7 +169,23 @@ fn main() -> Result<(), Box<dyn Error>> {
 
         // CaptureDriver で 1frame キャプチャ
         video_capture.record(width, height, 1)?;
-        let img = video_capture.read_image_mat(0)?;
+        let mut img = video_capture.read_image_mat(0)?;
+
+        // ソフトウェア AWB
+        if color {
+            let raw = img.data_typed_mut::<u16>()?;
+            match awb.update(raw, width, height) {
+                Ok(_) => awb_failed = false,
+                Err(e) => {
+                    // 同じエラーを毎フレーム出さない
+                    if !awb_failed {
+                        println!("awb : {}", e);
+                    }
+                    awb_failed = true;
+                }
+            }
+            awb.apply_raw(raw, width, awb.gains(), 1023);
+        }
 
         // 10bit 画像なので加工して表示
         let mut view = Mat::default();
@@ -177,6 +203,15 @@ fn main() -> Result<(), Box<dyn Error>> {
         let ch = key as u8 as char;
         match ch {
             'q' => { break; },
+            'w' => {
+                awb.set_lock(!awb.locked());
+                println!("awb lock : {} (gains {:?})", awb.locked(), awb.gains());
+            },
+            'b' => {
+                let mode = if awb.mode() == AwbMode::GrayWorld { AwbMode::WhitePatch } else { AwbMode::GrayWorld };
+                awb.set_mode(mode);
+                println!("awb mode : {:?}", mode);
+            },
             'p' => {
                 println!("fps : {:8.3} ({:8.3} ns)", cam.measure_fps(), cam.measure_frame_period());
             },
@@ -6,6 +6,7 @@ use jelly_mem_access::*;
 
 use rtcl_p3s7_shared::camera_driver::*;
 use rtcl_p3s7_shared::capture_driver::*;
+use rtcl_p3s7_shared::pixel_format::PixelFormat;
 use rtcl_p3s7_shared::timing_generator_driver::TimingGeneratorDriver;
 
 const ZYBO_DPHY_SPEED_BPS: f64 = 950_000_000.0;
@@ -141,6 +142,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("camera sensor id      : {:04x}", cam.sensor_id()?);
 
     let mut video_capture = CaptureDriver::new(reg_wdma_img, udmabuf_acc.clone())?;
+    video_capture.set_pixel_format(PixelFormat::Planes8x2);
 
     // ウィンドウ作成
     highgui::named_window("img", highgui::WINDOW_AUTOSIZE)?;
@@ -204,19 +206,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
         // CaptureDriver で 1frame キャプチャ
         video_capture.record(width, height, 1)?;
-        let src_bytes = video_capture.read_image_vec(0)?;
-        let mut img_bytes = vec![0u8; height * width];
-        let mut cls_bytes = vec![0u8; height * width];
-        for y in 0..height {
-            for x in 0..width {
-                img_bytes[y*width+x] = src_bytes[(y*width+x)*2];
-                cls_bytes[y*width+x] = src_bytes[(y*width+x)*2+1];
-            }
-        }
-        let img = Mat::from_slice(&img_bytes)?;
-        let img = img.reshape(1, height as i32)?;
-        let class = Mat::from_slice(&cls_bytes)?;
-        let class = class.reshape(1, height as i32)?;
+        let (img, class) = video_capture.read_planes_mat(0)?;
         let mut cls = Mat::zeros_size(Size::new(width as i32, height as i32), CV_8UC3)?.to_mat()?;
         // クラスごとに色付け
         for y in 0..height as i32 {
@@ -266,7 +256,13 @@ fn main() -> Result<(), Box<dyn Error>> {
                 let frames = 100;
                 video_capture.record(width, height, frames)?;
                 for f in 0..frames {
-                   let img = video_capture.read_image_mat(f)?;
+                    // 2 プレーンを詰めた 16bit ワードのまま x64 して保存
+                    let words: Vec<u16> = video_capture.read_image_vec(f)?
+                        .chunks_exact(2)
+                        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                        .collect();
+                    let img = Mat::from_slice(&words)?;
+                    let img = img.reshape(1, height as i32)?;
                     let mut view = Mat::default();
                     img.convert_to(&mut view, CV_16U, 64.0, 0.0)?;
                     let file_name = format!("{}/img{:04}.png", dir_name, f);